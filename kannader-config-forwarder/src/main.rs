@@ -54,6 +54,25 @@ struct QueueCfg {
 struct ServerCfg {
     cert_path: PathBuf,
     key_path: PathBuf,
+    #[serde(default)]
+    tls_certificates: Vec<kannader_types::TlsCertificate>,
+    tls_handler: Option<kannader_types::TlsHandler>,
+    tls_profile: Option<kannader_types::TlsProfile>,
+    tls_client_ca_file: Option<PathBuf>,
+    #[serde(default)]
+    dkim: Vec<kannader_types::DkimSigningKey>,
+    arc_sealing: Option<kannader_types::ArcSealing>,
+    dmarc_reporting: Option<kannader_types::DmarcReporting>,
+    max_message_size: Option<u64>,
+    #[serde(default)]
+    milters: Vec<kannader_types::Milter>,
+    quarantine_dir: Option<PathBuf>,
+    content_filter: Option<kannader_types::ContentFilter>,
+    antivirus: Option<kannader_types::Antivirus>,
+    spam_scanner: Option<kannader_types::SpamScanner>,
+    after_queue_filter: Option<kannader_types::AfterQueueFilter>,
+    greylisting: Option<kannader_types::Greylisting>,
+    authserv_id: Option<String>,
 }
 
 impl kannader_config::Config for Config {
@@ -111,6 +130,103 @@ impl kannader_config::ServerConfig for ServerConfig {
         cfg.server.key_path.clone()
     }
 
+    fn tls_certificates(cfg: &Config) -> Vec<kannader_types::TlsCertificate> {
+        cfg.server.tls_certificates.clone()
+    }
+
+    fn tls_handler(cfg: &Config) -> kannader_types::TlsHandler {
+        cfg.server
+            .tls_handler
+            .unwrap_or(kannader_types::TlsHandler::Rustls)
+    }
+
+    fn tls_profile(cfg: &Config) -> kannader_types::TlsProfile {
+        cfg.server
+            .tls_profile
+            .clone()
+            .unwrap_or(kannader_types::TlsProfile::Compatible)
+    }
+
+    fn tls_client_ca_file(cfg: &Config) -> Option<PathBuf> {
+        cfg.server.tls_client_ca_file.clone()
+    }
+
+    fn dkim_signing_keys(cfg: &Config) -> Vec<kannader_types::DkimSigningKey> {
+        cfg.server.dkim.clone()
+    }
+
+    fn dmarc_reporting(cfg: &Config) -> Option<kannader_types::DmarcReporting> {
+        cfg.server.dmarc_reporting.clone()
+    }
+
+    fn max_message_size(cfg: &Config) -> u64 {
+        cfg.server.max_message_size.unwrap_or(64 * 1024 * 1024)
+    }
+
+    fn milters(cfg: &Config) -> Vec<kannader_types::Milter> {
+        cfg.server.milters.clone()
+    }
+
+    fn quarantine_dir(cfg: &Config) -> Option<PathBuf> {
+        cfg.server.quarantine_dir.clone()
+    }
+
+    fn content_filter(cfg: &Config) -> Option<kannader_types::ContentFilter> {
+        cfg.server.content_filter.clone()
+    }
+
+    fn antivirus(cfg: &Config) -> Option<kannader_types::Antivirus> {
+        cfg.server.antivirus.clone()
+    }
+
+    fn spam_scanner(cfg: &Config) -> Option<kannader_types::SpamScanner> {
+        cfg.server.spam_scanner.clone()
+    }
+
+    fn after_queue_filter(cfg: &Config) -> Option<kannader_types::AfterQueueFilter> {
+        cfg.server.after_queue_filter.clone()
+    }
+
+    fn greylisting(cfg: &Config) -> Option<kannader_types::Greylisting> {
+        cfg.server.greylisting.clone()
+    }
+
+    fn authserv_id(cfg: &Config) -> Option<String> {
+        cfg.server.authserv_id.clone()
+    }
+
+    fn arc_sealing(cfg: &Config) -> Option<kannader_types::ArcSealing> {
+        cfg.server.arc_sealing.clone()
+    }
+
+    fn dkim_signing_domain(
+        cfg: &Config,
+        meta: &mut server::MailMeta,
+        _conn_meta: &mut server::ConnMeta,
+    ) -> Option<String> {
+        // Sign on behalf of the MAIL FROM domain, if we have a key for it
+        let domain = match meta.from.as_ref()?.hostname.as_ref()? {
+            Hostname::AsciiDomain { raw } => raw,
+            Hostname::Utf8Domain { punycode, .. } => punycode,
+            Hostname::Ipv4 { .. } | Hostname::Ipv6 { .. } => return None,
+        };
+        cfg.server
+            .dkim
+            .iter()
+            .find(|k| k.domain.eq_ignore_ascii_case(domain))
+            .map(|k| k.domain.clone())
+    }
+
+    fn arc_sealing_domain(
+        cfg: &Config,
+        _meta: &mut server::MailMeta,
+        _conn_meta: &mut server::ConnMeta,
+    ) -> Option<String> {
+        // Everything is relayed, so seal all mail with our own key
+        let sealing = cfg.server.arc_sealing.as_ref()?;
+        sealing.keys.first().map(|k| k.domain.clone())
+    }
+
     fn welcome_banner_reply(_cfg: &Config, _conn_meta: &mut server::ConnMeta) -> Reply {
         reply::welcome_banner("localhost", "Service ready")
     }
@@ -127,8 +243,14 @@ impl kannader_config::ServerConfig for ServerConfig {
             hostname: hostname.clone(),
         });
         server::SerializableDecision::Accept {
-            reply: reply::okay_hello(is_extended, "localhost", "", Self::can_do_tls(cfg, cm))
-                .convert(),
+            reply: reply::okay_hello(
+                is_extended,
+                "localhost",
+                "",
+                Self::can_do_tls(cfg, cm),
+                conn_meta.is_encrypted,
+            )
+            .convert(),
             res: server::HelloInfo {
                 is_extended,
                 hostname,
@@ -700,10 +700,36 @@ static CLIENT_CONFIG: fn() -> Communicator = communicator! {
             false
         }
 
-        fn tls_handler(&self) -> (kannader_types::TlsHandler) {
+        // TLS implementation with which to talk to the servers of `domain`
+        fn tls_handler(&self, domain: () String) -> (kannader_types::TlsHandler) {
             kannader_types::TlsHandler::Rustls
         }
 
+        // TLS parameters allowed when connecting to other servers
+        fn tls_profile(&self) -> (kannader_types::TlsProfile) {
+            kannader_types::TlsProfile::Compatible
+        }
+
+        fn tls_policy(&self, domain: () String) -> (kannader_types::OutboundTlsPolicy) {
+            kannader_types::OutboundTlsPolicy::Opportunistic
+        }
+
+        // Certificate to present to the servers of `domain`, if any
+        fn tls_client_certificate(
+            &self,
+            domain: () String,
+        ) -> (Option<kannader_types::TlsClientCertificate>) {
+            None
+        }
+
+        fn tls_reporting(&self) -> (Option<kannader_types::TlsReporting>) {
+            None
+        }
+
+        fn can_do_mta_sts(&self) -> (bool) {
+            true
+        }
+
         fn banner_read_timeout_in_millis(&self) -> (i64) {
             // 5 minutes in ms
             5 * 60 * 1000
@@ -832,25 +858,119 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
         // TODO: THIS HAS THE CONFUSED DEPUTY PROBLEM! Figure out how
         // to not have it. For now it's probably not too bad as it's
         // only calling from config anyway.
-        // TODO: also support setting SNI
+        // Certificate served when the client asks for no name through SNI,
+        // or for one none of `tls_certificates` is for
         fn tls_cert_file(&self) -> (std::path::PathBuf) ;
         fn tls_key_file(&self) -> (std::path::PathBuf) ;
 
+        // TODO: THIS HAS THE CONFUSED DEPUTY PROBLEM! (see above)
+        fn tls_certificates(&self) -> (Vec<kannader_types::TlsCertificate>) {
+            Vec::new()
+        }
+
+        // TLS implementation with which to accept the clients
+        fn tls_handler(&self) -> (kannader_types::TlsHandler) {
+            kannader_types::TlsHandler::Rustls
+        }
+
+        // TLS parameters allowed for the clients connecting to us
+        fn tls_profile(&self) -> (kannader_types::TlsProfile) {
+            kannader_types::TlsProfile::Compatible
+        }
+
+        // TODO: THIS HAS THE CONFUSED DEPUTY PROBLEM! (see above)
+        // Certificate authorities against which to check the certificates
+        // clients authenticate with, if they are to be asked for one
+        fn tls_client_ca_file(&self) -> (Option<std::path::PathBuf>) {
+            None
+        }
+
+        // How often the keys protecting the TLS session tickets are replaced,
+        // the tickets being accepted for at least as long
+        fn tls_ticket_key_rotation(&self) -> (std::time::Duration) {
+            std::time::Duration::from_secs(60 * 60)
+        }
+
+        // TODO: THIS HAS THE CONFUSED DEPUTY PROBLEM! (see above)
+        fn dkim_signing_keys(&self) -> (Vec<kannader_types::DkimSigningKey>) {
+            Vec::new()
+        }
+
+        fn dmarc_reporting(&self) -> (Option<kannader_types::DmarcReporting>) {
+            None
+        }
+
+        // Milters to call, in order, for each incoming connection
+        fn milters(&self) -> (Vec<kannader_types::Milter>) {
+            Vec::new()
+        }
+
+        // Maximum size of the message data, as received, beyond which the
+        // mail is rejected
+        fn max_message_size(&self) -> (u64) {
+            64 * 1024 * 1024
+        }
+
+        // Directory in which to store the mail that filters put in
+        // quarantine, if none it is rejected instead
+        fn quarantine_dir(&self) -> (Option<std::path::PathBuf>) {
+            None
+        }
+
+        // External command through which to pipe the mail before queuing it,
+        // after the milters
+        fn content_filter(&self) -> (Option<kannader_types::ContentFilter>) {
+            None
+        }
+
+        // clamd with which to check the mail for viruses before queuing it,
+        // after the content filter
+        fn antivirus(&self) -> (Option<kannader_types::Antivirus>) {
+            None
+        }
+
+        // Spam scanner with which to check the mail before queuing it, after
+        // the antivirus
+        fn spam_scanner(&self) -> (Option<kannader_types::SpamScanner>) {
+            None
+        }
+
+        // Filter to which to hand the queued mail before relaying it
+        fn after_queue_filter(&self) -> (Option<kannader_types::AfterQueueFilter>) {
+            None
+        }
+
+        fn greylisting(&self) -> (Option<kannader_types::Greylisting>) {
+            None
+        }
+
+        // Identifier under which to record authentication results, defaults
+        // to the EHLO hostname
+        fn authserv_id(&self) -> (Option<String>) {
+            None
+        }
+
+        // ARC sealing is opt-in, the mail is never sealed if this is none
+        // TODO: THIS HAS THE CONFUSED DEPUTY PROBLEM! (see above)
+        fn arc_sealing(&self) -> (Option<kannader_types::ArcSealing>) {
+            None
+        }
+
         fn welcome_banner_reply(
             &self,
-            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (smtp_message::Reply) ;
 
         fn filter_hello(
             &self,
             is_extended: () bool,
             hostname: () smtp_message::Hostname,
-            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (smtp_server_types::SerializableDecision<smtp_server_types::HelloInfo>) ;
 
         fn can_do_tls(
             &self,
-            conn_meta: () smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            conn_meta: () smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (bool)
         {
             !conn_meta.is_encrypted &&
@@ -859,27 +979,39 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
 
         fn new_mail(
             &self,
-            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (Vec<u8>) ;
 
         fn filter_from(
             &self,
             from: () Option<smtp_message::Email>,
-            meta: (&mut) smtp_server_types::MailMetadata<Vec<u8>>,
-            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            meta: (&mut) smtp_server_types::MailMetadata<kannader_types::MailUser>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (smtp_server_types::SerializableDecision<Option<smtp_message::Email>>) ;
 
         fn filter_to(
             &self,
             to: () smtp_message::Email,
-            meta: (&mut) smtp_server_types::MailMetadata<Vec<u8>>,
-            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            meta: (&mut) smtp_server_types::MailMetadata<kannader_types::MailUser>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (smtp_server_types::SerializableDecision<smtp_message::Email>) ;
 
+        // Called for each recipient accepted by `filter_to` when greylisting
+        // is enabled, returns whether to greylist it
+        fn greylist_recipient(
+            &self,
+            to: () smtp_message::Email,
+            meta: (&mut) smtp_server_types::MailMetadata<kannader_types::MailUser>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
+        ) -> (bool)
+        {
+            true
+        }
+
         fn filter_data(
             &self,
-            meta: (&mut) smtp_server_types::MailMetadata<Vec<u8>>,
-            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            meta: (&mut) smtp_server_types::MailMetadata<kannader_types::MailUser>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (smtp_server_types::SerializableDecision<()>)
         {
             smtp_server_types::SerializableDecision::Accept {
@@ -888,10 +1020,47 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
             }
         }
 
+        // Called once the message data has been received, before it is
+        // enqueued, with the verdict of the spam scanner in `meta.user.spam`
+        fn filter_data_end(
+            &self,
+            meta: (&mut) smtp_server_types::MailMetadata<kannader_types::MailUser>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
+        ) -> (smtp_server_types::SerializableDecision<()>)
+        {
+            smtp_server_types::SerializableDecision::Accept {
+                reply: smtp_server_types::reply::okay_mail().convert(),
+                res: (),
+            }
+        }
+
+        // Called before the message data is received, returns the domain
+        // on behalf of which to DKIM-sign the message, if any
+        fn dkim_signing_domain(
+            &self,
+            meta: (&mut) smtp_server_types::MailMetadata<kannader_types::MailUser>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
+        ) -> (Option<String>)
+        {
+            None
+        }
+
+        // Called before the message data is received if ARC sealing is
+        // enabled, returns the domain on behalf of which to add an ARC set
+        // to the message, if any
+        fn arc_sealing_domain(
+            &self,
+            meta: (&mut) smtp_server_types::MailMetadata<kannader_types::MailUser>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
+        ) -> (Option<String>)
+        {
+            None
+        }
+
         fn handle_rset(
             &self,
-            meta: (&mut) Option<smtp_server_types::MailMetadata<Vec<u8>>>,
-            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            meta: (&mut) Option<smtp_server_types::MailMetadata<kannader_types::MailUser>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (smtp_server_types::SerializableDecision<()>)
         {
             smtp_server_types::SerializableDecision::Accept {
@@ -902,7 +1071,7 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
 
         fn handle_starttls(
             &self,
-            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (smtp_server_types::SerializableDecision<()>)
         {
             if Self::can_do_tls(cfg, (*conn_meta).clone()) {
@@ -920,7 +1089,7 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
         fn handle_expn(
             &self,
             name: () smtp_message::MaybeUtf8<String>,
-            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (smtp_server_types::SerializableDecision<()>)
         {
             smtp_server_types::SerializableDecision::Reject {
@@ -931,7 +1100,7 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
         fn handle_vrfy(
             &self,
             name: () smtp_message::MaybeUtf8<String>,
-            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (smtp_server_types::SerializableDecision<()>)
         {
             smtp_server_types::SerializableDecision::Accept {
@@ -943,7 +1112,7 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
         fn handle_help(
             &self,
             subject: () smtp_message::MaybeUtf8<String>,
-            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (smtp_server_types::SerializableDecision<()>)
         {
             smtp_server_types::SerializableDecision::Accept {
@@ -955,7 +1124,7 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
         fn handle_noop(
             &self,
             string: () smtp_message::MaybeUtf8<String>,
-            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (smtp_server_types::SerializableDecision<()>)
         {
             smtp_server_types::SerializableDecision::Accept {
@@ -966,7 +1135,7 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
 
         fn handle_quit(
             &self,
-            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (smtp_server_types::SerializableDecision<()>)
         {
             smtp_server_types::SerializableDecision::Kill {
@@ -977,7 +1146,7 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
 
         fn already_did_hello(
             &self,
-            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (smtp_message::Reply)
         {
             smtp_server_types::reply::bad_sequence().convert()
@@ -985,7 +1154,7 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
 
         fn mail_before_hello(
             &self,
-            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (smtp_message::Reply)
         {
             smtp_server_types::reply::bad_sequence().convert()
@@ -993,15 +1162,23 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
 
         fn already_in_mail(
             &self,
-            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (smtp_message::Reply)
         {
             smtp_server_types::reply::bad_sequence().convert()
         }
 
+        fn require_tls_without_tls(
+            &self,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
+        ) -> (smtp_message::Reply)
+        {
+            smtp_server_types::reply::require_tls_without_tls().convert()
+        }
+
         fn rcpt_before_mail(
             &self,
-            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (smtp_message::Reply)
         {
             smtp_server_types::reply::bad_sequence().convert()
@@ -1009,7 +1186,7 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
 
         fn data_before_rcpt(
             &self,
-            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (smtp_message::Reply)
         {
             smtp_server_types::reply::bad_sequence().convert()
@@ -1017,7 +1194,7 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
 
         fn data_before_mail(
             &self,
-            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (smtp_message::Reply)
         {
             smtp_server_types::reply::bad_sequence().convert()
@@ -1025,7 +1202,7 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
 
         fn starttls_unsupported(
             &self,
-            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (smtp_message::Reply)
         {
             smtp_server_types::reply::command_not_supported().convert()
@@ -1033,7 +1210,7 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
 
         fn command_unrecognized(
             &self,
-            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (smtp_message::Reply)
         {
             smtp_server_types::reply::command_unrecognized().convert()
@@ -1041,7 +1218,7 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
 
         fn pipeline_forbidden_after_starttls(
             &self,
-            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (smtp_message::Reply)
         {
             smtp_server_types::reply::pipeline_forbidden_after_starttls().convert()
@@ -1049,7 +1226,7 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
 
         fn line_too_long(
             &self,
-            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (smtp_message::Reply)
         {
             smtp_server_types::reply::line_too_long().convert()
@@ -1057,7 +1234,7 @@ static SERVER_CONFIG: fn() -> Communicator = communicator! {
 
         fn handle_mail_did_not_call_complete(
             &self,
-            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<Vec<u8>>,
+            conn_meta: (&mut) smtp_server_types::ConnectionMetadata<kannader_types::ConnUser>,
         ) -> (smtp_message::Reply)
         {
             smtp_server_types::reply::handle_mail_did_not_call_complete().convert()
@@ -22,10 +22,14 @@ pub mod queue {
     pub use smtp_queue_types::{QueueId, ScheduleInfo};
 }
 pub mod server {
+    pub use kannader_types::{
+        ArcResult, ArcStatus, ConnUser, DkimResult, DkimStatus, DmarcResult, DmarcStatus, MailUser,
+        ReverseDns, SpamAction, SpamResult, SpfResult,
+    };
     pub use smtp_server_types::{HelloInfo, SerializableDecision};
 
-    pub type ConnMeta = smtp_server_types::ConnectionMetadata<Vec<u8>>;
-    pub type MailMeta = smtp_server_types::MailMetadata<Vec<u8>>;
+    pub type ConnMeta = smtp_server_types::ConnectionMetadata<ConnUser>;
+    pub type MailMeta = smtp_server_types::MailMetadata<MailUser>;
 }
 pub use smtp_server_types::reply;
 
@@ -1,11 +1,350 @@
-use std::path::PathBuf;
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
 
-#[derive(serde::Deserialize, serde::Serialize)]
+pub use smtp_dkim::{ArcResult, ArcStatus, DkimResult, DkimStatus};
+pub use smtp_dmarc::{DmarcResult, DmarcStatus};
+pub use smtp_scanner::{SpamAction, SpamResult};
+pub use smtp_spf::SpfResult;
+
+/// TLS implementation with which to talk to the peers
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
 pub enum TlsHandler {
     Rustls,
+    /// The system TLS library, eg. OpenSSL, for the peers only negotiating
+    /// what rustls refuses
+    ///
+    /// This needs kannader to be built with the `native-tls` feature. Keys
+    /// must be PKCS#8, and DANE-TA records cannot be checked, as the library
+    /// does not expose the server's certificate chain. When accepting
+    /// clients, the configuration is refused if it sets per-name
+    /// certificates, a TLS profile other than `Compatible` or client
+    /// certificate authorities, as the library's own defaults are used, and
+    /// the name clients ask for through SNI is not recorded.
+    NativeTls,
+}
+
+/// TLS protocol versions, cipher suites and key exchange groups to allow
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub enum TlsProfile {
+    /// TLS 1.3 only
+    Modern,
+    /// TLS 1.2 and 1.3, with forward-secret AEAD cipher suites
+    Intermediate,
+    /// Everything supported, for the oldest peers
+    ///
+    /// With rustls this is the same as `Intermediate`, as it supports nothing
+    /// older.
+    Compatible,
+    /// Explicit lists, eg. `TLSv1_3`, `TLS13_AES_128_GCM_SHA256` and
+    /// `X25519`, an empty list allowing everything supported
+    Custom {
+        versions: Vec<String>,
+        cipher_suites: Vec<String>,
+        kx_groups: Vec<String>,
+    },
+}
+
+/// Certificate served to the clients asking for one of `names` through SNI
+///
+/// Several certificates, eg. an RSA and an ECDSA one, can be given for the
+/// same name, the one picked being the first the client supports.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct TlsCertificate {
+    /// Host names, possibly wildcards like `*.example.org`
+    pub names: Vec<String>,
+    /// PEM file holding the certificate, followed by its intermediate
+    /// certificates in order
+    pub cert_file: PathBuf,
+    /// PEM file holding the private key, as PKCS#8, PKCS#1 (RSA) or SEC1 (EC)
+    pub key_file: PathBuf,
+}
+
+/// Certificate presented to the servers that ask for one
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct TlsClientCertificate {
+    /// PEM file holding the certificate, followed by its intermediate
+    /// certificates in order
+    pub cert_file: PathBuf,
+    /// PEM file holding the private key, as PKCS#8, PKCS#1 (RSA) or SEC1 (EC)
+    pub key_file: PathBuf,
+}
+
+/// How TLS is used with the servers of a destination domain
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub enum OutboundTlsPolicy {
+    /// Never negotiate TLS
+    None,
+    /// Negotiate TLS when offered, accepting any certificate
+    Opportunistic,
+    /// Require TLS with a certificate valid for the server name, issued by
+    /// one of the PEM certificates of `trust_store`, or else by one of the
+    /// system's trusted roots
+    Verify { trust_store: Option<PathBuf> },
+    /// Require TLS with a certificate whose SubjectPublicKeyInfo has one of
+    /// these hex-encoded SHA-256 hashes
+    Fingerprint { spki_sha256: Vec<String> },
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
 pub enum QueueStorage {
     Fs(PathBuf),
 }
+
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize)]
+pub enum DkimCanonicalization {
+    Simple,
+    Relaxed,
+}
+
+/// Key used for DKIM-signing the mail of a domain
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct DkimSigningKey {
+    pub domain: String,
+    pub selector: String,
+    /// PEM file holding the private key, RSA or Ed25519
+    pub key_file: PathBuf,
+    /// Names of the header fields to sign, `From` is always signed
+    pub signed_headers: Vec<String>,
+    pub header_canonicalization: DkimCanonicalization,
+    pub body_canonicalization: DkimCanonicalization,
+}
+
+/// Configuration of the ARC sealing of relayed mail (RFC 8617)
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct ArcSealing {
+    /// Keys of the domains on behalf of which the mail can be sealed
+    pub keys: Vec<DkimSigningKey>,
+    /// Whether to also seal the mail DKIM-signed by kannader, which usually
+    /// originates from here rather than being relayed
+    #[serde(default)]
+    pub seal_signed: bool,
+}
+
+/// Configuration of DMARC aggregate reporting
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct DmarcReporting {
+    /// File in which the DMARC results are recorded until reports are sent
+    pub store: PathBuf,
+    pub org_name: String,
+    /// Address the reports are sent from
+    pub email: String,
+    pub extra_contact_info: Option<String>,
+}
+
+/// Configuration of SMTP TLS reporting (RFC 8460)
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct TlsReporting {
+    /// File in which the outcomes of outbound TLS sessions are recorded until
+    /// reports are sent
+    pub store: PathBuf,
+    pub org_name: String,
+    /// Address the reports are sent from
+    pub email: String,
+    /// Address or URI at which the organization can be contacted
+    pub contact_info: String,
+}
+
+/// Configuration of greylisting, keyed on the (client network, sender,
+/// recipient) triplet
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Greylisting {
+    /// File in which the greylisting triplets are persisted
+    pub store: PathBuf,
+    /// Seconds during which a new triplet is rejected
+    pub initial_delay: u64,
+    /// Seconds after the initial delay during which a retry lets the triplet
+    /// pass, after which it is considered new again
+    pub retry_window: u64,
+    /// Seconds after their last use after which passed triplets are forgotten
+    pub lifetime: u64,
+    /// Number of triplets that need to pass for a client network to no longer
+    /// be greylisted, 0 to disable client whitelisting
+    pub whitelist_client_after: u32,
+    /// Prefix length of the client network of IPv4 clients
+    pub ipv4_prefix: u8,
+    /// Prefix length of the client network of IPv6 clients
+    pub ipv6_prefix: u8,
+}
+
+/// Socket on which a milter or a content scanner listens
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub enum MilterSocket {
+    /// `host:port` address
+    Inet(String),
+    Unix(PathBuf),
+}
+
+/// Configuration of a milter, see the Sendmail milter protocol
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Milter {
+    /// Name of the milter, for the logs
+    pub name: String,
+    pub socket: MilterSocket,
+    /// Seconds to wait for the milter at each protocol step
+    pub timeout: u64,
+    /// Whether to go on without the milter when it fails, instead of
+    /// temporarily rejecting the mail
+    pub fail_open: bool,
+}
+
+/// Configuration of an external command that filters messages before they are
+/// queued
+///
+/// The message is written to the standard input of the command. On exit code
+/// 0, its standard output is the message to queue, an empty output being an
+/// error. On exit code 75 (`EX_TEMPFAIL`), the message is temporarily
+/// rejected, and on any other exit code it is rejected. When rejecting, the
+/// standard output may be an SMTP reply to send to the client instead of the
+/// default one.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct ContentFilter {
+    pub command: PathBuf,
+    pub args: Vec<String>,
+    /// Seconds after which the command is killed and the message temporarily
+    /// rejected
+    pub timeout: u64,
+    /// Maximum size of the output of the command, in bytes
+    pub max_output: u64,
+    /// Limit on the CPU time of the command, in seconds
+    pub max_cpu_time: Option<u64>,
+    /// Limit on the address space of the command, in bytes
+    pub max_memory: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize)]
+pub enum FilterProtocol {
+    Smtp,
+    Lmtp,
+}
+
+/// Configuration of a filter that queued mail is handed to, and that then
+/// reinjects it into kannader
+///
+/// The filter must keep the header fields of the mail, through which the
+/// `REQUIRETLS` and `TLS-Required: No` states of the mail are restored once
+/// it is reinjected. Mail with `REQUIRETLS` is only handed to a filter
+/// listening on a loopback address, as it could not leave the host otherwise.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct AfterQueueFilter {
+    /// Address on which the filter listens
+    pub filter: SocketAddr,
+    pub protocol: FilterProtocol,
+    /// Address on which to listen for the mail reinjected by the filter
+    ///
+    /// The mail received there is queued without any further check, so it
+    /// must be a loopback address unless `reinjection_peers` is set.
+    pub reinjection: SocketAddr,
+    /// Addresses of the peers allowed to reinject mail, only loopback ones
+    /// being allowed if empty
+    #[serde(default)]
+    pub reinjection_peers: Vec<IpAddr>,
+}
+
+/// What to do with a message in which the antivirus found a virus
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize)]
+pub enum VirusAction {
+    Reject,
+    /// Accept the message into the quarantine directory instead of queuing it
+    Quarantine,
+}
+
+/// Configuration of clamd, that checks messages for viruses before they are
+/// queued
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Antivirus {
+    pub socket: MilterSocket,
+    /// Seconds to wait for clamd
+    pub timeout: u64,
+    /// Messages bigger than this many bytes are not checked, this should not
+    /// be more than the `StreamMaxLength` setting of clamd
+    pub max_size: u64,
+    pub on_virus: VirusAction,
+    /// Whether to go on without checking the mail when clamd fails, instead
+    /// of temporarily rejecting it
+    pub fail_open: bool,
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize)]
+pub enum SpamScannerKind {
+    /// SpamAssassin's spamd, spoken to with the SPAMC protocol
+    Spamd,
+    /// rspamd, spoken to with its `/checkv2` HTTP endpoint
+    Rspamd,
+}
+
+/// Configuration of a spam scanner that checks messages before they are
+/// queued
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct SpamScanner {
+    pub kind: SpamScannerKind,
+    pub socket: MilterSocket,
+    /// Seconds to wait for the scanner
+    pub timeout: u64,
+    /// Whether to go on without a verdict when the scanner fails, instead of
+    /// temporarily rejecting the mail
+    pub fail_open: bool,
+    /// Whether to add the `X-Spam-*` header fields to the message
+    pub add_headers: bool,
+}
+
+/// Result of a forward-confirmed reverse DNS check, see RFC 8601 §3
+#[derive(Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum ReverseDns {
+    /// The IP has a PTR name that resolves back to it, lowercased and without
+    /// trailing dot
+    Pass(String),
+    /// None of the PTR names of the IP resolve back to it
+    Fail,
+    /// The IP has no PTR record
+    None,
+    TempError,
+}
+
+impl ReverseDns {
+    /// Name of the result, as used for the `iprev` method of
+    /// `Authentication-Results` headers
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReverseDns::Pass(_) => "pass",
+            ReverseDns::Fail => "fail",
+            ReverseDns::None => "permerror",
+            ReverseDns::TempError => "temperror",
+        }
+    }
+}
+
+/// What kannader keeps in the `user` field of the connection metadata the
+/// wasm configuration sees
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct ConnUser {
+    /// Data of the wasm configuration itself
+    pub wasm: Vec<u8>,
+    /// Forward-confirmed reverse DNS of the peer address, if it was checked
+    pub reverse_dns: Option<ReverseDns>,
+}
+
+/// What kannader keeps in the `user` field of the mail metadata the wasm
+/// configuration sees
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct MailUser {
+    /// Data of the wasm configuration itself
+    pub wasm: Vec<u8>,
+    /// SPF result for the HELO identity, if it was checked
+    pub spf_helo: Option<SpfResult>,
+    /// SPF result for the MAIL FROM identity, if it was checked
+    pub spf_mail_from: Option<SpfResult>,
+    /// DKIM results for each signature of the message, only filled in once
+    /// the message data has been received
+    pub dkim: Vec<DkimResult>,
+    /// DMARC result of the message, only filled in once the message data has
+    /// been received
+    pub dmarc: Option<DmarcResult>,
+    /// ARC result of the message, only filled in once the message data has
+    /// been received
+    pub arc: Option<ArcResult>,
+    /// Verdict of the spam scanner, only filled in once the message data has
+    /// been received
+    pub spam: Option<SpamResult>,
+}
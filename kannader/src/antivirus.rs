@@ -0,0 +1,23 @@
+use std::time::Duration;
+
+use anyhow::Context;
+
+use smtp_scanner::VirusResult;
+
+use crate::spam::connect;
+
+/// Checks `message` for viruses with clamd
+pub async fn scan(cfg: &kannader_types::Antivirus, message: &[u8]) -> anyhow::Result<VirusResult> {
+    let timeout = Duration::from_secs(cfg.timeout);
+    let res = match &cfg.socket {
+        kannader_types::MilterSocket::Inet(addr) => {
+            let stream = connect(smol::net::TcpStream::connect(addr.as_str()), timeout).await?;
+            smtp_scanner::check_clamd(stream, message, timeout).await
+        }
+        kannader_types::MilterSocket::Unix(path) => {
+            let stream = connect(smol::net::unix::UnixStream::connect(path), timeout).await?;
+            smtp_scanner::check_clamd(stream, message, timeout).await
+        }
+    };
+    res.context("Checking the mail with clamd")
+}
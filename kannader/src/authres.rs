@@ -0,0 +1,348 @@
+use smtp_message::{AuthResult, AuthenticationResults};
+
+use crate::server_config::{hostname_domain, ConnMeta, MailMeta};
+
+/// Size above which a header field is kept or dropped based on its beginning
+/// only, so as not to buffer it whole
+const MAX_BUFFERED_FIELD: usize = 64 * 1024;
+
+/// Builds the authentication results of the checks that ran on `meta` and
+/// `conn_meta`
+pub fn authentication_results(
+    authserv_id: &str,
+    meta: &MailMeta,
+    conn_meta: &ConnMeta,
+) -> AuthenticationResults {
+    let mut results = Vec::new();
+    if let (Some(rdns), Some(addr)) = (&conn_meta.user.reverse_dns, conn_meta.peer_addr) {
+        results.push(AuthResult::new("iprev", rdns.name()).with_property(
+            "policy",
+            "iprev",
+            &addr.ip().to_string(),
+        ));
+    }
+    let mail_from = meta
+        .from
+        .as_ref()
+        .and_then(|f| f.hostname.as_ref())
+        .and_then(hostname_domain);
+    if let Some(spf) = &meta.user.spf_mail_from {
+        let res = AuthResult::new("spf", spf.name());
+        results.push(match mail_from {
+            Some(domain) => res.with_property("smtp", "mailfrom", domain),
+            None => res,
+        });
+    } else if let Some(spf) = &meta.user.spf_helo {
+        results.push(AuthResult::new("spf", spf.name()));
+    }
+    for dkim in &meta.user.dkim {
+        let mut res = AuthResult::new("dkim", dkim.status.name());
+        if let Some(reason) = &dkim.reason {
+            res = res.with_reason(reason);
+        }
+        if let Some(domain) = &dkim.domain {
+            res = res.with_property("header", "d", domain);
+        }
+        if let Some(selector) = &dkim.selector {
+            res = res.with_property("header", "s", selector);
+        }
+        results.push(res);
+    }
+    if let Some(dmarc) = &meta.user.dmarc {
+        let mut res = AuthResult::new("dmarc", dmarc.status.name());
+        if let Some(reason) = &dmarc.reason {
+            res = res.with_reason(reason);
+        }
+        if let Some(domain) = &dmarc.header_from {
+            res = res.with_property("header", "from", domain);
+        }
+        results.push(res);
+    }
+    if let Some(arc) = &meta.user.arc {
+        let mut res = AuthResult::new("arc", arc.status.name());
+        if let Some(reason) = &arc.reason {
+            res = res.with_reason(reason);
+        }
+        results.push(res);
+    }
+    AuthenticationResults {
+        authserv_id: authserv_id.to_owned(),
+        results,
+    }
+}
+
+/// Streaming filter that removes from the header section of a message the
+/// `Authentication-Results` header fields that claim to come from us, see
+/// RFC 8601 §5
+pub struct ForgedAuthResFilter<'a> {
+    authserv_id: &'a str,
+    /// Beginning of the current header field, while it is not known whether
+    /// to keep it
+    field: Vec<u8>,
+    /// Whether to keep the rest of the current header field, once known
+    keep: Option<bool>,
+    line_start: bool,
+    line_len: usize,
+    last: u8,
+    in_body: bool,
+}
+
+impl<'a> ForgedAuthResFilter<'a> {
+    pub fn new(authserv_id: &'a str) -> ForgedAuthResFilter<'a> {
+        ForgedAuthResFilter {
+            authserv_id,
+            field: Vec::new(),
+            keep: None,
+            line_start: true,
+            line_len: 0,
+            last: 0,
+            in_body: false,
+        }
+    }
+
+    fn is_forged(&self, field: &[u8]) -> bool {
+        let colon = match field.iter().position(|&b| b == b':') {
+            Some(colon) => colon,
+            None => return false,
+        };
+        let name = String::from_utf8_lossy(&field[..colon]);
+        if !name
+            .trim_end()
+            .eq_ignore_ascii_case("authentication-results")
+        {
+            return false;
+        }
+        let value = String::from_utf8_lossy(&field[colon + 1..]);
+        AuthenticationResults::parse_authserv_id(&value)
+            .map_or(false, |id| id.eq_ignore_ascii_case(self.authserv_id))
+    }
+
+    fn end_field(&mut self, out: &mut Vec<u8>) {
+        if self.keep.is_none() && !self.is_forged(&self.field) {
+            out.extend_from_slice(&self.field);
+        }
+        self.field.clear();
+        self.keep = None;
+    }
+
+    /// Feeds in the next bytes of the message, appending to `out` the ones to
+    /// keep
+    pub fn update(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        for (i, &b) in data.iter().enumerate() {
+            if self.in_body {
+                out.extend_from_slice(&data[i..]);
+                return;
+            }
+            if self.line_start && b != b' ' && b != b'\t' {
+                self.end_field(out);
+            }
+            self.line_start = false;
+            match self.keep {
+                None => self.field.push(b),
+                Some(true) => out.push(b),
+                Some(false) => (),
+            }
+            self.line_len += 1;
+            if b == b'\n' {
+                if self.line_len == 2 && self.last == b'\r' {
+                    // Empty line, the body starts
+                    self.end_field(out);
+                    self.in_body = true;
+                }
+                self.line_start = true;
+                self.line_len = 0;
+            }
+            self.last = b;
+            if self.keep.is_none() && self.field.len() > MAX_BUFFERED_FIELD {
+                let keep = !self.is_forged(&self.field);
+                if keep {
+                    out.extend_from_slice(&self.field);
+                }
+                self.field.clear();
+                self.keep = Some(keep);
+            }
+        }
+    }
+
+    /// Marks the end of the message, appending to `out` the remaining bytes
+    /// to keep
+    pub fn finish(mut self, out: &mut Vec<u8>) {
+        self.end_field(out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kannader_types::ReverseDns;
+    use smtp_dkim::{ArcResult, ArcStatus, DkimResult, DkimStatus};
+    use smtp_dmarc::{DmarcResult, DmarcStatus};
+    use smtp_message::Email;
+    use smtp_spf::SpfResult;
+
+    use crate::server_config::ConnUserMeta;
+
+    use super::*;
+
+    const ID: &str = "mx.example.org";
+
+    /// Filters `msg`, fed to the filter in chunks of `chunk_size` bytes
+    fn filter(msg: &[u8], chunk_size: usize) -> Vec<u8> {
+        let mut filter = ForgedAuthResFilter::new(ID);
+        let mut out = Vec::new();
+        for chunk in msg.chunks(chunk_size) {
+            filter.update(chunk, &mut out);
+        }
+        filter.finish(&mut out);
+        out
+    }
+
+    #[test]
+    fn forged_removed() {
+        let tests: &[(&str, &str)] = &[
+            (
+                "Received: from a\r\nAuthentication-Results: mx.example.org; \
+                 spf=pass\r\nauthentication-results : MX.Example.Org;\r\n\tdkim=pass\r\n \
+                 header.d=example.org\r\nAuthentication-Results: (forged) \"mx.example.org\" 1; \
+                 none\r\nAuthentication-Results: other.example.org; \
+                 spf=pass\r\nX-Authentication-Results: mx.example.org; spf=pass\r\nSubject: \
+                 Authentication-Results: mx.example.org\r\n\r\nAuthentication-Results: \
+                 mx.example.org; spf=pass\r\n",
+                "Received: from a\r\nAuthentication-Results: other.example.org; \
+                 spf=pass\r\nX-Authentication-Results: mx.example.org; spf=pass\r\nSubject: \
+                 Authentication-Results: mx.example.org\r\n\r\nAuthentication-Results: \
+                 mx.example.org; spf=pass\r\n",
+            ),
+            (
+                // Header section without a body nor a final CRLF
+                "Subject: Hello\r\nAuthentication-Results: mx.example.org; none",
+                "Subject: Hello\r\n",
+            ),
+            (
+                "Authentication-Results: mx.example.org.evil; \
+                 none\r\nAuthentication-Results:\r\n\tmx.example.org; \
+                 none\r\nAuthentication-Results: ; none\r\n",
+                "Authentication-Results: mx.example.org.evil; none\r\nAuthentication-Results: ; \
+                 none\r\n",
+            ),
+        ];
+        for (msg, out) in tests {
+            for chunk_size in 1..=msg.len() {
+                println!("Testing {:?} in chunks of {}", msg, chunk_size);
+                assert_eq!(
+                    String::from_utf8(filter(msg.as_bytes(), chunk_size)).unwrap(),
+                    *out
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn forged_large_fields() {
+        let fold = "\r\n\t".to_owned() + &"a".repeat(1000);
+        let large = fold.repeat(MAX_BUFFERED_FIELD / 1000 + 2);
+        let kept = format!(
+            "X-Large:{}\r\nAuthentication-Results: other;{}\r\n",
+            large, large
+        );
+        let msg = format!(
+            "{}Authentication-Results: mx.example.org;{}\r\nSubject: Hi\r\n\r\nBody\r\n",
+            kept, large
+        );
+        let out = format!("{}Subject: Hi\r\n\r\nBody\r\n", kept);
+        for chunk_size in &[1, 3, 64, 4096, MAX_BUFFERED_FIELD, msg.len()] {
+            println!("Testing chunks of {}", chunk_size);
+            assert!(filter(msg.as_bytes(), *chunk_size) == out.as_bytes());
+        }
+    }
+
+    fn meta() -> MailMeta {
+        MailMeta {
+            user: kannader_types::MailUser::default(),
+            from: Email::parse_bracketed(b"<joe@example.org>").ok(),
+            to: Vec::new(),
+            require_tls: false,
+        }
+    }
+
+    fn conn_meta() -> ConnMeta {
+        ConnMeta {
+            user: ConnUserMeta::default(),
+            peer_addr: None,
+            hello: None,
+            is_encrypted: false,
+            tls_server_name: None,
+            tls_client_cert: None,
+        }
+    }
+
+    fn dkim(status: DkimStatus, reason: Option<&str>, selector: Option<&str>) -> DkimResult {
+        DkimResult {
+            status,
+            reason: reason.map(str::to_owned),
+            domain: selector.map(|_| String::from("example.org")),
+            selector: selector.map(str::to_owned),
+            identity: None,
+            b: None,
+        }
+    }
+
+    #[test]
+    fn results() {
+        let results = authentication_results(ID, &meta(), &conn_meta());
+        assert_eq!(results.to_string(), "mx.example.org; none");
+        assert_eq!(
+            results.to_header(),
+            "Authentication-Results: mx.example.org; none\r\n"
+        );
+
+        let mut meta = meta();
+        meta.user.spf_helo = Some(SpfResult::Fail { explanation: None });
+        meta.user.spf_mail_from = Some(SpfResult::Pass);
+        meta.user.dkim = vec![
+            dkim(DkimStatus::Pass, None, Some("sel")),
+            dkim(DkimStatus::Fail, Some("body hash mismatch"), Some("old")),
+            dkim(DkimStatus::PermError, Some("no-tag"), None),
+        ];
+        meta.user.dmarc = Some(DmarcResult {
+            header_from: Some(String::from("example.org")),
+            ..DmarcResult::new(DmarcStatus::Pass, None)
+        });
+        meta.user.arc = Some(ArcResult {
+            status: ArcStatus::Fail,
+            reason: Some(String::from("seal 1 is invalid")),
+            sealers: vec![String::from("example.net")],
+        });
+        let mut conn_meta = conn_meta();
+        conn_meta.peer_addr = Some("[2001:db8::1]:25".parse().unwrap());
+        conn_meta.user.reverse_dns = Some(ReverseDns::Pass(String::from("mail.example.org")));
+        assert_eq!(
+            authentication_results(ID, &meta, &conn_meta).to_string(),
+            "mx.example.org;\r\n\tiprev=pass policy.iprev=\"2001:db8::1\";\r\n\tspf=pass \
+             smtp.mailfrom=example.org;\r\n\tdkim=pass header.d=example.org \
+             header.s=sel;\r\n\tdkim=fail reason=\"body hash mismatch\" header.d=example.org \
+             header.s=old;\r\n\tdkim=permerror reason=no-tag;\r\n\tdmarc=pass \
+             header.from=example.org;\r\n\tarc=fail reason=\"seal 1 is invalid\""
+        );
+    }
+
+    #[test]
+    fn results_spf() {
+        let mut meta = meta();
+        meta.user.spf_helo = Some(SpfResult::Pass);
+        let mut conn_meta = conn_meta();
+        conn_meta.peer_addr = Some("192.0.2.1:25".parse().unwrap());
+        conn_meta.user.reverse_dns = Some(ReverseDns::None);
+        assert_eq!(
+            authentication_results("[192.0.2.2]", &meta, &conn_meta).to_string(),
+            "\"[192.0.2.2]\";\r\n\tiprev=permerror policy.iprev=192.0.2.1;\r\n\tspf=pass"
+        );
+
+        // The null reverse-path has no domain to report
+        meta.from = None;
+        meta.user.spf_mail_from = Some(SpfResult::SoftFail);
+        assert_eq!(
+            authentication_results(ID, &meta, &conn_meta).to_string(),
+            "mx.example.org;\r\n\tiprev=permerror policy.iprev=192.0.2.1;\r\n\tspf=softfail"
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use std::{io, path::Path, sync::Arc, time::SystemTime};
+
+use anyhow::Context;
+use rustls::{
+    server::{AllowAnyAuthenticatedClient, ClientCertVerified, ClientCertVerifier},
+    sign::CertifiedKey,
+};
+use tracing::{debug, warn};
+
+use smtp_client::x509;
+use smtp_server::ClientCertificate;
+
+/// Requests a certificate from the clients, checking it against a set of
+/// certificate authorities
+///
+/// Clients with certificates from other authorities are still let in, as
+/// MTAs commonly present whatever certificate they have: the certificate is
+/// only checked once the handshake is over, with `check`.
+pub struct ClientAuth {
+    verifier: Arc<dyn ClientCertVerifier>,
+}
+
+impl ClientAuth {
+    /// Trusts the PEM certificates of `ca_file`
+    pub fn load(ca_file: &Path) -> anyhow::Result<ClientAuth> {
+        let certs = rustls_pemfile::certs(&mut io::BufReader::new(
+            std::fs::File::open(ca_file)
+                .with_context(|| format!("Opening the client CA file ‘{}’", ca_file.display()))?,
+        ))
+        .with_context(|| format!("Parsing the client CA file ‘{}’", ca_file.display()))?;
+        let mut roots = rustls::RootCertStore::empty();
+        let (added, ignored) = roots.add_parsable_certificates(&certs);
+        if ignored > 0 {
+            warn!(file = %ca_file.display(), ignored, "Ignoring invalid client CA certificates");
+        }
+        anyhow::ensure!(
+            added > 0,
+            "Client CA file ‘{}’ holds no valid certificate",
+            ca_file.display()
+        );
+        Ok(ClientAuth {
+            verifier: AllowAnyAuthenticatedClient::new(roots),
+        })
+    }
+
+    /// Checks the certificate chain the client presented, returning its
+    /// details if it is trusted
+    pub fn check(&self, chain: &[rustls::Certificate]) -> Option<ClientCertificate> {
+        let (end_entity, intermediates) = chain.split_first()?;
+        if let Err(e) =
+            self.verifier
+                .verify_client_cert(end_entity, intermediates, SystemTime::now())
+        {
+            debug!(error = ?e, "Client certificate is not trusted");
+            return None;
+        }
+        Some(ClientCertificate {
+            subject: x509::subject_name(&end_entity.0)?,
+            alt_names: x509::alt_names(&end_entity.0)?
+                .iter()
+                .map(|n| n.to_string())
+                .collect(),
+            sha256_fingerprint: x509::sha256_fingerprint(&end_entity.0),
+        })
+    }
+}
+
+impl ClientCertVerifier for ClientAuth {
+    fn client_auth_mandatory(&self) -> Option<bool> {
+        Some(false)
+    }
+
+    fn client_auth_root_subjects(&self) -> Option<rustls::DistinguishedNames> {
+        self.verifier.client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        // rustls still checks that the client holds the key of the certificate
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+/// Presents the same certificate to all the servers
+pub struct ClientCert(pub Arc<CertifiedKey>);
+
+impl rustls::client::ResolvesClientCert for ClientCert {
+    fn resolve(
+        &self,
+        _acceptable_issuers: &[&[u8]],
+        _sigschemes: &[rustls::SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
@@ -1,24 +1,253 @@
-use std::{io, pin::Pin};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
 
 use async_trait::async_trait;
 use futures::{AsyncRead, AsyncReadExt, AsyncWrite};
-use tracing::error;
+use rustls::sign::CertifiedKey;
+use tracing::{debug, error, warn};
 
 use smtp_message::Hostname;
 
-use crate::WASM_CONFIG;
+use crate::{
+    client_auth::ClientCert,
+    dane::DaneVerifier,
+    sni,
+    tls_session::{ClientSessions, SessionKey},
+    verifier::{Roots, RootsVerifier, VerifyError},
+    WASM_CONFIG,
+};
 
 pub type DynAsyncReadWrite =
     duplexify::Duplex<Pin<Box<dyn Send + AsyncRead>>, Pin<Box<dyn Send + AsyncWrite>>>;
 
+pub type TlsClientBuilder = rustls::ConfigBuilder<rustls::ClientConfig, rustls::WantsVerifier>;
+
 pub struct ClientConfig {
-    connector: tokio_rustls::TlsConnector,
+    /// rustls configuration restricted to the allowed TLS parameters, for the
+    /// configurations built on demand
+    tls: TlsClientBuilder,
+    /// Configuration accepting any certificate
+    unverified: Arc<rustls::ClientConfig>,
+    /// System roots, against which the certificates are checked by default
+    roots: Arc<Roots>,
+    /// Roots of the trust stores of the `Verify` TLS policies, by path
+    trust_stores: Mutex<HashMap<PathBuf, Arc<Roots>>>,
+    /// Client certificates, by certificate and key file, along with the time
+    /// the certificate file was last modified when they were loaded
+    client_certs: Mutex<HashMap<(PathBuf, PathBuf), (SystemTime, Arc<CertifiedKey>)>>,
+    /// Sessions to resume, by destination
+    sessions: ClientSessions,
+    /// File in which the outcomes of TLS sessions are recorded for TLS
+    /// reporting, if enabled
+    tls_report_store: Option<Arc<PathBuf>>,
 }
 
 impl ClientConfig {
-    pub fn new(connector: tokio_rustls::TlsConnector) -> ClientConfig {
-        ClientConfig { connector }
+    pub fn new(
+        tls: TlsClientBuilder,
+        unverified: Arc<rustls::ClientConfig>,
+        roots: Arc<Roots>,
+        tls_report_store: Option<Arc<PathBuf>>,
+    ) -> ClientConfig {
+        ClientConfig {
+            tls,
+            unverified,
+            roots,
+            trust_stores: Mutex::new(HashMap::new()),
+            client_certs: Mutex::new(HashMap::new()),
+            sessions: ClientSessions::default(),
+            tls_report_store,
+        }
+    }
+
+    /// Returns the PEM certificates of `path`, as roots
+    fn trust_store(&self, path: &Path) -> io::Result<Arc<Roots>> {
+        if let Some(roots) = self.trust_stores.lock().unwrap().get(path) {
+            return Ok(roots.clone());
+        }
+        let certs = rustls_pemfile::certs(&mut io::BufReader::new(std::fs::File::open(path)?))?;
+        let (roots, ignored) = Roots::parse(certs.iter().map(|c| c.as_slice()));
+        if ignored > 0 {
+            warn!(
+                trust_store = %path.display(),
+                ignored, "Ignoring invalid trusted certificates"
+            );
+        }
+        let roots = Arc::new(roots);
+        self.trust_stores
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), roots.clone());
+        Ok(roots)
     }
+
+    /// Returns a configuration checking the certificates with `verifier`
+    fn verifying<V>(&self, verifier: V) -> Arc<rustls::ClientConfig>
+    where
+        V: 'static + rustls::client::ServerCertVerifier,
+    {
+        Arc::new(
+            self.tls
+                .clone()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+                .with_no_client_auth(),
+        )
+    }
+
+    /// Loads the client certificate `cert`, reloading it when its file
+    /// changes
+    fn client_cert(
+        &self,
+        cert: kannader_types::TlsClientCertificate,
+    ) -> io::Result<Arc<CertifiedKey>> {
+        let modified = std::fs::metadata(&cert.cert_file)?.modified()?;
+        let files = (cert.cert_file, cert.key_file);
+        if let Some((loaded, key)) = self.client_certs.lock().unwrap().get(&files) {
+            if *loaded == modified {
+                return Ok(key.clone());
+            }
+        }
+        let key = sni::load_certified_key(&files.0, &files.1)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:#}", e)))?;
+        let key = Arc::new(key);
+        self.client_certs
+            .lock()
+            .unwrap()
+            .insert(files, (modified, key.clone()));
+        Ok(key)
+    }
+
+    /// Negotiates TLS, verifying the certificate against the system roots
+    async fn tls_connect_verified<IO>(
+        &self,
+        io: IO,
+        hostname: &str,
+    ) -> io::Result<DynAsyncReadWrite>
+    where
+        IO: 'static + Unpin + Send + AsyncRead + AsyncWrite,
+    {
+        let error = VerifyError::default();
+        self.connect(
+            self.verifying(RootsVerifier::new(self.roots.clone(), error.clone())),
+            &Verification::SystemRoots,
+            None,
+            io,
+            server_name(hostname)?,
+            hostname,
+        )
+        .await
+        .map_err(|e| error.replace(e))
+    }
+
+    /// Negotiates TLS with `cfg`, presenting `client_cert` if any, and
+    /// resuming a previous session with `hostname` established the same way
+    /// if there is one
+    async fn connect<IO>(
+        &self,
+        cfg: Arc<rustls::ClientConfig>,
+        verification: &Verification,
+        client_cert: Option<(PathBuf, Arc<CertifiedKey>)>,
+        io: IO,
+        name: rustls::ServerName,
+        hostname: &str,
+    ) -> io::Result<DynAsyncReadWrite>
+    where
+        IO: 'static + Unpin + Send + AsyncRead + AsyncWrite,
+    {
+        use async_compat::CompatExt;
+        let mut cfg = (*cfg).clone();
+        cfg.session_storage = self.sessions.get(SessionKey {
+            hostname: hostname.to_owned(),
+            verification: format!("{:?}", verification),
+            client_cert: client_cert.as_ref().map(|(path, _)| path.clone()),
+        });
+        if let Some((_, key)) = client_cert {
+            cfg.client_auth_cert_resolver = Arc::new(ClientCert(key));
+        }
+        let io = tokio_rustls::TlsConnector::from(Arc::new(cfg))
+            .connect(name, io.compat())
+            .await?;
+        Ok(established(io, hostname))
+    }
+}
+
+/// How the certificate of a server is checked
+#[derive(Debug)]
+pub enum Verification {
+    None,
+    SystemRoots,
+    TrustStore(PathBuf),
+    /// With DANE TLSA records, pinned keys being DANE-EE records
+    Pinned(Vec<smtp_client::dane::Tlsa>),
+}
+
+fn server_name(hostname: &str) -> io::Result<rustls::ServerName> {
+    use std::convert::TryFrom;
+    rustls::ServerName::try_from(hostname)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Logs the parameters negotiated for the TLS session `io` with `hostname`,
+/// and makes it usable by smtp-client
+fn established<IO>(
+    io: tokio_rustls::client::TlsStream<async_compat::Compat<IO>>,
+    hostname: &str,
+) -> DynAsyncReadWrite
+where
+    IO: 'static + Unpin + Send + AsyncRead + AsyncWrite,
+{
+    use async_compat::CompatExt;
+    let conn = io.get_ref().1;
+    debug!(
+        hostname,
+        version = ?conn.protocol_version(),
+        cipher_suite = ?conn.negotiated_cipher_suite().map(|s| s.suite()),
+        "Established outbound TLS session"
+    );
+    split(io.compat())
+}
+
+fn split<IO>(io: IO) -> DynAsyncReadWrite
+where
+    IO: 'static + Send + AsyncRead + AsyncWrite,
+{
+    let (r, w) = io.split();
+    duplexify::Duplex::new(
+        Box::pin(r) as Pin<Box<dyn Send + AsyncRead>>,
+        Box::pin(w) as Pin<Box<dyn Send + AsyncWrite>>,
+    )
+}
+
+/// Turns a hex-encoded SHA-256 hash of a SubjectPublicKeyInfo into the
+/// equivalent DANE-EE(3) SPKI(1) SHA-256(1) record
+fn pinned_key(hash: &str) -> io::Result<smtp_client::dane::Tlsa> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid SPKI fingerprint ‘{}’", hash),
+        )
+    };
+    let hex = hash.replace(':', "");
+    if hex.len() != 64 {
+        return Err(invalid());
+    }
+    let data = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(invalid)?;
+    Ok(smtp_client::dane::Tlsa {
+        usage: smtp_client::dane::Usage::EndEntity,
+        selector: smtp_client::dane::Selector::Spki,
+        matching: smtp_client::dane::Matching::Sha256,
+        data,
+    })
 }
 
 // TODO: share across *_config.rs files?
@@ -51,33 +280,178 @@ impl smtp_client::Config for ClientConfig {
         run_hook!(must_do_tls() || false)
     }
 
+    fn tls_policy(&self, domain: &str) -> smtp_client::TlsPolicy {
+        use kannader_types::OutboundTlsPolicy;
+        let policy = run_hook!(tls_policy(domain.to_owned()) || OutboundTlsPolicy::Opportunistic);
+        match policy {
+            OutboundTlsPolicy::None => smtp_client::TlsPolicy::None,
+            OutboundTlsPolicy::Opportunistic => smtp_client::TlsPolicy::Opportunistic,
+            OutboundTlsPolicy::Verify { .. } | OutboundTlsPolicy::Fingerprint { .. } => {
+                smtp_client::TlsPolicy::Verify
+            }
+        }
+    }
+
     /// Note: If this function can only fail, make can_do_tls return false
-    async fn tls_connect<IO>(&self, io: IO) -> io::Result<DynAsyncReadWrite>
+    async fn tls_connect<IO>(
+        &self,
+        io: IO,
+        target: &smtp_client::TlsTarget<'_>,
+    ) -> io::Result<DynAsyncReadWrite>
     where
         IO: 'static + Unpin + Send + AsyncRead + AsyncWrite,
     {
-        use kannader_types::TlsHandler;
-        let handler = run_hook!(tls_handler() || TlsHandler::Rustls);
+        use kannader_types::{OutboundTlsPolicy, TlsHandler};
+        let verification = if !target.tlsa.is_empty() {
+            Verification::Pinned(target.tlsa.to_vec())
+        } else if !target.verify {
+            Verification::None
+        } else {
+            let policy =
+                run_hook!(tls_policy(target.domain.to_owned()) || OutboundTlsPolicy::Opportunistic);
+            match policy {
+                OutboundTlsPolicy::Verify {
+                    trust_store: Some(path),
+                } => Verification::TrustStore(path),
+                OutboundTlsPolicy::Fingerprint { spki_sha256 } => Verification::Pinned(
+                    spki_sha256
+                        .iter()
+                        .map(|h| pinned_key(h))
+                        .collect::<io::Result<Vec<_>>>()?,
+                ),
+                // REQUIRETLS and MTA-STS validate against the system roots
+                _ => Verification::SystemRoots,
+            }
+        };
+        let client_cert = run_hook!(tls_client_certificate(target.domain.to_owned()) || None);
+        let handler = run_hook!(tls_handler(target.domain.to_owned()) || TlsHandler::Rustls);
         match handler {
             TlsHandler::Rustls => {
-                // TODO: what should `nodomainyet` be here? for SNI maybe?
                 // TODO: switch everywhere to tokio?
-                use async_compat::CompatExt;
                 use std::convert::TryFrom;
-                let io = self
-                    .connector
-                    .connect(
+                let error = VerifyError::default();
+                let (cfg, name) = match &verification {
+                    // TODO: what should `nodomainyet` be here? for SNI maybe?
+                    Verification::None => (
+                        self.unverified.clone(),
                         rustls::ServerName::try_from("nodomainyet").unwrap(),
-                        io.compat(),
-                    )
-                    .await?;
-                let (r, w) = io.compat().split();
-                let io = duplexify::Duplex::new(
-                    Box::pin(r) as Pin<Box<dyn Send + AsyncRead>>,
-                    Box::pin(w) as Pin<Box<dyn Send + AsyncWrite>>,
+                    ),
+                    Verification::SystemRoots => (
+                        self.verifying(RootsVerifier::new(self.roots.clone(), error.clone())),
+                        server_name(target.hostname)?,
+                    ),
+                    Verification::TrustStore(path) => {
+                        let roots = self.trust_store(path).map_err(|e| {
+                            io::Error::new(
+                                e.kind(),
+                                format!("Loading trust store ‘{}’: {}", path.display(), e),
+                            )
+                        })?;
+                        (
+                            self.verifying(RootsVerifier::new(roots, error.clone())),
+                            server_name(target.hostname)?,
+                        )
+                    }
+                    Verification::Pinned(tlsa) => {
+                        let verifier = DaneVerifier::new(
+                            tlsa.clone(),
+                            target.hostname.to_owned(),
+                            error.clone(),
+                        );
+                        (self.verifying(verifier), server_name(target.hostname)?)
+                    }
+                };
+                let client_cert = client_cert
+                    .map(|cert| {
+                        let path = cert.cert_file.clone();
+                        match self.client_cert(cert) {
+                            Ok(key) => Ok((path, key)),
+                            Err(e) => Err(io::Error::new(
+                                e.kind(),
+                                format!("Loading client certificate ‘{}’: {}", path.display(), e),
+                            )),
+                        }
+                    })
+                    .transpose()?;
+                self.connect(cfg, &verification, client_cert, io, name, target.hostname)
+                    .await
+                    .map_err(|e| error.replace(e))
+            }
+            #[cfg(feature = "native-tls")]
+            TlsHandler::NativeTls => {
+                use async_compat::CompatExt;
+                let io =
+                    crate::tls_native::connect(io, target.hostname, &verification, client_cert)
+                        .await?;
+                debug!(
+                    hostname = target.hostname,
+                    "Established outbound TLS session with native-tls"
                 );
-                Ok(io)
+                Ok(split(io.compat()))
+            }
+            #[cfg(not(feature = "native-tls"))]
+            TlsHandler::NativeTls => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "kannader was built without the ‘native-tls’ feature",
+            )),
+        }
+    }
+
+    fn can_do_mta_sts(&self) -> bool {
+        run_hook!(can_do_mta_sts() || true)
+    }
+
+    async fn fetch_mta_sts_policy(
+        &self,
+        domain: &str,
+    ) -> Result<smtp_client::mta_sts::Policy, smtp_client::mta_sts::PolicyError> {
+        use smtp_client::mta_sts::PolicyError;
+        let host = format!("mta-sts.{}", domain);
+        let timeout = self.mta_sts_fetch_timeout();
+        let io = smol::future::or(
+            async {
+                let io = smol::net::TcpStream::connect((host.as_str(), 443))
+                    .await
+                    .map_err(PolicyError::Io)?;
+                self.tls_connect_verified(io, &host)
+                    .await
+                    .map_err(PolicyError::Io)
+            },
+            async {
+                smol::Timer::after(timeout.to_std().unwrap_or_default()).await;
+                Err(PolicyError::TimedOut)
+            },
+        )
+        .await?;
+        smtp_client::mta_sts::fetch_policy(io, domain, timeout).await
+    }
+
+    fn record_tls_result(&self, observation: smtp_client::tlsrpt::Observation) {
+        if let Some(store) = &self.tls_report_store {
+            let store = store.clone();
+            smol::spawn(async move {
+                if let Err(e) = crate::tls_report::record(store, observation).await {
+                    warn!(error = ?e, "Failed recording the TLS result");
+                }
+            })
+            .detach();
+        }
+    }
+
+    fn tls_failure_type(&self, error: &io::Error) -> smtp_client::tlsrpt::ResultType {
+        use smtp_client::tlsrpt::ResultType;
+        // The verifiers replace the rustls errors of the certificates they
+        // reject with the webpki ones
+        match error
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<webpki::Error>())
+        {
+            Some(webpki::Error::CertExpired | webpki::Error::CertNotValidYet) => {
+                ResultType::CertificateExpired
             }
+            Some(webpki::Error::CertNotValidForName) => ResultType::CertificateHostMismatch,
+            Some(webpki::Error::UnknownIssuer) => ResultType::CertificateNotTrusted,
+            _ => ResultType::ValidationFailure,
         }
     }
 
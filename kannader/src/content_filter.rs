@@ -0,0 +1,289 @@
+use std::{
+    io,
+    os::unix::process::CommandExt,
+    process::{ExitStatus, Stdio},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use futures::{AsyncReadExt, AsyncWriteExt};
+use smol::future::FutureExt;
+use tracing::warn;
+
+use smtp_message::{Reply, ReplyCodeKind};
+
+use crate::{
+    escaping,
+    server_config::{parse_reply, ConnMeta, MailMeta},
+};
+
+/// Exit code with which the command asks for a temporary rejection
+const EX_TEMPFAIL: i32 = 75;
+
+/// Outcome of running the content filter on a message
+pub enum Verdict {
+    /// The message to queue
+    Accept(Vec<u8>),
+    /// Temporary rejection, with the reply given by the command if any
+    TempFail(Option<Reply>),
+    /// Rejection, with the reply given by the command if any
+    Reject(Option<Reply>),
+}
+
+/// Sets the resource limit `resource` to `value`, from the `pre_exec` hook of
+/// the child process
+macro_rules! set_limit {
+    ($resource:expr, $value:expr) => {{
+        let limit = libc::rlimit {
+            rlim_cur: $value as libc::rlim_t,
+            rlim_max: $value as libc::rlim_t,
+        };
+        if libc::setrlimit($resource, &limit) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }};
+}
+
+/// Reads at most `max` bytes from `reader`, failing if there are more
+async fn read_limited<R>(reader: Option<R>, max: u64, what: &str) -> anyhow::Result<Vec<u8>>
+where
+    R: Unpin + futures::AsyncRead,
+{
+    let mut res = Vec::new();
+    if let Some(reader) = reader {
+        reader
+            .take(max.saturating_add(1))
+            .read_to_end(&mut res)
+            .await
+            .with_context(|| format!("Reading the {} of the content filter", what))?;
+        anyhow::ensure!(
+            res.len() as u64 <= max,
+            "The {} of the content filter is bigger than {} bytes",
+            what,
+            max
+        );
+    }
+    Ok(res)
+}
+
+/// Pipes `message` through the content filter command
+///
+/// `message` is escaped as spooled, the command is given it unescaped and
+/// the message it outputs is escaped back.
+///
+/// The command is given the envelope of the message in the
+/// `KANNADER_CLIENT_ADDRESS`, `KANNADER_HELO`, `KANNADER_FROM` and
+/// `KANNADER_RECIPIENTS` environment variables, the recipients being separated
+/// by spaces.
+pub async fn run(
+    cfg: &kannader_types::ContentFilter,
+    message: &[u8],
+    meta: &MailMeta,
+    conn_meta: &ConnMeta,
+) -> anyhow::Result<Verdict> {
+    let mut cmd = std::process::Command::new(&cfg.command);
+    cmd.args(&cfg.args)
+        .env(
+            "KANNADER_CLIENT_ADDRESS",
+            conn_meta
+                .peer_addr
+                .map(|a| a.ip().to_string())
+                .unwrap_or_default(),
+        )
+        .env(
+            "KANNADER_HELO",
+            conn_meta
+                .hello
+                .as_ref()
+                .map(|h| h.hostname.to_string())
+                .unwrap_or_default(),
+        )
+        .env(
+            "KANNADER_FROM",
+            meta.from
+                .as_ref()
+                .map_or_else(|| String::from("<>"), |f| f.to_string()),
+        )
+        .env(
+            "KANNADER_RECIPIENTS",
+            meta.to
+                .iter()
+                .map(|t| t.to_string())
+                .collect::<Vec<_>>()
+                .join(" "),
+        );
+    let (max_cpu_time, max_memory) = (cfg.max_cpu_time, cfg.max_memory);
+    // Safety: the closure only calls setrlimit, that is async-signal-safe
+    unsafe {
+        cmd.pre_exec(move || {
+            if let Some(t) = max_cpu_time {
+                set_limit!(libc::RLIMIT_CPU, t);
+            }
+            if let Some(m) = max_memory {
+                set_limit!(libc::RLIMIT_AS, m);
+            }
+            Ok(())
+        });
+    }
+    // The standard streams must be configured after the conversion, as it
+    // otherwise resets them to being inherited
+    let mut cmd = smol::process::Command::from(cmd);
+    cmd.stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    let mut child = cmd
+        .spawn()
+        .with_context(|| format!("Spawning the content filter ‘{}’", cfg.command.display()))?;
+
+    let message = escaping::unescape(message);
+    let mut stdin = child.stdin.take();
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let write = async move {
+        if let Some(stdin) = &mut stdin {
+            match stdin.write_all(&message).await {
+                // The command may legitimately not read the whole message
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => (),
+                r => r.context("Writing the message to the content filter")?,
+            }
+        }
+        // Dropping stdin closes it, signalling the end of the message
+        Ok::<_, anyhow::Error>(())
+    };
+    let interaction = async {
+        let ((), stdout, stderr) = futures::future::try_join3(
+            write,
+            read_limited(stdout, cfg.max_output, "output"),
+            read_limited(stderr, cfg.max_output, "error output"),
+        )
+        .await?;
+        let status = child
+            .status()
+            .await
+            .context("Waiting for the content filter to exit")?;
+        Ok::<(ExitStatus, Vec<u8>, Vec<u8>), anyhow::Error>((status, stdout, stderr))
+    };
+    let (status, stdout, stderr) = interaction
+        .or(async {
+            smol::Timer::after(Duration::from_secs(cfg.timeout)).await;
+            Err(anyhow!("The content filter timed out"))
+        })
+        .await?;
+
+    if !stderr.is_empty() {
+        warn!(
+            status = %status,
+            stderr = %String::from_utf8_lossy(&stderr),
+            "Content filter wrote error output"
+        );
+    }
+    let reply = |kind| parse_reply(&String::from_utf8_lossy(&stdout), kind);
+    match status.code() {
+        Some(0) if stdout.is_empty() => Err(anyhow!("The content filter output no message")),
+        Some(0) => Ok(Verdict::Accept(escaping::escape(&stdout))),
+        Some(EX_TEMPFAIL) => Ok(Verdict::TempFail(reply(ReplyCodeKind::TransientNegative))),
+        Some(_) => Ok(Verdict::Reject(reply(ReplyCodeKind::PermanentNegative))),
+        None => Err(anyhow!("The content filter was killed: {}", status)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smtp_message::Email;
+
+    use crate::server_config::ConnUserMeta;
+
+    use super::*;
+
+    const MESSAGE: &[u8] = b"Subject: Dots\r\n\r\n..hidden\r\n.\r\n";
+
+    fn filter(script: &str, timeout: u64) -> anyhow::Result<Verdict> {
+        let cfg = kannader_types::ContentFilter {
+            command: "/bin/sh".into(),
+            args: vec![String::from("-c"), String::from(script)],
+            timeout,
+            max_output: 1024,
+            max_cpu_time: None,
+            max_memory: None,
+        };
+        let meta = MailMeta {
+            user: kannader_types::MailUser::default(),
+            from: Email::parse_bracketed(b"<joe@example.org>").ok(),
+            to: Vec::new(),
+            require_tls: false,
+        };
+        let conn_meta = ConnMeta {
+            user: ConnUserMeta::default(),
+            peer_addr: None,
+            hello: None,
+            is_encrypted: false,
+            tls_server_name: None,
+            tls_client_cert: None,
+        };
+        smol::block_on(run(&cfg, MESSAGE, &meta, &conn_meta))
+    }
+
+    fn reply(r: Option<Reply>) -> Option<String> {
+        r.map(|r| r.to_string())
+    }
+
+    #[test]
+    fn accepted() {
+        match filter("cat", 10).unwrap() {
+            Verdict::Accept(message) => assert_eq!(message, MESSAGE),
+            _ => panic!("The message was not accepted"),
+        }
+        // The command sees the message unescaped, and may leave the last line
+        // unterminated
+        match filter("grep -q '^\\.hidden' && printf 'Subject: Dots'", 10).unwrap() {
+            Verdict::Accept(message) => assert_eq!(message, b"Subject: Dots\r\n.\r\n"),
+            _ => panic!("The message was not accepted"),
+        }
+    }
+
+    #[test]
+    fn accepted_bare_lf() {
+        // A bare LF followed by a dot must not end the data at the next hop
+        match filter("printf 'Subject: Dots\\n.\\r\\nMAIL FROM:<>\\r\\n'", 10).unwrap() {
+            Verdict::Accept(message) => assert_eq!(
+                message,
+                b"Subject: Dots\r\n..\r\nMAIL FROM:<>\r\n.\r\n".as_ref()
+            ),
+            _ => panic!("The message was not accepted"),
+        }
+    }
+
+    #[test]
+    fn rejected() {
+        match filter("exit 75", 10).unwrap() {
+            Verdict::TempFail(None) => (),
+            _ => panic!("The message was not temporarily rejected"),
+        }
+        match filter("echo '451 4.7.1 Try again later'; exit 75", 10).unwrap() {
+            Verdict::TempFail(r) => {
+                assert_eq!(reply(r).as_deref(), Some("451 4.7.1 Try again later\r\n"))
+            }
+            _ => panic!("The message was not temporarily rejected"),
+        }
+        match filter("echo '550 5.7.1 No thanks'; exit 1", 10).unwrap() {
+            Verdict::Reject(r) => {
+                assert_eq!(reply(r).as_deref(), Some("550 5.7.1 No thanks\r\n"))
+            }
+            _ => panic!("The message was not rejected"),
+        }
+        // Replies of the wrong kind are ignored
+        match filter("echo '250 Fine'; exit 1", 10).unwrap() {
+            Verdict::Reject(None) => (),
+            _ => panic!("The message was not rejected"),
+        }
+    }
+
+    #[test]
+    fn failed() {
+        let e = filter("exec sleep 10", 1).err().unwrap();
+        assert_eq!(e.to_string(), "The content filter timed out");
+        assert!(filter("true", 10).is_err());
+        assert!(filter("kill -9 $$", 10).is_err());
+    }
+}
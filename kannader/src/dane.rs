@@ -0,0 +1,67 @@
+use std::{convert::TryFrom, time::SystemTime};
+
+use smtp_client::dane;
+
+use crate::verifier::{VerifyError, SIGNATURE_ALGORITHMS};
+
+/// Authenticates the server with its DANE TLSA records (RFC 7672)
+///
+/// Pinned public keys are checked this way too, as DANE-EE records.
+pub struct DaneVerifier {
+    tlsa: Vec<dane::Tlsa>,
+    /// Name of the MX host, against which DANE-TA chains are checked
+    hostname: String,
+    error: VerifyError,
+}
+
+impl DaneVerifier {
+    pub fn new(tlsa: Vec<dane::Tlsa>, hostname: String, error: VerifyError) -> DaneVerifier {
+        DaneVerifier {
+            tlsa,
+            hostname,
+            error,
+        }
+    }
+}
+
+impl rustls::client::ServerCertVerifier for DaneVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        _server_name: &rustls::client::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        // DANE-EE pins the certificate itself, regardless of its name or dates
+        if dane::matches_end_entity(&self.tlsa, &end_entity.0) {
+            return Ok(rustls::client::ServerCertVerified::assertion());
+        }
+
+        // DANE-TA designates a trust anchor that must be part of the chain
+        let chain = intermediates
+            .iter()
+            .map(|c| c.0.as_slice())
+            .collect::<Vec<_>>();
+        let anchor = dane::trust_anchor(&self.tlsa, &chain).ok_or_else(|| {
+            rustls::Error::InvalidCertificateData(String::from("no TLSA record matches"))
+        })?;
+        let record = |e| self.error.record(e);
+        let anchor = webpki::TrustAnchor::try_from_cert_der(anchor).map_err(record)?;
+        let cert = webpki::EndEntityCert::try_from(end_entity.0.as_slice()).map_err(record)?;
+        let time =
+            webpki::Time::try_from(now).map_err(|_| rustls::Error::FailedToGetCurrentTime)?;
+        cert.verify_is_valid_tls_server_cert(
+            SIGNATURE_ALGORITHMS,
+            &webpki::TlsServerTrustAnchors(&[anchor]),
+            &chain,
+            time,
+        )
+        .map_err(record)?;
+        let name = webpki::DnsNameRef::try_from_ascii_str(&self.hostname)
+            .map_err(|_| rustls::Error::UnsupportedNameType)?;
+        cert.verify_is_valid_for_dns_name(name).map_err(record)?;
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
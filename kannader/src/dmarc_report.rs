@@ -0,0 +1,198 @@
+use std::{
+    ffi::OsString,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Context};
+use chrono::Utc;
+use futures::AsyncWriteExt;
+use smol::unblock;
+use tracing::{info, warn};
+
+use smtp_message::Email;
+use smtp_queue::{Storage, StorageEnqueuer};
+use smtp_queue_fs::FsStorage;
+
+use crate::{Meta, Opt, WasmConfig};
+
+/// Appends `observation` to the DMARC results store
+pub async fn record(
+    store: Arc<PathBuf>,
+    observation: smtp_dmarc::Observation,
+) -> anyhow::Result<()> {
+    unblock(move || {
+        let mut line = serde_json::to_vec(&observation).context("Serializing the DMARC result")?;
+        line.push(b'\n');
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&*store)
+            .and_then(|mut f| f.write_all(&line))
+            .with_context(|| format!("Appending to the DMARC results store ‘{}’", store.display()))
+    })
+    .await
+}
+
+/// Path to which a results store is moved while its reports are being sent
+pub fn sending_path(store: &Path) -> PathBuf {
+    let mut sending = OsString::from(store);
+    sending.push(".sending");
+    PathBuf::from(sending)
+}
+
+/// Moves the results store out of the way to `sending`, so that the results
+/// recorded from now on go to the next reports, and returns its contents
+///
+/// If `sending` is still there, the last run failed and it is retried first.
+/// `kind` is the kind of the results, for error messages.
+pub async fn take_store(
+    store: PathBuf,
+    sending: PathBuf,
+    kind: &'static str,
+) -> anyhow::Result<Option<String>> {
+    unblock(move || {
+        if !sending.exists() {
+            match std::fs::rename(&store, &sending) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => {
+                    return Err(e).with_context(|| {
+                        format!("Moving the {} results store ‘{}’", kind, store.display())
+                    })
+                }
+            }
+        }
+        std::fs::read_to_string(&sending)
+            .with_context(|| format!("Reading the {} results ‘{}’", kind, sending.display()))
+            .map(Some)
+    })
+    .await
+}
+
+pub fn parse_email(addr: &str) -> Option<Email> {
+    Email::parse_bracketed(format!("<{}>", addr).as_bytes()).ok()
+}
+
+/// Generates the DMARC aggregate reports for the results recorded so far, and
+/// enqueues them for sending
+///
+/// The reports are sent by kannader once it picks them up from the queue,
+/// which currently happens when it starts up.
+pub fn send_dmarc_reports(opt: &Opt) -> anyhow::Result<()> {
+    let engine = wasmtime::Engine::default();
+    let module = wasmtime::Module::from_file(&engine, &opt.wasm_blob)
+        .context("Compiling the wasm configuration blob")?;
+    let wasm_config = WasmConfig::new(&opt.dirs, &opt.config, &engine, &module)
+        .context("Preparing the wasm configuration blob")?;
+    let (reporting, storage) = {
+        let mut store = wasm_config.store.borrow_mut();
+        let reporting = (wasm_config.server_config.dmarc_reporting)(&mut *store)
+            .context("Retrieving the DMARC reporting configuration")?;
+        let storage = (wasm_config.queue_config.storage_type)(&mut *store)
+            .context("Retrieving storage type")?;
+        (reporting, storage)
+    };
+    let reporting = reporting.ok_or_else(|| anyhow!("DMARC reporting is not configured"))?;
+    let from = parse_email(&reporting.email)
+        .ok_or_else(|| anyhow!("Invalid DMARC report sender address ‘{}’", reporting.email))?;
+
+    smol::block_on(async move {
+        let sending = sending_path(&reporting.store);
+        let contents = take_store(reporting.store, sending.clone(), "DMARC").await?;
+        let contents = match contents {
+            Some(c) => c,
+            None => {
+                info!("No DMARC results recorded, not sending any report");
+                return Ok(());
+            }
+        };
+
+        let mut observations = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            match serde_json::from_str(line) {
+                Ok(o) => observations.push(o),
+                Err(e) => warn!(line = i + 1, error = ?e, "Ignoring invalid DMARC result"),
+            }
+        }
+
+        let reporter = smtp_dmarc::Reporter {
+            org_name: reporting.org_name,
+            email: reporting.email,
+            extra_contact_info: reporting.extra_contact_info,
+        };
+        let resolver = async_std_resolver::resolver_from_system_conf()
+            .await
+            .context("Configuring a resolver from system configuration")?;
+        let storage = match storage {
+            kannader_types::QueueStorage::Fs(path) => FsStorage::<Meta>::new(Arc::new(path))
+                .await
+                .context("Opening the queue storage folder")?,
+        };
+
+        for report in smtp_dmarc::aggregate(observations) {
+            let mut to = Vec::new();
+            for addr in report.recipients() {
+                match smtp_dmarc::verify_destination(&resolver, &report.policy_domain, &addr).await
+                {
+                    Ok(true) => match parse_email(&addr) {
+                        Some(email) => to.push((addr, email)),
+                        None => warn!(address = %addr, "Invalid DMARC reporting address"),
+                    },
+                    Ok(false) => warn!(
+                        address = %addr,
+                        domain = %report.policy_domain,
+                        "DMARC reporting address does not accept reports for the domain",
+                    ),
+                    Err(e) => warn!(
+                        address = %addr,
+                        error = ?e,
+                        "Failed verifying the DMARC reporting address",
+                    ),
+                }
+            }
+            if to.is_empty() {
+                info!(domain = %report.policy_domain, "No DMARC reporting address to send the report to");
+                continue;
+            }
+
+            let addrs = to.iter().map(|(a, _)| a.clone()).collect::<Vec<_>>();
+            let mail = report.to_mail(&reporter, &addrs, &Utc::now().to_rfc2822());
+            let mut enqueuer = storage.enqueue().await.context("Opening an enqueuer")?;
+            enqueuer
+                .write_all(&mail)
+                .await
+                .context("Writing the DMARC report to the queue")?;
+            let destinations = to
+                .into_iter()
+                .map(|(_, to)| {
+                    (
+                        smtp_queue::MailMetadata {
+                            from: Some(from.clone()),
+                            to,
+                            require_tls: false,
+                            tls_optional: false,
+                            metadata: Meta::default(),
+                        },
+                        smtp_queue::ScheduleInfo {
+                            at: Utc::now(),
+                            last_attempt: None,
+                        },
+                    )
+                })
+                .collect();
+            enqueuer
+                .commit(destinations)
+                .await
+                .context("Committing the DMARC report to the queue")?;
+            info!(domain = %report.policy_domain, report_id = %report.report_id, "Enqueued DMARC report");
+        }
+
+        unblock(move || {
+            std::fs::remove_file(&sending)
+                .with_context(|| format!("Removing the sent DMARC results ‘{}’", sending.display()))
+        })
+        .await
+    })
+}
@@ -0,0 +1,490 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    io::{self, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use smol::unblock;
+use tracing::warn;
+
+/// State of a triplet or of a client network
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize)]
+struct Entry {
+    /// Unix timestamp, in seconds
+    first_seen: u64,
+    /// Unix timestamp, in seconds
+    last_seen: u64,
+    /// Number of times the triplet was accepted, or number of triplets of the
+    /// client network that passed greylisting
+    passes: u32,
+}
+
+/// Line of the store, describing a triplet or, without sender and
+/// recipient, a client network
+#[derive(serde::Deserialize, serde::Serialize)]
+struct Line {
+    network: String,
+    triplet: Option<(String, String)>,
+    entry: Entry,
+}
+
+type Triplet = (String, String, String);
+
+/// Minimum number of lines in the store before it gets compacted
+const MIN_COMPACTED_LINES: usize = 1024;
+
+/// Seconds after which the store gets compacted anyway, to forget the entries
+/// that expired
+const COMPACTION_INTERVAL: u64 = 3600;
+
+#[derive(Default)]
+struct State {
+    triplets: HashMap<Triplet, Entry>,
+    clients: HashMap<String, Entry>,
+    /// Number of lines in the store
+    lines: usize,
+    /// Unix timestamp of the last compaction, in seconds
+    compacted_at: u64,
+}
+
+impl State {
+    fn needs_compaction(&self, now: u64) -> bool {
+        let entries = self.triplets.len() + self.clients.len();
+        self.lines > MIN_COMPACTED_LINES.max(2 * entries)
+            || now >= self.compacted_at + COMPACTION_INTERVAL
+    }
+}
+
+/// Keeps the latest of the entries recorded for `key`, as concurrent writes
+/// may have been reordered in the store
+fn keep_latest<K: Eq + Hash>(map: &mut HashMap<K, Entry>, key: K, entry: Entry) {
+    let e = map.entry(key).or_insert(entry);
+    if e.last_seen <= entry.last_seen {
+        *e = entry;
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Greylisting state, shared by all the connections and persisted as an
+/// append-only log of JSON lines
+///
+/// The log is compacted when opened, whenever it grew to twice the number of
+/// entries, and every [`COMPACTION_INTERVAL`](COMPACTION_INTERVAL).
+pub struct Greylist {
+    cfg: kannader_types::Greylisting,
+    store: Arc<PathBuf>,
+    /// Held while writing to the store, so that appends do not get lost while
+    /// it is compacted
+    store_lock: smol::lock::Mutex<()>,
+    state: Mutex<State>,
+    /// Returns the current Unix timestamp, in seconds
+    clock: Box<dyn Send + Sync + Fn() -> u64>,
+}
+
+impl Greylist {
+    /// Loads the store, and rewrites it without the entries that expired
+    ///
+    /// This does blocking I/O.
+    pub fn open(cfg: kannader_types::Greylisting) -> anyhow::Result<Greylist> {
+        Greylist::with_clock(cfg, Box::new(now))
+    }
+
+    fn with_clock(
+        cfg: kannader_types::Greylisting,
+        clock: Box<dyn Send + Sync + Fn() -> u64>,
+    ) -> anyhow::Result<Greylist> {
+        let contents = match std::fs::read_to_string(&cfg.store) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("Reading the greylisting store ‘{}’", cfg.store.display())
+                })
+            }
+        };
+
+        let store = Arc::new(cfg.store.clone());
+        let res = Greylist {
+            cfg,
+            store,
+            store_lock: smol::lock::Mutex::new(()),
+            state: Mutex::new(State::default()),
+            clock,
+        };
+        let now = (res.clock)();
+        let mut state = State::default();
+        for (i, line) in contents.lines().enumerate() {
+            let line: Line = match serde_json::from_str(line) {
+                Ok(l) => l,
+                Err(e) => {
+                    warn!(line = i + 1, error = ?e, "Ignoring invalid greylisting entry");
+                    continue;
+                }
+            };
+            match line.triplet {
+                Some((from, to)) => {
+                    keep_latest(&mut state.triplets, (line.network, from, to), line.entry)
+                }
+                None => keep_latest(&mut state.clients, line.network, line.entry),
+            }
+        }
+        let compacted = res.compact(&mut state, now)?;
+        rewrite(&res.store, &compacted)?;
+
+        *res.state.lock().unwrap() = state;
+        Ok(res)
+    }
+
+    /// Forgets the entries that expired, and returns the contents of the store
+    /// with only the remaining ones
+    fn compact(&self, state: &mut State, now: u64) -> anyhow::Result<Vec<u8>> {
+        state.triplets.retain(|_, e| !self.is_expired(e, now));
+        state.clients.retain(|_, e| !self.is_expired(e, now));
+
+        let mut compacted = Vec::new();
+        for ((network, from, to), entry) in &state.triplets {
+            compacted.push(Line {
+                network: network.clone(),
+                triplet: Some((from.clone(), to.clone())),
+                entry: *entry,
+            });
+        }
+        for (network, entry) in &state.clients {
+            compacted.push(Line {
+                network: network.clone(),
+                triplet: None,
+                entry: *entry,
+            });
+        }
+        state.lines = compacted.len();
+        state.compacted_at = now;
+        serialize(&compacted)
+    }
+
+    fn is_expired(&self, entry: &Entry, now: u64) -> bool {
+        if entry.passes == 0 {
+            now > entry.first_seen + self.cfg.initial_delay + self.cfg.retry_window
+        } else {
+            now > entry.last_seen + self.cfg.lifetime
+        }
+    }
+
+    fn network(&self, ip: IpAddr) -> String {
+        let ip = match ip {
+            IpAddr::V6(ip) => ip
+                .to_ipv4_mapped()
+                .map(IpAddr::V4)
+                .unwrap_or(IpAddr::V6(ip)),
+            ip => ip,
+        };
+        match ip {
+            IpAddr::V4(ip) => {
+                let prefix = self.cfg.ipv4_prefix.min(32);
+                let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+                format!("{}/{}", Ipv4Addr::from(u32::from(ip) & mask), prefix)
+            }
+            IpAddr::V6(ip) => {
+                let prefix = self.cfg.ipv6_prefix.min(128);
+                let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+                format!("{}/{}", Ipv6Addr::from(u128::from(ip) & mask), prefix)
+            }
+        }
+    }
+
+    /// Returns whether mail from `ip` and `from` to `to` is to be accepted
+    /// now, recording the attempt
+    pub async fn check(&self, ip: IpAddr, from: &str, to: &str) -> anyhow::Result<bool> {
+        let now = (self.clock)();
+        let network = self.network(ip);
+        let mut lines = Vec::new();
+        let pass = {
+            let mut state = self.state.lock().unwrap();
+            let whitelisted = match state.clients.get_mut(&network) {
+                Some(client)
+                    if !self.is_expired(client, now)
+                        && self.cfg.whitelist_client_after > 0
+                        && client.passes >= self.cfg.whitelist_client_after =>
+                {
+                    client.last_seen = now;
+                    lines.push(Line {
+                        network: network.clone(),
+                        triplet: None,
+                        entry: *client,
+                    });
+                    true
+                }
+                _ => false,
+            };
+
+            if whitelisted {
+                true
+            } else {
+                let key = (network.clone(), from.to_lowercase(), to.to_lowercase());
+                let (entry, is_new) = match state.triplets.get(&key) {
+                    Some(e) if !self.is_expired(e, now) => (*e, false),
+                    _ => {
+                        let e = Entry {
+                            first_seen: now,
+                            last_seen: now,
+                            passes: 0,
+                        };
+                        (e, true)
+                    }
+                };
+                let pass = entry.passes > 0 || now >= entry.first_seen + self.cfg.initial_delay;
+                if pass && entry.passes == 0 {
+                    // The triplet passed greylisting, count it for its client network
+                    let client = state
+                        .clients
+                        .entry(network.clone())
+                        .and_modify(|c| {
+                            if self.is_expired(c, now) {
+                                c.first_seen = now;
+                                c.passes = 0;
+                            }
+                        })
+                        .or_insert(Entry {
+                            first_seen: now,
+                            last_seen: now,
+                            passes: 0,
+                        });
+                    client.last_seen = now;
+                    client.passes += 1;
+                    lines.push(Line {
+                        network: network.clone(),
+                        triplet: None,
+                        entry: *client,
+                    });
+                }
+                if pass || is_new {
+                    let entry = Entry {
+                        last_seen: now,
+                        passes: entry.passes + u32::from(pass),
+                        ..entry
+                    };
+                    state.triplets.insert(key.clone(), entry);
+                    lines.push(Line {
+                        network: key.0,
+                        triplet: Some((key.1, key.2)),
+                        entry,
+                    });
+                }
+                pass
+            }
+        };
+
+        let _store_lock = self.store_lock.lock().await;
+        if !lines.is_empty() {
+            let data = serialize(&lines)?;
+            self.state.lock().unwrap().lines += lines.len();
+            let store = self.store.clone();
+            unblock(move || {
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&*store)
+                    .and_then(|mut f| f.write_all(&data))
+                    .with_context(|| {
+                        format!("Appending to the greylisting store ‘{}’", store.display())
+                    })
+            })
+            .await?;
+        }
+        let compacted = {
+            let mut state = self.state.lock().unwrap();
+            if state.needs_compaction(now) {
+                Some(self.compact(&mut state, now)?)
+            } else {
+                None
+            }
+        };
+        if let Some(compacted) = compacted {
+            let store = self.store.clone();
+            unblock(move || rewrite(&store, &compacted)).await?;
+        }
+        Ok(pass)
+    }
+}
+
+/// Atomically replaces the contents of the store with `data`
+fn rewrite(store: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let mut tmp = store.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    std::fs::write(&tmp, data)
+        .and_then(|()| std::fs::rename(&tmp, store))
+        .with_context(|| format!("Rewriting the greylisting store ‘{}’", store.display()))
+}
+
+fn serialize(lines: &[Line]) -> anyhow::Result<Vec<u8>> {
+    let mut res = Vec::new();
+    for l in lines {
+        serde_json::to_writer(&mut res, l).context("Serializing a greylisting entry")?;
+        res.push(b'\n');
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+
+    const START: u64 = 1_000_000;
+
+    struct Test {
+        greylist: Greylist,
+        now: Arc<AtomicU64>,
+    }
+
+    impl Test {
+        fn new(name: &str, whitelist_client_after: u32) -> Test {
+            let store = std::env::temp_dir().join(format!(
+                "kannader-greylist-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let _ = std::fs::remove_file(&store);
+            let cfg = kannader_types::Greylisting {
+                store,
+                initial_delay: 300,
+                retry_window: 3600,
+                lifetime: 86400,
+                whitelist_client_after,
+                ipv4_prefix: 24,
+                ipv6_prefix: 64,
+            };
+            let now = Arc::new(AtomicU64::new(START));
+            Test {
+                greylist: Test::open(cfg, now.clone()),
+                now,
+            }
+        }
+
+        fn open(cfg: kannader_types::Greylisting, now: Arc<AtomicU64>) -> Greylist {
+            Greylist::with_clock(cfg, Box::new(move || now.load(Ordering::SeqCst))).unwrap()
+        }
+
+        fn reopen(&mut self) {
+            self.greylist = Test::open(self.greylist.cfg.clone(), self.now.clone());
+        }
+
+        fn check_at(&self, time: u64, ip: &str, to: &str) -> bool {
+            self.now.store(START + time, Ordering::SeqCst);
+            let ip = ip.parse().unwrap();
+            smol::block_on(self.greylist.check(ip, "joe@example.org", to)).unwrap()
+        }
+
+        fn store_lines(&self) -> usize {
+            std::fs::read_to_string(&self.greylist.cfg.store)
+                .unwrap()
+                .lines()
+                .count()
+        }
+    }
+
+    impl Drop for Test {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.greylist.cfg.store);
+        }
+    }
+
+    #[test]
+    fn initial_delay() {
+        let mut t = Test::new("initial_delay", 0);
+        assert!(!t.check_at(0, "192.0.2.1", "foo@example.net"));
+        assert!(!t.check_at(299, "192.0.2.1", "foo@example.net"));
+        // Same client network, and addresses are compared case-insensitively
+        assert!(t.check_at(300, "192.0.2.2", "FOO@example.net"));
+        assert!(t.check_at(301, "192.0.2.1", "foo@example.net"));
+        // Another triplet
+        assert!(!t.check_at(302, "192.0.2.1", "bar@example.net"));
+        assert!(!t.check_at(303, "198.51.100.1", "foo@example.net"));
+        // The store keeps the state across restarts
+        t.reopen();
+        assert!(t.check_at(304, "192.0.2.1", "foo@example.net"));
+        assert!(!t.check_at(305, "192.0.2.1", "baz@example.net"));
+    }
+
+    #[test]
+    fn retry_window() {
+        let t = Test::new("retry_window", 0);
+        assert!(!t.check_at(0, "192.0.2.1", "foo@example.net"));
+        // Retrying after the window restarts the delay
+        assert!(!t.check_at(3901, "192.0.2.1", "foo@example.net"));
+        assert!(!t.check_at(4200, "192.0.2.1", "foo@example.net"));
+        assert!(t.check_at(4201, "192.0.2.1", "foo@example.net"));
+        // Retrying at the end of the window passes
+        assert!(!t.check_at(5000, "192.0.2.1", "bar@example.net"));
+        assert!(t.check_at(8900, "192.0.2.1", "bar@example.net"));
+    }
+
+    #[test]
+    fn lifetime() {
+        let t = Test::new("lifetime", 0);
+        assert!(!t.check_at(0, "192.0.2.1", "foo@example.net"));
+        assert!(t.check_at(300, "192.0.2.1", "foo@example.net"));
+        // Every use extends the lifetime
+        assert!(t.check_at(300 + 86400, "192.0.2.1", "foo@example.net"));
+        assert!(t.check_at(300 + 2 * 86400, "192.0.2.1", "foo@example.net"));
+        assert!(!t.check_at(301 + 3 * 86400, "192.0.2.1", "foo@example.net"));
+    }
+
+    #[test]
+    fn client_whitelisting() {
+        let t = Test::new("client_whitelisting", 2);
+        for to in &["foo@example.net", "bar@example.net"] {
+            assert!(!t.check_at(0, "192.0.2.1", to));
+        }
+        for to in &["foo@example.net", "bar@example.net"] {
+            assert!(t.check_at(300, "192.0.2.1", to));
+        }
+        assert!(t.check_at(301, "192.0.2.42", "baz@example.net"));
+        assert!(!t.check_at(302, "198.51.100.1", "baz@example.net"));
+        // The whitelisting is forgotten with the client network
+        assert!(t.check_at(301 + 86400, "192.0.2.42", "baz@example.net"));
+        assert!(!t.check_at(302 + 2 * 86400, "192.0.2.42", "qux@example.net"));
+
+        let t = Test::new("no_client_whitelisting", 0);
+        for to in &["foo@example.net", "bar@example.net", "baz@example.net"] {
+            assert!(!t.check_at(0, "192.0.2.1", to));
+        }
+        for to in &["foo@example.net", "bar@example.net", "baz@example.net"] {
+            assert!(t.check_at(300, "192.0.2.1", to));
+        }
+        assert!(!t.check_at(301, "192.0.2.1", "qux@example.net"));
+    }
+
+    #[test]
+    fn compaction() {
+        // The store is compacted once it gets twice as long as needed
+        let t = Test::new("compaction", 0);
+        assert!(!t.check_at(0, "192.0.2.1", "foo@example.net"));
+        for i in 0..3 * MIN_COMPACTED_LINES as u64 {
+            assert!(t.check_at(300 + i, "192.0.2.1", "foo@example.net"));
+            assert!(t.store_lines() <= MIN_COMPACTED_LINES);
+        }
+
+        // And periodically, to forget the entries that expired
+        let t = Test::new("periodic_compaction", 0);
+        for i in 0..100 {
+            assert!(!t.check_at(0, "192.0.2.1", &format!("{}@example.net", i)));
+        }
+        assert!(!t.check_at(3000, "192.0.2.1", "foo@example.net"));
+        assert_eq!(t.store_lines(), 101);
+        assert!(!t.check_at(4000, "192.0.2.1", "bar@example.net"));
+        assert_eq!(t.store_lines(), 2);
+    }
+}
@@ -7,34 +7,77 @@
 // TODO: make everything configurable, and actually implement the wasm scheme
 // described in the docs
 
-use std::{convert::TryFrom, io, path::PathBuf, sync::Arc, time::SystemTime};
+use std::{collections::HashMap, convert::TryFrom, path::PathBuf, sync::Arc, time::SystemTime};
 
 use anyhow::Context;
 use easy_parallel::Parallel;
 use futures::StreamExt;
 use scoped_tls::scoped_thread_local;
 use smol::{future::FutureExt, unblock};
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
 use smtp_queue_fs::FsStorage;
 
 const NUM_THREADS: usize = 4;
 const DATABUF_SIZE: usize = 16 * 1024;
 
+mod antivirus;
+mod authres;
+mod client_auth;
 mod client_config;
+mod content_filter;
+mod dane;
+mod dmarc_report;
+mod escaping;
+mod greylist;
+mod milter;
+mod quarantine;
 mod queue_config;
 mod queue_transport;
+mod reverse_dns;
 mod server_config;
+mod sni;
+mod spam;
+#[cfg(feature = "native-tls")]
+mod tls_native;
+mod tls_profile;
+mod tls_report;
+mod tls_session;
+mod verifier;
 mod wasm_config;
 
 use client_config::ClientConfig;
+pub use dmarc_report::send_dmarc_reports;
 use queue_config::QueueConfig;
 use queue_transport::QueueTransport;
 use server_config::ServerConfig;
+pub use tls_report::send_tls_reports;
 use wasm_config::WasmConfig;
 
-#[derive(serde::Deserialize, serde::Serialize)]
-pub struct Meta;
+/// Metadata of the queued mails
+#[derive(Default, serde::Serialize)]
+pub struct Meta {
+    /// Whether the mail is still to be handed to the after-queue filter
+    pub after_queue_filter: bool,
+}
+
+impl<'de> serde::Deserialize<'de> for Meta {
+    fn deserialize<D>(deserializer: D) -> Result<Meta, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        // Mails queued back when `Meta` had no fields have it recorded as `null`
+        #[derive(serde::Deserialize)]
+        struct Fields {
+            #[serde(default)]
+            after_queue_filter: bool,
+        }
+        let fields = Option::<Fields>::deserialize(deserializer)?;
+        Ok(Meta {
+            after_queue_filter: fields.map_or(false, |f| f.after_queue_filter),
+        })
+    }
+}
 
 struct NoCertVerifier;
 
@@ -54,6 +97,61 @@ impl rustls::client::ServerCertVerifier for NoCertVerifier {
 
 scoped_thread_local!(static WASM_CONFIG: WasmConfig);
 
+fn dkim_canonicalization(c: kannader_types::DkimCanonicalization) -> smtp_dkim::Canonicalization {
+    match c {
+        kannader_types::DkimCanonicalization::Simple => smtp_dkim::Canonicalization::Simple,
+        kannader_types::DkimCanonicalization::Relaxed => smtp_dkim::Canonicalization::Relaxed,
+    }
+}
+
+/// Reads the signing keys, indexed by lowercase domain, `kind` being the
+/// purpose of the keys for error messages
+fn load_signing_keys(
+    keys: Vec<kannader_types::DkimSigningKey>,
+    kind: &str,
+) -> anyhow::Result<HashMap<String, Arc<smtp_dkim::SigningConfig>>> {
+    let mut res = HashMap::new();
+    for k in keys {
+        let pem = std::fs::read_to_string(&k.key_file)
+            .with_context(|| format!("Reading the {} key file ‘{}’", kind, k.key_file.display()))?;
+        let key = smtp_dkim::SigningKey::from_pem(&pem)
+            .with_context(|| format!("Parsing the {} key file ‘{}’", kind, k.key_file.display()))?;
+        let config = smtp_dkim::SigningConfig {
+            domain: k.domain.clone(),
+            selector: k.selector,
+            key,
+            signed_headers: k.signed_headers,
+            header_canonicalization: dkim_canonicalization(k.header_canonicalization),
+            body_canonicalization: dkim_canonicalization(k.body_canonicalization),
+        };
+        res.insert(k.domain.to_ascii_lowercase(), Arc::new(config));
+    }
+    Ok(res)
+}
+
+/// Returns the system root certificates
+fn system_roots() -> anyhow::Result<Arc<verifier::Roots>> {
+    let certs =
+        rustls_native_certs::load_native_certs().context("Loading the system root certificates")?;
+    let (roots, ignored) = verifier::Roots::parse(certs.iter().map(|c| c.0.as_slice()));
+    if ignored > 0 {
+        warn!(ignored, "Ignoring invalid system root certificates");
+    }
+    Ok(Arc::new(roots))
+}
+
+/// Returns a TLS client configuration checking the certificates against the
+/// system roots
+fn verified_tls_config(
+    tls: rustls::ConfigBuilder<rustls::ClientConfig, rustls::WantsVerifier>,
+) -> anyhow::Result<Arc<rustls::ClientConfig>> {
+    let verifier = verifier::RootsVerifier::new(system_roots()?, verifier::VerifyError::default());
+    Ok(Arc::new(
+        tls.with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth(),
+    ))
+}
+
 fn parse_dirs(s: &str) -> anyhow::Result<(PathBuf, PathBuf)> {
     let d = s.split("::").collect::<Vec<_>>();
     anyhow::ensure!(d.len() == 2, "invalid syntax");
@@ -83,6 +181,19 @@ pub struct Opt {
     /// Directories to make available to the wasm configuration blob
     #[structopt(short, long = "dir", value_name = "GUEST_DIR::HOST_DIR", parse(try_from_str = parse_dirs))]
     pub dirs: Vec<(PathBuf, PathBuf)>,
+
+    #[structopt(subcommand)]
+    pub cmd: Option<Command>,
+}
+
+#[derive(structopt::StructOpt)]
+pub enum Command {
+    /// Generate DMARC aggregate reports from the recorded results, and enqueue
+    /// them for sending
+    DmarcReport,
+    /// Generate SMTP TLS reports from the recorded results, and send them;
+    /// meant to be run daily
+    TlsReport,
 }
 
 pub fn run(opt: &Opt, shutdown: smol::channel::Receiver<()>) -> anyhow::Result<()> {
@@ -127,21 +238,55 @@ pub fn run(opt: &Opt, shutdown: smol::channel::Receiver<()>) -> anyhow::Result<(
                 smol::block_on(async move {
                     // Prepare the clients
                     debug!("Preparing the client configuration");
-                    // TODO: see for configuring persistence, for more performance?
-                    let tls_client_cfg = rustls::ClientConfig::builder()
-                        .with_cipher_suites(rustls::ALL_CIPHER_SUITES)
-                        .with_kx_groups(&rustls::ALL_KX_GROUPS)
-                        .with_protocol_versions(rustls::ALL_VERSIONS)
-                        .context("Configuring the rustls client")?
-                        .with_custom_certificate_verifier(Arc::new(NoCertVerifier))
-                        .with_no_client_auth();
-                    let connector = tokio_rustls::TlsConnector::from(Arc::new(tls_client_cfg));
-                    let client = smtp_client::Client::new(
-                        async_std_resolver::resolver_from_system_conf()
-                            .await
-                            .context("Configuring a resolver from system configuration")?,
-                        Arc::new(ClientConfig::new(connector)),
+                    let client_tls_profile = {
+                        let mut store = wasm_config.store.borrow_mut();
+                        (wasm_config.client_config.tls_profile)(&mut *store)
+                            .context("Retrieving the outbound TLS profile")?
+                    };
+                    let client_tls =
+                        tls_profile::apply(&client_tls_profile, rustls::ClientConfig::builder())
+                            .context("Configuring the rustls client")?;
+                    let unverified_tls_cfg = Arc::new(
+                        client_tls
+                            .clone()
+                            .with_custom_certificate_verifier(Arc::new(NoCertVerifier))
+                            .with_no_client_auth(),
                     );
+                    // Used for REQUIRETLS, MTA-STS and the `Verify` policies without trust store
+                    let roots = system_roots()?;
+                    let tls_report_store = {
+                        let mut store = wasm_config.store.borrow_mut();
+                        (wasm_config.client_config.tls_reporting)(&mut *store)
+                            .context("Retrieving the TLS reporting configuration")?
+                            .map(|r| Arc::new(r.store))
+                    };
+                    let resolver = async_std_resolver::resolver_from_system_conf()
+                        .await
+                        .context("Configuring a resolver from system configuration")?;
+                    // TLSA records are only worth anything when DNSSEC-validated
+                    let (dns_config, mut dns_opts) =
+                        trust_dns_resolver::system_conf::read_system_conf()
+                            .context("Reading the system resolver configuration")?;
+                    dns_opts.validate = true;
+                    let dane_resolver = async_std_resolver::resolver(dns_config, dns_opts)
+                        .await
+                        .context("Configuring a DNSSEC-validating resolver")?;
+                    let client = smtp_client::Client::new(
+                        resolver.clone(),
+                        Arc::new(ClientConfig::new(
+                            client_tls,
+                            unverified_tls_cfg,
+                            roots,
+                            tls_report_store,
+                        )),
+                    )
+                    .with_dane(dane_resolver);
+
+                    let after_queue_filter = {
+                        let mut store = wasm_config.store.borrow_mut();
+                        (wasm_config.server_config.after_queue_filter)(&mut *store)
+                            .context("Retrieving the after-queue filter")?
+                    };
 
                     // Spawn the queue
                     debug!("Preparing the queue configuration");
@@ -159,7 +304,7 @@ pub fn run(opt: &Opt, shutdown: smol::channel::Receiver<()>) -> anyhow::Result<(
                         ex.clone(),
                         QueueConfig::new(),
                         storage,
-                        QueueTransport::new(client),
+                        QueueTransport::new(client, after_queue_filter.clone()),
                     )
                     .await;
 
@@ -177,71 +322,213 @@ pub fn run(opt: &Opt, shutdown: smol::channel::Receiver<()>) -> anyhow::Result<(
                         (wasm_config.server_config.tls_key_file)(&mut *store)
                             .context("Getting the path to the TLS key file")?
                     };
-                    let tls_server_cfg = unblock(move || {
-                        // Load the certificates and keys
-                        let certs = rustls_pemfile::certs(&mut io::BufReader::new(
-                            std::fs::File::open(&cert_file).with_context(|| {
-                                format!("Opening the certificate file ‘{}’", cert_file.display())
-                            })?,
-                        ))
-                        .with_context(|| {
-                            format!("Parsing the TLS certificate file ‘{}’", cert_file.display())
-                        })?
-                        .into_iter()
-                        .map(rustls::Certificate)
-                        .collect::<Vec<_>>();
-                        debug!(num_certs = certs.len(), "Parsed certificates");
-
-                        let keys = rustls_pemfile::pkcs8_private_keys(&mut io::BufReader::new(
-                            std::fs::File::open(&keys_file).with_context(|| {
-                                format!("Opening the key file ‘{}’", keys_file.display())
-                            })?,
-                        ))
-                        .with_context(|| {
-                            format!("Parsing the key file ‘{}’", keys_file.display())
-                        })?;
-                        debug!(num_keys = keys.len(), "Parsed keys");
-                        anyhow::ensure!(
-                            keys.len() == 1,
-                            "Key file did not have just one key, but had {}",
-                            keys.len()
+                    let certificates = {
+                        let mut store = wasm_config.store.borrow_mut();
+                        (wasm_config.server_config.tls_certificates)(&mut *store)
+                            .context("Getting the SNI certificates")?
+                    };
+                    let profile = {
+                        let mut store = wasm_config.store.borrow_mut();
+                        (wasm_config.server_config.tls_profile)(&mut *store)
+                            .context("Retrieving the inbound TLS profile")?
+                    };
+                    let handler = {
+                        let mut store = wasm_config.store.borrow_mut();
+                        (wasm_config.server_config.tls_handler)(&mut *store)
+                            .context("Retrieving the inbound TLS handler")?
+                    };
+                    let client_ca_file = {
+                        let mut store = wasm_config.store.borrow_mut();
+                        (wasm_config.server_config.tls_client_ca_file)(&mut *store)
+                            .context("Retrieving the client CA file")?
+                    };
+                    let ticket_key_rotation = {
+                        let mut store = wasm_config.store.borrow_mut();
+                        (wasm_config.server_config.tls_ticket_key_rotation)(&mut *store)
+                            .context("Retrieving the TLS ticket key rotation period")?
+                    };
+                    if handler == kannader_types::TlsHandler::NativeTls {
+                        warn!(
+                            "The ‘NativeTls’ TLS handler resumes sessions its own way, ignoring \
+                             the ticket key rotation period"
                         );
-                        let key = rustls::PrivateKey(keys.into_iter().next().unwrap());
-
-                        // Configure rustls
-                        // TODO: see for configuring persistence, for more performance?
-                        // TODO: support SNI
-                        let tls_server_cfg = rustls::ServerConfig::builder()
-                            .with_cipher_suites(rustls::ALL_CIPHER_SUITES)
-                            .with_kx_groups(&rustls::ALL_KX_GROUPS)
-                            .with_protocol_versions(rustls::ALL_VERSIONS)
-                            .context("Configuring the rustls server")?
-                            .with_no_client_auth()
-                            .with_single_cert(certs, key)
-                            .context("Setting the key and certificates")?;
-
-                        Ok(tls_server_cfg)
-                    })
-                    .await?;
-                    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tls_server_cfg));
+                    }
+                    let cert_files = Arc::new(sni::CertFiles {
+                        cert_file,
+                        key_file: keys_file,
+                        certificates,
+                        handler,
+                        profile,
+                        client_ca_file,
+                        session_cache: rustls::server::ServerSessionMemoryCache::new(
+                            tls_session::SERVER_SESSIONS,
+                        ),
+                        ticketer: tls_session::RotatingTicketer::new(ticket_key_rotation)
+                            .context("Preparing the TLS session ticketer")?,
+                    });
+                    let tls_server = {
+                        let cert_files = cert_files.clone();
+                        unblock(move || cert_files.load()).await?
+                    };
+                    let acceptor = sni::Acceptor::new(tls_server);
+                    debug!("Loaded the TLS certificates");
+                    ex.spawn(sni::watch(cert_files, acceptor.clone())).detach();
+
+                    debug!("Loading the DKIM signing keys");
+                    let dkim_keys = {
+                        let mut store = wasm_config.store.borrow_mut();
+                        (wasm_config.server_config.dkim_signing_keys)(&mut *store)
+                            .context("Retrieving the DKIM signing keys")?
+                    };
+                    let dkim_keys = unblock(move || load_signing_keys(dkim_keys, "DKIM")).await?;
+                    debug!(num_keys = dkim_keys.len(), "Loaded DKIM signing keys");
+
+                    let arc_sealing = {
+                        let mut store = wasm_config.store.borrow_mut();
+                        (wasm_config.server_config.arc_sealing)(&mut *store)
+                            .context("Retrieving the ARC sealing configuration")?
+                    };
+                    let arc_sealing = match arc_sealing {
+                        Some(cfg) => {
+                            debug!("Loading the ARC sealing keys");
+                            let keys = unblock(move || load_signing_keys(cfg.keys, "ARC")).await?;
+                            debug!(num_keys = keys.len(), "Loaded ARC sealing keys");
+                            Some(Arc::new(server_config::ArcSealing {
+                                keys,
+                                seal_signed: cfg.seal_signed,
+                            }))
+                        }
+                        None => None,
+                    };
+
+                    // The name we give in EHLO is also the name of the host performing SPF
+                    // checks
+                    let local_hostname = {
+                        let mut store = wasm_config.store.borrow_mut();
+                        (wasm_config.client_config.ehlo_hostname)(&mut *store)
+                            .context("Retrieving the local hostname")?
+                    };
+                    let authserv_id = {
+                        let mut store = wasm_config.store.borrow_mut();
+                        (wasm_config.server_config.authserv_id)(&mut *store)
+                            .context("Retrieving the authserv-id")?
+                    }
+                    .unwrap_or_else(|| local_hostname.to_string());
+
+                    let dmarc_store = {
+                        let mut store = wasm_config.store.borrow_mut();
+                        (wasm_config.server_config.dmarc_reporting)(&mut *store)
+                            .context("Retrieving the DMARC reporting configuration")?
+                            .map(|r| Arc::new(r.store))
+                    };
+
+                    let greylisting = {
+                        let mut store = wasm_config.store.borrow_mut();
+                        (wasm_config.server_config.greylisting)(&mut *store)
+                            .context("Retrieving the greylisting configuration")?
+                    };
+                    let greylist = match greylisting {
+                        Some(cfg) => {
+                            debug!("Loading the greylisting store");
+                            Some(unblock(move || greylist::Greylist::open(cfg)).await?)
+                        }
+                        None => None,
+                    };
+
+                    let max_message_size = {
+                        let mut store = wasm_config.store.borrow_mut();
+                        (wasm_config.server_config.max_message_size)(&mut *store)
+                            .context("Retrieving the maximum message size")?
+                    };
+                    let (milters, quarantine_dir, content_filter, antivirus, spam_scanner) = {
+                        let mut store = wasm_config.store.borrow_mut();
+                        let milters = (wasm_config.server_config.milters)(&mut *store)
+                            .context("Retrieving the milters")?;
+                        let quarantine_dir =
+                            (wasm_config.server_config.quarantine_dir)(&mut *store)
+                                .context("Retrieving the quarantine directory")?;
+                        let content_filter =
+                            (wasm_config.server_config.content_filter)(&mut *store)
+                                .context("Retrieving the content filter")?;
+                        let antivirus = (wasm_config.server_config.antivirus)(&mut *store)
+                            .context("Retrieving the antivirus")?;
+                        let spam_scanner = (wasm_config.server_config.spam_scanner)(&mut *store)
+                            .context("Retrieving the spam scanner")?;
+                        (milters, quarantine_dir, content_filter, antivirus, spam_scanner)
+                    };
+                    debug!(num_milters = milters.len(), "Configured milters");
+                    let milters = if milters.is_empty() {
+                        None
+                    } else {
+                        Some(Arc::new(milter::Milters::new(milters)))
+                    };
 
                     debug!("Reopening the listener as async");
-                    let server_cfg = Arc::new(ServerConfig::new(acceptor, queue));
+                    let server_cfg = Arc::new(ServerConfig::new(
+                        acceptor,
+                        queue,
+                        resolver,
+                        server_config::ServerOptions {
+                            local_hostname: local_hostname.to_string(),
+                            authserv_id,
+                            dkim_keys,
+                            arc_sealing,
+                            dmarc_store,
+                            greylist,
+                            max_message_size,
+                            milters,
+                            quarantine_dir: quarantine_dir.map(Arc::new),
+                            content_filter,
+                            antivirus,
+                            spam_scanner,
+                            after_queue_filter: after_queue_filter.is_some(),
+                        },
+                    ));
                     let listener = smol::net::TcpListener::try_from(listener)
                         .context("Making listener async")?;
-                    let mut incoming = listener.incoming();
+
+                    let reinjection = match &after_queue_filter {
+                        Some(filter) => {
+                            anyhow::ensure!(
+                                filter.reinjection.ip().is_loopback()
+                                    || !filter.reinjection_peers.is_empty(),
+                                "The reinjection listener ‘{}’ is not on a loopback address, so \
+                                 the peers allowed to reinject mail must be set",
+                                filter.reinjection
+                            );
+                            debug!(address = %filter.reinjection, "Binding the reinjection listener");
+                            let listener = smol::net::TcpListener::bind(filter.reinjection)
+                                .await
+                                .context("Binding the reinjection listener")?;
+                            let cfg = server_cfg.for_reinjection(filter.reinjection_peers.clone());
+                            Some((listener, Arc::new(cfg)))
+                        }
+                        None => None,
+                    };
+                    let reinjected = match &reinjection {
+                        Some((listener, _)) => listener.incoming().map(|s| (s, true)).left_stream(),
+                        None => futures::stream::empty().right_stream(),
+                    };
+                    let mut incoming =
+                        futures::stream::select(listener.incoming().map(|s| (s, false)), reinjected);
 
                     info!("Server up, waiting for connections");
-                    while let Some(stream) = incoming.next().await {
+                    while let Some((stream, is_reinjection)) = incoming.next().await {
                         let stream = stream.context("Receiving a new incoming stream")?;
                         // TODO: attach uuid metadata to stream for logging purposes (or in
                         // smtp-server directly?)
-                        tracing::trace!("New incoming stream");
+                        tracing::trace!(is_reinjection, "New incoming stream");
+                        let peer_addr = stream.peer_addr().ok();
+                        let cfg = match (&reinjection, is_reinjection) {
+                            (Some((_, cfg)), true) => cfg.clone(),
+                            _ => server_cfg.clone(),
+                        };
                         ex.spawn(smtp_server::interact(
                             stream,
                             smtp_server::IsAlreadyTls::No,
-                            Vec::new(), // TODO
-                            server_cfg.clone(),
+                            peer_addr,
+                            server_config::ConnUserMeta::default(), // TODO
+                            cfg,
                         ))
                         .detach();
                     }
@@ -7,5 +7,10 @@ fn main() -> anyhow::Result<()> {
     // (ie. drop(signal) when the user wants to stop the server)
     let (_signal, shutdown) = smol::channel::unbounded::<()>();
 
-    kannader::run(&kannader::Opt::from_args(), shutdown)
+    let opt = kannader::Opt::from_args();
+    match opt.cmd {
+        None => kannader::run(&opt, shutdown),
+        Some(kannader::Command::DmarcReport) => kannader::send_dmarc_reports(&opt),
+        Some(kannader::Command::TlsReport) => kannader::send_tls_reports(&opt),
+    }
 }
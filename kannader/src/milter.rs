@@ -0,0 +1,341 @@
+use std::{pin::Pin, time::Duration};
+
+use anyhow::Context;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite};
+use smol::future::FutureExt;
+use tracing::{info, warn};
+
+use kannader_types::ReverseDns;
+use smtp_message::{Email, Reply, ReplyCodeKind};
+use smtp_milter::{Client, Modification, Response};
+use smtp_server::reply;
+
+use crate::{
+    escaping,
+    server_config::{parse_reply, ConnMeta},
+};
+
+type Stream = duplexify::Duplex<Pin<Box<dyn Send + AsyncRead>>, Pin<Box<dyn Send + AsyncWrite>>>;
+
+enum State {
+    Active(Client<Stream>),
+    /// The milter accepted the connection, or failed while configured to
+    /// fail open
+    Done,
+    /// The milter failed while configured to fail closed
+    Failed,
+}
+
+struct Session {
+    state: State,
+    /// Whether the milter accepted the current message
+    skip_message: bool,
+    /// Whether the milter was told about a message it did not see the end of
+    in_message: bool,
+}
+
+/// Milter sessions of a connection
+pub struct Connection {
+    sessions: Vec<Session>,
+    /// Whether a milter asked to discard the current message
+    discard: bool,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // The sessions are kept around until the connection is over
+        for session in self.sessions.drain(..) {
+            if let State::Active(client) = session.state {
+                smol::spawn(async move {
+                    if let Err(e) = client.quit().await {
+                        let e = anyhow::Error::new(e);
+                        warn!(error = ?e, "Failed closing the milter session");
+                    }
+                })
+                .detach();
+            }
+        }
+    }
+}
+
+/// Outcome of running the milters on a message
+pub enum MessageVerdict {
+    Accept {
+        /// The message, with the modifications of the milters applied
+        message: Vec<u8>,
+        /// Recipient changes, in order
+        recipients: Vec<Modification>,
+        /// Reason for putting the message in quarantine, if any
+        quarantine: Option<String>,
+    },
+    Discard,
+    Reject(Reply),
+}
+
+/// Builds the reply with which to reject a command, for a milter that did
+/// not let it pass
+fn reject_reply(response: Response) -> Reply {
+    match response {
+        Response::TempFail => reply::filter_tempfail().convert(),
+        Response::ReplyCode(r) => parse_reply(&r, ReplyCodeKind::PermanentNegative)
+            .or_else(|| parse_reply(&r, ReplyCodeKind::TransientNegative))
+            .unwrap_or_else(|| reply::rejected_by_filter().convert()),
+        _ => reply::rejected_by_filter().convert(),
+    }
+}
+
+/// Parses the address of a recipient change, that may or may not be in angle
+/// brackets
+pub fn parse_address(addr: &str) -> Option<Email> {
+    let addr = addr.trim();
+    let addr = addr
+        .strip_prefix('<')
+        .and_then(|a| a.strip_suffix('>'))
+        .unwrap_or(addr);
+    Email::parse_bracketed(format!("<{}>", addr).as_bytes()).ok()
+}
+
+async fn open(cfg: &kannader_types::Milter) -> anyhow::Result<Client<Stream>> {
+    let timeout = Duration::from_secs(cfg.timeout);
+    let connect = async {
+        Ok(match &cfg.socket {
+            kannader_types::MilterSocket::Inet(addr) => {
+                let (r, w) = smol::net::TcpStream::connect(addr.as_str()).await?.split();
+                duplexify::Duplex::new(
+                    Box::pin(r) as Pin<Box<dyn Send + AsyncRead>>,
+                    Box::pin(w) as Pin<Box<dyn Send + AsyncWrite>>,
+                )
+            }
+            kannader_types::MilterSocket::Unix(path) => {
+                let (r, w) = smol::net::unix::UnixStream::connect(path).await?.split();
+                duplexify::Duplex::new(
+                    Box::pin(r) as Pin<Box<dyn Send + AsyncRead>>,
+                    Box::pin(w) as Pin<Box<dyn Send + AsyncWrite>>,
+                )
+            }
+        })
+    };
+    let stream = connect
+        .or(async {
+            smol::Timer::after(timeout).await;
+            Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "timed out connecting to the milter",
+            ))
+        })
+        .await
+        .context("Connecting to the milter")?;
+    Client::negotiate(stream, timeout)
+        .await
+        .context("Negotiating with the milter")
+}
+
+/// Runs a protocol step on the milters that are still interested in the
+/// connection, returning the reply with which to reject the command if any
+macro_rules! run_step {
+    ($self:expr, $conn:expr, accept_connection: $accept_conn:expr, |$client:ident| $step:expr) => {{
+        let mut res = None;
+        for (cfg, session) in $self.cfgs.iter().zip($conn.sessions.iter_mut()) {
+            if session.skip_message {
+                continue;
+            }
+            let $client = match &mut session.state {
+                State::Active(client) => client,
+                State::Done => continue,
+                State::Failed => {
+                    res = Some(reply::filter_tempfail().convert());
+                    break;
+                }
+            };
+            match $step.await {
+                Ok(Response::Continue) => (),
+                Ok(Response::Accept) if $accept_conn => session.state = State::Done,
+                Ok(Response::Accept) => session.skip_message = true,
+                Ok(Response::Discard) => {
+                    session.skip_message = true;
+                    $conn.discard = true;
+                }
+                Ok(r) => {
+                    info!(milter = %cfg.name, response = ?r, "Milter rejected the command");
+                    res = Some(reject_reply(r));
+                    break;
+                }
+                Err(e) => {
+                    if let Some(r) = fail(cfg, session, anyhow::Error::new(e)) {
+                        res = Some(r);
+                        break;
+                    }
+                }
+            }
+        }
+        res
+    }};
+}
+
+/// Marks `session` as failed, returning the reply with which to reject the
+/// command if the milter is configured to fail closed
+fn fail(cfg: &kannader_types::Milter, session: &mut Session, e: anyhow::Error) -> Option<Reply> {
+    warn!(milter = %cfg.name, error = ?e, fail_open = cfg.fail_open, "Milter failed");
+    if cfg.fail_open {
+        session.state = State::Done;
+        None
+    } else {
+        session.state = State::Failed;
+        Some(reply::filter_tempfail().convert())
+    }
+}
+
+/// Milters to run on every connection
+pub struct Milters {
+    cfgs: Vec<kannader_types::Milter>,
+}
+
+impl Milters {
+    pub fn new(cfgs: Vec<kannader_types::Milter>) -> Milters {
+        Milters { cfgs }
+    }
+
+    /// Connects to the milters and tells them about the new connection,
+    /// keeping the sessions in `conn_meta`
+    pub async fn connect(&self, conn_meta: &mut ConnMeta) -> Option<Reply> {
+        let addr = conn_meta.peer_addr;
+        let hostname = match (&conn_meta.user.reverse_dns, addr) {
+            (Some(ReverseDns::Pass(name)), _) => name.clone(),
+            (_, Some(addr)) => format!("[{}]", addr.ip()),
+            (_, None) => String::from("localhost"),
+        };
+        let mut conn = Connection {
+            sessions: Vec::with_capacity(self.cfgs.len()),
+            discard: false,
+        };
+        let mut res = None;
+        for cfg in &self.cfgs {
+            let mut session = Session {
+                state: State::Done,
+                skip_message: false,
+                in_message: false,
+            };
+            if res.is_none() {
+                match open(cfg).await {
+                    Ok(client) => session.state = State::Active(client),
+                    Err(e) => res = fail(cfg, &mut session, e),
+                }
+            }
+            conn.sessions.push(session);
+        }
+        if res.is_none() {
+            res = run_step!(self, conn, accept_connection: true, |client| client
+                .connect(&hostname, addr));
+        }
+        conn_meta.user.milters = Some(conn);
+        res
+    }
+
+    pub async fn helo(&self, conn_meta: &mut ConnMeta, helo: &str) -> Option<Reply> {
+        let conn = conn_meta.user.milters.as_mut()?;
+        run_step!(self, conn, accept_connection: true, |client| client.helo(helo))
+    }
+
+    /// Starts a new message, aborting the previous one if it was not
+    /// finished
+    pub async fn mail(&self, conn_meta: &mut ConnMeta, from: Option<&Email>) -> Option<Reply> {
+        let conn = conn_meta.user.milters.as_mut()?;
+        conn.discard = false;
+        for (cfg, session) in self.cfgs.iter().zip(conn.sessions.iter_mut()) {
+            session.skip_message = false;
+            if let State::Active(client) = &mut session.state {
+                if session.in_message {
+                    if let Err(e) = client.abort().await {
+                        fail(cfg, session, anyhow::Error::new(e));
+                        continue;
+                    }
+                }
+                session.in_message = true;
+            }
+        }
+        let from = from.map_or_else(|| String::from("<>"), |f| f.to_string());
+        run_step!(self, conn, accept_connection: false, |client| client
+            .mail(&from, &[]))
+    }
+
+    pub async fn rcpt(&self, conn_meta: &mut ConnMeta, to: &Email) -> Option<Reply> {
+        let conn = conn_meta.user.milters.as_mut()?;
+        let to = to.to_string();
+        run_step!(self, conn, accept_connection: false, |client| client
+            .rcpt(&to, &[]))
+    }
+
+    /// Sends `message` to the milters in turn, each one seeing the
+    /// modifications of the previous ones
+    ///
+    /// `message` is the spooled data, that the milters see unescaped. The
+    /// message they return is escaped back.
+    pub async fn message(&self, conn_meta: &mut ConnMeta, message: &[u8]) -> MessageVerdict {
+        let conn = match conn_meta.user.milters.as_mut() {
+            Some(conn) => conn,
+            None => {
+                return MessageVerdict::Reject(reply::filter_tempfail().convert());
+            }
+        };
+        let mut message = escaping::unescape(message);
+        let mut recipients = Vec::new();
+        let mut quarantine = None;
+        let mut res = None;
+        for (cfg, session) in self.cfgs.iter().zip(conn.sessions.iter_mut()) {
+            if session.skip_message || conn.discard {
+                continue;
+            }
+            let client = match &mut session.state {
+                State::Active(client) => client,
+                State::Done => continue,
+                State::Failed => {
+                    res = Some(MessageVerdict::Reject(reply::filter_tempfail().convert()));
+                    break;
+                }
+            };
+            match client.message(&message).await {
+                Ok((Response::Continue, mods)) | Ok((Response::Accept, mods)) => {
+                    session.in_message = false;
+                    message = smtp_milter::apply_modifications(&message, &mods);
+                    for m in mods {
+                        match m {
+                            Modification::AddRecipient(_) | Modification::DeleteRecipient(_) => {
+                                recipients.push(m)
+                            }
+                            Modification::Quarantine(reason) => {
+                                info!(milter = %cfg.name, reason = %reason, "Milter quarantined the message");
+                                quarantine = Some(reason);
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+                Ok((Response::Discard, _)) => {
+                    session.in_message = false;
+                    info!(milter = %cfg.name, "Milter discarded the message");
+                    conn.discard = true;
+                }
+                Ok((r, _)) => {
+                    info!(milter = %cfg.name, response = ?r, "Milter rejected the message");
+                    res = Some(MessageVerdict::Reject(reject_reply(r)));
+                    break;
+                }
+                Err(e) => {
+                    if let Some(r) = fail(cfg, session, anyhow::Error::new(e)) {
+                        res = Some(MessageVerdict::Reject(r));
+                        break;
+                    }
+                }
+            }
+        }
+        match res {
+            Some(res) => res,
+            None if conn.discard => MessageVerdict::Discard,
+            None => MessageVerdict::Accept {
+                message: escaping::escape(&message),
+                recipients,
+                quarantine,
+            },
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Context;
+use chrono::Utc;
+use smol::unblock;
+
+use smtp_message::Email;
+
+/// Distinguishes the messages quarantined within the same nanosecond
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(serde::Serialize)]
+struct Info {
+    reason: String,
+    from: Option<String>,
+    to: Vec<String>,
+}
+
+/// Stores `message` in the quarantine directory `dir`, as a `.eml` file along
+/// with a `.json` file describing its envelope and the reason for quarantining
+/// it
+pub async fn store(
+    dir: Arc<PathBuf>,
+    message: Vec<u8>,
+    reason: String,
+    from: Option<&Email>,
+    to: &[Email],
+) -> anyhow::Result<()> {
+    let id = format!(
+        "{}-{}",
+        Utc::now().format("%Y%m%dT%H%M%S%.9f"),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let info = Info {
+        reason,
+        from: from.map(|f| f.to_string()),
+        to: to.iter().map(|t| t.to_string()).collect(),
+    };
+    let info = serde_json::to_vec(&info).context("Serializing the quarantine information")?;
+    unblock(move || {
+        std::fs::create_dir_all(&*dir)
+            .and_then(|()| std::fs::write(dir.join(format!("{}.eml", id)), message))
+            .and_then(|()| std::fs::write(dir.join(format!("{}.json", id)), info))
+            .with_context(|| format!("Writing to the quarantine directory ‘{}’", dir.display()))
+    })
+    .await
+}
@@ -1,11 +1,20 @@
+use std::fmt;
+
 use async_trait::async_trait;
-use futures::AsyncRead;
+use futures::{AsyncRead, AsyncReadExt};
 use tracing::{info, warn};
 
 use smtp_message::Hostname;
 
 use crate::{ClientConfig, Meta};
 
+/// Header field with which mail with `REQUIRETLS` is handed to the after-queue
+/// filter, for it to still have it once reinjected
+///
+/// Mail that comes in with it can thus get `REQUIRETLS` once filtered, which
+/// only ever makes its own delivery stricter.
+pub const REQUIRE_TLS_FIELD: &str = "Kannader-Require-TLS";
+
 // TODO: are there things that are interesting to configure in here?
 fn transport_error_client_to_queue(
     err: smtp_client::TransportError,
@@ -40,18 +49,44 @@ fn transport_error_client_to_queue(
     }
 }
 
-pub struct QueueTransport<C, P>(smtp_client::Client<C, P, ClientConfig>)
+#[derive(Eq, Hash, PartialEq)]
+pub enum Destination {
+    /// The after-queue filter
+    Filter,
+    Remote(smtp_client::Destination),
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Destination::Filter => write!(f, "after-queue filter"),
+            Destination::Remote(dest) => dest.fmt(f),
+        }
+    }
+}
+
+pub struct QueueTransport<C, P>
 where
     C: trust_dns_resolver::proto::DnsHandle<Error = trust_dns_resolver::error::ResolveError>,
-    P: trust_dns_resolver::ConnectionProvider<Conn = C>;
+    P: trust_dns_resolver::ConnectionProvider<Conn = C>,
+{
+    client: smtp_client::Client<C, P, ClientConfig>,
+    after_queue_filter: Option<kannader_types::AfterQueueFilter>,
+}
 
 impl<C, P> QueueTransport<C, P>
 where
     C: trust_dns_resolver::proto::DnsHandle<Error = trust_dns_resolver::error::ResolveError>,
     P: trust_dns_resolver::ConnectionProvider<Conn = C>,
 {
-    pub fn new(client: smtp_client::Client<C, P, ClientConfig>) -> QueueTransport<C, P> {
-        QueueTransport(client)
+    pub fn new(
+        client: smtp_client::Client<C, P, ClientConfig>,
+        after_queue_filter: Option<kannader_types::AfterQueueFilter>,
+    ) -> QueueTransport<C, P> {
+        QueueTransport {
+            client,
+            after_queue_filter,
+        }
     }
 }
 
@@ -61,22 +96,47 @@ where
     C: trust_dns_resolver::proto::DnsHandle<Error = trust_dns_resolver::error::ResolveError>,
     P: trust_dns_resolver::ConnectionProvider<Conn = C>,
 {
-    type Destination = smtp_client::Destination;
+    type Destination = Destination;
     type Sender = QueueTransportSender;
 
     async fn destination(
         &self,
         meta: &smtp_queue::MailMetadata<Meta>,
     ) -> Result<Self::Destination, smtp_queue::TransportFailure> {
+        if meta.metadata.after_queue_filter {
+            match &self.after_queue_filter {
+                // REQUIRETLS only lets the mail leave the host over verified
+                // TLS, that the filter cannot be reached over
+                Some(filter) if meta.require_tls && !filter.filter.ip().is_loopback() => {
+                    warn!(
+                        filter = %filter.filter,
+                        "Not handing mail with REQUIRETLS to an after-queue filter outside of this host"
+                    );
+                    return Err(smtp_queue::TransportFailure::MailPermanent);
+                }
+                Some(_) => return Ok(Destination::Filter),
+                None => warn!(
+                    "Mail was queued for the after-queue filter, that is no longer configured"
+                ),
+            }
+        }
+        let tls = if meta.require_tls {
+            smtp_client::TlsRequirement::Required
+        } else if meta.tls_optional {
+            smtp_client::TlsRequirement::Optional
+        } else {
+            smtp_client::TlsRequirement::Default
+        };
         // TODO: this should most likely be a const or similar; and definitely not
         // recomputed on each call to destination
         let localhost = Hostname::parse(b"localhost")
             .expect("failed to parse constant hostname")
             .1
             .to_owned();
-        self.0
-            .get_destination(meta.to.hostname.as_ref().unwrap_or(&localhost))
+        self.client
+            .get_destination(meta.to.hostname.as_ref().unwrap_or(&localhost), tls)
             .await
+            .map(Destination::Remote)
             .map_err(|e| {
                 transport_error_client_to_queue(
                     e,
@@ -91,10 +151,27 @@ where
     ) -> Result<Self::Sender, smtp_queue::TransportFailure> {
         info!(destination = %dest, "Connecting to remote server");
         // TODO: log the IP to which we're connecting
-        self.0
-            .connect(dest)
-            .await
-            .map(QueueTransportSender)
+        let sender = match (dest, &self.after_queue_filter) {
+            (Destination::Remote(dest), _) => self.client.connect(dest).await,
+            (Destination::Filter, Some(filter)) => {
+                let (ip, port) = (filter.filter.ip(), filter.filter.port());
+                match filter.protocol {
+                    kannader_types::FilterProtocol::Smtp => {
+                        self.client.connect_to_ip(ip, port).await
+                    }
+                    kannader_types::FilterProtocol::Lmtp => {
+                        self.client.connect_lmtp_to_ip(ip, port).await
+                    }
+                }
+            }
+            (Destination::Filter, None) => {
+                // `destination` only returns this when there is a filter
+                return Err(smtp_queue::TransportFailure::Local);
+            }
+        };
+        let to_filter = matches!(dest, Destination::Filter);
+        sender
+            .map(|sender| QueueTransportSender { sender, to_filter })
             .map_err(|e| {
                 transport_error_client_to_queue(
                     e,
@@ -104,7 +181,11 @@ where
     }
 }
 
-pub struct QueueTransportSender(smtp_client::Sender<ClientConfig>);
+pub struct QueueTransportSender {
+    sender: smtp_client::Sender<ClientConfig>,
+    /// Whether this sends to the after-queue filter
+    to_filter: bool,
+}
 
 #[async_trait]
 impl smtp_queue::TransportSender<Meta> for QueueTransportSender {
@@ -117,11 +198,18 @@ impl smtp_queue::TransportSender<Meta> for QueueTransportSender {
         Reader: Send + AsyncRead,
     {
         // TODO: pass through mail id so that it's possible to log it
-        self.0
-            .send(meta.from.as_ref(), &meta.to, mail)
-            .await
-            .map_err(|e| {
-                transport_error_client_to_queue(e, "Transport error while trying to send email")
-            })
+        let from = meta.from.as_ref();
+        let res = if self.to_filter && meta.require_tls {
+            let field = format!("{}: Yes\r\n", REQUIRE_TLS_FIELD).into_bytes();
+            let mail = futures::io::Cursor::new(field).chain(mail);
+            self.sender.send(from, &meta.to, mail).await
+        } else {
+            self.sender.send(from, &meta.to, mail).await
+        };
+        res.map_err(|e| {
+            transport_error_client_to_queue(e, "Transport error while trying to send email")
+        })?;
+        info!(to = %meta.to, tls = %self.sender.tls_verification(), "Delivered mail");
+        Ok(())
     }
 }
@@ -0,0 +1,139 @@
+use std::net::IpAddr;
+
+use tracing::trace;
+
+use kannader_types::ReverseDns;
+use smtp_spf::Lookup;
+
+/// Maximum number of PTR names that are checked for pointing back to the IP
+const MAX_PTR_NAMES: usize = 10;
+
+/// Checks that a PTR name of `ip` resolves back to `ip`
+pub async fn check(lookup: &dyn Lookup, ip: IpAddr) -> ReverseDns {
+    let names = match lookup.lookup_ptr(ip).await {
+        Ok(names) => names,
+        Err(e) => {
+            trace!(error = ?e, "PTR lookup failed");
+            return ReverseDns::TempError;
+        }
+    };
+    if names.is_empty() {
+        return ReverseDns::None;
+    }
+    let mut temp_error = false;
+    for name in names.into_iter().take(MAX_PTR_NAMES) {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let confirmed = match ip {
+            IpAddr::V4(ip) => lookup.lookup_a(&name).await.map(|ips| ips.contains(&ip)),
+            IpAddr::V6(ip) => lookup.lookup_aaaa(&name).await.map(|ips| ips.contains(&ip)),
+        };
+        match confirmed {
+            Ok(true) => return ReverseDns::Pass(name),
+            Ok(false) => (),
+            Err(e) => {
+                trace!(error = ?e, "Forward lookup of a PTR name failed");
+                temp_error = true;
+            }
+        }
+    }
+    if temp_error {
+        ReverseDns::TempError
+    } else {
+        ReverseDns::Fail
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        net::{Ipv4Addr, Ipv6Addr},
+    };
+
+    use async_trait::async_trait;
+    use smtp_spf::LookupError;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct StubLookup {
+        a: HashMap<&'static str, Vec<Ipv4Addr>>,
+        ptr: HashMap<IpAddr, Vec<&'static str>>,
+        failing: Vec<String>,
+    }
+
+    impl StubLookup {
+        fn fail(&self, name: &str) -> Result<(), LookupError> {
+            if self.failing.iter().any(|f| f == name) {
+                return Err(LookupError {
+                    name: name.to_owned(),
+                    reason: "stub failure".to_owned(),
+                });
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl Lookup for StubLookup {
+        async fn lookup_txt(&self, _: &str) -> Result<Vec<String>, LookupError> {
+            Ok(Vec::new())
+        }
+
+        async fn lookup_a(&self, name: &str) -> Result<Vec<Ipv4Addr>, LookupError> {
+            self.fail(name)?;
+            Ok(self.a.get(name).cloned().unwrap_or_default())
+        }
+
+        async fn lookup_aaaa(&self, name: &str) -> Result<Vec<Ipv6Addr>, LookupError> {
+            self.fail(name)?;
+            Ok(Vec::new())
+        }
+
+        async fn lookup_mx(&self, _: &str) -> Result<Vec<String>, LookupError> {
+            Ok(Vec::new())
+        }
+
+        async fn lookup_ptr(&self, ip: IpAddr) -> Result<Vec<String>, LookupError> {
+            self.fail(&ip.to_string())?;
+            let names = self.ptr.get(&ip).cloned().unwrap_or_default();
+            Ok(names.into_iter().map(String::from).collect())
+        }
+    }
+
+    #[test]
+    fn reverse_dns() {
+        let mut lookup = StubLookup::default();
+        let ptr = |ip: &str, names| (ip.parse().unwrap(), names);
+        lookup.ptr.extend(vec![
+            ptr("192.0.2.80", vec!["Host.PTR.example.org."]),
+            ptr("192.0.2.81", vec!["forged.ptr.example.org."]),
+            ptr("192.0.2.82", vec!["host.down.example.org."]),
+        ]);
+        lookup
+            .a
+            .insert("host.ptr.example.org", vec![Ipv4Addr::new(192, 0, 2, 80)]);
+        lookup.failing = vec![
+            String::from("host.down.example.org"),
+            String::from("192.0.2.83"),
+        ];
+        let tests: &[(&str, ReverseDns)] = &[
+            (
+                "192.0.2.80",
+                ReverseDns::Pass(String::from("host.ptr.example.org")),
+            ),
+            ("192.0.2.81", ReverseDns::Fail),
+            ("192.0.2.82", ReverseDns::TempError),
+            ("192.0.2.83", ReverseDns::TempError),
+            ("192.0.2.10", ReverseDns::None),
+            ("2001:db8::25", ReverseDns::None),
+        ];
+        for (ip, res) in tests {
+            println!("Testing {}", ip);
+            assert_eq!(
+                futures::executor::block_on(check(&lookup, ip.parse().unwrap())),
+                *res
+            );
+        }
+    }
+}
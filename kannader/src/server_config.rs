@@ -1,22 +1,197 @@
-use std::{io, pin::Pin};
+use std::{
+    collections::HashMap,
+    io,
+    net::IpAddr,
+    ops::{Deref, DerefMut},
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+use async_std_resolver::AsyncStdResolver;
 use async_trait::async_trait;
 use chrono::Utc;
 use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tracing::error;
+use tracing::{debug, error, info, warn};
 
-use smtp_message::{Email, Hostname, MaybeUtf8, Reply};
+use kannader_types::ReverseDns;
+use smtp_message::{Email, Hostname, MaybeUtf8, Reply, ReplyCodeKind};
 use smtp_queue_fs::FsStorage;
+use smtp_scanner::VirusResult;
 use smtp_server::{reply, Decision, HelloInfo};
 
-use crate::{Meta, QueueConfig, DATABUF_SIZE, WASM_CONFIG};
+use crate::{
+    antivirus,
+    authres::{authentication_results, ForgedAuthResFilter},
+    content_filter::{self, Verdict},
+    escaping::Unescaper,
+    greylist::Greylist,
+    milter::{self, MessageVerdict, Milters},
+    quarantine,
+    queue_transport::REQUIRE_TLS_FIELD,
+    reverse_dns, sni, spam, Meta, QueueConfig, DATABUF_SIZE, WASM_CONFIG,
+};
 
-pub type ConnMeta = smtp_server::ConnectionMetadata<Vec<u8>>;
-pub type MailMeta = smtp_server::MailMetadata<Vec<u8>>;
+/// Per-connection state of the server
+#[derive(Default)]
+pub struct ConnUserMeta {
+    /// User data of the wasm configuration
+    pub wasm: Vec<u8>,
+    /// Forward-confirmed reverse DNS of the peer address, if it was checked
+    pub reverse_dns: Option<ReverseDns>,
+    /// Sessions with the milters, opened when the connection is accepted
+    pub milters: Option<milter::Connection>,
+}
+
+pub type ConnMeta = smtp_server::ConnectionMetadata<ConnUserMeta>;
+pub type MailMeta = smtp_server::MailMetadata<kannader_types::MailUser>;
+
+/// Connection metadata as the wasm configuration sees it
+type WasmConnMeta = smtp_server::ConnectionMetadata<kannader_types::ConnUser>;
+
+/// Copies the part of `conn_meta` that the wasm configuration sees
+fn wasm_view(conn_meta: &ConnMeta) -> WasmConnMeta {
+    WasmConnMeta {
+        user: kannader_types::ConnUser {
+            wasm: conn_meta.user.wasm.clone(),
+            reverse_dns: conn_meta.user.reverse_dns.clone(),
+        },
+        peer_addr: conn_meta.peer_addr,
+        hello: conn_meta.hello.clone(),
+        is_encrypted: conn_meta.is_encrypted,
+        tls_server_name: conn_meta.tls_server_name.clone(),
+        tls_client_cert: conn_meta.tls_client_cert.clone(),
+    }
+}
+
+/// Lends the part of `conn_meta` that the wasm configuration sees to a hook,
+/// writing back its changes once dropped
+struct WasmMeta<'a> {
+    conn_meta: &'a mut ConnMeta,
+    wasm: WasmConnMeta,
+}
+
+fn wasm_meta(conn_meta: &mut ConnMeta) -> WasmMeta<'_> {
+    let wasm = WasmConnMeta {
+        user: kannader_types::ConnUser {
+            wasm: std::mem::take(&mut conn_meta.user.wasm),
+            reverse_dns: conn_meta.user.reverse_dns.take(),
+        },
+        peer_addr: conn_meta.peer_addr,
+        hello: conn_meta.hello.take(),
+        is_encrypted: conn_meta.is_encrypted,
+        tls_server_name: conn_meta.tls_server_name.take(),
+        tls_client_cert: conn_meta.tls_client_cert.take(),
+    };
+    WasmMeta { conn_meta, wasm }
+}
+
+impl Deref for WasmMeta<'_> {
+    type Target = WasmConnMeta;
+
+    fn deref(&self) -> &WasmConnMeta {
+        &self.wasm
+    }
+}
+
+impl DerefMut for WasmMeta<'_> {
+    fn deref_mut(&mut self) -> &mut WasmConnMeta {
+        &mut self.wasm
+    }
+}
+
+impl Drop for WasmMeta<'_> {
+    fn drop(&mut self) {
+        let wasm = &mut self.wasm;
+        let conn_meta = &mut *self.conn_meta;
+        conn_meta.user.wasm = std::mem::take(&mut wasm.user.wasm);
+        conn_meta.user.reverse_dns = wasm.user.reverse_dns.take();
+        conn_meta.peer_addr = wasm.peer_addr;
+        conn_meta.hello = wasm.hello.take();
+        conn_meta.is_encrypted = wasm.is_encrypted;
+        conn_meta.tls_server_name = wasm.tls_server_name.take();
+        conn_meta.tls_client_cert = wasm.tls_client_cert.take();
+    }
+}
 
 pub struct ServerConfig<T> {
-    acceptor: tokio_rustls::TlsAcceptor,
+    acceptor: sni::Acceptor,
     queue: smtp_queue::Queue<Meta, QueueConfig, FsStorage<Meta>, T>,
+    resolver: AsyncStdResolver,
+    local_hostname: String,
+    /// Identifier under which the authentication results are recorded
+    authserv_id: String,
+    /// DKIM signing configuration, indexed by lowercase domain
+    dkim_keys: HashMap<String, Arc<smtp_dkim::SigningConfig>>,
+    /// ARC sealing configuration, if sealing is enabled
+    arc_sealing: Option<Arc<ArcSealing>>,
+    /// File in which to record DMARC results for aggregate reports, if any
+    dmarc_store: Option<Arc<PathBuf>>,
+    greylist: Option<Greylist>,
+    /// Maximum size of the message data, as received, checked while reading
+    /// it as the filters need it buffered in memory
+    max_message_size: u64,
+    milters: Option<Arc<Milters>>,
+    /// Directory in which to store the messages quarantined by milters or the
+    /// antivirus, if any
+    quarantine_dir: Option<Arc<PathBuf>>,
+    content_filter: Option<kannader_types::ContentFilter>,
+    antivirus: Option<kannader_types::Antivirus>,
+    spam_scanner: Option<kannader_types::SpamScanner>,
+    /// Whether the mail is to be handed to the after-queue filter once queued
+    after_queue_filter: bool,
+    /// Whether this is the configuration of the listener on which the
+    /// after-queue filter reinjects mail
+    reinjection: bool,
+    /// Peers allowed to reinject mail, only loopback ones if empty
+    reinjection_peers: Vec<IpAddr>,
+}
+
+/// How the server handles the mail it receives, see the fields of
+/// [`ServerConfig`](ServerConfig)
+pub struct ServerOptions {
+    pub local_hostname: String,
+    pub authserv_id: String,
+    pub dkim_keys: HashMap<String, Arc<smtp_dkim::SigningConfig>>,
+    pub arc_sealing: Option<Arc<ArcSealing>>,
+    pub dmarc_store: Option<Arc<PathBuf>>,
+    pub greylist: Option<Greylist>,
+    pub max_message_size: u64,
+    pub milters: Option<Arc<Milters>>,
+    pub quarantine_dir: Option<Arc<PathBuf>>,
+    pub content_filter: Option<kannader_types::ContentFilter>,
+    pub antivirus: Option<kannader_types::Antivirus>,
+    pub spam_scanner: Option<kannader_types::SpamScanner>,
+    pub after_queue_filter: bool,
+}
+
+/// How relayed mail is ARC-sealed
+pub struct ArcSealing {
+    /// Sealing keys, indexed by lowercase domain
+    pub keys: HashMap<String, Arc<smtp_dkim::SigningConfig>>,
+    /// Whether to also seal the mail DKIM-signed here
+    pub seal_signed: bool,
+}
+
+/// Returns the domain of `hostname`, if it is not an address literal
+pub fn hostname_domain(hostname: &Hostname) -> Option<&str> {
+    match hostname {
+        Hostname::AsciiDomain { raw } => Some(raw.as_str()),
+        Hostname::Utf8Domain { punycode, .. } => Some(punycode.as_str()),
+        Hostname::Ipv4 { .. } | Hostname::Ipv6 { .. } => None,
+    }
+}
+
+/// Parses a reply given by a filter, `text` being its lines without their
+/// final CRLF, returning it only if it has the expected `kind`
+pub fn parse_reply(text: &str, kind: ReplyCodeKind) -> Option<Reply> {
+    let mut buf = text.trim_end().replace("\r\n", "\n").replace('\n', "\r\n");
+    buf.push_str("\r\n");
+    match Reply::parse(buf.as_bytes()) {
+        Ok((rem, reply)) if rem.is_empty() && reply.code.kind() == kind => Some(reply),
+        _ => None,
+    }
 }
 
 impl<T> ServerConfig<T>
@@ -24,13 +199,241 @@ where
     T: smtp_queue::Transport<Meta>,
 {
     pub fn new(
-        acceptor: tokio_rustls::TlsAcceptor,
+        acceptor: sni::Acceptor,
         queue: smtp_queue::Queue<Meta, QueueConfig, FsStorage<Meta>, T>,
+        resolver: AsyncStdResolver,
+        opts: ServerOptions,
     ) -> ServerConfig<T> {
-        ServerConfig { acceptor, queue }
+        let ServerOptions {
+            local_hostname,
+            authserv_id,
+            dkim_keys,
+            arc_sealing,
+            dmarc_store,
+            greylist,
+            max_message_size,
+            milters,
+            quarantine_dir,
+            content_filter,
+            antivirus,
+            spam_scanner,
+            after_queue_filter,
+        } = opts;
+        ServerConfig {
+            acceptor,
+            queue,
+            resolver,
+            local_hostname,
+            authserv_id,
+            dkim_keys,
+            arc_sealing,
+            dmarc_store,
+            greylist,
+            max_message_size,
+            milters,
+            quarantine_dir,
+            content_filter,
+            antivirus,
+            spam_scanner,
+            after_queue_filter,
+            reinjection: false,
+            reinjection_peers: Vec::new(),
+        }
+    }
+
+    /// Returns the configuration of the listener on which the after-queue
+    /// filter reinjects mail
+    ///
+    /// The mail reinjected there was already checked before being handed to
+    /// the filter, so it is queued as is, and marked as filtered so that it
+    /// does not loop back to the filter. Only `peers`, or loopback peers if it
+    /// is empty, may connect to it.
+    pub fn for_reinjection(&self, peers: Vec<IpAddr>) -> ServerConfig<T> {
+        ServerConfig {
+            acceptor: self.acceptor.clone(),
+            queue: self.queue.clone(),
+            resolver: self.resolver.clone(),
+            local_hostname: self.local_hostname.clone(),
+            authserv_id: self.authserv_id.clone(),
+            dkim_keys: self.dkim_keys.clone(),
+            arc_sealing: self.arc_sealing.clone(),
+            dmarc_store: self.dmarc_store.clone(),
+            greylist: None,
+            max_message_size: self.max_message_size,
+            milters: None,
+            quarantine_dir: None,
+            content_filter: None,
+            antivirus: None,
+            spam_scanner: None,
+            after_queue_filter: false,
+            reinjection: true,
+            reinjection_peers: peers,
+        }
+    }
+
+    /// Fills in the SPF results of `meta`, for both the HELO and the MAIL FROM
+    /// identities
+    async fn check_spf(&self, from: &Option<Email>, meta: &mut MailMeta, conn_meta: &ConnMeta) {
+        let ip = match conn_meta.peer_addr {
+            Some(addr) => addr.ip(),
+            None => return,
+        };
+        let hello = match &conn_meta.hello {
+            Some(hello) => &hello.hostname,
+            None => return,
+        };
+        // SPF can only check domains, not address literals
+        let helo_domain = hostname_domain(hello);
+        let helo_str = helo_domain.unwrap_or_else(|| hello.raw().as_str());
+
+        if let Some(helo) = helo_domain {
+            meta.user.spf_helo = Some(
+                smtp_spf::check_host(
+                    &self.resolver,
+                    &smtp_spf::Query::helo(ip, helo, &self.local_hostname),
+                )
+                .await,
+            );
+        }
+
+        meta.user.spf_mail_from = match from {
+            // The null reverse-path is checked with the HELO identity
+            None => meta.user.spf_helo.clone(),
+            Some(Email {
+                localpart,
+                hostname: Some(Hostname::AsciiDomain { raw: domain }),
+            })
+            | Some(Email {
+                localpart,
+                hostname:
+                    Some(Hostname::Utf8Domain {
+                        punycode: domain, ..
+                    }),
+            }) => {
+                let localpart = localpart.unquote();
+                let query = smtp_spf::Query::mail_from(
+                    ip,
+                    helo_str,
+                    Some((localpart.as_str(), domain.as_str())),
+                    &self.local_hostname,
+                );
+                Some(smtp_spf::check_host(&self.resolver, &query).await)
+            }
+            Some(_) => None,
+        };
+    }
+
+    /// Fills in the DMARC result of `meta` once its SPF and DKIM results are
+    /// known, and records it for aggregate reports
+    async fn check_dmarc(
+        &self,
+        headers: &smtp_dkim::HeaderSection,
+        meta: &mut MailMeta,
+        conn_meta: &ConnMeta,
+    ) {
+        let header_from = match smtp_dmarc::header_from_domain(headers.fields()) {
+            Ok(d) => d,
+            Err(e) => {
+                meta.user.dmarc = Some(smtp_dmarc::DmarcResult::new(
+                    smtp_dmarc::DmarcStatus::PermError,
+                    Some(e.to_string()),
+                ));
+                return;
+            }
+        };
+        // The domain authenticated by SPF is the MAIL FROM one, or the HELO one
+        // for the null reverse-path
+        let envelope_from = meta
+            .from
+            .as_ref()
+            .and_then(|f| f.hostname.as_ref())
+            .and_then(hostname_domain);
+        let spf = match &meta.from {
+            Some(_) => envelope_from
+                .zip(meta.user.spf_mail_from.as_ref())
+                .map(|(d, r)| (d, smtp_dmarc::SpfScope::MailFrom, r)),
+            None => conn_meta
+                .hello
+                .as_ref()
+                .and_then(|h| hostname_domain(&h.hostname))
+                .zip(meta.user.spf_helo.as_ref())
+                .map(|(d, r)| (d, smtp_dmarc::SpfScope::Helo, r)),
+        };
+        let query = smtp_dmarc::Query {
+            header_from: &header_from,
+            spf: spf.map(|(d, _, r)| (d, r)),
+            dkim: &meta.user.dkim,
+        };
+        let dmarc = smtp_dmarc::evaluate(&self.resolver, &query).await;
+
+        if let (Some(store), Some(peer_addr)) = (&self.dmarc_store, conn_meta.peer_addr) {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let observation = smtp_dmarc::Observation::new(
+                now,
+                peer_addr.ip(),
+                envelope_from.map(str::to_owned),
+                &dmarc,
+                &meta.user.dkim,
+                spf,
+            );
+            if let Some(o) = observation {
+                if let Err(e) = crate::dmarc_report::record(store.clone(), o).await {
+                    error!(error = ?e, "Failed recording the DMARC result");
+                }
+            }
+        }
+        meta.user.dmarc = Some(dmarc);
+    }
+}
+
+/// Applies the recipient changes requested by milters to `to`
+fn apply_recipient_changes(to: &mut Vec<Email>, changes: Vec<smtp_milter::Modification>) {
+    for change in changes {
+        match change {
+            smtp_milter::Modification::AddRecipient(addr) => match milter::parse_address(&addr) {
+                Some(addr) if !to.contains(&addr) => to.push(addr),
+                Some(_) => (),
+                None => warn!(address = %addr, "Ignoring invalid recipient added by milter"),
+            },
+            smtp_milter::Modification::DeleteRecipient(addr) => {
+                match milter::parse_address(&addr) {
+                    Some(addr) => to.retain(|t| *t != addr),
+                    None => warn!(address = %addr, "Ignoring invalid recipient deleted by milter"),
+                }
+            }
+            _ => (),
+        }
     }
 }
 
+/// Returns whether one of the header `fields` is named `name`
+fn has_field<'a>(mut fields: impl Iterator<Item = &'a [u8]>, name: &str) -> bool {
+    fields.any(|f| {
+        let f = String::from_utf8_lossy(f);
+        match f.split_once(':') {
+            Some((n, _)) => n.trim().eq_ignore_ascii_case(name),
+            None => false,
+        }
+    })
+}
+
+/// Returns whether the header has a `TLS-Required: No` field (RFC 8689)
+fn tls_required_no<'a>(mut fields: impl Iterator<Item = &'a [u8]>) -> bool {
+    fields.any(|f| {
+        let f = String::from_utf8_lossy(f);
+        match f.split_once(':') {
+            Some((name, value)) => {
+                name.trim().eq_ignore_ascii_case("tls-required")
+                    && value.trim().eq_ignore_ascii_case("no")
+            }
+            None => false,
+        }
+    })
+}
+
 macro_rules! run_hook {
     ($fn:ident($($arg:expr),*)) => {
         run_hook!($fn($($arg),*) ||
@@ -55,13 +458,154 @@ macro_rules! run_hook {
     };
 }
 
+impl<T> ServerConfig<T>
+where
+    T: smtp_queue::Transport<Meta>,
+{
+    /// Greylists the recipient accepted by `decision`, if greylisting is
+    /// enabled
+    async fn check_greylist(
+        &self,
+        decision: Decision<Email>,
+        meta: &mut MailMeta,
+        conn_meta: &mut ConnMeta,
+    ) -> Decision<Email> {
+        let (greylist, peer_addr) = match (&self.greylist, conn_meta.peer_addr) {
+            (Some(greylist), Some(peer_addr)) => (greylist, peer_addr),
+            _ => return decision,
+        };
+        let (accept_reply, to) = match decision {
+            Decision::Accept { reply, res } => (reply, res),
+            decision => return decision,
+        };
+        if !run_hook!(greylist_recipient(to.clone(), meta, &mut wasm_meta(conn_meta)) || true) {
+            return Decision::Accept {
+                reply: accept_reply,
+                res: to,
+            };
+        }
+        let from = meta
+            .from
+            .as_ref()
+            .map(|f| f.to_string())
+            .unwrap_or_default();
+        match greylist.check(peer_addr.ip(), &from, &to.to_string()).await {
+            Ok(true) => Decision::Accept {
+                reply: accept_reply,
+                res: to,
+            },
+            Ok(false) => Decision::Reject {
+                reply: reply::greylisted().convert(),
+            },
+            Err(e) => {
+                error!(error = ?e, "Failed checking greylisting");
+                Decision::Reject {
+                    reply: reply::internal_server_error().convert(),
+                }
+            }
+        }
+    }
+
+    /// Queues mail reinjected by the after-queue filter, as is
+    async fn reinject<R>(
+        &self,
+        stream: &mut smtp_message::EscapedDataReader<'_, R>,
+        meta: MailMeta,
+    ) -> Decision<()>
+    where
+        R: Send + Unpin + AsyncRead,
+    {
+        let mut enqueuer = match self.queue.enqueue().await {
+            Ok(enqueuer) => enqueuer,
+            Err(e) => {
+                let e = anyhow::Error::new(e);
+                error!(error = ?e, "Internal server error while opening an enqueuer");
+                return Decision::Reject {
+                    reply: reply::internal_server_error().convert(),
+                };
+            }
+        };
+        let mut unescaper = Unescaper::new();
+        let mut unescaped = Vec::with_capacity(DATABUF_SIZE);
+        let mut headers = smtp_dkim::HeaderSection::new();
+        let mut buf = [0; DATABUF_SIZE];
+        loop {
+            match stream.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    unescaped.clear();
+                    unescaper.update(&buf[..n], &mut unescaped);
+                    headers.update(&unescaped);
+                    if let Err(e) = enqueuer.write_all(&buf[..n]).await {
+                        error!(error = ?e, "Internal server error while writing data to queue");
+                        return Decision::Reject {
+                            reply: reply::internal_server_error().convert(),
+                        };
+                    }
+                }
+                Err(e) => {
+                    error!(error = ?e, "Internal server error while reading data from network");
+                    return Decision::Reject {
+                        reply: reply::internal_server_error().convert(),
+                    };
+                }
+            }
+        }
+        if !stream.is_finished() {
+            error!("Stream stopped returning any bytes without actually finishing");
+            return Decision::Reject {
+                reply: reply::internal_server_error().convert(),
+            };
+        }
+        stream.complete();
+        headers.finish();
+        // The TLS requirements of the mail as it was handed to the filter are
+        // restored from its header fields
+        let from = &meta.from;
+        let require_tls = meta.require_tls || has_field(headers.fields(), REQUIRE_TLS_FIELD);
+        let tls_optional = !require_tls && tls_required_no(headers.fields());
+        let destinations = meta
+            .to
+            .into_iter()
+            .map(move |to| {
+                (
+                    smtp_queue::MailMetadata {
+                        from: from.clone(),
+                        to,
+                        require_tls,
+                        tls_optional,
+                        metadata: Meta {
+                            after_queue_filter: false,
+                        },
+                    },
+                    smtp_queue::ScheduleInfo {
+                        at: Utc::now(),
+                        last_attempt: None,
+                    },
+                )
+            })
+            .collect();
+        if let Err(e) = enqueuer.commit(destinations).await {
+            error!(error = ?e, "Internal server error while committing mail");
+            Decision::Reject {
+                reply: reply::internal_server_error().convert(),
+            }
+        } else {
+            Decision::Accept {
+                reply: reply::okay_mail().convert(),
+                res: (),
+            }
+        }
+    }
+}
+
 #[async_trait]
 impl<T> smtp_server::Config for ServerConfig<T>
 where
     T: smtp_queue::Transport<Meta>,
 {
-    type ConnectionUserMeta = Vec<u8>;
-    type MailUserMeta = Vec<u8>;
+    type ConnectionUserMeta = ConnUserMeta;
+    type MailUserMeta = kannader_types::MailUser;
     type Protocol = smtp_server::protocol::Smtp;
 
     fn hostname(&self, _: &ConnMeta) -> &str {
@@ -73,7 +617,41 @@ where
     }
 
     fn welcome_banner_reply(&self, conn_meta: &mut ConnMeta) -> Reply {
-        run_hook!(welcome_banner_reply(conn_meta) || reply::internal_server_error().convert())
+        run_hook!(
+            welcome_banner_reply(&mut wasm_meta(conn_meta))
+                || reply::internal_server_error().convert()
+        )
+    }
+
+    async fn filter_connection(&self, conn_meta: &mut ConnMeta) -> Decision<()> {
+        if self.reinjection {
+            let allowed = match conn_meta.peer_addr {
+                Some(addr) if self.reinjection_peers.is_empty() => addr.ip().is_loopback(),
+                Some(addr) => self.reinjection_peers.contains(&addr.ip()),
+                None => false,
+            };
+            if !allowed {
+                warn!(
+                    peer_addr = ?conn_meta.peer_addr,
+                    "Refusing a connection to the reinjection listener from a peer not allowed to"
+                );
+                return Decision::Reject {
+                    reply: reply::connection_not_allowed().convert(),
+                };
+            }
+        } else if let Some(addr) = conn_meta.peer_addr {
+            let rdns = reverse_dns::check(&self.resolver, addr.ip()).await;
+            conn_meta.user.reverse_dns = Some(rdns);
+        }
+        if let Some(milters) = &self.milters {
+            if let Some(reply) = milters.connect(conn_meta).await {
+                return Decision::Reject { reply };
+            }
+        }
+        Decision::Accept {
+            reply: self.welcome_banner_reply(conn_meta),
+            res: (),
+        }
     }
 
     fn hello_banner(&self, _: &ConnMeta) -> &str {
@@ -86,14 +664,27 @@ where
         hostname: Hostname,
         conn_meta: &mut ConnMeta,
     ) -> Decision<HelloInfo> {
-        run_hook!(filter_hello(is_extended, hostname, conn_meta))
+        let decision = run_hook!(filter_hello(
+            is_extended,
+            hostname,
+            &mut wasm_meta(conn_meta)
+        ));
+        match (&self.milters, decision) {
+            (Some(milters), Decision::Accept { reply, res }) => {
+                match milters.helo(conn_meta, res.hostname.raw()).await {
+                    Some(reply) => Decision::Reject { reply },
+                    None => Decision::Accept { reply, res },
+                }
+            }
+            (_, decision) => decision,
+        }
     }
 
     fn can_do_tls(&self, conn_meta: &ConnMeta) -> bool {
         // Unfortunately, there is no good way to gracefully fail here
         run_hook!(
             // TODO: rust should auto-deref here, report a rust bug
-            can_do_tls((*conn_meta).clone()) || panic!("Error while running the ‘can_do_tls’ hook")
+            can_do_tls(wasm_view(conn_meta)) || panic!("Error while running the ‘can_do_tls’ hook")
         )
     }
 
@@ -108,21 +699,52 @@ where
     async fn tls_accept<IO>(
         &self,
         io: IO,
-        _conn_meta: &mut ConnMeta,
+        conn_meta: &mut ConnMeta,
     ) -> io::Result<
         duplexify::Duplex<Pin<Box<dyn Send + AsyncRead>>, Pin<Box<dyn Send + AsyncWrite>>>,
     >
     where
         IO: 'static + Unpin + Send + AsyncRead + AsyncWrite,
     {
-        // TODO: figure out a way to cleanly configure this... maybe having the wasm
-        // blob return one of “rustls” and “native-tls” and then picking the correct
-        // implementation? and then also make the rustls parameters in main.rs
-        // configurable... anyway we have to think about having multiple TLS certs for
-        // multiple SNI hostnames / multiple IP addresses
+        // TODO: we have to think about having multiple TLS certs for multiple IP
+        // addresses
         // TODO: switch everything to tokio?
         use async_compat::CompatExt;
-        let io = self.acceptor.accept(io.compat()).await?;
+        let tls = self.acceptor.get();
+        #[cfg(feature = "native-tls")]
+        if let Some(native) = &tls.native {
+            // The library exposes neither the name asked for through SNI nor
+            // client certificates, which `sni::CertFiles::load` made sure the
+            // configuration does not rely on
+            let io = native
+                .accept(io.compat())
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            debug!(
+                peer_addr = ?conn_meta.peer_addr,
+                "Established inbound TLS session with native-tls"
+            );
+            let (r, w) = io.compat().split();
+            return Ok(duplexify::Duplex::new(
+                Box::pin(r) as Pin<Box<dyn Send + AsyncRead>>,
+                Box::pin(w) as Pin<Box<dyn Send + AsyncWrite>>,
+            ));
+        }
+        let io = tls.acceptor.accept(io.compat()).await?;
+        let conn = io.get_ref().1;
+        conn_meta.tls_server_name = conn.sni_hostname().map(|n| n.to_owned());
+        conn_meta.tls_client_cert = match (&tls.client_auth, conn.peer_certificates()) {
+            (Some(client_auth), Some(chain)) => client_auth.check(chain),
+            _ => None,
+        };
+        debug!(
+            peer_addr = ?conn_meta.peer_addr,
+            server_name = ?conn_meta.tls_server_name,
+            client_cert = ?conn_meta.tls_client_cert.as_ref().map(|c| &c.subject),
+            version = ?conn.protocol_version(),
+            cipher_suite = ?conn.negotiated_cipher_suite().map(|s| s.suite()),
+            "Established inbound TLS session"
+        );
         let (r, w) = io.compat().split();
         let io = duplexify::Duplex::new(
             Box::pin(r) as Pin<Box<dyn Send + AsyncRead>>,
@@ -131,9 +753,16 @@ where
         Ok(io)
     }
 
-    async fn new_mail(&self, conn_meta: &mut ConnMeta) -> Vec<u8> {
+    async fn new_mail(&self, conn_meta: &mut ConnMeta) -> kannader_types::MailUser {
         // Unfortunately, there is no good way to gracefully fail here
-        run_hook!(new_mail(conn_meta) || panic!("Error while running the ‘new_mail’ hook"))
+        let wasm = run_hook!(
+            new_mail(&mut wasm_meta(conn_meta))
+                || panic!("Error while running the ‘new_mail’ hook")
+        );
+        kannader_types::MailUser {
+            wasm,
+            ..Default::default()
+        }
     }
 
     async fn filter_from(
@@ -142,7 +771,23 @@ where
         meta: &mut MailMeta,
         conn_meta: &mut ConnMeta,
     ) -> Decision<Option<Email>> {
-        run_hook!(filter_from(from, meta, conn_meta))
+        if self.reinjection {
+            return Decision::Accept {
+                reply: reply::okay_from().convert(),
+                res: from,
+            };
+        }
+        self.check_spf(&from, meta, conn_meta).await;
+        let decision = run_hook!(filter_from(from, meta, &mut wasm_meta(conn_meta)));
+        match (&self.milters, decision) {
+            (Some(milters), Decision::Accept { reply, res }) => {
+                match milters.mail(conn_meta, res.as_ref()).await {
+                    Some(reply) => Decision::Reject { reply },
+                    None => Decision::Accept { reply, res },
+                }
+            }
+            (_, decision) => decision,
+        }
     }
 
     async fn filter_to(
@@ -151,11 +796,33 @@ where
         meta: &mut MailMeta,
         conn_meta: &mut ConnMeta,
     ) -> Decision<Email> {
-        run_hook!(filter_to(to, meta, conn_meta))
+        if self.reinjection {
+            return Decision::Accept {
+                reply: reply::okay_to().convert(),
+                res: to,
+            };
+        }
+        let decision = run_hook!(filter_to(to, meta, &mut wasm_meta(conn_meta)));
+        let decision = self.check_greylist(decision, meta, conn_meta).await;
+        match (&self.milters, decision) {
+            (Some(milters), Decision::Accept { reply, res }) => {
+                match milters.rcpt(conn_meta, &res).await {
+                    Some(reply) => Decision::Reject { reply },
+                    None => Decision::Accept { reply, res },
+                }
+            }
+            (_, decision) => decision,
+        }
     }
 
     async fn filter_data(&self, meta: &mut MailMeta, conn_meta: &mut ConnMeta) -> Decision<()> {
-        run_hook!(filter_data(meta, conn_meta))
+        if self.reinjection {
+            return Decision::Accept {
+                reply: reply::okay_data().convert(),
+                res: (),
+            };
+        }
+        run_hook!(filter_data(meta, &mut wasm_meta(conn_meta)))
     }
 
     /// Note: the EscapedDataReader has an inner buffer size of
@@ -168,12 +835,15 @@ where
     async fn handle_mail<'resp, R>(
         &'resp self,
         stream: &mut smtp_message::EscapedDataReader<'_, R>,
-        meta: MailMeta,
-        _conn_meta: &'resp mut ConnMeta,
+        mut meta: MailMeta,
+        conn_meta: &'resp mut ConnMeta,
     ) -> Decision<()>
     where
         R: Send + Unpin + AsyncRead,
     {
+        if self.reinjection {
+            return self.reinject(stream, meta).await;
+        }
         // TODO: figure out how to make this properly configurable, allowing to
         // configure filters, etc.
         let mut enqueuer = match self.queue.enqueue().await {
@@ -188,6 +858,74 @@ where
         };
         // TODO: MUST add Received header at least
         // TODO: factor out with the similar logic in smtp-client
+        let mut unescaper = Unescaper::new();
+        let mut unescaped = Vec::with_capacity(DATABUF_SIZE);
+        let mut dkim = smtp_dkim::Verifier::new();
+        let mut headers = smtp_dkim::HeaderSection::new();
+        let signing_domain: Option<String> =
+            run_hook!(dkim_signing_domain(&mut meta, &mut wasm_meta(conn_meta)) || None);
+        let mut signer = signing_domain.and_then(|d| {
+            let config = self.dkim_keys.get(&d.to_ascii_lowercase());
+            if config.is_none() {
+                warn!(domain = %d, "No DKIM signing key configured for domain, not signing");
+            }
+            config.map(|c| smtp_dkim::Signer::new(c.clone()))
+        });
+        let mut arc = smtp_dkim::ArcVerifier::new();
+        let mut sealer = match &self.arc_sealing {
+            // The mail we sign originates from here, so it needs no ARC set
+            // unless configured otherwise
+            Some(sealing) if signer.is_none() || sealing.seal_signed => {
+                let sealing_domain: Option<String> =
+                    run_hook!(arc_sealing_domain(&mut meta, &mut wasm_meta(conn_meta)) || None);
+                sealing_domain.and_then(|d| {
+                    let config = sealing.keys.get(&d.to_ascii_lowercase());
+                    if config.is_none() {
+                        warn!(domain = %d, "No ARC sealing key configured for domain, not sealing");
+                    }
+                    config.map(|c| smtp_dkim::Sealer::new(c.clone()))
+                })
+            }
+            _ => None,
+        };
+        // Forged authentication results are removed from the spooled data, so
+        // our own signatures must only see what remains
+        let mut filter = ForgedAuthResFilter::new(&self.authserv_id);
+        let mut filtered = Vec::with_capacity(DATABUF_SIZE);
+        let mut signed_unescaper = Unescaper::new();
+        let mut signed = Vec::with_capacity(DATABUF_SIZE);
+        // Milters, the content filter and the scanners can modify or reject
+        // the message, so it can only be spooled once they all saw it
+        let mut message = if self.milters.is_some()
+            || self.content_filter.is_some()
+            || self.antivirus.is_some()
+            || self.spam_scanner.is_some()
+        {
+            Some(Vec::new())
+        } else {
+            None
+        };
+        macro_rules! write_filtered {
+            () => {{
+                signed.clear();
+                signed_unescaper.update(&filtered, &mut signed);
+                if let Some(signer) = &mut signer {
+                    signer.update(&signed);
+                }
+                if let Some(sealer) = &mut sealer {
+                    sealer.update(&signed);
+                }
+                enqueuer.write_all(&filtered).await
+            }};
+        }
+        macro_rules! spool {
+            ($data:expr) => {{
+                filtered.clear();
+                filter.update($data, &mut filtered);
+                write_filtered!()
+            }};
+        }
+        let mut size = 0;
         let mut buf = [0; DATABUF_SIZE];
         loop {
             match stream.read(&mut buf).await {
@@ -197,7 +935,26 @@ where
                 }
                 Ok(n) => {
                     // Got n bytes
-                    if let Err(e) = enqueuer.write_all(&buf[..n]).await {
+                    size += n as u64;
+                    if size > self.max_message_size {
+                        // Read on until the end of the data, for the client to
+                        // get the reply, but do not keep any of it
+                        message = None;
+                        continue;
+                    }
+                    unescaped.clear();
+                    unescaper.update(&buf[..n], &mut unescaped);
+                    dkim.update(&unescaped);
+                    headers.update(&unescaped);
+                    arc.update(&unescaped);
+                    let res = match &mut message {
+                        Some(message) => {
+                            message.extend_from_slice(&buf[..n]);
+                            Ok(())
+                        }
+                        None => spool!(&buf[..n]),
+                    };
+                    if let Err(e) = res {
                         error!(error = ?e, "Internal server error while writing data to queue");
                         loop {
                             match stream.read(&mut buf).await {
@@ -231,10 +988,217 @@ where
                 reply: reply::internal_server_error().convert(),
             }
         } else {
-            // Stream is finished, let's complete it then commit the file to the queue and
-            // acept
+            // Stream is finished, let's complete it, give the hook a chance to reject the
+            // mail, then commit the file to the queue and accept
             stream.complete();
+            if size > self.max_message_size {
+                info!(
+                    max_size = self.max_message_size,
+                    "Rejecting mail bigger than the maximum message size"
+                );
+                return Decision::Reject {
+                    reply: reply::message_too_big().convert(),
+                };
+            }
+            let mut quarantine = None;
+            if let Some(mut message) = message {
+                let mut reason = None;
+                if let Some(milters) = &self.milters {
+                    message = match milters.message(conn_meta, &message).await {
+                        MessageVerdict::Accept {
+                            message,
+                            recipients,
+                            quarantine,
+                        } => {
+                            apply_recipient_changes(&mut meta.to, recipients);
+                            reason = quarantine;
+                            message
+                        }
+                        MessageVerdict::Discard => {
+                            return Decision::Accept {
+                                reply: reply::okay_mail().convert(),
+                                res: (),
+                            };
+                        }
+                        MessageVerdict::Reject(reply) => return Decision::Reject { reply },
+                    };
+                    if meta.to.is_empty() {
+                        info!("Milters removed all the recipients, dropping the mail");
+                        return Decision::Accept {
+                            reply: reply::okay_mail().convert(),
+                            res: (),
+                        };
+                    }
+                }
+                if let Some(cfg) = &self.content_filter {
+                    message = match content_filter::run(cfg, &message, &meta, conn_meta).await {
+                        Ok(Verdict::Accept(message)) => message,
+                        Ok(Verdict::TempFail(reply)) => {
+                            info!("Content filter temporarily rejected the mail");
+                            return Decision::Reject {
+                                reply: reply.unwrap_or_else(|| reply::filter_tempfail().convert()),
+                            };
+                        }
+                        Ok(Verdict::Reject(reply)) => {
+                            info!("Content filter rejected the mail");
+                            return Decision::Reject {
+                                reply: reply
+                                    .unwrap_or_else(|| reply::rejected_by_filter().convert()),
+                            };
+                        }
+                        Err(e) => {
+                            error!(error = ?e, "Failed running the content filter");
+                            return Decision::Reject {
+                                reply: reply::filter_tempfail().convert(),
+                            };
+                        }
+                    };
+                }
+                if let Some(cfg) = &self.antivirus {
+                    if message.len() as u64 > cfg.max_size {
+                        info!(
+                            size = message.len(),
+                            "Mail is too big for the antivirus, not checking it"
+                        );
+                    } else {
+                        match antivirus::scan(cfg, &message).await {
+                            Ok(VirusResult::Clean) => (),
+                            Ok(VirusResult::Infected(virus)) => {
+                                info!(virus = %virus, "Antivirus found a virus in the mail");
+                                match cfg.on_virus {
+                                    kannader_types::VirusAction::Reject => {
+                                        return Decision::Reject {
+                                            reply: reply::virus_found().convert(),
+                                        };
+                                    }
+                                    kannader_types::VirusAction::Quarantine => {
+                                        reason = Some(format!("Virus found: {}", virus));
+                                    }
+                                }
+                            }
+                            Err(e) if cfg.fail_open => {
+                                warn!(error = ?e, "Failed checking the mail for viruses, going on without it")
+                            }
+                            Err(e) => {
+                                error!(error = ?e, "Failed checking the mail for viruses");
+                                return Decision::Reject {
+                                    reply: reply::filter_tempfail().convert(),
+                                };
+                            }
+                        }
+                    }
+                }
+                if let Some(cfg) = &self.spam_scanner {
+                    match spam::scan(cfg, &message, &meta, conn_meta).await {
+                        Ok(res) => {
+                            info!(
+                                score = res.score,
+                                action = res.action.name(),
+                                "Spam scanner checked the mail"
+                            );
+                            if cfg.add_headers {
+                                let mut with_headers = res.headers().into_bytes();
+                                with_headers.extend_from_slice(&spam::strip_headers(&message));
+                                message = with_headers;
+                            }
+                            meta.user.spam = Some(res);
+                        }
+                        Err(e) if cfg.fail_open => {
+                            warn!(error = ?e, "Failed checking the mail for spam, going on without it")
+                        }
+                        Err(e) => {
+                            error!(error = ?e, "Failed checking the mail for spam");
+                            return Decision::Reject {
+                                reply: reply::filter_tempfail().convert(),
+                            };
+                        }
+                    }
+                }
+                if let Err(e) = spool!(&message) {
+                    error!(error = ?e, "Internal server error while writing data to queue");
+                    return Decision::Reject {
+                        reply: reply::internal_server_error().convert(),
+                    };
+                }
+                quarantine = reason.map(|r| (r, message));
+            }
+            filtered.clear();
+            filter.finish(&mut filtered);
+            if let Err(e) = write_filtered!() {
+                error!(error = ?e, "Internal server error while writing data to queue");
+                return Decision::Reject {
+                    reply: reply::internal_server_error().convert(),
+                };
+            }
+            meta.user.dkim = dkim.finish(&self.resolver).await;
+            headers.finish();
+            self.check_dmarc(&headers, &mut meta, conn_meta).await;
+            meta.user.arc = Some(arc.finish(&self.resolver).await);
+            let reply = match run_hook!(filter_data_end(&mut meta, &mut wasm_meta(conn_meta))) {
+                Decision::Accept { reply, res: () } => reply,
+                d => return d,
+            };
+            if let Some((reason, message)) = quarantine {
+                let dir = match &self.quarantine_dir {
+                    Some(dir) => dir.clone(),
+                    None => {
+                        warn!("No quarantine directory configured, rejecting the quarantined mail");
+                        return Decision::Reject {
+                            reply: reply::rejected_by_filter().convert(),
+                        };
+                    }
+                };
+                return match quarantine::store(dir, message, reason, meta.from.as_ref(), &meta.to)
+                    .await
+                {
+                    Ok(()) => Decision::Accept { reply, res: () },
+                    Err(e) => {
+                        error!(error = ?e, "Internal server error while quarantining mail");
+                        Decision::Reject {
+                            reply: reply::internal_server_error().convert(),
+                        }
+                    }
+                };
+            }
+            // Record the results and sign only once the mail is known to be
+            // accepted, so that the headers end up in the spooled data
+            let authres = authentication_results(&self.authserv_id, &meta, conn_meta);
+            let mut prepended = authres.to_header().into_bytes();
+            if let Some(signer) = signer {
+                match signer.finish() {
+                    Ok(header) => {
+                        prepended.splice(0..0, header);
+                    }
+                    Err(e) => {
+                        warn!(error = ?e, "Failed DKIM-signing the mail, enqueuing it unsigned")
+                    }
+                }
+            }
+            // The ARC set goes on top of our own DKIM signature
+            if let Some(sealer) = sealer {
+                let chain = meta
+                    .user
+                    .arc
+                    .as_ref()
+                    .map_or(smtp_dkim::ArcStatus::None, |r| r.status);
+                match sealer.finish(chain, &authres.to_string()) {
+                    Ok(set) => {
+                        prepended.splice(0..0, set);
+                    }
+                    Err(e) => warn!(error = ?e, "Not ARC-sealing the mail"),
+                }
+            }
+            // Prepending copies the whole spooled data, so do it only once
+            if let Err(e) = enqueuer.prepend(prepended).await {
+                error!(error = ?e, "Internal server error while prepending the authentication headers");
+                return Decision::Reject {
+                    reply: reply::internal_server_error().convert(),
+                };
+            }
             let from = &meta.from;
+            let require_tls = meta.require_tls;
+            // RFC 8689 has REQUIRETLS take precedence over the header field
+            let tls_optional = !require_tls && tls_required_no(headers.fields());
             let destinations = meta
                 .to
                 .into_iter()
@@ -243,7 +1207,11 @@ where
                         smtp_queue::MailMetadata {
                             from: from.clone(),
                             to,
-                            metadata: Meta,
+                            require_tls,
+                            tls_optional,
+                            metadata: Meta {
+                                after_queue_filter: self.after_queue_filter,
+                            },
                         },
                         smtp_queue::ScheduleInfo {
                             at: Utc::now(),
@@ -258,10 +1226,7 @@ where
                     reply: reply::internal_server_error().convert(),
                 }
             } else {
-                Decision::Accept {
-                    reply: reply::okay_mail().convert(),
-                    res: (),
-                }
+                Decision::Accept { reply, res: () }
             }
         }
     }
@@ -271,19 +1236,19 @@ where
         meta: &mut Option<MailMeta>,
         conn_meta: &mut ConnMeta,
     ) -> Decision<()> {
-        run_hook!(handle_rset(meta, conn_meta))
+        run_hook!(handle_rset(meta, &mut wasm_meta(conn_meta)))
     }
 
     async fn handle_starttls(&self, conn_meta: &mut ConnMeta) -> Decision<()> {
-        run_hook!(handle_starttls(conn_meta))
+        run_hook!(handle_starttls(&mut wasm_meta(conn_meta)))
     }
 
     async fn handle_expn(&self, name: MaybeUtf8<&str>, conn_meta: &mut ConnMeta) -> Decision<()> {
-        run_hook!(handle_expn(name.convert(), conn_meta))
+        run_hook!(handle_expn(name.convert(), &mut wasm_meta(conn_meta)))
     }
 
     async fn handle_vrfy(&self, name: MaybeUtf8<&str>, conn_meta: &mut ConnMeta) -> Decision<()> {
-        run_hook!(handle_vrfy(name.convert(), conn_meta))
+        run_hook!(handle_vrfy(name.convert(), &mut wasm_meta(conn_meta)))
     }
 
     async fn handle_help(
@@ -291,63 +1256,76 @@ where
         subject: MaybeUtf8<&str>,
         conn_meta: &mut ConnMeta,
     ) -> Decision<()> {
-        run_hook!(handle_help(subject.convert(), conn_meta))
+        run_hook!(handle_help(subject.convert(), &mut wasm_meta(conn_meta)))
     }
 
     async fn handle_noop(&self, string: MaybeUtf8<&str>, conn_meta: &mut ConnMeta) -> Decision<()> {
-        run_hook!(handle_noop(string.convert(), conn_meta))
+        run_hook!(handle_noop(string.convert(), &mut wasm_meta(conn_meta)))
     }
 
     async fn handle_quit(&self, conn_meta: &mut ConnMeta) -> Decision<()> {
-        run_hook!(handle_quit(conn_meta))
+        run_hook!(handle_quit(&mut wasm_meta(conn_meta)))
     }
 
     fn already_did_hello(&self, conn_meta: &mut ConnMeta) -> Reply {
-        run_hook!(already_did_hello(conn_meta) || reply::bad_sequence().convert())
+        run_hook!(already_did_hello(&mut wasm_meta(conn_meta)) || reply::bad_sequence().convert())
     }
 
     fn mail_before_hello(&self, conn_meta: &mut ConnMeta) -> Reply {
-        run_hook!(mail_before_hello(conn_meta) || reply::bad_sequence().convert())
+        run_hook!(mail_before_hello(&mut wasm_meta(conn_meta)) || reply::bad_sequence().convert())
     }
 
     fn already_in_mail(&self, conn_meta: &mut ConnMeta) -> Reply {
-        run_hook!(already_in_mail(conn_meta) || reply::bad_sequence().convert())
+        run_hook!(already_in_mail(&mut wasm_meta(conn_meta)) || reply::bad_sequence().convert())
+    }
+
+    fn require_tls_without_tls(&self, conn_meta: &mut ConnMeta) -> Reply {
+        run_hook!(
+            require_tls_without_tls(&mut wasm_meta(conn_meta))
+                || reply::require_tls_without_tls().convert()
+        )
     }
 
     fn rcpt_before_mail(&self, conn_meta: &mut ConnMeta) -> Reply {
-        run_hook!(rcpt_before_mail(conn_meta) || reply::bad_sequence().convert())
+        run_hook!(rcpt_before_mail(&mut wasm_meta(conn_meta)) || reply::bad_sequence().convert())
     }
 
     fn data_before_rcpt(&self, conn_meta: &mut ConnMeta) -> Reply {
-        run_hook!(data_before_rcpt(conn_meta) || reply::bad_sequence().convert())
+        run_hook!(data_before_rcpt(&mut wasm_meta(conn_meta)) || reply::bad_sequence().convert())
     }
 
     fn data_before_mail(&self, conn_meta: &mut ConnMeta) -> Reply {
-        run_hook!(data_before_mail(conn_meta) || reply::bad_sequence().convert())
+        run_hook!(data_before_mail(&mut wasm_meta(conn_meta)) || reply::bad_sequence().convert())
     }
 
     fn starttls_unsupported(&self, conn_meta: &mut ConnMeta) -> Reply {
-        run_hook!(starttls_unsupported(conn_meta) || reply::command_not_supported().convert())
+        run_hook!(
+            starttls_unsupported(&mut wasm_meta(conn_meta))
+                || reply::command_not_supported().convert()
+        )
     }
 
     fn command_unrecognized(&self, conn_meta: &mut ConnMeta) -> Reply {
-        run_hook!(command_unrecognized(conn_meta) || reply::command_unrecognized().convert())
+        run_hook!(
+            command_unrecognized(&mut wasm_meta(conn_meta))
+                || reply::command_unrecognized().convert()
+        )
     }
 
     fn pipeline_forbidden_after_starttls(&self, conn_meta: &mut ConnMeta) -> Reply {
         run_hook!(
-            pipeline_forbidden_after_starttls(conn_meta)
+            pipeline_forbidden_after_starttls(&mut wasm_meta(conn_meta))
                 || reply::pipeline_forbidden_after_starttls().convert()
         )
     }
 
     fn line_too_long(&self, conn_meta: &mut ConnMeta) -> Reply {
-        run_hook!(line_too_long(conn_meta) || reply::line_too_long().convert())
+        run_hook!(line_too_long(&mut wasm_meta(conn_meta)) || reply::line_too_long().convert())
     }
 
     fn handle_mail_did_not_call_complete(&self, conn_meta: &mut ConnMeta) -> Reply {
         run_hook!(
-            handle_mail_did_not_call_complete(conn_meta)
+            handle_mail_did_not_call_complete(&mut wasm_meta(conn_meta))
                 || reply::handle_mail_did_not_call_complete().convert()
         )
     }
@@ -0,0 +1,455 @@
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context};
+use chrono::Utc;
+use rustls::{
+    server::{ClientHello, ResolvesServerCert, ServerSessionMemoryCache},
+    sign::CertifiedKey,
+};
+use smol::unblock;
+use tracing::{debug, error, info, warn};
+
+use kannader_types::TlsHandler;
+use smtp_client::x509;
+
+use crate::{client_auth::ClientAuth, tls_profile, tls_session::RotatingTicketer};
+
+/// How often the certificate files are checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Set by SIGHUP, to force reloading the certificates
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Certificates expiring within this many days get a warning when loaded
+const EXPIRY_WARNING_DAYS: i64 = 14;
+
+/// Signature schemes with which to check that a key matches its certificate
+static KEY_CHECK_SCHEMES: &[(rustls::SignatureScheme, &webpki::SignatureAlgorithm)] = &[
+    (rustls::SignatureScheme::ED25519, &webpki::ED25519),
+    (
+        rustls::SignatureScheme::ECDSA_NISTP256_SHA256,
+        &webpki::ECDSA_P256_SHA256,
+    ),
+    (
+        rustls::SignatureScheme::ECDSA_NISTP384_SHA384,
+        &webpki::ECDSA_P384_SHA384,
+    ),
+    (
+        rustls::SignatureScheme::RSA_PKCS1_SHA256,
+        &webpki::RSA_PKCS1_2048_8192_SHA256,
+    ),
+];
+
+fn read_pem(file: &Path, what: &str) -> anyhow::Result<Vec<rustls_pemfile::Item>> {
+    rustls_pemfile::read_all(&mut io::BufReader::new(
+        std::fs::File::open(file)
+            .with_context(|| format!("Opening the {} file ‘{}’", what, file.display()))?,
+    ))
+    .with_context(|| format!("Reading the {} file ‘{}’ as PEM", what, file.display()))
+}
+
+/// Checks that `key` is the private key of the certificate `cert`, by signing
+/// with it and checking the signature against the certificate's public key
+fn key_matches(key: &dyn rustls::sign::SigningKey, cert: &[u8]) -> anyhow::Result<bool> {
+    let schemes = KEY_CHECK_SCHEMES
+        .iter()
+        .map(|(s, _)| *s)
+        .collect::<Vec<_>>();
+    let signer = match key.choose_scheme(&schemes) {
+        Some(signer) => signer,
+        None => return Ok(false),
+    };
+    let alg = KEY_CHECK_SCHEMES
+        .iter()
+        .find(|(s, _)| *s == signer.scheme())
+        .map(|(_, alg)| *alg)
+        .ok_or_else(|| anyhow!("Key signed with an unexpected scheme"))?;
+    let msg = b"kannader key check";
+    let sig = signer.sign(msg).context("Signing with the private key")?;
+    let cert = webpki::EndEntityCert::try_from(cert)
+        .map_err(|e| anyhow!("Parsing the server certificate: {:?}", e))?;
+    Ok(cert.verify_signature(alg, msg, &sig).is_ok())
+}
+
+/// Loads the certificate chain of `cert_file` along with the private key of
+/// `key_file`
+///
+/// The key must be the one of the first certificate, and each of the next
+/// certificates must be the issuer of the previous one.
+pub fn load_certified_key(cert_file: &Path, key_file: &Path) -> anyhow::Result<CertifiedKey> {
+    let certs = read_pem(cert_file, "certificate")?
+        .into_iter()
+        .filter_map(|i| match i {
+            rustls_pemfile::Item::X509Certificate(c) => Some(rustls::Certificate(c)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    anyhow::ensure!(
+        !certs.is_empty(),
+        "Certificate file ‘{}’ holds no “CERTIFICATE” PEM block",
+        cert_file.display()
+    );
+    debug!(num_certs = certs.len(), file = %cert_file.display(), "Parsed certificates");
+
+    let mut keys = read_pem(key_file, "key")?
+        .into_iter()
+        .filter_map(|i| match i {
+            rustls_pemfile::Item::RSAKey(k)
+            | rustls_pemfile::Item::PKCS8Key(k)
+            | rustls_pemfile::Item::ECKey(k) => Some(rustls::PrivateKey(k)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    anyhow::ensure!(
+        keys.len() == 1,
+        "Key file ‘{}’ should hold exactly one “PRIVATE KEY”, “RSA PRIVATE KEY” or “EC PRIVATE \
+         KEY” PEM block, but holds {}",
+        key_file.display(),
+        keys.len()
+    );
+    let key = rustls::sign::any_supported_type(&keys.remove(0)).map_err(|_| {
+        anyhow!(
+            "The private key in ‘{}’ is not a valid RSA, ECDSA P-256/P-384 or Ed25519 key",
+            key_file.display()
+        )
+    })?;
+
+    anyhow::ensure!(
+        key_matches(&*key, &certs[0].0)
+            .with_context(|| format!("Checking the key of ‘{}’", cert_file.display()))?,
+        "The private key in ‘{}’ is not the one of the first certificate in ‘{}’",
+        key_file.display(),
+        cert_file.display()
+    );
+
+    for (i, pair) in certs.windows(2).enumerate() {
+        let issuer = x509::issuer(&pair[0].0);
+        anyhow::ensure!(
+            issuer.is_some() && issuer == x509::subject(&pair[1].0),
+            "Certificate {} in ‘{}’ was not issued by the certificate following it: the file must \
+             hold the server certificate first, then each intermediate certificate in order",
+            i + 1,
+            cert_file.display()
+        );
+    }
+
+    let now = Utc::now();
+    for (i, cert) in certs.iter().enumerate() {
+        match x509::not_after(&cert.0) {
+            Some(end) if end < now => warn!(
+                file = %cert_file.display(),
+                certificate = i + 1,
+                expired = %end,
+                "TLS certificate has expired"
+            ),
+            Some(end) if end < now + chrono::Duration::days(EXPIRY_WARNING_DAYS) => warn!(
+                file = %cert_file.display(),
+                certificate = i + 1,
+                expires = %end,
+                "TLS certificate expires soon"
+            ),
+            Some(_) => (),
+            None => warn!(
+                file = %cert_file.display(),
+                certificate = i + 1,
+                "Could not read the expiry date of the TLS certificate"
+            ),
+        }
+    }
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Picks the certificate to serve according to the name the client asks for
+/// through SNI
+pub struct CertResolver {
+    /// Certificates by lowercase name, in order of preference
+    by_name: HashMap<String, Vec<Arc<CertifiedKey>>>,
+    default: Arc<CertifiedKey>,
+}
+
+impl CertResolver {
+    pub fn new(default: CertifiedKey) -> CertResolver {
+        CertResolver {
+            by_name: HashMap::new(),
+            default: Arc::new(default),
+        }
+    }
+
+    pub fn add(&mut self, name: &str, key: Arc<CertifiedKey>) {
+        self.by_name
+            .entry(name.to_ascii_lowercase())
+            .or_default()
+            .push(key);
+    }
+
+    /// Returns the first certificate for `name` whose key can sign with one
+    /// of `schemes`
+    fn find(&self, name: &str, schemes: &[rustls::SignatureScheme]) -> Option<Arc<CertifiedKey>> {
+        self.by_name
+            .get(name)?
+            .iter()
+            .find(|k| k.key.choose_scheme(schemes).is_some())
+            .cloned()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let schemes = client_hello.signature_schemes();
+        if let Some(name) = client_hello.server_name() {
+            let name = name.to_ascii_lowercase();
+            if let Some(key) = self.find(&name, schemes) {
+                return Some(key);
+            }
+            // Wildcards only ever cover a single label
+            if let Some((_, parent)) = name.split_once('.') {
+                if let Some(key) = self.find(&format!("*.{}", parent), schemes) {
+                    return Some(key);
+                }
+            }
+        }
+        Some(self.default.clone())
+    }
+}
+
+/// The certificate and key files the TLS server configuration is built from
+pub struct CertFiles {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    pub certificates: Vec<kannader_types::TlsCertificate>,
+    pub handler: TlsHandler,
+    pub profile: kannader_types::TlsProfile,
+    /// Certificate authorities against which to check client certificates,
+    /// if they are to be requested
+    pub client_ca_file: Option<PathBuf>,
+    /// Kept across reloads, for the sessions to still be resumable
+    pub session_cache: Arc<ServerSessionMemoryCache>,
+    pub ticketer: Arc<RotatingTicketer>,
+}
+
+/// TLS server configuration
+#[derive(Clone)]
+pub struct TlsServer {
+    pub acceptor: tokio_rustls::TlsAcceptor,
+    /// Used instead of `acceptor` when the `NativeTls` handler is picked
+    #[cfg(feature = "native-tls")]
+    pub native: Option<tokio_native_tls::TlsAcceptor>,
+    /// Checks the client certificates, if they are requested
+    pub client_auth: Option<Arc<ClientAuth>>,
+}
+
+impl CertFiles {
+    /// Returns the configured settings that the `NativeTls` handler cannot
+    /// honour
+    fn unsupported_by_native_tls(&self) -> Vec<&'static str> {
+        let mut res = Vec::new();
+        if !self.certificates.is_empty() {
+            res.push("per-name certificates (‘tls_certificates’)");
+        }
+        if !matches!(self.profile, kannader_types::TlsProfile::Compatible) {
+            res.push("TLS profiles (‘tls_profile’)");
+        }
+        if self.client_ca_file.is_some() {
+            res.push("client certificates (‘tls_client_ca_file’)");
+        }
+        res
+    }
+
+    /// Loads all the certificates, and builds the TLS server configuration
+    /// serving them
+    pub fn load(&self) -> anyhow::Result<TlsServer> {
+        if self.handler == TlsHandler::NativeTls {
+            let unsupported = self.unsupported_by_native_tls();
+            anyhow::ensure!(
+                unsupported.is_empty(),
+                "The ‘NativeTls’ TLS handler does not support {}, either use the ‘Rustls’ one or \
+                 remove these settings",
+                unsupported.join(", ")
+            );
+        }
+        let mut resolver = CertResolver::new(load_certified_key(&self.cert_file, &self.key_file)?);
+        for c in &self.certificates {
+            let key = Arc::new(load_certified_key(&c.cert_file, &c.key_file)?);
+            for name in &c.names {
+                resolver.add(name, key.clone());
+            }
+        }
+        #[cfg(feature = "native-tls")]
+        let native = match self.handler {
+            TlsHandler::Rustls => None,
+            TlsHandler::NativeTls => Some(crate::tls_native::acceptor(
+                &self.cert_file,
+                &self.key_file,
+            )?),
+        };
+        #[cfg(not(feature = "native-tls"))]
+        anyhow::ensure!(
+            self.handler == TlsHandler::Rustls,
+            "The ‘NativeTls’ TLS handler needs kannader to be built with the ‘native-tls’ feature"
+        );
+        let client_auth = match &self.client_ca_file {
+            Some(path) => Some(Arc::new(ClientAuth::load(path)?)),
+            None => None,
+        };
+
+        let builder = tls_profile::apply(&self.profile, rustls::ServerConfig::builder())
+            .context("Configuring the rustls server")?;
+        let builder = match &client_auth {
+            Some(client_auth) => builder.with_client_cert_verifier(client_auth.clone()),
+            None => builder.with_no_client_auth(),
+        };
+        let mut cfg = builder.with_cert_resolver(Arc::new(resolver));
+        cfg.session_storage = self.session_cache.clone();
+        cfg.ticketer = self.ticketer.clone();
+        Ok(TlsServer {
+            acceptor: Arc::new(cfg).into(),
+            #[cfg(feature = "native-tls")]
+            native,
+            client_auth,
+        })
+    }
+
+    /// Returns the modification times of all the files, `None` for those
+    /// that cannot be read
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        std::iter::once((&self.cert_file, &self.key_file))
+            .chain(
+                self.certificates
+                    .iter()
+                    .map(|c| (&c.cert_file, &c.key_file)),
+            )
+            .flat_map(|(c, k)| [c, k])
+            .chain(&self.client_ca_file)
+            .map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+}
+
+/// TLS server configuration that can be swapped while running, the
+/// connections already established keeping the one they started with
+#[derive(Clone)]
+pub struct Acceptor(Arc<RwLock<TlsServer>>);
+
+impl Acceptor {
+    pub fn new(tls: TlsServer) -> Acceptor {
+        Acceptor(Arc::new(RwLock::new(tls)))
+    }
+
+    pub fn get(&self) -> TlsServer {
+        self.0.read().unwrap().clone()
+    }
+
+    fn set(&self, tls: TlsServer) {
+        *self.0.write().unwrap() = tls;
+    }
+}
+
+extern "C" fn request_reload(_: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Reloads the certificates into `acceptor` whenever one of the files
+/// changes, or on SIGHUP
+///
+/// If the new files cannot be loaded, the current certificates are kept.
+pub async fn watch(files: Arc<CertFiles>, acceptor: Acceptor) {
+    // Safety: the handler only stores into an atomic, which is
+    // async-signal-safe
+    unsafe {
+        libc::signal(
+            libc::SIGHUP,
+            request_reload as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+
+    let mut modified = files.modified();
+    loop {
+        smol::Timer::after(WATCH_INTERVAL).await;
+        let now_modified = {
+            let files = files.clone();
+            unblock(move || files.modified()).await
+        };
+        let requested = RELOAD_REQUESTED.swap(false, Ordering::Relaxed);
+        if !requested && now_modified == modified {
+            continue;
+        }
+        modified = now_modified;
+
+        info!(on_signal = requested, "Reloading the TLS certificates");
+        let res = {
+            let files = files.clone();
+            unblock(move || files.load()).await
+        };
+        match res {
+            Ok(tls) => {
+                acceptor.set(tls);
+                info!("Reloaded the TLS certificates");
+            }
+            Err(e) => error!(
+                error = ?e,
+                "Failed reloading the TLS certificates, keeping the current ones"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kannader_types::{TlsCertificate, TlsProfile};
+
+    use super::*;
+
+    fn cert_files(handler: TlsHandler) -> CertFiles {
+        CertFiles {
+            cert_file: PathBuf::from("/nonexistent/cert.pem"),
+            key_file: PathBuf::from("/nonexistent/key.pem"),
+            certificates: Vec::new(),
+            handler,
+            profile: TlsProfile::Compatible,
+            client_ca_file: None,
+            session_cache: ServerSessionMemoryCache::new(1),
+            ticketer: RotatingTicketer::new(Duration::from_secs(60)).unwrap(),
+        }
+    }
+
+    #[test]
+    fn native_tls_unsupported() {
+        let mut files = cert_files(TlsHandler::NativeTls);
+        assert!(files.unsupported_by_native_tls().is_empty());
+
+        files.certificates.push(TlsCertificate {
+            names: vec![String::from("mx.example.org")],
+            cert_file: PathBuf::from("/nonexistent/mx.pem"),
+            key_file: PathBuf::from("/nonexistent/mx.key"),
+        });
+        files.profile = TlsProfile::Modern;
+        files.client_ca_file = Some(PathBuf::from("/nonexistent/ca.pem"));
+        assert_eq!(
+            files.load().err().unwrap().to_string(),
+            "The ‘NativeTls’ TLS handler does not support per-name certificates \
+             (‘tls_certificates’), TLS profiles (‘tls_profile’), client certificates \
+             (‘tls_client_ca_file’), either use the ‘Rustls’ one or remove these settings"
+        );
+
+        // The same settings are fine with rustls, which then fails on the
+        // missing files
+        files.handler = TlsHandler::Rustls;
+        assert!(files
+            .load()
+            .err()
+            .unwrap()
+            .to_string()
+            .starts_with("Opening the certificate file"));
+    }
+}
@@ -0,0 +1,102 @@
+use std::{future::Future, io, time::Duration};
+
+use anyhow::Context;
+use smol::future::FutureExt;
+
+use kannader_types::ReverseDns;
+use smtp_scanner::SpamResult;
+
+use crate::server_config::{ConnMeta, MailMeta};
+
+/// Connects to a scanner, `stream` being the connection attempt
+pub async fn connect<S>(
+    stream: impl Future<Output = io::Result<S>>,
+    timeout: Duration,
+) -> anyhow::Result<S> {
+    stream
+        .or(async {
+            smol::Timer::after(timeout).await;
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "timed out connecting to the scanner",
+            ))
+        })
+        .await
+        .context("Connecting to the scanner")
+}
+
+/// Checks `message` with the spam scanner
+pub async fn scan(
+    cfg: &kannader_types::SpamScanner,
+    message: &[u8],
+    meta: &MailMeta,
+    conn_meta: &ConnMeta,
+) -> anyhow::Result<SpamResult> {
+    let timeout = Duration::from_secs(cfg.timeout);
+    let envelope = smtp_scanner::Envelope {
+        ip: conn_meta.peer_addr.map(|a| a.ip()),
+        helo: conn_meta.hello.as_ref().map(|h| h.hostname.to_string()),
+        hostname: match &conn_meta.user.reverse_dns {
+            Some(ReverseDns::Pass(name)) => Some(name.clone()),
+            _ => None,
+        },
+        from: Some(
+            meta.from
+                .as_ref()
+                .map_or_else(|| String::from("<>"), |f| f.to_string()),
+        ),
+        rcpt: meta.to.iter().map(|t| t.to_string()).collect(),
+        user: None,
+    };
+    macro_rules! check {
+        ($stream:expr) => {
+            match cfg.kind {
+                kannader_types::SpamScannerKind::Spamd => {
+                    smtp_scanner::check_spamd($stream, message, None, timeout).await
+                }
+                kannader_types::SpamScannerKind::Rspamd => {
+                    smtp_scanner::check_rspamd($stream, message, &envelope, timeout).await
+                }
+            }
+        };
+    }
+    let res = match &cfg.socket {
+        kannader_types::MilterSocket::Inet(addr) => {
+            let stream = connect(smol::net::TcpStream::connect(addr.as_str()), timeout).await?;
+            check!(stream)
+        }
+        kannader_types::MilterSocket::Unix(path) => {
+            let stream = connect(smol::net::unix::UnixStream::connect(path), timeout).await?;
+            check!(stream)
+        }
+    };
+    res.context("Checking the mail with the spam scanner")
+}
+
+/// Removes the `X-Spam-*` header fields from `message`, so that the ones sent
+/// by the client cannot be mistaken for ours
+pub fn strip_headers(message: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(message.len());
+    let mut skipping = false;
+    let mut rest = message;
+    while !rest.is_empty() {
+        let len = rest
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .map_or(rest.len(), |p| p + 2);
+        let (line, next) = rest.split_at(len);
+        if line == b"\r\n" {
+            // End of the header section
+            break;
+        }
+        if !line.starts_with(b" ") && !line.starts_with(b"\t") {
+            skipping = line.len() >= 7 && line[..7].eq_ignore_ascii_case(b"X-Spam-");
+        }
+        if !skipping {
+            res.extend_from_slice(line);
+        }
+        rest = next;
+    }
+    res.extend_from_slice(rest);
+    res
+}
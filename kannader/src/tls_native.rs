@@ -0,0 +1,110 @@
+use std::{io, path::Path};
+
+use anyhow::Context;
+use async_compat::Compat;
+use futures::{AsyncRead, AsyncWrite};
+use tokio_native_tls::native_tls;
+
+use smtp_client::dane;
+
+use crate::client_config::Verification;
+
+fn read(file: &Path, what: &str) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(file).with_context(|| format!("Reading the {} file ‘{}’", what, file.display()))
+}
+
+fn identity(cert_file: &Path, key_file: &Path) -> anyhow::Result<native_tls::Identity> {
+    native_tls::Identity::from_pkcs8(&read(cert_file, "certificate")?, &read(key_file, "key")?)
+        .with_context(|| {
+            format!(
+                "Loading ‘{}’ with the key ‘{}’, which native-tls needs as a PKCS#8 “PRIVATE KEY” \
+                 PEM block",
+                cert_file.display(),
+                key_file.display()
+            )
+        })
+}
+
+/// Builds the native-tls acceptor serving the certificate of `cert_file`
+pub fn acceptor(
+    cert_file: &Path,
+    key_file: &Path,
+) -> anyhow::Result<tokio_native_tls::TlsAcceptor> {
+    let acceptor = native_tls::TlsAcceptor::new(identity(cert_file, key_file)?)
+        .context("Configuring the native-tls server")?;
+    Ok(acceptor.into())
+}
+
+fn other(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+/// Negotiates TLS with `hostname` through native-tls, checking its
+/// certificate according to `verification` and presenting `client_cert` if
+/// any
+pub async fn connect<IO>(
+    io: IO,
+    hostname: &str,
+    verification: &Verification,
+    client_cert: Option<kannader_types::TlsClientCertificate>,
+) -> io::Result<tokio_native_tls::TlsStream<Compat<IO>>>
+where
+    IO: 'static + Unpin + Send + AsyncRead + AsyncWrite,
+{
+    use async_compat::CompatExt;
+    let mut builder = native_tls::TlsConnector::builder();
+    match verification {
+        Verification::None | Verification::Pinned(_) => {
+            builder
+                .danger_accept_invalid_certs(true)
+                .danger_accept_invalid_hostnames(true);
+        }
+        Verification::SystemRoots => (),
+        Verification::TrustStore(path) => {
+            let certs = std::fs::File::open(path)
+                .and_then(|f| rustls_pemfile::certs(&mut io::BufReader::new(f)))
+                .map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("Loading trust store ‘{}’: {}", path.display(), e),
+                    )
+                })?;
+            builder.disable_built_in_roots(true);
+            for cert in certs {
+                builder
+                    .add_root_certificate(native_tls::Certificate::from_der(&cert).map_err(other)?);
+            }
+        }
+    }
+    if let Some(cert) = client_cert {
+        builder.identity(
+            identity(&cert.cert_file, &cert.key_file).map_err(|e| other(format!("{:#}", e)))?,
+        );
+    }
+    let connector = tokio_native_tls::TlsConnector::from(builder.build().map_err(other)?);
+    let io = connector
+        .connect(hostname, io.compat())
+        .await
+        .map_err(other)?;
+
+    if let Verification::Pinned(tlsa) = verification {
+        let cert = io
+            .get_ref()
+            .peer_certificate()
+            .map_err(other)?
+            .map(|c| c.to_der())
+            .transpose()
+            .map_err(other)?;
+        let matches = match cert {
+            Some(cert) => dane::matches_end_entity(tlsa, &cert),
+            None => false,
+        };
+        if !matches {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no DANE-EE TLSA record or pinned key matches the server certificate",
+            ));
+        }
+    }
+    Ok(io)
+}
@@ -0,0 +1,102 @@
+use anyhow::{anyhow, Context};
+use rustls::{
+    ConfigBuilder, ConfigSide, SupportedCipherSuite, SupportedKxGroup, SupportedProtocolVersion,
+    WantsCipherSuites, WantsVerifier,
+};
+
+use kannader_types::TlsProfile;
+
+/// Picks the items of `all` whose name is in `names`, or all of them if
+/// `names` is empty
+fn select<T: Copy>(
+    all: &[T],
+    names: &[String],
+    what: &str,
+    name: impl Fn(&T) -> String,
+) -> anyhow::Result<Vec<T>> {
+    if names.is_empty() {
+        return Ok(all.to_vec());
+    }
+    names
+        .iter()
+        .map(|n| {
+            all.iter()
+                .find(|i| name(i).eq_ignore_ascii_case(n))
+                .copied()
+                .ok_or_else(|| {
+                    let supported = all.iter().map(&name).collect::<Vec<_>>().join(", ");
+                    anyhow!("Unknown {} ‘{}’, supported are {}", what, n, supported)
+                })
+        })
+        .collect()
+}
+
+/// Restricts `builder` to the protocol versions, cipher suites and key
+/// exchange groups allowed by `profile`
+pub fn apply<S: ConfigSide>(
+    profile: &TlsProfile,
+    builder: ConfigBuilder<S, WantsCipherSuites>,
+) -> anyhow::Result<ConfigBuilder<S, WantsVerifier>> {
+    let (versions, cipher_suites, kx_groups): (
+        Vec<&'static SupportedProtocolVersion>,
+        Vec<SupportedCipherSuite>,
+        Vec<&'static SupportedKxGroup>,
+    ) = match profile {
+        TlsProfile::Modern => (
+            vec![&rustls::version::TLS13],
+            rustls::ALL_CIPHER_SUITES.to_vec(),
+            rustls::ALL_KX_GROUPS.to_vec(),
+        ),
+        // rustls only implements forward-secret AEAD cipher suites anyway
+        TlsProfile::Intermediate => (
+            vec![&rustls::version::TLS12, &rustls::version::TLS13],
+            rustls::ALL_CIPHER_SUITES.to_vec(),
+            rustls::ALL_KX_GROUPS.to_vec(),
+        ),
+        TlsProfile::Compatible => (
+            rustls::ALL_VERSIONS.to_vec(),
+            rustls::ALL_CIPHER_SUITES.to_vec(),
+            rustls::ALL_KX_GROUPS.to_vec(),
+        ),
+        TlsProfile::Custom {
+            versions,
+            cipher_suites,
+            kx_groups,
+        } => (
+            // Also accept the dotted form, eg. `TLSv1.3`
+            select(
+                rustls::ALL_VERSIONS,
+                &versions
+                    .iter()
+                    .map(|v| v.replace('.', "_"))
+                    .collect::<Vec<_>>(),
+                "TLS version",
+                |v| format!("{:?}", v.version),
+            )?,
+            select(
+                rustls::ALL_CIPHER_SUITES,
+                cipher_suites,
+                "cipher suite",
+                |s| format!("{:?}", s.suite()),
+            )?,
+            select(
+                &rustls::ALL_KX_GROUPS,
+                kx_groups,
+                "key exchange group",
+                |g| format!("{:?}", g.name),
+            )?,
+        ),
+    };
+
+    // Only keep the cipher suites of the allowed versions, rustls refusing
+    // the configuration if a version has none
+    let cipher_suites = cipher_suites
+        .into_iter()
+        .filter(|s| versions.iter().any(|v| v.version == s.version().version))
+        .collect::<Vec<_>>();
+    builder
+        .with_cipher_suites(&cipher_suites)
+        .with_kx_groups(&kx_groups)
+        .with_protocol_versions(&versions)
+        .with_context(|| format!("Applying the TLS profile {:?}", profile))
+}
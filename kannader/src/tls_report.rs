@@ -0,0 +1,258 @@
+use std::{convert::TryFrom, io::Write, path::PathBuf, sync::Arc};
+
+use anyhow::{anyhow, Context};
+use async_compat::CompatExt;
+use async_trait::async_trait;
+use chrono::Utc;
+use futures::AsyncWriteExt;
+use smol::unblock;
+use tracing::{info, warn};
+
+use smtp_client::tlsrpt::{self, Poster};
+use smtp_queue::{Storage, StorageEnqueuer};
+use smtp_queue_fs::FsStorage;
+
+use crate::{
+    dmarc_report::{parse_email, sending_path, take_store},
+    Meta, Opt, WasmConfig,
+};
+
+/// Appends `observation` to the TLS results store
+pub async fn record(store: Arc<PathBuf>, observation: tlsrpt::Observation) -> anyhow::Result<()> {
+    unblock(move || {
+        let mut line = serde_json::to_vec(&observation).context("Serializing the TLS result")?;
+        line.push(b'\n');
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&*store)
+            .and_then(|mut f| f.write_all(&line))
+            .with_context(|| format!("Appending to the TLS results store ‘{}’", store.display()))
+    })
+    .await
+}
+
+/// Uploads reports to `https:` reporting URIs, checking the certificate of the
+/// reporting host against the system roots
+struct HttpsPoster {
+    connector: tokio_rustls::TlsConnector,
+    timeout: chrono::Duration,
+}
+
+#[async_trait]
+impl tlsrpt::Poster for HttpsPoster {
+    async fn post(&self, uri: &str, report: &[u8]) -> Result<(), tlsrpt::PostError> {
+        use tlsrpt::PostError;
+        let (host, port, path) =
+            tlsrpt::https_uri(uri).ok_or_else(|| PostError::InvalidUri(uri.to_owned()))?;
+        let name = rustls::ServerName::try_from(host.as_str())
+            .map_err(|_| PostError::InvalidUri(uri.to_owned()))?;
+        let io = smol::future::or(
+            async {
+                let io = smol::net::TcpStream::connect((host.as_str(), port))
+                    .await
+                    .map_err(PostError::Io)?;
+                self.connector
+                    .connect(name, io.compat())
+                    .await
+                    .map_err(PostError::Io)
+            },
+            async {
+                smol::Timer::after(self.timeout.to_std().unwrap_or_default()).await;
+                Err(PostError::TimedOut)
+            },
+        )
+        .await?;
+        tlsrpt::post_report(io.compat(), &host, &path, report, self.timeout).await
+    }
+}
+
+/// Returns the reporting URIs that `domain` publishes in its `_smtp._tls` TXT
+/// record
+async fn reporting_uris(lookup: &dyn smtp_dkim::Lookup, domain: &str) -> Vec<String> {
+    let records = match lookup.lookup_txt(&format!("_smtp._tls.{}.", domain)).await {
+        Ok(records) => records,
+        Err(e) => {
+            warn!(domain, error = ?e, "Failed looking up the TLS reporting policy");
+            return Vec::new();
+        }
+    };
+    let records = records
+        .iter()
+        .filter(|r| r.starts_with("v=TLSRPTv1"))
+        .collect::<Vec<_>>();
+    // Multiple records mean there is no valid one
+    match &records[..] {
+        [record] => tlsrpt::parse_txt_record(record).unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+/// Generates the SMTP TLS reports (RFC 8460) for the results recorded so far,
+/// uploads them to the `https:` reporting URIs and enqueues them for the
+/// `mailto:` ones
+///
+/// Reports are meant to cover a day, so this is meant to be run daily, eg.
+/// from cron. The mailed reports are sent by kannader once it picks them up
+/// from the queue, which currently happens when it starts up.
+pub fn send_tls_reports(opt: &Opt) -> anyhow::Result<()> {
+    let engine = wasmtime::Engine::default();
+    let module = wasmtime::Module::from_file(&engine, &opt.wasm_blob)
+        .context("Compiling the wasm configuration blob")?;
+    let wasm_config = WasmConfig::new(&opt.dirs, &opt.config, &engine, &module)
+        .context("Preparing the wasm configuration blob")?;
+    let (reporting, tls_profile, storage, dkim_keys) = {
+        let mut store = wasm_config.store.borrow_mut();
+        let reporting = (wasm_config.client_config.tls_reporting)(&mut *store)
+            .context("Retrieving the TLS reporting configuration")?;
+        let tls_profile = (wasm_config.client_config.tls_profile)(&mut *store)
+            .context("Retrieving the outbound TLS profile")?;
+        let storage = (wasm_config.queue_config.storage_type)(&mut *store)
+            .context("Retrieving storage type")?;
+        let dkim_keys = (wasm_config.server_config.dkim_signing_keys)(&mut *store)
+            .context("Retrieving the DKIM signing keys")?;
+        (reporting, tls_profile, storage, dkim_keys)
+    };
+    let reporting = reporting.ok_or_else(|| anyhow!("TLS reporting is not configured"))?;
+    let from = parse_email(&reporting.email)
+        .ok_or_else(|| anyhow!("Invalid TLS report sender address ‘{}’", reporting.email))?;
+    // RFC 8460 requires the mailed reports to be DKIM-signed
+    let dkim_keys = crate::load_signing_keys(dkim_keys, "DKIM")?;
+    let sender_domain = reporting
+        .email
+        .rsplit('@')
+        .next()
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    let dkim_key = dkim_keys.get(&sender_domain).cloned();
+    if dkim_key.is_none() {
+        warn!(
+            domain = %sender_domain,
+            "No DKIM signing key for the TLS report sender domain, mailing reports unsigned"
+        );
+    }
+
+    smol::block_on(async move {
+        let sending = sending_path(&reporting.store);
+        let contents = take_store(reporting.store, sending.clone(), "TLS").await?;
+        let contents = match contents {
+            Some(c) => c,
+            None => {
+                info!("No TLS results recorded, not sending any report");
+                return Ok(());
+            }
+        };
+
+        let mut observations = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            match serde_json::from_str(line) {
+                Ok(o) => observations.push(o),
+                Err(e) => warn!(line = i + 1, error = ?e, "Ignoring invalid TLS result"),
+            }
+        }
+
+        let reporter = tlsrpt::Reporter {
+            org_name: reporting.org_name,
+            email: reporting.email,
+            contact_info: reporting.contact_info,
+        };
+        let resolver = async_std_resolver::resolver_from_system_conf()
+            .await
+            .context("Configuring a resolver from system configuration")?;
+        let storage = match storage {
+            kannader_types::QueueStorage::Fs(path) => FsStorage::<Meta>::new(Arc::new(path))
+                .await
+                .context("Opening the queue storage folder")?,
+        };
+        let poster = HttpsPoster {
+            connector: tokio_rustls::TlsConnector::from(crate::verified_tls_config(
+                crate::tls_profile::apply(&tls_profile, rustls::ClientConfig::builder())
+                    .context("Configuring the rustls client")?,
+            )?),
+            timeout: chrono::Duration::minutes(1),
+        };
+
+        for report in tlsrpt::aggregate(observations) {
+            let uris = reporting_uris(&resolver, &report.policy_domain).await;
+            if uris.is_empty() {
+                info!(domain = %report.policy_domain, "No TLS reporting URI to send the report to");
+                continue;
+            }
+            let json = report.to_json(&reporter);
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder
+                .write_all(json.as_bytes())
+                .context("Compressing the TLS report")?;
+            let gzipped = encoder.finish().context("Compressing the TLS report")?;
+
+            let mut to = Vec::new();
+            for uri in uris {
+                if let Some(addr) = tlsrpt::mailto_address(&uri) {
+                    match parse_email(&addr) {
+                        Some(email) => to.push((addr, email)),
+                        None => warn!(address = %addr, "Invalid TLS reporting address"),
+                    }
+                } else {
+                    match poster.post(&uri, &gzipped).await {
+                        Ok(()) => info!(%uri, report_id = %report.report_id, "Uploaded TLS report"),
+                        Err(e) => warn!(%uri, error = ?e, "Failed uploading the TLS report"),
+                    }
+                }
+            }
+            if to.is_empty() {
+                continue;
+            }
+
+            let addrs = to.iter().map(|(a, _)| a.clone()).collect::<Vec<_>>();
+            let mut mail = report.to_mail(&reporter, &addrs, &Utc::now().to_rfc2822(), &gzipped);
+            if let Some(key) = &dkim_key {
+                let mut signer = smtp_dkim::Signer::new(key.clone());
+                signer.update(&mail);
+                match signer.finish() {
+                    Ok(header) => {
+                        mail.splice(0..0, header);
+                    }
+                    Err(e) => {
+                        warn!(error = ?e, "Failed DKIM-signing the TLS report, mailing it unsigned")
+                    }
+                }
+            }
+            let mut enqueuer = storage.enqueue().await.context("Opening an enqueuer")?;
+            enqueuer
+                .write_all(&mail)
+                .await
+                .context("Writing the TLS report to the queue")?;
+            let destinations = to
+                .into_iter()
+                .map(|(_, to)| {
+                    (
+                        smtp_queue::MailMetadata {
+                            from: Some(from.clone()),
+                            to,
+                            require_tls: false,
+                            // The report must get through the TLS failures it is about
+                            tls_optional: true,
+                            metadata: Meta::default(),
+                        },
+                        smtp_queue::ScheduleInfo {
+                            at: Utc::now(),
+                            last_attempt: None,
+                        },
+                    )
+                })
+                .collect();
+            enqueuer
+                .commit(destinations)
+                .await
+                .context("Committing the TLS report to the queue")?;
+            info!(domain = %report.policy_domain, report_id = %report.report_id, "Enqueued TLS report");
+        }
+
+        unblock(move || {
+            std::fs::remove_file(&sending)
+                .with_context(|| format!("Removing the sent TLS results ‘{}’", sending.display()))
+        })
+        .await
+    })
+}
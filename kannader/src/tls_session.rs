@@ -0,0 +1,260 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use rustls::{client::ClientSessionMemoryCache, server::ProducesTickets};
+use tracing::{debug, warn};
+
+/// Number of inbound sessions kept for resumption by clients not supporting
+/// tickets
+pub const SERVER_SESSIONS: usize = 4096;
+
+/// Number of destinations for which outbound sessions are kept
+const CLIENT_DESTINATIONS: usize = 4096;
+
+/// Number of sessions kept for each destination
+const SESSIONS_PER_DESTINATION: usize = 8;
+
+/// rustls replaces the keys of its own ticketers every 6 hours, so they only
+/// accept the tickets of one rotation period longer than that
+const MAX_TICKET_KEY_ROTATION: Duration = Duration::from_secs(6 * 60 * 60);
+
+struct TicketKeys {
+    current: Arc<dyn ProducesTickets>,
+    previous: Option<Arc<dyn ProducesTickets>>,
+    since: Instant,
+}
+
+/// Session ticketer replacing its keys every `rotation`, the tickets issued
+/// with the previous keys being accepted for one more period
+///
+/// Keys are only rotated when tickets are issued or checked, like rustls'
+/// own ticketer does.
+pub struct RotatingTicketer {
+    rotation: Duration,
+    keys: Mutex<TicketKeys>,
+    clock: Box<dyn Send + Sync + Fn() -> Instant>,
+}
+
+impl RotatingTicketer {
+    pub fn new(rotation: Duration) -> anyhow::Result<Arc<RotatingTicketer>> {
+        RotatingTicketer::with_clock(rotation, Box::new(Instant::now))
+    }
+
+    fn with_clock(
+        rotation: Duration,
+        clock: Box<dyn Send + Sync + Fn() -> Instant>,
+    ) -> anyhow::Result<Arc<RotatingTicketer>> {
+        anyhow::ensure!(
+            rotation >= Duration::from_secs(1) && rotation <= MAX_TICKET_KEY_ROTATION,
+            "TLS ticket keys must be rotated at least every second and at most every {} hours, \
+             not every {:?}",
+            MAX_TICKET_KEY_ROTATION.as_secs() / 3600,
+            rotation
+        );
+        Ok(Arc::new(RotatingTicketer {
+            rotation,
+            keys: Mutex::new(TicketKeys {
+                current: rustls::Ticketer::new()?,
+                previous: None,
+                since: clock(),
+            }),
+            clock,
+        }))
+    }
+
+    /// Returns the keys to use, rotating them if it is time to
+    fn keys(&self) -> MutexGuard<TicketKeys> {
+        let mut keys = self.keys.lock().unwrap();
+        let now = (self.clock)();
+        let elapsed = now.saturating_duration_since(keys.since);
+        if elapsed >= self.rotation {
+            match rustls::Ticketer::new() {
+                Ok(new) => {
+                    let old = std::mem::replace(&mut keys.current, new);
+                    // Without any rotation for two periods, even the tickets
+                    // of the current keys have expired
+                    keys.previous = Some(old).filter(|_| elapsed < 2 * self.rotation);
+                    keys.since = now;
+                    debug!("Rotated the TLS ticket keys");
+                }
+                Err(e) => warn!(
+                    error = ?e,
+                    "Failed generating new TLS ticket keys, keeping the current ones"
+                ),
+            }
+        }
+        keys
+    }
+}
+
+impl ProducesTickets for RotatingTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        // A ticket issued just before a rotation is only accepted until the
+        // next one
+        self.rotation.as_secs() as u32
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.keys().current.encrypt(plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        let keys = self.keys();
+        keys.current
+            .decrypt(cipher)
+            .or_else(|| keys.previous.as_ref()?.decrypt(cipher))
+    }
+}
+
+/// What an outbound session can be resumed for: resuming skips checking the
+/// server certificate, so sessions are only resumed with the same host,
+/// checked the same way and presented the same client certificate
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct SessionKey {
+    pub hostname: String,
+    /// How the server certificate was checked, eg. the pinned keys
+    pub verification: String,
+    pub client_cert: Option<PathBuf>,
+}
+
+/// Outbound sessions, by destination
+#[derive(Default)]
+pub struct ClientSessions(Mutex<HashMap<SessionKey, Arc<ClientSessionMemoryCache>>>);
+
+impl ClientSessions {
+    /// Returns the session cache for `key`
+    pub fn get(&self, key: SessionKey) -> Arc<ClientSessionMemoryCache> {
+        let mut caches = self.0.lock().unwrap();
+        if let Some(cache) = caches.get(&key) {
+            return cache.clone();
+        }
+        if caches.len() >= CLIENT_DESTINATIONS {
+            // Forgetting any destination is fine, it will just need a full
+            // handshake next time
+            if let Some(forgotten) = caches.keys().next().cloned() {
+                caches.remove(&forgotten);
+            }
+        }
+        caches
+            .entry(key)
+            .or_insert_with(|| ClientSessionMemoryCache::new(SESSIONS_PER_DESTINATION))
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use rustls::client::StoresClientSessions;
+
+    use super::*;
+
+    /// Ticketer rotating its keys every minute, along with the number of
+    /// seconds elapsed on its clock
+    fn new_ticketer() -> (Arc<RotatingTicketer>, Arc<AtomicU64>) {
+        let start = Instant::now();
+        let elapsed = Arc::new(AtomicU64::new(0));
+        let clock = {
+            let elapsed = elapsed.clone();
+            move || start + Duration::from_secs(elapsed.load(Ordering::SeqCst))
+        };
+        let ticketer =
+            RotatingTicketer::with_clock(Duration::from_secs(60), Box::new(clock)).unwrap();
+        (ticketer, elapsed)
+    }
+
+    #[test]
+    fn rotation() {
+        let (ticketer, elapsed) = new_ticketer();
+        assert_eq!(ticketer.lifetime(), 60);
+        let first = ticketer.encrypt(b"first").unwrap();
+        elapsed.store(59, Ordering::SeqCst);
+        assert_eq!(ticketer.decrypt(&first).as_deref(), Some(&b"first"[..]));
+
+        // The previous keys are still accepted for one more period
+        elapsed.store(60, Ordering::SeqCst);
+        let second = ticketer.encrypt(b"second").unwrap();
+        assert_eq!(ticketer.decrypt(&first).as_deref(), Some(&b"first"[..]));
+        assert_eq!(ticketer.decrypt(&second).as_deref(), Some(&b"second"[..]));
+
+        elapsed.store(120, Ordering::SeqCst);
+        assert_eq!(ticketer.decrypt(&first), None);
+        assert_eq!(ticketer.decrypt(&second).as_deref(), Some(&b"second"[..]));
+
+        elapsed.store(180, Ordering::SeqCst);
+        assert_eq!(ticketer.decrypt(&second), None);
+        assert_eq!(ticketer.decrypt(b"garbage"), None);
+    }
+
+    #[test]
+    fn rotation_window() {
+        // Rotating late still accepts the tickets of the previous keys...
+        let (ticketer, elapsed) = new_ticketer();
+        let ticket = ticketer.encrypt(b"ticket").unwrap();
+        elapsed.store(119, Ordering::SeqCst);
+        assert_eq!(ticketer.decrypt(&ticket).as_deref(), Some(&b"ticket"[..]));
+
+        // ... unless they are older than two periods
+        let (ticketer, elapsed) = new_ticketer();
+        let ticket = ticketer.encrypt(b"ticket").unwrap();
+        elapsed.store(120, Ordering::SeqCst);
+        assert_eq!(ticketer.decrypt(&ticket), None);
+    }
+
+    #[test]
+    fn client_sessions() {
+        let sessions = ClientSessions::default();
+        let key = SessionKey {
+            hostname: "mx.example.org".to_owned(),
+            verification: "SystemRoots".to_owned(),
+            client_cert: None,
+        };
+        sessions
+            .get(key.clone())
+            .put(b"session".to_vec(), b"data".to_vec());
+        assert_eq!(
+            sessions.get(key.clone()).get(b"session"),
+            Some(b"data".to_vec())
+        );
+
+        let others = [
+            SessionKey {
+                hostname: "mx.example.com".to_owned(),
+                ..key.clone()
+            },
+            SessionKey {
+                verification: "None".to_owned(),
+                ..key.clone()
+            },
+            SessionKey {
+                client_cert: Some("/etc/kannader/client.pem".into()),
+                ..key.clone()
+            },
+        ];
+        for other in others {
+            assert_eq!(
+                sessions.get(other.clone()).get(b"session"),
+                None,
+                "{:?}",
+                other
+            );
+        }
+
+        for i in 0..CLIENT_DESTINATIONS {
+            sessions.get(SessionKey {
+                hostname: format!("mx{}.example.org", i),
+                ..key.clone()
+            });
+        }
+        assert_eq!(sessions.0.lock().unwrap().len(), CLIENT_DESTINATIONS);
+    }
+}
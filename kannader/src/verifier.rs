@@ -0,0 +1,241 @@
+use std::{
+    convert::TryFrom,
+    io,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+pub static SIGNATURE_ALGORITHMS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+/// The webpki error of a failed certificate verification
+///
+/// rustls only reports these errors as text, so the verifiers keep them here
+/// for the connection to report them with their type.
+#[derive(Clone, Default)]
+pub struct VerifyError(Arc<Mutex<Option<webpki::Error>>>);
+
+impl VerifyError {
+    /// Records `e`, returning the error for rustls
+    pub fn record(&self, e: webpki::Error) -> rustls::Error {
+        *self.0.lock().unwrap() = Some(e);
+        rustls::Error::InvalidCertificateData(format!("invalid peer certificate: {}", e))
+    }
+
+    /// Replaces `e`, returned by the connection, with the recorded webpki
+    /// error if there is one
+    pub fn replace(&self, e: io::Error) -> io::Error {
+        match self.0.lock().unwrap().take() {
+            Some(webpki) => io::Error::new(io::ErrorKind::InvalidData, webpki),
+            None => e,
+        }
+    }
+}
+
+struct Anchor {
+    subject: Vec<u8>,
+    spki: Vec<u8>,
+    name_constraints: Option<Vec<u8>>,
+}
+
+/// Trusted root certificates
+pub struct Roots(Vec<Anchor>);
+
+impl Roots {
+    /// Parses the DER-encoded `certs`, returning the number of invalid ones
+    /// alongside
+    pub fn parse<'a, I>(certs: I) -> (Roots, usize)
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let mut anchors = Vec::new();
+        let mut ignored = 0;
+        for cert in certs {
+            match webpki::TrustAnchor::try_from_cert_der(cert) {
+                Ok(a) => anchors.push(Anchor {
+                    subject: a.subject.to_vec(),
+                    spki: a.spki.to_vec(),
+                    name_constraints: a.name_constraints.map(|n| n.to_vec()),
+                }),
+                Err(_) => ignored += 1,
+            }
+        }
+        (Roots(anchors), ignored)
+    }
+}
+
+/// Checks that the certificate chains to `roots` and is valid for the server
+/// name
+pub struct RootsVerifier {
+    roots: Arc<Roots>,
+    error: VerifyError,
+}
+
+impl RootsVerifier {
+    pub fn new(roots: Arc<Roots>, error: VerifyError) -> RootsVerifier {
+        RootsVerifier { roots, error }
+    }
+}
+
+impl rustls::client::ServerCertVerifier for RootsVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        server_name: &rustls::client::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        let name = match server_name {
+            rustls::client::ServerName::DnsName(name) => name.as_ref(),
+            _ => return Err(rustls::Error::UnsupportedNameType),
+        };
+        let name = webpki::DnsNameRef::try_from_ascii_str(name)
+            .map_err(|_| rustls::Error::UnsupportedNameType)?;
+        let anchors = self
+            .roots
+            .0
+            .iter()
+            .map(|a| webpki::TrustAnchor {
+                subject: &a.subject,
+                spki: &a.spki,
+                name_constraints: a.name_constraints.as_deref(),
+            })
+            .collect::<Vec<_>>();
+        let chain = intermediates
+            .iter()
+            .map(|c| c.0.as_slice())
+            .collect::<Vec<_>>();
+        let cert = webpki::EndEntityCert::try_from(end_entity.0.as_slice())
+            .map_err(|e| self.error.record(e))?;
+        let time =
+            webpki::Time::try_from(now).map_err(|_| rustls::Error::FailedToGetCurrentTime)?;
+        cert.verify_is_valid_tls_server_cert(
+            SIGNATURE_ALGORITHMS,
+            &webpki::TlsServerTrustAnchors(&anchors),
+            &chain,
+            time,
+        )
+        .map_err(|e| self.error.record(e))?;
+        cert.verify_is_valid_for_dns_name(name)
+            .map_err(|e| self.error.record(e))?;
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use rustls::client::ServerCertVerifier;
+
+    use super::*;
+
+    const CA: &str = "-----BEGIN CERTIFICATE-----
+MIIBizCCATGgAwIBAgIUf9HmfjYhI0yZLnLZNcFemLubsWMwCgYIKoZIzj0EAwIw
+EjEQMA4GA1UEAwwHVGVzdCBDQTAgFw0yNjEwMTgyMDQ4MzFaGA8yMTI2MDkyNDIw
+NDgzMVowEjEQMA4GA1UEAwwHVGVzdCBDQTBZMBMGByqGSM49AgEGCCqGSM49AwEH
+A0IABOMT9j+fUKDSC6IJv5+85VAglPmWQEG12Jj0szjrHBsceC/7stFSUCtOi3sj
+IzbWAzLmwbZOWr/Mr8gxS24Jg1CjYzBhMB0GA1UdDgQWBBSzI/6YmPYMHeoa4wMn
+c90dsJ62GTAfBgNVHSMEGDAWgBSzI/6YmPYMHeoa4wMnc90dsJ62GTAPBgNVHRMB
+Af8EBTADAQH/MA4GA1UdDwEB/wQEAwICBDAKBggqhkjOPQQDAgNIADBFAiEArevN
+D7IUacwTlStaAC/+Rzqpr4VKYqN4mgorbvdbCS4CIDWH17PBlLW+U+kYkvR0uUyb
+4EwYxhvT4F5uoStwBO6M
+-----END CERTIFICATE-----
+";
+
+    /// Certificate for `mx.example` issued by `CA`, valid from 2026-10-18 to
+    /// 2126-09-24
+    const MX: &str = "-----BEGIN CERTIFICATE-----
+MIIBtTCCAVygAwIBAgIUVMLz2dYGgwJS74QL0FaKJiMeonswCgYIKoZIzj0EAwIw
+EjEQMA4GA1UEAwwHVGVzdCBDQTAgFw0yNjEwMTgyMDQ4MzFaGA8yMTI2MDkyNDIw
+NDgzMVowFTETMBEGA1UEAwwKbXguZXhhbXBsZTBZMBMGByqGSM49AgEGCCqGSM49
+AwEHA0IABAXlA+B1G+FdNwcoz+T65zAV+6XhFOpJAVjUIJnXznYA921WT4D5Qcoe
+jFrJVZuaFO8hsX1NEYcm4xqgY1A9sdWjgYowgYcwCQYDVR0TBAIwADAOBgNVHQ8B
+Af8EBAMCB4AwEwYDVR0lBAwwCgYIKwYBBQUHAwEwFQYDVR0RBA4wDIIKbXguZXhh
+bXBsZTAdBgNVHQ4EFgQUfc6sqg/xzmQyW9XZR62yjhXc4QowHwYDVR0jBBgwFoAU
+syP+mJj2DB3qGuMDJ3PdHbCethkwCgYIKoZIzj0EAwIDRwAwRAIgMW+wHG6tME7E
+dmuY0kJzYFisZEt1gmLTo3LmkzNO/isCIHBxKtIhcKlETzvuKSuquKL2SHwOHnNW
+AN/X/DNBRpHQ
+-----END CERTIFICATE-----
+";
+
+    fn der(pem: &str) -> Vec<u8> {
+        rustls_pemfile::certs(&mut pem.as_bytes())
+            .unwrap()
+            .remove(0)
+    }
+
+    /// Verifies `MX` as `name` at `secs` after the epoch, returning the
+    /// recorded webpki error on failure
+    fn verify(roots: &[&str], name: &str, secs: u64) -> Result<(), Option<webpki::Error>> {
+        let roots = roots.iter().map(|r| der(r)).collect::<Vec<_>>();
+        let (roots, ignored) = Roots::parse(roots.iter().map(|r| r.as_slice()));
+        assert_eq!(ignored, 0);
+        let error = VerifyError::default();
+        RootsVerifier::new(Arc::new(roots), error.clone())
+            .verify_server_cert(
+                &rustls::Certificate(der(MX)),
+                &[],
+                &rustls::ServerName::try_from(name).unwrap(),
+                &mut std::iter::empty(),
+                &[],
+                UNIX_EPOCH + Duration::from_secs(secs),
+            )
+            .map(|_| ())
+            .map_err(|_| *error.0.lock().unwrap())
+    }
+
+    #[test]
+    fn roots() {
+        // 2030-01-01, 2020-01-01 and 2200-01-01
+        let (valid, before, after) = (1893456000, 1577836800, 7258118400);
+        assert_eq!(verify(&[CA], "mx.example", valid), Ok(()));
+        assert_eq!(verify(&[CA], "MX.example", valid), Ok(()));
+        assert_eq!(
+            verify(&[CA], "other.example", valid),
+            Err(Some(webpki::Error::CertNotValidForName))
+        );
+        assert_eq!(
+            verify(&[], "mx.example", valid),
+            Err(Some(webpki::Error::UnknownIssuer))
+        );
+        assert_eq!(
+            verify(&[MX], "mx.example", valid),
+            Err(Some(webpki::Error::UnknownIssuer))
+        );
+        assert_eq!(
+            verify(&[CA], "mx.example", before),
+            Err(Some(webpki::Error::CertNotValidYet))
+        );
+        assert_eq!(
+            verify(&[CA], "mx.example", after),
+            Err(Some(webpki::Error::CertExpired))
+        );
+    }
+
+    #[test]
+    fn replace() {
+        let error = VerifyError::default();
+        let other = || io::Error::new(io::ErrorKind::Other, "other");
+        assert_eq!(error.replace(other()).to_string(), "other");
+        let _ = error.record(webpki::Error::UnknownIssuer);
+        let replaced = error.replace(other());
+        assert_eq!(
+            replaced.get_ref().unwrap().downcast_ref::<webpki::Error>(),
+            Some(&webpki::Error::UnknownIssuer)
+        );
+        // The error is only reported once
+        assert_eq!(error.replace(other()).to_string(), "other");
+    }
+}
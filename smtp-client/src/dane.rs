@@ -0,0 +1,600 @@
+use async_trait::async_trait;
+use tracing::trace;
+use trust_dns_resolver::{
+    error::{ResolveError, ResolveErrorKind},
+    proto::{
+        rr::{
+            rdata::{tlsa, TLSA},
+            Name, RData, RecordType,
+        },
+        xfer::DnsRequestOptions,
+    },
+    AsyncResolver,
+};
+
+use crate::x509::spki;
+
+/// Certificate usage of a TLSA record, among the ones RFC 7672 allows for SMTP
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Usage {
+    /// DANE-TA(2): the record designates a trust anchor of the server's chain
+    TrustAnchor,
+    /// DANE-EE(3): the record designates the server's own certificate
+    EndEntity,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Selector {
+    /// The whole certificate
+    Full,
+    /// Its SubjectPublicKeyInfo
+    Spki,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Matching {
+    Exact,
+    Sha256,
+    Sha512,
+}
+
+/// A usable TLSA record
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Tlsa {
+    pub usage: Usage,
+    pub selector: Selector,
+    pub matching: Matching,
+    pub data: Vec<u8>,
+}
+
+impl Tlsa {
+    /// Converts a TLSA record, returning `None` if it is unusable for SMTP
+    ///
+    /// PKIX-TA(0) and PKIX-EE(1) are unusable as there is no agreed-upon set
+    /// of trusted CAs for SMTP.
+    pub fn from_record(record: &TLSA) -> Option<Tlsa> {
+        Some(Tlsa {
+            usage: match record.cert_usage() {
+                tlsa::CertUsage::TrustAnchor => Usage::TrustAnchor,
+                tlsa::CertUsage::DomainIssued => Usage::EndEntity,
+                _ => return None,
+            },
+            selector: match record.selector() {
+                tlsa::Selector::Full => Selector::Full,
+                tlsa::Selector::Spki => Selector::Spki,
+                _ => return None,
+            },
+            matching: match record.matching() {
+                tlsa::Matching::Raw => Matching::Exact,
+                tlsa::Matching::Sha256 => Matching::Sha256,
+                tlsa::Matching::Sha512 => Matching::Sha512,
+                _ => return None,
+            },
+            data: record.cert_data().to_vec(),
+        })
+    }
+
+    /// Returns whether the DER-encoded certificate `cert` matches this record
+    pub fn matches(&self, cert: &[u8]) -> bool {
+        let selected = match self.selector {
+            Selector::Full => cert,
+            Selector::Spki => match spki(cert) {
+                Some(spki) => spki,
+                None => return false,
+            },
+        };
+        match self.matching {
+            Matching::Exact => selected == &self.data[..],
+            Matching::Sha256 => {
+                ring::digest::digest(&ring::digest::SHA256, selected).as_ref() == &self.data[..]
+            }
+            Matching::Sha512 => {
+                ring::digest::digest(&ring::digest::SHA512, selected).as_ref() == &self.data[..]
+            }
+        }
+    }
+}
+
+/// Returns whether a DANE-EE record matches the server's certificate `cert`
+///
+/// Per RFC 7672, neither the name nor the validity period of the certificate
+/// are checked in this case.
+pub fn matches_end_entity(records: &[Tlsa], cert: &[u8]) -> bool {
+    records
+        .iter()
+        .any(|r| r.usage == Usage::EndEntity && r.matches(cert))
+}
+
+/// Returns the certificate of `chain` that a DANE-TA record designates as the
+/// trust anchor, if any
+///
+/// The caller still has to validate the chain up to this trust anchor, and
+/// check the name of the server.
+pub fn trust_anchor<'a>(records: &[Tlsa], chain: &[&'a [u8]]) -> Option<&'a [u8]> {
+    chain.iter().copied().find(|cert| {
+        records
+            .iter()
+            .any(|r| r.usage == Usage::TrustAnchor && r.matches(cert))
+    })
+}
+
+/// DNSSEC-validating DNS operations needed for DANE
+///
+/// Names that do not exist or have no records of the requested type must
+/// return an empty list if this is proven by DNSSEC, errors are reserved for
+/// failed lookups and for answers that could not be validated.
+#[async_trait]
+pub trait SecureLookup: Send + Sync {
+    async fn lookup(&self, name: Name, rtype: RecordType) -> Result<Vec<RData>, ResolveError>;
+}
+
+#[async_trait]
+impl<C, P> SecureLookup for AsyncResolver<C, P>
+where
+    C: trust_dns_resolver::proto::DnsHandle<Error = ResolveError>,
+    P: trust_dns_resolver::ConnectionProvider<Conn = C>,
+{
+    async fn lookup(&self, name: Name, rtype: RecordType) -> Result<Vec<RData>, ResolveError> {
+        match AsyncResolver::lookup(self, name, rtype, DnsRequestOptions::default()).await {
+            Ok(lookup) => Ok(lookup.iter().cloned().collect()),
+            Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Outcome of the lookup of the TLSA records of a server
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TlsaLookup {
+    /// The server has TLSA records, of which these are usable
+    ///
+    /// TLS is mandatory even if none is usable.
+    Secure(Vec<Tlsa>),
+    /// The server has no TLSA records, or its address is not secure
+    Absent,
+    /// The TLSA records of a server with a secure address could not be looked
+    /// up, mail must not be relayed to it for now
+    Failed(String),
+}
+
+/// Looks up the TLSA records of the SMTP server `hostname` listening on `port`
+///
+/// Following RFC 7672 §2.2, TLSA records are only looked up for servers whose
+/// address records are secure. Note: insecure zones cannot yet be told apart
+/// from bogus ones, so a server whose address records fail validation is
+/// handled as insecure.
+pub async fn lookup_tlsa<L>(lookup: &L, hostname: &str, port: u16) -> TlsaLookup
+where
+    L: ?Sized + SecureLookup,
+{
+    let name = match Name::from_ascii(format!("{}.", hostname)) {
+        Ok(name) => name,
+        Err(e) => return TlsaLookup::Failed(e.to_string()),
+    };
+    let mut secure = false;
+    for rtype in &[RecordType::A, RecordType::AAAA] {
+        match lookup.lookup(name.clone(), *rtype).await {
+            Ok(records) => secure |= records.iter().any(|r| r.to_record_type() == *rtype),
+            Err(e) => trace!(error = ?e, "The {} records of ‘{}’ are not secure", rtype, hostname),
+        }
+    }
+    if !secure {
+        return TlsaLookup::Absent;
+    }
+
+    let name = match Name::from_ascii(format!("_{}._tcp.{}.", port, hostname)) {
+        Ok(name) => name,
+        Err(e) => return TlsaLookup::Failed(e.to_string()),
+    };
+    match lookup.lookup(name, RecordType::TLSA).await {
+        Ok(records) => {
+            let records = records
+                .iter()
+                .filter_map(|r| match r {
+                    RData::TLSA(tlsa) => Some(tlsa),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            if records.is_empty() {
+                TlsaLookup::Absent
+            } else {
+                TlsaLookup::Secure(records.into_iter().filter_map(Tlsa::from_record).collect())
+            }
+        }
+        Err(e) => TlsaLookup::Failed(e.to_string()),
+    }
+}
+
+/// Looks up the MX records of `domain`, as preference and exchange pairs
+///
+/// This fails unless the answer, or the absence of MX records, is validated.
+pub async fn lookup_mx<L>(lookup: &L, domain: &str) -> Result<Vec<(u16, Name)>, String>
+where
+    L: ?Sized + SecureLookup,
+{
+    let name = Name::from_ascii(format!("{}.", domain)).map_err(|e| e.to_string())?;
+    let records = lookup
+        .lookup(name, RecordType::MX)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(records
+        .into_iter()
+        .filter_map(|r| match r {
+            RData::MX(mx) => Some((mx.preference(), mx.exchange().clone())),
+            _ => None,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CERT: &[u8] = include_bytes!("../res/ee.der");
+    const SPKI: &[u8] = include_bytes!("../res/ee-spki.der");
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn tlsa(usage: Usage, selector: Selector, matching: Matching, data: Vec<u8>) -> Tlsa {
+        Tlsa {
+            usage,
+            selector,
+            matching,
+            data,
+        }
+    }
+
+    #[test]
+    fn matching() {
+        use Matching::*;
+        use Selector::*;
+        use Usage::*;
+        let cert_sha256 = unhex("9aced2ab8f486eb3cc19302159444d77bbdfe2835c404efeb95d3cffda1fc328");
+        let spki_sha256 = unhex("9d9b729cbc3860a5165fe602845442fb93ccca6ec478ccba15e4fcbc4a162377");
+        let spki_sha512 = unhex(
+            "f2ee6ca1950e1dbab3096a317d54ab5a5c261f6bb4e6bd18badbcac75121c465b21fa22b7fe30b9d9e829473623d713f35958513eb87843fda3cf6f3719fc752",
+        );
+        let matching = [
+            tlsa(EndEntity, Full, Exact, CERT.to_vec()),
+            tlsa(EndEntity, Full, Sha256, cert_sha256.clone()),
+            tlsa(EndEntity, Spki, Exact, SPKI.to_vec()),
+            tlsa(EndEntity, Spki, Sha256, spki_sha256.clone()),
+            tlsa(EndEntity, Spki, Sha512, spki_sha512),
+        ];
+        for record in &matching {
+            assert!(record.matches(CERT), "{:?}", record);
+            assert!(
+                matches_end_entity(std::slice::from_ref(record), CERT),
+                "{:?}",
+                record
+            );
+        }
+        let not_matching = [
+            tlsa(EndEntity, Full, Sha256, spki_sha256.clone()),
+            tlsa(EndEntity, Spki, Sha256, cert_sha256.clone()),
+            tlsa(EndEntity, Spki, Sha512, spki_sha256.clone()),
+        ];
+        for record in &not_matching {
+            assert!(!record.matches(CERT), "{:?}", record);
+        }
+
+        // DANE-TA records are not for the server's own certificate
+        let ta = [tlsa(TrustAnchor, Spki, Sha256, spki_sha256)];
+        assert!(!matches_end_entity(&ta, CERT));
+        assert_eq!(trust_anchor(&ta, &[b"not a cert", CERT]), Some(CERT));
+        assert_eq!(trust_anchor(&ta, &[b"not a cert"]), None);
+        assert_eq!(trust_anchor(&matching, &[CERT]), None);
+    }
+
+    #[test]
+    fn unusable_records() {
+        let record = |usage, selector, matching| {
+            Tlsa::from_record(&TLSA::new(
+                tlsa::CertUsage::from(usage),
+                tlsa::Selector::from(selector),
+                tlsa::Matching::from(matching),
+                vec![1, 2, 3],
+            ))
+        };
+        assert_eq!(
+            record(3, 1, 1),
+            Some(tlsa(
+                Usage::EndEntity,
+                Selector::Spki,
+                Matching::Sha256,
+                vec![1, 2, 3]
+            ))
+        );
+        assert_eq!(
+            record(2, 0, 2).map(|r| (r.usage, r.selector, r.matching)),
+            Some((Usage::TrustAnchor, Selector::Full, Matching::Sha512))
+        );
+        assert_eq!(record(0, 1, 1), None);
+        assert_eq!(record(1, 1, 1), None);
+        assert_eq!(record(3, 2, 1), None);
+        assert_eq!(record(3, 1, 3), None);
+    }
+
+    mod signed_zone {
+        use std::{net::Ipv4Addr, sync::Arc};
+
+        use futures::{future, stream, TryStreamExt};
+        use ring::signature::{Ed25519KeyPair, KeyPair};
+        use trust_dns_resolver::proto::{
+            error::ProtoError,
+            op::{Message, MessageType, Query},
+            rr::{
+                dnssec::{
+                    rdata::{DNSSECRData, DNSKEY, NSEC, SIG},
+                    tbs, Algorithm, PublicKeyBuf, TrustAnchor,
+                },
+                rdata::{MX, SOA},
+                DNSClass, Record,
+            },
+            xfer::{DnsRequest, DnsResponse, DnssecDnsHandle},
+            DnsHandle,
+        };
+
+        use super::*;
+
+        /// The `example.` zone, signed with a trust-anchored key
+        #[derive(Clone)]
+        struct Zone(Arc<Vec<Record>>);
+
+        impl DnsHandle for Zone {
+            type Error = ProtoError;
+            type Response = stream::Once<future::Ready<Result<DnsResponse, ProtoError>>>;
+
+            fn send<R: Into<DnsRequest> + Unpin + Send + 'static>(
+                &mut self,
+                req: R,
+            ) -> Self::Response {
+                let req = req.into();
+                let query = req.queries()[0].clone();
+                if query.name().to_ascii() == "_25._tcp.servfail.example." {
+                    return stream::once(future::err(ProtoError::from("SERVFAIL")));
+                }
+                let covers = |r: &Record, rtype: RecordType| match r.data() {
+                    Some(RData::DNSSEC(DNSSECRData::SIG(sig))) => sig.type_covered() == rtype,
+                    _ => r.rr_type() == rtype,
+                };
+                let rrset = |name: &Name, rtype: RecordType| {
+                    self.0
+                        .iter()
+                        .filter(|r| r.name() == name && covers(r, rtype))
+                        .cloned()
+                        .collect::<Vec<_>>()
+                };
+                let mut res = Message::new();
+                res.set_id(req.id())
+                    .set_message_type(MessageType::Response)
+                    .add_query(query.clone());
+                let answers = rrset(query.name(), query.query_type());
+                if answers.is_empty() {
+                    res.insert_name_servers(
+                        rrset(&Name::from_ascii("example.").unwrap(), RecordType::SOA)
+                            .into_iter()
+                            .chain(rrset(query.name(), RecordType::NSEC))
+                            .collect(),
+                    );
+                } else {
+                    res.insert_answers(answers);
+                }
+                stream::once(future::ok(DnsResponse::from(res)))
+            }
+        }
+
+        struct Validating(DnssecDnsHandle<Zone>);
+
+        #[async_trait]
+        impl SecureLookup for Validating {
+            async fn lookup(
+                &self,
+                name: Name,
+                rtype: RecordType,
+            ) -> Result<Vec<RData>, ResolveError> {
+                let res = self
+                    .0
+                    .clone()
+                    .lookup(Query::query(name, rtype), DnsRequestOptions::default())
+                    .try_next()
+                    .await?
+                    .ok_or_else(|| ProtoError::from("no response"))?;
+                Ok(res
+                    .answers()
+                    .iter()
+                    .filter(|r| r.rr_type() == rtype)
+                    .filter_map(|r| r.data().cloned())
+                    .collect())
+            }
+        }
+
+        fn name(name: &str) -> Name {
+            Name::from_ascii(name).unwrap()
+        }
+
+        fn address(host: &str) -> Record {
+            Record::from_rdata(name(host), 3600, RData::A(Ipv4Addr::new(192, 0, 2, 1)))
+        }
+
+        fn tlsa_record(host: &str) -> Record {
+            let tlsa = TLSA::new(
+                tlsa::CertUsage::DomainIssued,
+                tlsa::Selector::Spki,
+                tlsa::Matching::Sha256,
+                vec![1, 2, 3],
+            );
+            Record::from_rdata(name(&format!("_25._tcp.{}", host)), 3600, RData::TLSA(tlsa))
+        }
+
+        fn mx_record(domain: &str) -> Record {
+            Record::from_rdata(
+                name(domain),
+                3600,
+                RData::MX(MX::new(10, name("mx.example."))),
+            )
+        }
+
+        fn sign(key: &Ed25519KeyPair, key_tag: u16, record: &Record) -> Record {
+            let sig = SIG::new(
+                record.rr_type(),
+                Algorithm::ED25519,
+                record.name().num_labels(),
+                3600,
+                u32::MAX,
+                0,
+                key_tag,
+                name("example."),
+                Vec::new(),
+            );
+            let tbs = tbs::rrset_tbs_with_sig(
+                record.name(),
+                DNSClass::IN,
+                &sig,
+                std::slice::from_ref(record),
+            )
+            .unwrap();
+            let sig = sig.set_sig(key.sign(tbs.as_ref()).as_ref().to_vec());
+            let mut rrsig = Record::from_rdata(
+                record.name().clone(),
+                3600,
+                RData::DNSSEC(DNSSECRData::SIG(sig)),
+            );
+            rrsig.set_rr_type(RecordType::RRSIG);
+            rrsig
+        }
+
+        fn validating_zone() -> Validating {
+            let key = Ed25519KeyPair::from_seed_unchecked(&[42; 32]).unwrap();
+            let public_key = key.public_key().as_ref().to_vec();
+            let dnskey = DNSKEY::new(true, true, false, Algorithm::ED25519, public_key.clone());
+            let key_tag = dnskey.calculate_key_tag().unwrap();
+            let soa = SOA::new(
+                name("ns.example."),
+                name("hostmaster.example."),
+                1,
+                3600,
+                600,
+                86400,
+                3600,
+            );
+            let nsec = NSEC::new(name("zz.example."), vec![
+                RecordType::NSEC,
+                RecordType::RRSIG,
+            ]);
+
+            let mut forged_tlsa = tlsa_record("forged.example.");
+            let forged_sig = sign(&key, key_tag, &forged_tlsa);
+            if let Some(RData::TLSA(tlsa)) = forged_tlsa.data() {
+                let mut data = tlsa.cert_data().to_vec();
+                data[0] ^= 1;
+                forged_tlsa.set_data(Some(RData::TLSA(TLSA::new(
+                    tlsa.cert_usage(),
+                    tlsa.selector(),
+                    tlsa.matching(),
+                    data,
+                ))));
+            }
+
+            let unsigned = vec![
+                Record::from_rdata(
+                    name("example."),
+                    3600,
+                    RData::DNSSEC(DNSSECRData::DNSKEY(dnskey)),
+                ),
+                address("insecure.example."),
+                tlsa_record("insecure.example."),
+                mx_record("unsigned.example."),
+                tlsa_record("stripped.example."),
+                forged_tlsa,
+                forged_sig,
+            ];
+            let signed = vec![
+                Record::from_rdata(name("example."), 3600, RData::SOA(soa)),
+                address("mx.example."),
+                tlsa_record("mx.example."),
+                address("nodane.example."),
+                Record::from_rdata(
+                    name("_25._tcp.nodane.example."),
+                    3600,
+                    RData::DNSSEC(DNSSECRData::NSEC(nsec)),
+                ),
+                address("stripped.example."),
+                address("forged.example."),
+                address("servfail.example."),
+                mx_record("mail.example."),
+                Record::from_rdata(
+                    name("nomx.example."),
+                    3600,
+                    RData::DNSSEC(DNSSECRData::NSEC(NSEC::new(name("zz.example."), vec![
+                        RecordType::NSEC,
+                        RecordType::RRSIG,
+                    ]))),
+                ),
+            ];
+            let mut records = unsigned;
+            for record in signed {
+                records.push(sign(&key, key_tag, &record));
+                records.push(record);
+            }
+
+            let mut trust_anchor = TrustAnchor::new();
+            trust_anchor.insert_trust_anchor(&PublicKeyBuf::new(public_key));
+            Validating(DnssecDnsHandle::with_trust_anchor(
+                Zone(Arc::new(records)),
+                trust_anchor,
+            ))
+        }
+
+        fn lookup(host: &str) -> TlsaLookup {
+            futures::executor::block_on(lookup_tlsa(&validating_zone(), host, 25))
+        }
+
+        #[test]
+        fn secure() {
+            assert_eq!(
+                lookup("mx.example"),
+                TlsaLookup::Secure(vec![tlsa(
+                    Usage::EndEntity,
+                    Selector::Spki,
+                    Matching::Sha256,
+                    vec![1, 2, 3]
+                )])
+            );
+            assert_eq!(lookup("nodane.example"), TlsaLookup::Absent);
+        }
+
+        #[test]
+        fn insecure() {
+            assert_eq!(lookup("insecure.example"), TlsaLookup::Absent);
+            assert_eq!(lookup("unknown.example"), TlsaLookup::Absent);
+        }
+
+        #[test]
+        fn fail_closed() {
+            for host in &["stripped.example", "forged.example", "servfail.example"] {
+                assert!(
+                    matches!(lookup(host), TlsaLookup::Failed(_)),
+                    "{}: {:?}",
+                    host,
+                    lookup(host)
+                );
+            }
+        }
+
+        #[test]
+        fn mx() {
+            let lookup =
+                |domain| futures::executor::block_on(lookup_mx(&validating_zone(), domain));
+            assert_eq!(lookup("mail.example"), Ok(vec![(10, name("mx.example."))]));
+            assert_eq!(lookup("nomx.example"), Ok(Vec::new()));
+            assert!(lookup("unsigned.example").is_err());
+            assert!(lookup("unknown.example").is_err());
+        }
+    }
+}
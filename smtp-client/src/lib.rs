@@ -1,6 +1,13 @@
 use std::{
-    cmp, collections::BTreeMap, fmt, future::Future, io, net::IpAddr, ops::Range, pin::Pin,
-    sync::Arc,
+    cmp,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    future::Future,
+    io,
+    net::IpAddr,
+    ops::Range,
+    pin::Pin,
+    sync::{Arc, Mutex},
 };
 
 use async_trait::async_trait;
@@ -9,7 +16,7 @@ use chrono::Utc;
 use futures::{pin_mut, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use rand::prelude::SliceRandom;
 use smol::net::TcpStream;
-use tracing::trace;
+use tracing::{trace, warn};
 use trust_dns_resolver::{
     error::{ResolveError, ResolveErrorKind},
     proto::error::ProtoError,
@@ -17,9 +24,15 @@ use trust_dns_resolver::{
 };
 
 use smtp_message::{
-    nom, Command, Email, EnhancedReplyCodeSubject, Hostname, Parameters, Reply, ReplyCodeKind,
+    nom, Command, Email, EnhancedReplyCodeSubject, Hostname, ParameterName, Parameters, Reply,
+    ReplyCodeKind,
 };
 
+pub mod dane;
+pub mod mta_sts;
+pub mod tlsrpt;
+pub mod x509;
+
 const SMTP_PORT: u16 = 25;
 
 const RDBUF_SIZE: usize = 16 * 1024;
@@ -31,9 +44,153 @@ const ZERO_DURATION: std::time::Duration = std::time::Duration::from_secs(0);
 pub type DynAsyncReadWrite =
     duplexify::Duplex<Pin<Box<dyn Send + AsyncRead>>, Pin<Box<dyn Send + AsyncWrite>>>;
 
+/// How the TLS protection of a mail's hops is decided (RFC 8689)
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum TlsRequirement {
+    /// Follow `can_do_tls` and `must_do_tls`
+    Default,
+    /// The mail had `REQUIRETLS`: only relay it over verified TLS, to a server
+    /// that supports `REQUIRETLS` too
+    ///
+    /// The MX records are checked against the MTA-STS policy of the domain,
+    /// even in testing mode, or else looked up with the DNSSEC-validating
+    /// resolver given to `Client::with_dane`. Mail is not relayed when neither
+    /// can vouch for them.
+    Required,
+    /// The mail had a `TLS-Required: No` header field: ignore `must_do_tls`,
+    /// the TLS policy of the destination, MTA-STS and DANE
+    Optional,
+}
+
+/// How TLS is used with the servers of a destination domain, as configured
+/// locally
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TlsPolicy {
+    /// Never negotiate TLS, unless REQUIRETLS, MTA-STS or DANE demand it
+    None,
+    /// Negotiate TLS when offered, without authenticating the server
+    Opportunistic,
+    /// Only relay over TLS, with a server that `Config::tls_connect`
+    /// authenticates
+    Verify,
+}
+
+/// How the server was authenticated, once connected
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TlsVerification {
+    /// TLS was not negotiated
+    Plaintext,
+    /// TLS was negotiated without authenticating the server
+    Unverified,
+    /// The server's certificate was verified by `Config::tls_connect`
+    Verified,
+    /// The server was authenticated with its DANE TLSA records
+    Dane,
+}
+
+impl fmt::Display for TlsVerification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TlsVerification::Plaintext => "plaintext",
+            TlsVerification::Unverified => "unverified",
+            TlsVerification::Verified => "verified",
+            TlsVerification::Dane => "dane",
+        })
+    }
+}
+
+/// The server with which `Config::tls_connect` negotiates TLS
+pub struct TlsTarget<'a> {
+    /// The domain the mail is relayed to, empty when unknown
+    pub domain: &'a str,
+    /// The name of the server, against which its certificate is verified
+    pub hostname: &'a str,
+    /// When not empty, the server must be authenticated with these DANE
+    /// records (RFC 7672) instead
+    pub tlsa: &'a [dane::Tlsa],
+    /// Whether the server must be authenticated, per the TLS policy of
+    /// `domain` or else against the system's trusted roots
+    pub verify: bool,
+}
+
+/// What a connection needs from TLS, once the policies of the mail and of its
+/// destination are known
+#[derive(Clone)]
+struct TlsMode {
+    requirement: TlsRequirement,
+    /// The domain the mail is relayed to, empty when unknown
+    domain: String,
+    policy: TlsPolicy,
+    /// Whether the destination has an MTA-STS policy to enforce
+    mta_sts: bool,
+    /// The MTA-STS policy of the destination, enforced or not
+    sts_policy: Option<mta_sts::Policy>,
+    /// Whether the host has DNSSEC-validated TLSA records, usable or not,
+    /// which makes TLS mandatory
+    dane: bool,
+    /// The usable TLSA records of the host, with which it must authenticate
+    tlsa: Vec<dane::Tlsa>,
+}
+
+impl TlsMode {
+    fn new(requirement: TlsRequirement) -> TlsMode {
+        TlsMode {
+            requirement,
+            domain: String::new(),
+            policy: TlsPolicy::Opportunistic,
+            mta_sts: false,
+            sts_policy: None,
+            dane: false,
+            tlsa: Vec::new(),
+        }
+    }
+
+    /// Whether the connection must use TLS with a certificate valid for the
+    /// host name
+    fn verified(&self) -> bool {
+        match self.requirement {
+            TlsRequirement::Required => true,
+            TlsRequirement::Default => self.mta_sts || self.policy == TlsPolicy::Verify,
+            TlsRequirement::Optional => false,
+        }
+    }
+
+    /// The policy the connection is reported under, for TLS reporting
+    fn reported_policy(&self) -> tlsrpt::AppliedPolicy {
+        if self.dane {
+            tlsrpt::AppliedPolicy::tlsa(&self.tlsa)
+        } else if let Some(policy) = &self.sts_policy {
+            tlsrpt::AppliedPolicy::sts(policy)
+        } else {
+            tlsrpt::AppliedPolicy::none()
+        }
+    }
+
+    fn error(&self, msg: String) -> TransportError {
+        if self.requirement == TlsRequirement::Required {
+            TransportError::RequireTls(msg)
+        } else if self.dane {
+            TransportError::Dane(msg)
+        } else if self.mta_sts {
+            TransportError::MtaSts(msg)
+        } else {
+            TransportError::TlsPolicy(msg)
+        }
+    }
+}
+
+/// MTA-STS policy cached for a domain
+#[derive(Clone)]
+struct CachedPolicy {
+    id: String,
+    policy: mta_sts::Policy,
+    expires: chrono::DateTime<Utc>,
+}
+
 #[derive(Eq, Hash, PartialEq)]
 pub struct Destination {
     host: Hostname,
+    tls: TlsRequirement,
 }
 
 impl fmt::Display for Destination {
@@ -50,16 +207,50 @@ pub trait Config {
         true
     }
 
-    // TODO: make this parameterized on the destination
     fn must_do_tls(&self) -> bool {
         false
     }
 
+    /// Returns the TLS policy for the servers of `domain`
+    fn tls_policy(&self, _domain: &str) -> TlsPolicy {
+        TlsPolicy::Opportunistic
+    }
+
     /// Note: If this function can only fail, make can_do_tls return false
-    async fn tls_connect<IO>(&self, io: IO) -> io::Result<DynAsyncReadWrite>
+    async fn tls_connect<IO>(
+        &self,
+        io: IO,
+        target: &TlsTarget<'_>,
+    ) -> io::Result<DynAsyncReadWrite>
     where
         IO: 'static + Unpin + Send + AsyncRead + AsyncWrite;
 
+    fn can_do_mta_sts(&self) -> bool {
+        true
+    }
+
+    /// Fetches the MTA-STS policy of `domain`, usually by validating TLS with
+    /// `mta-sts.<domain>` on port 443 and then calling
+    /// [`mta_sts::fetch_policy`]
+    ///
+    /// Note: If this function can only fail, make can_do_mta_sts return false
+    async fn fetch_mta_sts_policy(
+        &self,
+        domain: &str,
+    ) -> Result<mta_sts::Policy, mta_sts::PolicyError>;
+
+    fn mta_sts_fetch_timeout(&self) -> chrono::Duration {
+        chrono::Duration::minutes(1)
+    }
+
+    /// Records the outcome of a TLS session, for TLS reporting (RFC 8460)
+    fn record_tls_result(&self, _observation: tlsrpt::Observation) {}
+
+    /// Classifies an error returned by `tls_connect`, for TLS reporting
+    fn tls_failure_type(&self, _error: &io::Error) -> tlsrpt::ResultType {
+        tlsrpt::ResultType::ValidationFailure
+    }
+
     fn banner_read_timeout(&self) -> chrono::Duration {
         chrono::Duration::minutes(5)
     }
@@ -138,6 +329,18 @@ pub enum TransportError {
     #[error("Cannot do TLS with remote server")]
     CannotDoTls,
 
+    #[error("Cannot relay mail requiring TLS: {0}")]
+    RequireTls(String),
+
+    #[error("MTA-STS policy not satisfied: {0}")]
+    MtaSts(String),
+
+    #[error("DANE authentication failed: {0}")]
+    Dane(String),
+
+    #[error("TLS policy not satisfied: {0}")]
+    TlsPolicy(String),
+
     // TODO: add the command as error context
     #[error("Mail-level transient issue: {0}")]
     TransientMail(Reply),
@@ -200,6 +403,10 @@ impl TransportError {
             TransportError::SendingCommand(_) => TransportErrorSeverity::NetworkTransient,
             TransportError::NegotiatingTls(_) => TransportErrorSeverity::NetworkTransient, /* TODO: MailSystemPermanent? */
             TransportError::CannotDoTls => TransportErrorSeverity::NetworkTransient, /* TODO: MailSystemPermanent? */
+            TransportError::RequireTls(_) => TransportErrorSeverity::MailPermanent,
+            TransportError::MtaSts(_) => TransportErrorSeverity::MailSystemTransient,
+            TransportError::Dane(_) => TransportErrorSeverity::MailSystemTransient,
+            TransportError::TlsPolicy(_) => TransportErrorSeverity::MailSystemTransient,
             TransportError::TransientMail(_) => TransportErrorSeverity::MailTransient,
             TransportError::TransientMailbox(_) => TransportErrorSeverity::MailboxTransient,
             TransportError::TransientMailSystem(_) => TransportErrorSeverity::MailSystemTransient,
@@ -366,7 +573,10 @@ where
     Cfg: Config,
 {
     resolver: AsyncResolver<C, P>,
+    /// DNSSEC-validating lookups, used for the TLSA records
+    dane_lookup: Option<Box<dyn dane::SecureLookup>>,
     cfg: Arc<Cfg>,
+    mta_sts_cache: Mutex<HashMap<String, CachedPolicy>>,
 }
 
 impl<C, P, Cfg> Client<C, P, Cfg>
@@ -381,65 +591,255 @@ where
     /// comes first doesn't successfully connect. In particular, it means that
     /// performance could be degraded.
     pub fn new(resolver: AsyncResolver<C, P>, cfg: Arc<Cfg>) -> Client<C, P, Cfg> {
-        Client { resolver, cfg }
+        Client {
+            resolver,
+            dane_lookup: None,
+            cfg,
+            mta_sts_cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Enables DANE (RFC 7672), looking up TLSA records with `lookup`
+    ///
+    /// `lookup` must validate DNSSEC, as the TLSA records would otherwise be
+    /// worthless. If the TLSA records of a server with secure address records
+    /// cannot be looked up or validated, mail is not relayed to it, with a
+    /// temporary error. Note: servers whose address records fail validation
+    /// are handled as if they had no TLSA records, as insecure zones cannot
+    /// yet be told apart from bogus ones.
+    pub fn with_dane<L>(mut self, lookup: L) -> Client<C, P, Cfg>
+    where
+        L: 'static + dane::SecureLookup,
+    {
+        self.dane_lookup = Some(Box::new(lookup));
+        self
+    }
+
+    /// Records the outcome of a TLS session with a server of `domain`
+    fn record_tls(
+        &self,
+        domain: &str,
+        policy: tlsrpt::AppliedPolicy,
+        failure: Option<tlsrpt::Failure>,
+    ) {
+        if domain.is_empty() {
+            return;
+        }
+        self.cfg.record_tls_result(tlsrpt::Observation {
+            time: Utc::now().timestamp() as u64,
+            policy_domain: domain.to_owned(),
+            policy,
+            failure,
+        });
     }
 
-    pub async fn get_destination(&self, host: &Hostname) -> Result<Destination, TransportError> {
+    fn record_tls_failure(
+        &self,
+        tls: &TlsMode,
+        result_type: tlsrpt::ResultType,
+        hostname: &str,
+        info: String,
+    ) {
+        let failure = tlsrpt::Failure {
+            result_type,
+            receiving_mx_hostname: Some(hostname.to_owned()).filter(|h| !h.is_empty()),
+            additional_information: Some(info),
+        };
+        self.record_tls(&tls.domain, tls.reported_policy(), Some(failure));
+    }
+
+    pub async fn get_destination(
+        &self,
+        host: &Hostname,
+        tls: TlsRequirement,
+    ) -> Result<Destination, TransportError> {
         // TODO: already resolve here, but that means having to handle DNS expiration
         // down the road
-        Ok(Destination { host: host.clone() })
+        Ok(Destination {
+            host: host.clone(),
+            tls,
+        })
     }
 
     pub async fn connect(&self, dest: &Destination) -> Result<Sender<Cfg>, TransportError> {
-        match dest.host {
-            Hostname::Ipv4 { ip, .. } => self.connect_to_ip(IpAddr::V4(ip), SMTP_PORT).await,
-            Hostname::Ipv6 { ip, .. } => self.connect_to_ip(IpAddr::V6(ip), SMTP_PORT).await,
-            Hostname::AsciiDomain { ref raw } => self.connect_to_mx(raw).await,
-            Hostname::Utf8Domain { ref punycode, .. } => self.connect_to_mx(punycode).await,
+        let ip = match dest.host {
+            Hostname::Ipv4 { ip, .. } => IpAddr::V4(ip),
+            Hostname::Ipv6 { ip, .. } => IpAddr::V6(ip),
+            Hostname::AsciiDomain { ref raw } => {
+                return self
+                    .connect_to_mx_impl(raw, self.tls_mode(raw, dest.tls))
+                    .await
+            }
+            Hostname::Utf8Domain { ref punycode, .. } => {
+                return self
+                    .connect_to_mx_impl(punycode, self.tls_mode(punycode, dest.tls))
+                    .await
+            }
+        };
+        let ip_str = ip.to_string();
+        let mode = self.tls_mode(&ip_str, dest.tls);
+        if mode.verified() {
+            // There is no name to verify the certificate against
+            return Err(mode.error(format!(
+                "cannot verify the TLS certificate of address literal ‘{}’",
+                ip
+            )));
+        }
+        self.connect_to_ip_impl(ip, SMTP_PORT, &mode, &ip_str).await
+    }
+
+    fn tls_mode(&self, domain: &str, requirement: TlsRequirement) -> TlsMode {
+        TlsMode {
+            domain: domain.to_owned(),
+            policy: self.cfg.tls_policy(domain),
+            ..TlsMode::new(requirement)
+        }
+    }
+
+    /// Returns the MTA-STS policy of `domain`, refreshing the cached one when
+    /// its TXT record announces a new one
+    async fn mta_sts_policy(&self, domain: &str) -> Option<mta_sts::Policy> {
+        if !self.cfg.can_do_mta_sts() {
+            return None;
+        }
+        let id = match self
+            .resolver
+            .txt_lookup(format!("_mta-sts.{}.", domain))
+            .await
+        {
+            Ok(lookup) => {
+                let records = lookup
+                    .iter()
+                    .map(|txt| {
+                        txt.txt_data()
+                            .iter()
+                            .map(|d| String::from_utf8_lossy(d))
+                            .collect::<String>()
+                    })
+                    .filter(|r| r.starts_with("v=STSv1"))
+                    .collect::<Vec<_>>();
+                // Multiple records mean there is no valid one
+                match &records[..] {
+                    [record] => mta_sts::parse_txt_record(record),
+                    _ => None,
+                }
+            }
+            Err(_) => None,
+        };
+        let now = Utc::now();
+        let cached = self
+            .mta_sts_cache
+            .lock()
+            .unwrap()
+            .get(domain)
+            .filter(|c| c.expires > now)
+            .cloned();
+        let id = match (id, cached) {
+            // Without a valid TXT record, the cached policy keeps applying
+            (None, cached) => return cached.map(|c| c.policy),
+            (Some(id), Some(cached)) if cached.id == id => return Some(cached.policy),
+            (Some(id), _) => id,
+        };
+        match self.cfg.fetch_mta_sts_policy(domain).await {
+            Ok(policy) => {
+                let expires = now + chrono::Duration::seconds(policy.max_age as i64);
+                self.mta_sts_cache
+                    .lock()
+                    .unwrap()
+                    .insert(domain.to_owned(), CachedPolicy {
+                        id,
+                        policy: policy.clone(),
+                        expires,
+                    });
+                Some(policy)
+            }
+            Err(e) => {
+                warn!(domain, error = ?e, "Failed fetching the MTA-STS policy");
+                let result_type = match e {
+                    mta_sts::PolicyError::Io(_)
+                    | mta_sts::PolicyError::TimedOut
+                    | mta_sts::PolicyError::Status(_) => tlsrpt::ResultType::StsPolicyFetchError,
+                    _ => tlsrpt::ResultType::StsPolicyInvalid,
+                };
+                let policy = tlsrpt::AppliedPolicy {
+                    policy_type: tlsrpt::PolicyType::Sts,
+                    policy_string: Vec::new(),
+                    mx_host: Vec::new(),
+                };
+                self.record_tls(
+                    domain,
+                    policy,
+                    Some(tlsrpt::Failure {
+                        result_type,
+                        receiving_mx_hostname: None,
+                        additional_information: Some(e.to_string()),
+                    }),
+                );
+                // The previous policy, if any, is still better than none
+                let cached = self.mta_sts_cache.lock().unwrap().get(domain).cloned();
+                cached.filter(|c| c.expires > now).map(|c| c.policy)
+            }
         }
     }
 
     pub async fn connect_to_mx(&self, host: &str) -> Result<Sender<Cfg>, TransportError> {
+        self.connect_to_mx_impl(host, self.tls_mode(host, TlsRequirement::Default))
+            .await
+    }
+
+    async fn connect_to_mx_impl(
+        &self,
+        host: &str,
+        mut mode: TlsMode,
+    ) -> Result<Sender<Cfg>, TransportError> {
+        // `TLS-Required: No` asks to ignore the MTA-STS policy
+        let policy = match mode.requirement {
+            TlsRequirement::Optional => None,
+            _ => self.mta_sts_policy(host).await,
+        };
+        // REQUIRETLS needs validated MX records, even in testing mode
+        mode.mta_sts = match policy.as_ref().map(|p| p.mode) {
+            Some(mta_sts::Mode::Enforce) => true,
+            Some(mta_sts::Mode::Testing) => mode.requirement == TlsRequirement::Required,
+            _ => false,
+        };
+        mode.sts_policy = policy.clone().filter(|p| p.mode != mta_sts::Mode::None);
+
         // TODO: consider adding a `.` at the end of `host`... but is it
         // actually allowed?
-        // Run MX lookup
-        let lookup = self.resolver.mx_lookup(host).await;
-        let lookup = match lookup {
-            Ok(l) => l,
-            Err(e) => {
-                if let ResolveErrorKind::NoRecordsFound { .. } = e.kind() {
-                    // If there are no MX records, try A/AAAA records
-                    return self
-                        .connect_to_host(
-                            host.into_name()
-                                .map_err(|e| TransportError::HostToTrustDns(host.to_owned(), e))?,
-                            SMTP_PORT,
-                        )
-                        .await;
-                } else {
-                    return Err(TransportError::DnsMx(host.to_owned(), e));
-                }
+        // Run MX lookup, REQUIRETLS needing DNSSEC to vouch for the MX records
+        // when MTA-STS does not
+        let records = if mode.requirement == TlsRequirement::Required && !mode.mta_sts {
+            self.secure_mx_lookup(host).await?
+        } else {
+            match self.resolver.mx_lookup(host).await {
+                Ok(lookup) => lookup
+                    .iter()
+                    .map(|r| (r.preference(), r.exchange().clone()))
+                    .collect(),
+                Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => Vec::new(),
+                Err(e) => return Err(TransportError::DnsMx(host.to_owned(), e)),
             }
         };
 
         // Retrieve the actual records
         let mut mx_records = BTreeMap::new();
-        for record in lookup.iter() {
+        for (preference, exchange) in records {
             mx_records
-                .entry(record.preference())
+                .entry(preference)
                 .or_insert_with(|| Vec::with_capacity(1))
-                .push(record.exchange());
+                .push(exchange);
         }
 
         // If there are no MX records, try A/AAAA records
         if mx_records.is_empty() {
-            // TODO: is this actually required? trust_dns_resolver should return
-            // NoRecordsFound anyway
+            self.check_mx(policy.as_ref(), &mode, host)?;
             return self
                 .connect_to_host(
                     host.into_name()
                         .map_err(|e| TransportError::HostToTrustDns(host.to_owned(), e))?,
                     SMTP_PORT,
+                    &mode,
                 )
                 .await;
         }
@@ -458,7 +858,11 @@ where
             // in the answer to the MX request, in which case we could directly
             // connect_to_ip
             for mx in mxes {
-                match self.connect_to_host(mx.clone(), SMTP_PORT).await {
+                let res = match self.check_mx(policy.as_ref(), &mode, &mx.to_ascii()) {
+                    Ok(()) => self.connect_to_host(mx, SMTP_PORT, &mode).await,
+                    Err(e) => Err(e),
+                };
+                match res {
                     Ok(sender) => return Ok(sender),
                     Err(e) => first_error = first_error.or(Some(e)),
                 }
@@ -476,11 +880,61 @@ where
         Err(first_error.unwrap())
     }
 
+    /// Looks up the MX records of `host` with the DNSSEC-validating resolver,
+    /// failing if their answer is not authenticated
+    async fn secure_mx_lookup(
+        &self,
+        host: &str,
+    ) -> Result<Vec<(u16, trust_dns_resolver::Name)>, TransportError> {
+        let lookup = self.dane_lookup.as_ref().ok_or_else(|| {
+            TransportError::RequireTls(format!(
+                "‘{}’ has no MTA-STS policy and DNSSEC validation is not enabled, so its MX \
+                 records cannot be trusted",
+                host
+            ))
+        })?;
+        dane::lookup_mx(&**lookup, host).await.map_err(|e| {
+            TransportError::RequireTls(format!(
+                "the MX records of ‘{}’ are not authenticated by DNSSEC: {}",
+                host, e
+            ))
+        })
+    }
+
     async fn connect_to_host(
         &self,
         name: trust_dns_resolver::Name,
         port: u16,
+        tls: &TlsMode,
     ) -> Result<Sender<Cfg>, TransportError> {
+        // The certificate must be valid for the name we looked up
+        let hostname = name.to_ascii();
+        let hostname = hostname.trim_end_matches('.');
+
+        // `TLS-Required: No` asks to ignore the TLSA records too
+        let mut tls = tls.clone();
+        let dane_lookup = self.dane_lookup.as_ref();
+        if let Some(lookup) = dane_lookup.filter(|_| tls.requirement != TlsRequirement::Optional) {
+            match dane::lookup_tlsa(&**lookup, hostname, SMTP_PORT).await {
+                dane::TlsaLookup::Secure(tlsa) => {
+                    tls.dane = true;
+                    tls.tlsa = tlsa;
+                }
+                dane::TlsaLookup::Absent => (),
+                dane::TlsaLookup::Failed(e) => {
+                    tls.dane = true;
+                    let msg = format!("Looking up the TLSA records of ‘{}’: {}", hostname, e);
+                    self.record_tls_failure(
+                        &tls,
+                        tlsrpt::ResultType::DnssecInvalid,
+                        hostname,
+                        msg.clone(),
+                    );
+                    return Err(TransportError::Dane(msg));
+                }
+            }
+        }
+
         // Lookup the IP addresses associated with this name
         let lookup = self
             .resolver
@@ -493,7 +947,7 @@ where
         // error
         let mut first_error = None;
         for ip in lookup.iter() {
-            match self.connect_to_ip(ip, port).await {
+            match self.connect_to_ip_impl(ip, port, &tls, hostname).await {
                 Ok(sender) => return Ok(sender),
                 Err(e) => first_error = first_error.or(Some(e)),
             }
@@ -507,6 +961,17 @@ where
         &self,
         ip: IpAddr,
         port: u16,
+    ) -> Result<Sender<Cfg>, TransportError> {
+        self.connect_to_ip_impl(ip, port, &TlsMode::new(TlsRequirement::Default), "")
+            .await
+    }
+
+    async fn connect_to_ip_impl(
+        &self,
+        ip: IpAddr,
+        port: u16,
+        tls: &TlsMode,
+        hostname: &str,
     ) -> Result<Sender<Cfg>, TransportError> {
         // TODO: introduce a connection uuid to associate log messages together
         trace!("Connecting to ip {}:{}", ip, port);
@@ -516,7 +981,22 @@ where
             .await
             .map_err(|e| TransportError::Connecting(ip, port, e))?;
         let (reader, writer) = io.split();
-        self.connect_to_stream(duplexify::Duplex::new(Box::pin(reader), Box::pin(writer)))
+        let io = duplexify::Duplex::new(Box::pin(reader) as _, Box::pin(writer) as _);
+        self.connect_to_stream_impl(io, false, tls, hostname).await
+    }
+
+    /// Connects to the LMTP server listening on `ip` and `port`
+    pub async fn connect_lmtp_to_ip(
+        &self,
+        ip: IpAddr,
+        port: u16,
+    ) -> Result<Sender<Cfg>, TransportError> {
+        trace!("Connecting to LMTP ip {}:{}", ip, port);
+        let io = TcpStream::connect((ip, port))
+            .await
+            .map_err(|e| TransportError::Connecting(ip, port, e))?;
+        let (reader, writer) = io.split();
+        self.connect_lmtp_to_stream(duplexify::Duplex::new(Box::pin(reader), Box::pin(writer)))
             .await
     }
 
@@ -526,11 +1006,42 @@ where
         &self,
         io: DynAsyncReadWrite,
     ) -> Result<Sender<Cfg>, TransportError> {
+        self.connect_to_stream_impl(io, false, &TlsMode::new(TlsRequirement::Default), "")
+            .await
+    }
+
+    /// Note: as a single recipient is given to each
+    /// [`Sender::send`](Sender::send) call, LMTP then behaves just like SMTP
+    pub async fn connect_lmtp_to_stream(
+        &self,
+        io: DynAsyncReadWrite,
+    ) -> Result<Sender<Cfg>, TransportError> {
+        self.connect_to_stream_impl(io, true, &TlsMode::new(TlsRequirement::Default), "")
+            .await
+    }
+
+    /// `hostname` is the name against which the certificate is verified, if
+    /// `tls` asks for it
+    async fn connect_to_stream_impl(
+        &self,
+        io: DynAsyncReadWrite,
+        lmtp: bool,
+        tls: &TlsMode,
+        hostname: &str,
+    ) -> Result<Sender<Cfg>, TransportError> {
+        let require_tls = tls.requirement == TlsRequirement::Required;
+        // DANE authentication satisfies the other policies too
+        let dane_auth = !tls.tlsa.is_empty();
+        let verified = tls.verified() || dane_auth;
+        let mandatory = verified || tls.dane;
+        let can_do_tls = tls.policy != TlsPolicy::None && self.cfg.can_do_tls();
         let mut sender = Sender {
             io,
             rdbuf: [0; RDBUF_SIZE],
             unhandled: 0..0,
             extensions: Extensions::empty(),
+            require_tls,
+            tls: TlsVerification::Plaintext,
             cfg: self.cfg.clone(),
         };
         // TODO: Are there interesting things to do with replies apart from checking
@@ -549,11 +1060,23 @@ where
         // Send EHLO
         // TODO: fallback to HELO if EHLO fails (also record somewhere that this
         // destination doesn't support HELO)
-        self.send_ehlo(&mut sender).await?;
+        self.send_ehlo(&mut sender, lmtp).await?;
 
         // Send STARTTLS if possible
         let mut did_tls = false;
-        if sender.extensions.contains(Extensions::STARTTLS) && self.cfg.can_do_tls() {
+        if (can_do_tls || mandatory) && !sender.extensions.contains(Extensions::STARTTLS) {
+            let msg = format!("‘{}’ does not support STARTTLS", hostname);
+            self.record_tls_failure(
+                tls,
+                tlsrpt::ResultType::StarttlsNotSupported,
+                hostname,
+                msg.clone(),
+            );
+            if mandatory {
+                return Err(tls.error(msg));
+            }
+        }
+        if sender.extensions.contains(Extensions::STARTTLS) && (can_do_tls || mandatory) {
             // Send STARTTLS and check the reply
             send_command(
                 &mut sender.io,
@@ -568,14 +1091,45 @@ where
                 self.cfg.starttls_reply_timeout(),
             )
             .await?;
+            let reply_text = reply.to_string();
             if let Ok(()) = verify_reply(reply, ReplyCodeKind::PositiveCompletion) {
                 // TODO: pipelining is forbidden across starttls, check unhandled.empty()
                 // Negotiate STARTTLS
-                sender.io = self
-                    .cfg
-                    .tls_connect(sender.io)
-                    .await
-                    .map_err(TransportError::NegotiatingTls)?;
+                let target = TlsTarget {
+                    domain: &tls.domain,
+                    hostname,
+                    tlsa: &tls.tlsa,
+                    verify: verified,
+                };
+                let io = match self.cfg.tls_connect(sender.io, &target).await {
+                    Ok(io) => io,
+                    Err(e) => {
+                        let result_type = self.cfg.tls_failure_type(&e);
+                        self.record_tls_failure(tls, result_type, hostname, e.to_string());
+                        return Err(if dane_auth {
+                            tls.error(format!(
+                                "negotiating DANE-authenticated TLS with ‘{}’: {}",
+                                hostname, e
+                            ))
+                        } else if verified {
+                            tls.error(format!(
+                                "negotiating verified TLS with ‘{}’: {}",
+                                hostname, e
+                            ))
+                        } else {
+                            TransportError::NegotiatingTls(e)
+                        });
+                    }
+                };
+                self.record_tls(&tls.domain, tls.reported_policy(), None);
+                sender.io = io;
+                sender.tls = if dane_auth {
+                    TlsVerification::Dane
+                } else if verified {
+                    TlsVerification::Verified
+                } else {
+                    TlsVerification::Unverified
+                };
                 // TODO: in case this call fails, maybe log? also, if
                 // we have must_do_tls, this server should probably be
                 // removed from the retry list as no matching ciphers
@@ -588,16 +1142,31 @@ where
                 // so as to know whether we should try STARTTLS again next time
 
                 // Send EHLO again
-                self.send_ehlo(&mut sender).await?;
+                self.send_ehlo(&mut sender, lmtp).await?;
                 did_tls = true;
             } else {
+                self.record_tls_failure(
+                    tls,
+                    tlsrpt::ResultType::StarttlsNotSupported,
+                    hostname,
+                    format!("‘{}’ refused STARTTLS: {}", hostname, reply_text),
+                );
                 // Server failed to accept STARTTLS. Let's fall through and
                 // continue without it (unless must_do_tls is enabled)
                 // TODO: maybe log? also, if we have must_do_tls and this
                 // returns a permanent error we definitely should bounce
             }
         }
-        if !did_tls && self.cfg.must_do_tls() {
+        if mandatory && !did_tls {
+            return Err(tls.error(format!("‘{}’ refused STARTTLS", hostname)));
+        }
+        if require_tls && !sender.extensions.contains(Extensions::REQUIRETLS) {
+            return Err(TransportError::RequireTls(format!(
+                "‘{}’ does not support REQUIRETLS",
+                hostname
+            )));
+        }
+        if !did_tls && tls.requirement == TlsRequirement::Default && self.cfg.must_do_tls() {
             return Err(TransportError::CannotDoTls);
         }
 
@@ -606,15 +1175,45 @@ where
         Ok(sender)
     }
 
-    async fn send_ehlo(&self, sender: &mut Sender<Cfg>) -> Result<(), TransportError> {
-        send_command(
-            &mut sender.io,
-            Command::Ehlo {
-                hostname: self.cfg.ehlo_hostname().to_ref(),
-            },
-            self.cfg.command_write_timeout(),
-        )
-        .await?;
+    /// Checks `mx` against the MTA-STS policy, only logging mismatches when the
+    /// policy is not enforced
+    fn check_mx(
+        &self,
+        policy: Option<&mta_sts::Policy>,
+        tls: &TlsMode,
+        mx: &str,
+    ) -> Result<(), TransportError> {
+        match policy {
+            Some(policy) if policy.mode != mta_sts::Mode::None && !policy.matches(mx) => {
+                let msg = format!("MX ‘{}’ does not match the MTA-STS policy", mx);
+                self.record_tls_failure(
+                    tls,
+                    tlsrpt::ResultType::ValidationFailure,
+                    mx,
+                    msg.clone(),
+                );
+                if tls.mta_sts {
+                    return Err(tls.error(msg));
+                }
+                if policy.mode == mta_sts::Mode::Testing {
+                    warn!("{}", msg);
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Sends `LHLO` instead of `EHLO` if `lmtp` is set
+    async fn send_ehlo(&self, sender: &mut Sender<Cfg>, lmtp: bool) -> Result<(), TransportError> {
+        let hostname = self.cfg.ehlo_hostname();
+        let hostname = hostname.to_ref();
+        let command = if lmtp {
+            Command::Lhlo { hostname }
+        } else {
+            Command::Ehlo { hostname }
+        };
+        send_command(&mut sender.io, command, self.cfg.command_write_timeout()).await?;
 
         // Parse the reply and verify it
         let reply = read_reply(
@@ -629,6 +1228,8 @@ where
             // TODO: parse other extensions that may be of interest (eg. pipelining)
             if line.as_str().eq_ignore_ascii_case("STARTTLS") {
                 sender.extensions.insert(Extensions::STARTTLS);
+            } else if line.as_str().eq_ignore_ascii_case("REQUIRETLS") {
+                sender.extensions.insert(Extensions::REQUIRETLS);
             }
         }
         verify_reply(reply, ReplyCodeKind::PositiveCompletion)?;
@@ -640,6 +1241,7 @@ where
 bitflags! {
     struct Extensions: u8 {
         const STARTTLS = 0b1;
+        const REQUIRETLS = 0b10;
     }
 }
 
@@ -648,6 +1250,9 @@ pub struct Sender<Cfg> {
     rdbuf: [u8; RDBUF_SIZE],
     unhandled: Range<usize>,
     extensions: Extensions,
+    /// Whether mail sent by this sender must carry `REQUIRETLS`
+    require_tls: bool,
+    tls: TlsVerification,
     cfg: Arc<Cfg>,
 }
 
@@ -655,6 +1260,12 @@ impl<Cfg> Sender<Cfg>
 where
     Cfg: Config,
 {
+    /// Returns how the server at the other end of this connection was
+    /// authenticated
+    pub fn tls_verification(&self) -> TlsVerification {
+        self.tls
+    }
+
     // TODO: Figure out a way to batch a single mail (with the same metadata) going
     // out to multiple recipients, so as to just use multiple RCPT TO
     /// Note: `mail` must be a reader of the *already escaped and
@@ -687,10 +1298,14 @@ where
         }
 
         // MAIL FROM
+        let mut params = Vec::new();
+        if self.require_tls {
+            params.push((ParameterName::Other("REQUIRETLS"), None));
+        }
         send_command!(Command::Mail {
             path: None,
             email: from.map(|f| f.to_ref()),
-            params: Parameters(Vec::new()),
+            params: Parameters(params),
         })
         .await?;
         read_reply!(
@@ -0,0 +1,341 @@
+use std::io;
+
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::ZERO_DURATION;
+
+/// Path at which the policy is served, on host `mta-sts.<domain>`
+pub const POLICY_PATH: &str = "/.well-known/mta-sts.txt";
+
+/// RFC 8461 allows policies of at most 64 KiB
+const MAX_POLICY_SIZE: usize = 64 * 1024;
+
+/// Room left for the HTTP status line and header fields
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+
+/// Policies cannot be cached for more than a year
+const MAX_MAX_AGE: u64 = 31_557_600;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mode {
+    /// Only deliver over validated TLS to a matching MX
+    Enforce,
+    /// Report failures, but deliver anyway
+    Testing,
+    /// The domain no longer has a policy
+    None,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Policy {
+    pub mode: Mode,
+    /// Patterns of the MX host names, in lowercase and without a trailing dot
+    pub mx: Vec<String>,
+    /// Number of seconds for which the policy can be cached
+    pub max_age: u64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PolicyError {
+    #[error("Fetching the policy")]
+    Io(#[source] io::Error),
+
+    #[error("Timed out fetching the policy")]
+    TimedOut,
+
+    #[error("Policy response is too big")]
+    TooBig,
+
+    #[error("Policy host replied with status ‘{0}’")]
+    Status(String),
+
+    #[error("Policy is not served as text/plain")]
+    ContentType,
+
+    #[error("Invalid policy: {0}")]
+    Invalid(&'static str),
+}
+
+impl Policy {
+    /// Returns whether the MX host name `mx` matches one of the patterns of
+    /// the policy
+    pub fn matches(&self, mx: &str) -> bool {
+        let mx = mx.trim_end_matches('.').to_ascii_lowercase();
+        self.mx
+            .iter()
+            .any(|pattern| match pattern.strip_prefix("*.") {
+                // The wildcard only stands for the leftmost label
+                Some(suffix) => mx
+                    .split_once('.')
+                    .is_some_and(|(label, rest)| !label.is_empty() && rest == suffix),
+                None => *pattern == mx,
+            })
+    }
+}
+
+/// Parses the `_mta-sts` TXT record, returning the policy id if it is valid
+pub fn parse_txt_record(record: &str) -> Option<String> {
+    let mut fields = record.split(';').map(str::trim).filter(|f| !f.is_empty());
+    if fields.next() != Some("v=STSv1") {
+        return None;
+    }
+    let id = fields.find_map(|f| f.strip_prefix("id="))?;
+    if id.is_empty() || id.len() > 32 || !id.bytes().all(|b| b.is_ascii_alphanumeric()) {
+        return None;
+    }
+    Some(id.to_owned())
+}
+
+/// Parses the text of a policy
+pub fn parse_policy(text: &str) -> Result<Policy, PolicyError> {
+    let mut version = None;
+    let mut mode = None;
+    let mut max_age = None;
+    let mut mx = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (key, value) = line
+            .split_once(':')
+            .ok_or(PolicyError::Invalid("line without a colon"))?;
+        let value = value.trim();
+        match key.trim() {
+            "version" => version = Some(value),
+            "mode" => {
+                mode = Some(match value {
+                    "enforce" => Mode::Enforce,
+                    "testing" => Mode::Testing,
+                    "none" => Mode::None,
+                    _ => return Err(PolicyError::Invalid("unknown mode")),
+                })
+            }
+            "max_age" => {
+                let age = value
+                    .parse::<u64>()
+                    .map_err(|_| PolicyError::Invalid("invalid max_age"))?;
+                max_age = Some(age.min(MAX_MAX_AGE));
+            }
+            "mx" => mx.push(value.trim_end_matches('.').to_ascii_lowercase()),
+            // Unknown keys are reserved for future extensions
+            _ => (),
+        }
+    }
+    if version != Some("STSv1") {
+        return Err(PolicyError::Invalid("not an STSv1 policy"));
+    }
+    let mode = mode.ok_or(PolicyError::Invalid("no mode"))?;
+    let max_age = max_age.ok_or(PolicyError::Invalid("no max_age"))?;
+    if mode != Mode::None && mx.is_empty() {
+        return Err(PolicyError::Invalid("no mx"));
+    }
+    Ok(Policy { mode, mx, max_age })
+}
+
+fn parse_response(response: &[u8]) -> Result<Policy, PolicyError> {
+    let header_end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or(PolicyError::Invalid("truncated HTTP response"))?;
+    let header = std::str::from_utf8(&response[..header_end])
+        .map_err(|_| PolicyError::Invalid("HTTP header is not UTF-8"))?;
+    let mut body = &response[header_end + 4..];
+    let mut lines = header.split("\r\n");
+    let status = lines.next().unwrap_or("");
+    let mut status_parts = status.splitn(3, ' ');
+    if !status_parts.next().is_some_and(|p| p.starts_with("HTTP/")) {
+        return Err(PolicyError::Invalid("not an HTTP response"));
+    }
+    // Redirects must not be followed, so anything but 200 is a failure
+    if status_parts.next() != Some("200") {
+        return Err(PolicyError::Status(status.to_owned()));
+    }
+    let mut is_text = false;
+    for line in lines {
+        let (name, value) = line.split_once(':').unwrap_or((line, ""));
+        let (name, value) = (name.trim(), value.trim());
+        if name.eq_ignore_ascii_case("content-type") {
+            let media_type = value.split(';').next().unwrap_or("").trim();
+            is_text = media_type.eq_ignore_ascii_case("text/plain");
+        } else if name.eq_ignore_ascii_case("content-length") {
+            match value.parse::<usize>() {
+                Ok(len) if len <= body.len() => body = &body[..len],
+                _ => return Err(PolicyError::Invalid("invalid content length")),
+            }
+        }
+    }
+    if !is_text {
+        return Err(PolicyError::ContentType);
+    }
+    if body.len() > MAX_POLICY_SIZE {
+        return Err(PolicyError::TooBig);
+    }
+    let text =
+        std::str::from_utf8(body).map_err(|_| PolicyError::Invalid("policy is not UTF-8"))?;
+    parse_policy(text)
+}
+
+/// Fetches the policy of `domain` from the policy host at the other end of
+/// `stream`
+///
+/// `stream` must already be a TLS connection to `mta-sts.<domain>` on port
+/// 443, with a certificate validated for this name.
+pub async fn fetch_policy<S>(
+    mut stream: S,
+    domain: &str,
+    timeout: chrono::Duration,
+) -> Result<Policy, PolicyError>
+where
+    S: Unpin + AsyncRead + AsyncWrite,
+{
+    // HTTP/1.0 saves us from having to handle the chunked encoding
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: mta-sts.{}\r\nConnection: close\r\n\r\n",
+        POLICY_PATH, domain
+    );
+    smol::future::or(
+        async {
+            stream
+                .write_all(request.as_bytes())
+                .await
+                .map_err(PolicyError::Io)?;
+            stream.flush().await.map_err(PolicyError::Io)?;
+            let max_size = MAX_HEADER_SIZE + MAX_POLICY_SIZE;
+            let mut response = Vec::new();
+            match (&mut stream)
+                .take(max_size as u64 + 1)
+                .read_to_end(&mut response)
+                .await
+            {
+                Ok(_) => (),
+                // Some servers close the connection without a TLS close_notify
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && !response.is_empty() => (),
+                Err(e) => return Err(PolicyError::Io(e)),
+            }
+            if response.len() > max_size {
+                return Err(PolicyError::TooBig);
+            }
+            parse_response(&response)
+        },
+        async {
+            smol::Timer::after(timeout.to_std().unwrap_or(ZERO_DURATION)).await;
+            Err(PolicyError::TimedOut)
+        },
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use smol::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    #[test]
+    fn txt_records() {
+        let tests: &[(&str, Option<&str>)] = &[
+            ("v=STSv1; id=20160831085700Z;", Some("20160831085700Z")),
+            ("v=STSv1;id=abc", Some("abc")),
+            ("v=STSv1; foo=bar; id=abc ;", Some("abc")),
+            ("id=abc; v=STSv1", None),
+            ("v=STSv2; id=abc", None),
+            ("v=STSv1; id=not-alnum", None),
+            ("v=STSv1; id=", None),
+            ("v=STSv1", None),
+        ];
+        for (record, id) in tests {
+            assert_eq!(parse_txt_record(record).as_deref(), *id, "{}", record);
+        }
+    }
+
+    #[test]
+    fn policies() {
+        let policy = parse_policy(
+            "version: STSv1\r\nmode: enforce\r\nmx: mail.example.com\r\nmx: \
+             *.Example.NET.\r\nmax_age: 604800\r\n",
+        )
+        .unwrap();
+        assert_eq!(policy, Policy {
+            mode: Mode::Enforce,
+            mx: vec!["mail.example.com".to_owned(), "*.example.net".to_owned()],
+            max_age: 604800,
+        });
+        assert!(policy.matches("mail.example.com"));
+        assert!(policy.matches("MAIL.example.com."));
+        assert!(policy.matches("mx1.example.net"));
+        assert!(!policy.matches("example.net"));
+        assert!(!policy.matches("a.b.example.net"));
+        assert!(!policy.matches("mail.example.org"));
+
+        let policy = parse_policy("version: STSv1\nmode: none\nmax_age: 99999999999\n").unwrap();
+        assert_eq!(policy.mode, Mode::None);
+        assert_eq!(policy.max_age, MAX_MAX_AGE);
+
+        for text in &[
+            "mode: enforce\nmx: a.example\nmax_age: 1\n",
+            "version: STSv1\nmode: enforce\nmax_age: 1\n",
+            "version: STSv1\nmode: block\nmx: a.example\nmax_age: 1\n",
+            "version: STSv1\nmode: testing\nmx: a.example\n",
+            "version: STSv1\nmode testing\n",
+        ] {
+            assert!(
+                matches!(parse_policy(text), Err(PolicyError::Invalid(_))),
+                "{}",
+                text
+            );
+        }
+    }
+
+    /// Runs a local policy server that answers `response`, returning the
+    /// request it received
+    fn fetch(response: &'static [u8]) -> (String, Result<Policy, PolicyError>) {
+        smol::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = smol::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                stream.write_all(response).await.unwrap();
+                String::from_utf8(request).unwrap()
+            });
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let res = fetch_policy(stream, "example.com", chrono::Duration::seconds(5)).await;
+            (server.await, res)
+        })
+    }
+
+    #[test]
+    fn fetching() {
+        let (request, res) = fetch(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n\
+              version: STSv1\nmode: testing\nmx: mx.example.com\nmax_age: 86400\n",
+        );
+        assert_eq!(
+            request,
+            "GET /.well-known/mta-sts.txt HTTP/1.0\r\nHost: mta-sts.example.com\r\nConnection: \
+             close\r\n\r\n"
+        );
+        assert_eq!(res.unwrap(), Policy {
+            mode: Mode::Testing,
+            mx: vec!["mx.example.com".to_owned()],
+            max_age: 86400,
+        });
+
+        let (_, res) = fetch(b"HTTP/1.1 301 Moved Permanently\r\nLocation: /elsewhere\r\n\r\n");
+        assert!(
+            matches!(res, Err(PolicyError::Status(s)) if s == "HTTP/1.1 301 Moved Permanently")
+        );
+
+        let (_, res) = fetch(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n\
+              version: STSv1\nmode: testing\nmx: mx.example.com\nmax_age: 86400\n",
+        );
+        assert!(matches!(res, Err(PolicyError::ContentType)));
+    }
+}
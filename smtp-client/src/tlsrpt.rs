@@ -0,0 +1,745 @@
+use std::{collections::HashMap, io};
+
+use async_trait::async_trait;
+use chrono::{TimeZone, Utc};
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{dane, mta_sts, ZERO_DURATION};
+
+/// Maximum length of the lines of the base64-encoded report
+const MAX_LINE_LEN: usize = 76;
+
+/// Replies to report uploads are only read for their status line
+const MAX_REPLY_SIZE: usize = 16 * 1024;
+
+/// Kind of policy a TLS session was subject to, see RFC 8460 §4.4
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum PolicyType {
+    Sts,
+    Tlsa,
+    NoPolicyFound,
+}
+
+impl PolicyType {
+    /// Name of the policy type, as used in reports
+    pub fn name(&self) -> &'static str {
+        match self {
+            PolicyType::Sts => "sts",
+            PolicyType::Tlsa => "tlsa",
+            PolicyType::NoPolicyFound => "no-policy-found",
+        }
+    }
+}
+
+/// Why a TLS session failed, see RFC 8460 §4.3
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum ResultType {
+    StarttlsNotSupported,
+    CertificateHostMismatch,
+    CertificateExpired,
+    CertificateNotTrusted,
+    ValidationFailure,
+    TlsaInvalid,
+    DnssecInvalid,
+    DaneRequired,
+    StsPolicyFetchError,
+    StsPolicyInvalid,
+    StsWebpkiInvalid,
+}
+
+impl ResultType {
+    /// Name of the result type, as used in reports
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResultType::StarttlsNotSupported => "starttls-not-supported",
+            ResultType::CertificateHostMismatch => "certificate-host-mismatch",
+            ResultType::CertificateExpired => "certificate-expired",
+            ResultType::CertificateNotTrusted => "certificate-not-trusted",
+            ResultType::ValidationFailure => "validation-failure",
+            ResultType::TlsaInvalid => "tlsa-invalid",
+            ResultType::DnssecInvalid => "dnssec-invalid",
+            ResultType::DaneRequired => "dane-required",
+            ResultType::StsPolicyFetchError => "sts-policy-fetch-error",
+            ResultType::StsPolicyInvalid => "sts-policy-invalid",
+            ResultType::StsWebpkiInvalid => "sts-webpki-invalid",
+        }
+    }
+}
+
+/// Policy a TLS session was subject to
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct AppliedPolicy {
+    pub policy_type: PolicyType,
+    /// Lines of the MTA-STS policy, or TLSA records in presentation format
+    pub policy_string: Vec<String>,
+    /// MX patterns of the MTA-STS policy
+    pub mx_host: Vec<String>,
+}
+
+impl AppliedPolicy {
+    pub fn none() -> AppliedPolicy {
+        AppliedPolicy {
+            policy_type: PolicyType::NoPolicyFound,
+            policy_string: Vec::new(),
+            mx_host: Vec::new(),
+        }
+    }
+
+    pub fn sts(policy: &mta_sts::Policy) -> AppliedPolicy {
+        let mode = match policy.mode {
+            mta_sts::Mode::Enforce => "enforce",
+            mta_sts::Mode::Testing => "testing",
+            mta_sts::Mode::None => "none",
+        };
+        let mut lines = vec!["version: STSv1".to_owned(), format!("mode: {}", mode)];
+        lines.extend(policy.mx.iter().map(|mx| format!("mx: {}", mx)));
+        lines.push(format!("max_age: {}", policy.max_age));
+        AppliedPolicy {
+            policy_type: PolicyType::Sts,
+            policy_string: lines,
+            mx_host: policy.mx.clone(),
+        }
+    }
+
+    pub fn tlsa(records: &[dane::Tlsa]) -> AppliedPolicy {
+        let record = |r: &dane::Tlsa| {
+            let usage = match r.usage {
+                dane::Usage::TrustAnchor => 2,
+                dane::Usage::EndEntity => 3,
+            };
+            let selector = match r.selector {
+                dane::Selector::Full => 0,
+                dane::Selector::Spki => 1,
+            };
+            let matching = match r.matching {
+                dane::Matching::Exact => 0,
+                dane::Matching::Sha256 => 1,
+                dane::Matching::Sha512 => 2,
+            };
+            let data = r
+                .data
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>();
+            format!("{} {} {} {}", usage, selector, matching, data)
+        };
+        AppliedPolicy {
+            policy_type: PolicyType::Tlsa,
+            policy_string: records.iter().map(record).collect(),
+            mx_host: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct Failure {
+    pub result_type: ResultType,
+    pub receiving_mx_hostname: Option<String>,
+    pub additional_information: Option<String>,
+}
+
+/// Outcome of one TLS session, as recorded for aggregate reports
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct Observation {
+    /// UNIX timestamp of the session
+    pub time: u64,
+    pub policy_domain: String,
+    pub policy: AppliedPolicy,
+    /// `None` if the session was successfully established
+    pub failure: Option<Failure>,
+}
+
+/// Information about the organization generating the reports
+pub struct Reporter {
+    pub org_name: String,
+    /// Address the reports are sent from
+    pub email: String,
+    /// Address or URI at which the organization can be contacted
+    pub contact_info: String,
+}
+
+struct PolicyReport {
+    policy: AppliedPolicy,
+    successes: u64,
+    failures: Vec<(Failure, u64)>,
+}
+
+/// Aggregate report for one policy domain, see RFC 8460 §4
+pub struct AggregateReport {
+    pub policy_domain: String,
+    pub report_id: String,
+    /// UNIX timestamps of the beginning and end of the reported period
+    pub begin: u64,
+    pub end: u64,
+    policies: Vec<PolicyReport>,
+}
+
+/// Groups `observations` into one report per policy domain
+pub fn aggregate(observations: Vec<Observation>) -> Vec<AggregateReport> {
+    let mut reports: Vec<AggregateReport> = Vec::new();
+    let mut report_idx = HashMap::new();
+    let mut policy_idx = HashMap::new();
+    let mut failure_idx = HashMap::new();
+    for o in observations {
+        let r = *report_idx
+            .entry(o.policy_domain.clone())
+            .or_insert_with(|| {
+                reports.push(AggregateReport {
+                    policy_domain: o.policy_domain.clone(),
+                    report_id: String::new(),
+                    begin: o.time,
+                    end: o.time,
+                    policies: Vec::new(),
+                });
+                reports.len() - 1
+            });
+        let report = &mut reports[r];
+        report.begin = std::cmp::min(report.begin, o.time);
+        report.end = std::cmp::max(report.end, o.time);
+        let policies = &mut report.policies;
+        let applied = o.policy;
+        let p = *policy_idx.entry((r, applied.clone())).or_insert_with(|| {
+            policies.push(PolicyReport {
+                policy: applied,
+                successes: 0,
+                failures: Vec::new(),
+            });
+            policies.len() - 1
+        });
+        let policy = &mut policies[p];
+        match o.failure {
+            None => policy.successes += 1,
+            Some(failure) => {
+                let failures = &mut policy.failures;
+                let f = *failure_idx
+                    .entry((r, p, failure.clone()))
+                    .or_insert_with(|| {
+                        failures.push((failure, 0));
+                        failures.len() - 1
+                    });
+                failures[f].1 += 1;
+            }
+        }
+    }
+    for r in &mut reports {
+        r.report_id = format!("{}.{}.{}", r.policy_domain, r.begin, r.end);
+    }
+    reports
+}
+
+fn json_string(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+fn json_strings(strings: &[String]) -> String {
+    let strings = strings.iter().map(|s| json_string(s)).collect::<Vec<_>>();
+    format!("[{}]", strings.join(", "))
+}
+
+fn datetime(timestamp: u64) -> String {
+    Utc.timestamp_opt(timestamp as i64, 0)
+        .single()
+        .unwrap_or_else(Utc::now)
+        .format("%Y-%m-%dT%H:%M:%SZ")
+        .to_string()
+}
+
+/// Returns the reporting URIs of the `_smtp._tls` TXT record, if it is valid
+///
+/// Only `mailto:` and `https:` URIs are kept.
+pub fn parse_txt_record(record: &str) -> Option<Vec<String>> {
+    let mut fields = record.split(';').map(str::trim).filter(|f| !f.is_empty());
+    if fields.next() != Some("v=TLSRPTv1") {
+        return None;
+    }
+    let rua = fields.find_map(|f| f.strip_prefix("rua="))?;
+    let uris = rua
+        .split(',')
+        .map(str::trim)
+        .filter(|u| mailto_address(u).is_some() || https_uri(u).is_some())
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    if uris.is_empty() {
+        None
+    } else {
+        Some(uris)
+    }
+}
+
+/// Returns the address of a `mailto:` reporting URI
+pub fn mailto_address(uri: &str) -> Option<String> {
+    if uri.len() < 7 || !uri[..7].eq_ignore_ascii_case("mailto:") {
+        return None;
+    }
+    let addr = uri[7..].split('?').next().unwrap_or("");
+    match addr.rfind('@') {
+        Some(i) if i > 0 && i + 1 < addr.len() => Some(addr.to_owned()),
+        _ => None,
+    }
+}
+
+/// Splits an `https:` reporting URI into its host, port and path
+pub fn https_uri(uri: &str) -> Option<(String, u16, String)> {
+    if uri.len() < 8 || !uri[..8].eq_ignore_ascii_case("https://") {
+        return None;
+    }
+    let rest = &uri[8..];
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (authority, 443),
+    };
+    if host.is_empty() || authority.contains('@') {
+        return None;
+    }
+    Some((host.to_owned(), port, path.to_owned()))
+}
+
+impl AggregateReport {
+    /// Returns the JSON report, see RFC 8460 §4.4
+    pub fn to_json(&self, reporter: &Reporter) -> String {
+        let mut res = String::from("{\n");
+        res.push_str(&format!(
+            "  \"organization-name\": {},\n",
+            json_string(&reporter.org_name)
+        ));
+        res.push_str(&format!(
+            "  \"date-range\": {{\n    \"start-datetime\": \"{}\",\n    \"end-datetime\": \
+             \"{}\"\n  }},\n",
+            datetime(self.begin),
+            datetime(self.end)
+        ));
+        res.push_str(&format!(
+            "  \"contact-info\": {},\n",
+            json_string(&reporter.contact_info)
+        ));
+        res.push_str(&format!(
+            "  \"report-id\": {},\n",
+            json_string(&self.report_id)
+        ));
+        res.push_str("  \"policies\": [");
+        for (i, p) in self.policies.iter().enumerate() {
+            if i > 0 {
+                res.push(',');
+            }
+            res.push_str("\n    {\n      \"policy\": {\n");
+            res.push_str(&format!(
+                "        \"policy-type\": \"{}\",\n",
+                p.policy.policy_type.name()
+            ));
+            if p.policy.policy_type != PolicyType::NoPolicyFound {
+                res.push_str(&format!(
+                    "        \"policy-string\": {},\n",
+                    json_strings(&p.policy.policy_string)
+                ));
+            }
+            if !p.policy.mx_host.is_empty() {
+                res.push_str(&format!(
+                    "        \"mx-host\": {},\n",
+                    json_strings(&p.policy.mx_host)
+                ));
+            }
+            res.push_str(&format!(
+                "        \"policy-domain\": {}\n      }},\n",
+                json_string(&self.policy_domain)
+            ));
+            let failed = p.failures.iter().map(|(_, n)| n).sum::<u64>();
+            res.push_str(&format!(
+                "      \"summary\": {{\n        \"total-successful-session-count\": {},\n        \
+                 \"total-failure-session-count\": {}\n      }}",
+                p.successes, failed
+            ));
+            if !p.failures.is_empty() {
+                res.push_str(",\n      \"failure-details\": [");
+                for (j, (f, count)) in p.failures.iter().enumerate() {
+                    if j > 0 {
+                        res.push(',');
+                    }
+                    res.push_str("\n        {\n");
+                    res.push_str(&format!(
+                        "          \"result-type\": \"{}\",\n",
+                        f.result_type.name()
+                    ));
+                    if let Some(mx) = &f.receiving_mx_hostname {
+                        res.push_str(&format!(
+                            "          \"receiving-mx-hostname\": {},\n",
+                            json_string(mx)
+                        ));
+                    }
+                    if let Some(info) = &f.additional_information {
+                        res.push_str(&format!(
+                            "          \"additional-information\": {},\n",
+                            json_string(info)
+                        ));
+                    }
+                    res.push_str(&format!(
+                        "          \"failed-session-count\": {}\n        }}",
+                        count
+                    ));
+                }
+                res.push_str("\n      ]");
+            }
+            res.push_str("\n    }");
+        }
+        res.push_str("\n  ]\n}\n");
+        res
+    }
+
+    /// Name of the report file, see RFC 8460 §5.1
+    pub fn filename(&self, reporter: &Reporter) -> String {
+        format!(
+            "{}!{}!{}!{}.json.gz",
+            submitter(reporter),
+            self.policy_domain,
+            self.begin,
+            self.end
+        )
+    }
+
+    /// Returns the mail carrying the report, see RFC 8460 §5.3, `date` being
+    /// the value of the `Date` header and `report` the gzip-compressed JSON
+    /// report
+    pub fn to_mail(
+        &self,
+        reporter: &Reporter,
+        recipients: &[String],
+        date: &str,
+        report: &[u8],
+    ) -> Vec<u8> {
+        let submitter = submitter(reporter);
+        let boundary = format!("report-{}", self.report_id);
+
+        let mut res = String::new();
+        res.push_str(&format!("From: {}\r\n", reporter.email));
+        res.push_str(&format!("To: {}\r\n", recipients.join(",\r\n ")));
+        res.push_str(&format!("Date: {}\r\n", date));
+        res.push_str(&format!(
+            "Subject: Report Domain: {} Submitter: {} Report-ID: <{}>\r\n",
+            self.policy_domain, submitter, self.report_id
+        ));
+        res.push_str(&format!(
+            "Message-ID: <{}@{}>\r\n",
+            self.report_id, submitter
+        ));
+        res.push_str(&format!("TLS-Report-Domain: {}\r\n", self.policy_domain));
+        res.push_str(&format!("TLS-Report-Submitter: {}\r\n", submitter));
+        res.push_str("MIME-Version: 1.0\r\n");
+        res.push_str(&format!(
+            "Content-Type: multipart/report; report-type=\"tlsrpt\"; boundary=\"{}\"\r\n",
+            boundary
+        ));
+        res.push_str("\r\n");
+
+        res.push_str(&format!("--{}\r\n", boundary));
+        res.push_str("Content-Type: text/plain; charset=utf-8\r\n\r\n");
+        res.push_str(&format!(
+            "This is an SMTP TLS report for {} from {}.\r\n",
+            self.policy_domain, reporter.org_name
+        ));
+
+        res.push_str(&format!("--{}\r\n", boundary));
+        res.push_str("Content-Type: application/tlsrpt+gzip\r\n");
+        res.push_str("Content-Transfer-Encoding: base64\r\n");
+        res.push_str(&format!(
+            "Content-Disposition: attachment; filename=\"{}\"\r\n\r\n",
+            self.filename(reporter)
+        ));
+        let report = base64::encode(report);
+        for line in report.as_bytes().chunks(MAX_LINE_LEN) {
+            // base64 is ASCII
+            res.push_str(std::str::from_utf8(line).unwrap());
+            res.push_str("\r\n");
+        }
+        res.push_str(&format!("--{}--\r\n", boundary));
+        res.into_bytes()
+    }
+}
+
+fn submitter(reporter: &Reporter) -> &str {
+    match reporter.email.rfind('@') {
+        Some(i) => &reporter.email[i + 1..],
+        None => reporter.email.as_str(),
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PostError {
+    #[error("Invalid reporting URI ‘{0}’")]
+    InvalidUri(String),
+
+    #[error("Uploading the report")]
+    Io(#[source] io::Error),
+
+    #[error("Timed out uploading the report")]
+    TimedOut,
+
+    #[error("Reporting host replied with status ‘{0}’")]
+    Status(String),
+}
+
+/// Uploads reports to `https:` reporting URIs
+#[async_trait]
+pub trait Poster {
+    /// POSTs the gzip-compressed JSON `report` to `uri`
+    async fn post(&self, uri: &str, report: &[u8]) -> Result<(), PostError>;
+}
+
+/// POSTs the gzip-compressed JSON `report` to `path` on the reporting host
+/// `host` at the other end of `stream`
+///
+/// `stream` must already be a TLS connection to `host`, with a certificate
+/// validated for this name.
+pub async fn post_report<S>(
+    mut stream: S,
+    host: &str,
+    path: &str,
+    report: &[u8],
+    timeout: chrono::Duration,
+) -> Result<(), PostError>
+where
+    S: Unpin + AsyncRead + AsyncWrite,
+{
+    // HTTP/1.0 saves us from having to handle the chunked encoding
+    let header = format!(
+        "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/tlsrpt+gzip\r\nContent-Length: \
+         {}\r\nConnection: close\r\n\r\n",
+        path,
+        host,
+        report.len()
+    );
+    smol::future::or(
+        async {
+            stream
+                .write_all(header.as_bytes())
+                .await
+                .map_err(PostError::Io)?;
+            stream.write_all(report).await.map_err(PostError::Io)?;
+            stream.flush().await.map_err(PostError::Io)?;
+            let mut reply = Vec::new();
+            match (&mut stream)
+                .take(MAX_REPLY_SIZE as u64)
+                .read_to_end(&mut reply)
+                .await
+            {
+                Ok(_) => (),
+                // Some servers close the connection without a TLS close_notify
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && !reply.is_empty() => (),
+                Err(e) => return Err(PostError::Io(e)),
+            }
+            let reply = String::from_utf8_lossy(&reply);
+            let status = reply.split("\r\n").next().unwrap_or("");
+            match status.split(' ').nth(1) {
+                Some(code) if code.len() == 3 && code.starts_with('2') => Ok(()),
+                _ => Err(PostError::Status(status.to_owned())),
+            }
+        },
+        async {
+            smol::Timer::after(timeout.to_std().unwrap_or(ZERO_DURATION)).await;
+            Err(PostError::TimedOut)
+        },
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use smol::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    fn observation(domain: &str, time: u64, failure: Option<ResultType>) -> Observation {
+        Observation {
+            time,
+            policy_domain: domain.to_owned(),
+            policy: AppliedPolicy::sts(&mta_sts::Policy {
+                mode: mta_sts::Mode::Enforce,
+                mx: vec!["*.example.com".to_owned()],
+                max_age: 86400,
+            }),
+            failure: failure.map(|result_type| Failure {
+                result_type,
+                receiving_mx_hostname: Some("mx.example.com".to_owned()),
+                additional_information: None,
+            }),
+        }
+    }
+
+    fn reporter() -> Reporter {
+        Reporter {
+            org_name: "Example \"Org\"".to_owned(),
+            email: "tlsrpt@reporter.example".to_owned(),
+            contact_info: "postmaster@reporter.example".to_owned(),
+        }
+    }
+
+    #[test]
+    fn txt_records() {
+        let tests: &[(&str, Option<&[&str]>)] = &[
+            (
+                "v=TLSRPTv1; rua=mailto:tls@example.com,https://r.example.com/tls",
+                Some(&["mailto:tls@example.com", "https://r.example.com/tls"]),
+            ),
+            (
+                "v=TLSRPTv1;rua=mailto:a@example.com, ftp://example.com/",
+                Some(&["mailto:a@example.com"]),
+            ),
+            ("v=TLSRPTv1; rua=ftp://example.com/", None),
+            ("rua=mailto:a@example.com; v=TLSRPTv1", None),
+            ("v=TLSRPTv1", None),
+        ];
+        for (record, uris) in tests {
+            let uris = uris.map(|u| u.iter().map(|u| u.to_string()).collect::<Vec<_>>());
+            assert_eq!(parse_txt_record(record), uris, "{}", record);
+        }
+        assert_eq!(
+            https_uri("https://r.example.com:8443/a/b"),
+            Some(("r.example.com".to_owned(), 8443, "/a/b".to_owned()))
+        );
+        assert_eq!(
+            https_uri("HTTPS://r.example.com"),
+            Some(("r.example.com".to_owned(), 443, "/".to_owned()))
+        );
+        assert_eq!(https_uri("https://user@r.example.com/"), None);
+        assert_eq!(https_uri("http://r.example.com/"), None);
+    }
+
+    #[test]
+    fn aggregation() {
+        let reports = aggregate(vec![
+            observation("example.com", 1000, None),
+            observation("example.org", 1500, None),
+            observation("example.com", 2000, Some(ResultType::CertificateExpired)),
+            observation("example.com", 500, None),
+            observation("example.com", 1200, Some(ResultType::CertificateExpired)),
+        ]);
+        assert_eq!(reports.len(), 2);
+        let report = &reports[0];
+        assert_eq!(report.policy_domain, "example.com");
+        assert_eq!((report.begin, report.end), (500, 2000));
+        assert_eq!(report.report_id, "example.com.500.2000");
+        assert_eq!(report.policies.len(), 1);
+        assert_eq!(report.policies[0].successes, 2);
+        assert_eq!(report.policies[0].failures.len(), 1);
+        assert_eq!(report.policies[0].failures[0].1, 2);
+
+        assert_eq!(
+            report.to_json(&reporter()),
+            r#"{
+  "organization-name": "Example \"Org\"",
+  "date-range": {
+    "start-datetime": "1970-01-01T00:08:20Z",
+    "end-datetime": "1970-01-01T00:33:20Z"
+  },
+  "contact-info": "postmaster@reporter.example",
+  "report-id": "example.com.500.2000",
+  "policies": [
+    {
+      "policy": {
+        "policy-type": "sts",
+        "policy-string": ["version: STSv1", "mode: enforce", "mx: *.example.com", "max_age: 86400"],
+        "mx-host": ["*.example.com"],
+        "policy-domain": "example.com"
+      },
+      "summary": {
+        "total-successful-session-count": 2,
+        "total-failure-session-count": 2
+      },
+      "failure-details": [
+        {
+          "result-type": "certificate-expired",
+          "receiving-mx-hostname": "mx.example.com",
+          "failed-session-count": 2
+        }
+      ]
+    }
+  ]
+}
+"#
+        );
+        assert_eq!(
+            report.filename(&reporter()),
+            "reporter.example!example.com!500!2000.json.gz"
+        );
+    }
+
+    #[test]
+    fn tlsa_policy() {
+        let policy = AppliedPolicy::tlsa(&[dane::Tlsa {
+            usage: dane::Usage::EndEntity,
+            selector: dane::Selector::Spki,
+            matching: dane::Matching::Sha256,
+            data: vec![0xab, 0x01],
+        }]);
+        assert_eq!(policy.policy_type, PolicyType::Tlsa);
+        assert_eq!(policy.policy_string, vec!["3 1 1 ab01".to_owned()]);
+    }
+
+    /// Runs a local reporting server that answers `response`, returning the
+    /// request it received
+    fn post(response: &'static [u8]) -> (Vec<u8>, Result<(), PostError>) {
+        smol::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = smol::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"report") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                stream.write_all(response).await.unwrap();
+                request
+            });
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let res = post_report(
+                stream,
+                "r.example.com",
+                "/tls",
+                b"report",
+                chrono::Duration::seconds(5),
+            )
+            .await;
+            (server.await, res)
+        })
+    }
+
+    #[test]
+    fn posting() {
+        let (request, res) = post(b"HTTP/1.1 201 Created\r\n\r\n");
+        assert_eq!(
+            request,
+            &b"POST /tls HTTP/1.0\r\nHost: r.example.com\r\nContent-Type: \
+               application/tlsrpt+gzip\r\nContent-Length: 6\r\nConnection: \
+               close\r\n\r\nreport"[..]
+        );
+        res.unwrap();
+
+        let (_, res) = post(b"HTTP/1.1 500 Internal Server Error\r\n\r\n");
+        assert!(
+            matches!(res, Err(PostError::Status(s)) if s == "HTTP/1.1 500 Internal Server Error")
+        );
+    }
+}
@@ -0,0 +1,384 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+struct DerElement<'a> {
+    tag: u8,
+    whole: &'a [u8],
+    contents: &'a [u8],
+    /// What follows the element
+    rest: &'a [u8],
+}
+
+/// Splits the DER element at the start of `der`
+fn der_element(der: &[u8]) -> Option<DerElement<'_>> {
+    let tag = *der.first()?;
+    let first = *der.get(1)? as usize;
+    let (len, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let n = first & 0x7f;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let len = der.get(2..2 + n)?;
+        (len.iter().fold(0, |l, b| (l << 8) | *b as usize), 2 + n)
+    };
+    let end = header.checked_add(len)?;
+    let whole = der.get(..end)?;
+    Some(DerElement {
+        tag,
+        whole,
+        contents: &whole[header..],
+        rest: &der[end..],
+    })
+}
+
+/// Returns the field of the TBSCertificate of `cert` at `index`, counting
+/// from the serial number, that is after the optional version
+fn tbs_field(cert: &[u8], index: usize) -> Option<DerElement<'_>> {
+    let cert = der_element(cert)?.contents;
+    let tbs = der_element(cert)?.contents;
+    // Skip the optional version
+    let version = der_element(tbs)?;
+    let mut rest = if version.tag == 0xa0 {
+        version.rest
+    } else {
+        tbs
+    };
+    for _ in 0..index {
+        rest = der_element(rest)?.rest;
+    }
+    der_element(rest)
+}
+
+/// Returns the DER-encoded SubjectPublicKeyInfo of the certificate `cert`
+pub fn spki(cert: &[u8]) -> Option<&[u8]> {
+    // After the serial number, signature algorithm, issuer, validity and subject
+    let spki = tbs_field(cert, 5)?;
+    if spki.tag == 0x30 {
+        Some(spki.whole)
+    } else {
+        None
+    }
+}
+
+/// Returns the DER-encoded issuer name of the certificate `cert`
+pub fn issuer(cert: &[u8]) -> Option<&[u8]> {
+    Some(tbs_field(cert, 2)?.whole)
+}
+
+/// Returns the DER-encoded subject name of the certificate `cert`
+pub fn subject(cert: &[u8]) -> Option<&[u8]> {
+    Some(tbs_field(cert, 4)?.whole)
+}
+
+/// Parses an UTCTime or GeneralizedTime, as found in the validity of
+/// certificates
+fn parse_time(time: &DerElement<'_>) -> Option<DateTime<Utc>> {
+    let text = std::str::from_utf8(time.contents).ok()?;
+    let time = match (time.tag, text.get(..2)?.parse::<u8>().ok()?) {
+        // RFC 5280 has two-digit years from 50 to 99 be in the 20th century
+        (0x17, y) if y >= 50 => format!("19{}", text),
+        (0x17, _) => format!("20{}", text),
+        (0x18, _) => text.to_owned(),
+        _ => return None,
+    };
+    let time = NaiveDateTime::parse_from_str(&time, "%Y%m%d%H%M%SZ").ok()?;
+    Some(DateTime::from_utc(time, Utc))
+}
+
+/// Returns the end of the validity period of the certificate `cert`
+pub fn not_after(cert: &[u8]) -> Option<DateTime<Utc>> {
+    let validity = tbs_field(cert, 3)?;
+    let not_before = der_element(validity.contents)?;
+    parse_time(&der_element(not_before.rest)?)
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Returns the hex-encoded SHA-256 hash of the certificate `cert`
+pub fn sha256_fingerprint(cert: &[u8]) -> String {
+    hex(ring::digest::digest(&ring::digest::SHA256, cert).as_ref())
+}
+
+/// Formats the DER-encoded object identifier `oid` in dotted form
+fn dotted_oid(oid: &[u8]) -> Option<String> {
+    if oid.last()? & 0x80 != 0 {
+        return None;
+    }
+    let mut ids = Vec::new();
+    let mut id = 0u64;
+    for b in oid {
+        id = id.checked_mul(128)? | u64::from(b & 0x7f);
+        if b & 0x80 == 0 {
+            ids.push(id);
+            id = 0;
+        }
+    }
+    // The first identifier encodes the first two arcs
+    let (first, second) = match ids[0] {
+        i @ 0..=39 => (0, i),
+        i @ 40..=79 => (1, i - 40),
+        i => (2, i - 80),
+    };
+    let arcs = [first, second]
+        .iter()
+        .chain(&ids[1..])
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>();
+    Some(arcs.join("."))
+}
+
+/// Returns the short name RFC 4514 gives to the attribute of DER-encoded
+/// object identifier `oid`, if any
+fn attribute_name(oid: &[u8]) -> Option<&'static str> {
+    Some(match oid {
+        b"\x55\x04\x03" => "CN",
+        b"\x55\x04\x06" => "C",
+        b"\x55\x04\x07" => "L",
+        b"\x55\x04\x08" => "ST",
+        b"\x55\x04\x09" => "STREET",
+        b"\x55\x04\x0a" => "O",
+        b"\x55\x04\x0b" => "OU",
+        b"\x09\x92\x26\x89\x93\xf2\x2c\x64\x01\x01" => "UID",
+        b"\x09\x92\x26\x89\x93\xf2\x2c\x64\x01\x19" => "DC",
+        _ => return None,
+    })
+}
+
+/// Decodes the value of an attribute, if it is a string
+fn string_value(value: &DerElement<'_>) -> Option<String> {
+    match value.tag {
+        // UTF8String, PrintableString and IA5String
+        0x0c | 0x13 | 0x16 => String::from_utf8(value.contents.to_vec()).ok(),
+        // TeletexString, in practice Latin-1
+        0x14 => Some(value.contents.iter().map(|b| *b as char).collect()),
+        // BMPString
+        0x1e => {
+            let units = value
+                .contents
+                .chunks(2)
+                .map(|c| (c.len() == 2).then(|| u16::from_be_bytes([c[0], c[1]])))
+                .collect::<Option<Vec<_>>>()?;
+            String::from_utf16(&units).ok()
+        }
+        _ => None,
+    }
+}
+
+/// Escapes `value` for use in a RFC 4514 string
+fn escape_value(value: &str) -> String {
+    let mut res = String::with_capacity(value.len());
+    for (i, c) in value.char_indices() {
+        let special = matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';')
+            || (i == 0 && (c == '#' || c == ' '))
+            || (i + 1 == value.len() && c == ' ');
+        if special {
+            res.push('\\');
+        }
+        res.push(c);
+    }
+    res
+}
+
+/// Formats the subject name of the certificate `cert` as a RFC 4514 string,
+/// eg. `CN=mx.example.org,O=Example`
+pub fn subject_name(cert: &[u8]) -> Option<String> {
+    let name = der_element(subject(cert)?)?;
+    let mut rdns = Vec::new();
+    let mut rest = name.contents;
+    while !rest.is_empty() {
+        let rdn = der_element(rest)?;
+        rest = rdn.rest;
+        let mut attributes = Vec::new();
+        let mut attribute_rest = rdn.contents;
+        while !attribute_rest.is_empty() {
+            let attribute = der_element(attribute_rest)?;
+            attribute_rest = attribute.rest;
+            let oid = der_element(attribute.contents)?;
+            let value = der_element(oid.rest)?;
+            let name = match attribute_name(oid.contents) {
+                Some(name) => name.to_owned(),
+                None => dotted_oid(oid.contents)?,
+            };
+            let value = match string_value(&value) {
+                Some(v) => escape_value(&v),
+                None => format!("#{}", hex(value.whole)),
+            };
+            attributes.push(format!("{}={}", name, value));
+        }
+        rdns.push(attributes.join("+"));
+    }
+    // RFC 4514 starts with the last RDN
+    rdns.reverse();
+    Some(rdns.join(","))
+}
+
+/// Subject alternative name of a certificate
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AltName {
+    Dns(String),
+    Email(String),
+    Uri(String),
+    Ip(IpAddr),
+}
+
+impl fmt::Display for AltName {
+    /// Formats the name the way OpenSSL configuration files do, eg.
+    /// `DNS:mx.example.org`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AltName::Dns(n) => write!(f, "DNS:{}", n),
+            AltName::Email(n) => write!(f, "email:{}", n),
+            AltName::Uri(n) => write!(f, "URI:{}", n),
+            AltName::Ip(n) => write!(f, "IP:{}", n),
+        }
+    }
+}
+
+/// Returns the subject alternative names of the certificate `cert`, leaving
+/// out the ones that are not DNS names, email addresses, URIs nor IP
+/// addresses
+pub fn alt_names(cert: &[u8]) -> Option<Vec<AltName>> {
+    // After the SubjectPublicKeyInfo come the optional unique identifiers, and
+    // then the optional extensions
+    let mut rest = tbs_field(cert, 5)?.rest;
+    let extensions = loop {
+        let field = match der_element(rest) {
+            Some(field) => field,
+            None => return Some(Vec::new()),
+        };
+        if field.tag == 0xa3 {
+            break der_element(field.contents)?;
+        }
+        rest = field.rest;
+    };
+
+    let mut rest = extensions.contents;
+    while !rest.is_empty() {
+        let extension = der_element(rest)?;
+        rest = extension.rest;
+        let oid = der_element(extension.contents)?;
+        if oid.contents != b"\x55\x1d\x11" {
+            continue;
+        }
+        let mut value = der_element(oid.rest)?;
+        // Skip the criticality
+        if value.tag == 0x01 {
+            value = der_element(value.rest)?;
+        }
+        let names = der_element(value.contents)?;
+
+        let mut res = Vec::new();
+        let mut rest = names.contents;
+        while !rest.is_empty() {
+            let name = der_element(rest)?;
+            rest = name.rest;
+            let text = || String::from_utf8(name.contents.to_vec()).ok();
+            let name = match (name.tag, name.contents.len()) {
+                (0x81, _) => text().map(AltName::Email),
+                (0x82, _) => text().map(AltName::Dns),
+                (0x86, _) => text().map(AltName::Uri),
+                (0x87, 4) => {
+                    let mut ip = [0; 4];
+                    ip.copy_from_slice(name.contents);
+                    Some(AltName::Ip(Ipv4Addr::from(ip).into()))
+                }
+                (0x87, 16) => {
+                    let mut ip = [0; 16];
+                    ip.copy_from_slice(name.contents);
+                    Some(AltName::Ip(Ipv6Addr::from(ip).into()))
+                }
+                _ => None,
+            };
+            res.extend(name);
+        }
+        return Some(res);
+    }
+    Some(Vec::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    const CERT: &[u8] = include_bytes!("../res/ee.der");
+    const SPKI: &[u8] = include_bytes!("../res/ee-spki.der");
+    const CLIENT_CERT: &[u8] = include_bytes!("../res/client.der");
+
+    #[test]
+    fn spki_extraction() {
+        assert_eq!(spki(CERT), Some(SPKI));
+        assert_eq!(spki(&CERT[..100]), None);
+        assert_eq!(spki(SPKI), None);
+    }
+
+    #[test]
+    fn certificate_fields() {
+        let name = b"\x30\x19\x31\x17\x30\x15\x06\x03\x55\x04\x03\x0c\x0emx.example.com";
+        assert_eq!(issuer(CERT), Some(&name[..]));
+        assert_eq!(subject(CERT), Some(&name[..]));
+        assert_eq!(
+            not_after(CERT),
+            Some(Utc.ymd(2126, 9, 24).and_hms(18, 54, 53))
+        );
+        assert_eq!(not_after(&CERT[..100]), None);
+
+        let utc_time = der_element(b"\x17\x0d491231235959Z").unwrap();
+        assert_eq!(
+            parse_time(&utc_time),
+            Some(Utc.ymd(2049, 12, 31).and_hms(23, 59, 59))
+        );
+        let utc_time = der_element(b"\x17\x0d500101000000Z").unwrap();
+        assert_eq!(
+            parse_time(&utc_time),
+            Some(Utc.ymd(1950, 1, 1).and_hms(0, 0, 0))
+        );
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(subject_name(CERT).as_deref(), Some("CN=mx.example.com"));
+        assert_eq!(
+            subject_name(CLIENT_CERT).as_deref(),
+            Some("CN=mx.partner.example,O=Example\\, Inc.,C=FR")
+        );
+        assert_eq!(alt_names(CERT), Some(Vec::new()));
+        let names = alt_names(CLIENT_CERT)
+            .unwrap()
+            .iter()
+            .map(|n| n.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names, [
+            "DNS:mx.partner.example",
+            "DNS:*.partner.example",
+            "email:postmaster@partner.example",
+            "IP:192.0.2.1",
+            "IP:2001:db8::1",
+            "URI:https://partner.example/",
+        ]);
+        assert_eq!(
+            sha256_fingerprint(CLIENT_CERT),
+            "a56968fe15722c143b91a6222e5dad45469b59a7aada191e65a3df379e829509"
+        );
+    }
+
+    #[test]
+    fn oids() {
+        assert_eq!(
+            dotted_oid(b"\x2a\x86\x48\x86\xf7\x0d\x01\x09\x01").as_deref(),
+            Some("1.2.840.113549.1.9.1")
+        );
+        assert_eq!(dotted_oid(b"\x55\x04\x03").as_deref(), Some("2.5.4.3"));
+        assert_eq!(dotted_oid(b"\x2a\x86"), None);
+        assert_eq!(dotted_oid(b""), None);
+    }
+}
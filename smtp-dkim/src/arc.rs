@@ -0,0 +1,714 @@
+use std::{sync::Arc, time::SystemTime};
+
+use tracing::trace;
+
+use crate::{
+    canonicalization::{canonicalize_header, Canonicalization},
+    message::{field_name, signature_field_data, HeaderSection},
+    signature::{
+        parse_algorithm, parse_base64, parse_instance, Algorithm, Signature, SignatureError,
+        MAX_ARC_INSTANCE,
+    },
+    signer::{sign_field, signed_names, SignError, SigningConfig},
+    tag,
+    verifier::{check_key, fetch_key, verify_signature, BodyHasher},
+    ArcResult, ArcStatus, Lookup,
+};
+
+/// A parsed `ARC-Seal` header field, see RFC 8617 §4.1.3
+struct Seal {
+    algorithm: Algorithm,
+    signature: Vec<u8>,
+    /// `cv=` tag
+    chain: ArcStatus,
+    domain: String,
+    selector: String,
+}
+
+impl Seal {
+    fn parse(value: &str) -> Result<Seal, SignatureError> {
+        let tags = tag::parse_tag_list(value)?;
+        let required = |name: &'static str| -> Result<&str, SignatureError> {
+            tag::get(&tags, name).ok_or(SignatureError::MissingTag(name))
+        };
+        // Seals cover the ARC sets, not a list of header fields
+        if tag::get(&tags, "h").is_some() {
+            return Err(SignatureError::InvalidValue("h"));
+        }
+        let chain = match required("cv")? {
+            "none" => ArcStatus::None,
+            "pass" => ArcStatus::Pass,
+            "fail" => ArcStatus::Fail,
+            _ => return Err(SignatureError::InvalidValue("cv")),
+        };
+        let domain = required("d")?.to_owned();
+        let selector = required("s")?.to_owned();
+        if domain.is_empty() || selector.is_empty() {
+            return Err(SignatureError::InvalidValue(if domain.is_empty() {
+                "d"
+            } else {
+                "s"
+            }));
+        }
+        Ok(Seal {
+            algorithm: parse_algorithm(required("a")?)?,
+            signature: parse_base64("b", required("b")?)?,
+            chain,
+            domain,
+            selector,
+        })
+    }
+}
+
+/// Indices of the header fields of one ARC set in the header section
+struct ArcSet {
+    aar: usize,
+    ams: usize,
+    seal: usize,
+}
+
+/// The ARC sets of a message, ordered by instance
+struct Chain {
+    sets: Vec<ArcSet>,
+    seals: Vec<Seal>,
+}
+
+fn field_value(raw: &[u8]) -> Option<&str> {
+    let raw = std::str::from_utf8(raw).ok()?;
+    raw.split_once(':').map(|(_, v)| v)
+}
+
+/// Returns the instance of an `ARC-Authentication-Results` header field, which
+/// is the `i=` tag its value starts with
+fn aar_instance(value: &str) -> Option<u32> {
+    let (i, _) = value.split_once(';')?;
+    let (name, i) = i.split_once('=')?;
+    if tag::trim_fws(name) != "i" {
+        return None;
+    }
+    parse_instance(tag::trim_fws(i)).ok()
+}
+
+/// Collects the ARC sets of a message given its header fields, checking that
+/// there is exactly one of each ARC header field for each instance from 1 up
+/// to the highest one, see RFC 8617 §5.1.1
+fn parse_chain(fields: &[&[u8]]) -> Result<Chain, String> {
+    let mut sets: Vec<[Option<usize>; 3]> = Vec::new();
+    for (i, raw) in fields.iter().enumerate() {
+        let name = field_name(raw);
+        let kind = if name.eq_ignore_ascii_case(b"arc-authentication-results") {
+            0
+        } else if name.eq_ignore_ascii_case(b"arc-message-signature") {
+            1
+        } else if name.eq_ignore_ascii_case(b"arc-seal") {
+            2
+        } else {
+            continue;
+        };
+        let name = String::from_utf8_lossy(name);
+        let value = field_value(raw).ok_or_else(|| format!("{} is not valid UTF-8", name))?;
+        let instance = if kind == 0 {
+            aar_instance(value)
+        } else {
+            tag::parse_tag_list(value)
+                .ok()
+                .and_then(|tags| tag::get(&tags, "i").and_then(|i| parse_instance(i).ok()))
+        };
+        let instance = instance.ok_or_else(|| format!("{} has no valid instance", name))? as usize;
+        if sets.len() < instance {
+            sets.resize(instance, [None; 3]);
+        }
+        let slot = &mut sets[instance - 1][kind];
+        if slot.is_some() {
+            return Err(format!("duplicate {} for instance {}", name, instance));
+        }
+        *slot = Some(i);
+    }
+
+    let mut chain = Chain {
+        sets: Vec::with_capacity(sets.len()),
+        seals: Vec::with_capacity(sets.len()),
+    };
+    for (i, set) in sets.into_iter().enumerate() {
+        let set = match set {
+            [Some(aar), Some(ams), Some(seal)] => ArcSet { aar, ams, seal },
+            _ => return Err(format!("ARC set {} is incomplete", i + 1)),
+        };
+        let seal = field_value(fields[set.seal])
+            .map(Seal::parse)
+            .unwrap_or(Err(SignatureError::InvalidValue("b")))
+            .map_err(|e| format!("ARC-Seal i={} is invalid: {}", i + 1, e))?;
+        chain.sets.push(set);
+        chain.seals.push(seal);
+    }
+    Ok(chain)
+}
+
+impl Chain {
+    /// Checks the chain validation statuses recorded by the seals, see RFC
+    /// 8617 §5.2
+    fn check_cv(&self) -> Result<(), String> {
+        if let Some(seal) = self.seals.last() {
+            if seal.chain == ArcStatus::Fail {
+                return Err(String::from("ARC chain has already failed"));
+            }
+        }
+        for (i, seal) in self.seals.iter().enumerate() {
+            let expected = if i == 0 {
+                ArcStatus::None
+            } else {
+                ArcStatus::Pass
+            };
+            if seal.chain != expected {
+                return Err(format!("ARC-Seal i={} has an invalid cv= tag", i + 1));
+            }
+        }
+        Ok(())
+    }
+
+    fn sealers(&self) -> Vec<String> {
+        self.seals.iter().map(|s| s.domain.clone()).collect()
+    }
+}
+
+/// Returns the data covered by an `ARC-Seal`, save for the seal itself, given
+/// the header fields of the preceding ARC sets and the
+/// `ARC-Authentication-Results` and `ARC-Message-Signature` of its own set,
+/// see RFC 8617 §5.1.1
+fn seal_data(fields: &[&[u8]], previous: &[ArcSet], aar: &[u8], ams: &[u8]) -> Vec<u8> {
+    let canon = Canonicalization::Relaxed;
+    let mut data = Vec::new();
+    for set in previous {
+        canonicalize_header(canon, fields[set.aar], &mut data);
+        canonicalize_header(canon, fields[set.ams], &mut data);
+        canonicalize_header(canon, fields[set.seal], &mut data);
+    }
+    canonicalize_header(canon, aar, &mut data);
+    canonicalize_header(canon, ams, &mut data);
+    data
+}
+
+/// Verifies `signature` over `data` with the key published by `domain` under
+/// `selector`, returning the reason for the failure if any
+async fn verify_with_key<L: Lookup>(
+    lookup: &L,
+    selector: &str,
+    domain: &str,
+    algorithm: Algorithm,
+    data: &[u8],
+    signature: &[u8],
+) -> Result<(), String> {
+    let key = fetch_key(lookup, selector, domain)
+        .await
+        .map_err(|(_, reason)| reason)?;
+    check_key(&key, algorithm)?;
+    let public_key = key
+        .ring_public_key()
+        .map_err(|_| String::from("invalid public key"))?;
+    if verify_signature(algorithm, public_key, data, signature) {
+        Ok(())
+    } else {
+        Err(String::from("signature did not verify"))
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+struct PendingChain {
+    chain: Chain,
+    /// `ARC-Message-Signature` of the highest instance, which is the only one
+    /// that gets verified
+    ams: Signature,
+    hasher: BodyHasher,
+}
+
+/// Streaming ARC chain validator, see RFC 8617 §5.2
+///
+/// The whole message, headers included, is to be passed through `update`,
+/// after which `finish` returns the validation status of its ARC chain.
+pub struct ArcVerifier {
+    headers: HeaderSection,
+    /// Set once the header section is complete, to the result if it is
+    /// already known
+    chain: Option<Result<PendingChain, ArcResult>>,
+}
+
+impl Default for ArcVerifier {
+    fn default() -> ArcVerifier {
+        ArcVerifier::new()
+    }
+}
+
+fn fail(reason: String, sealers: Vec<String>) -> ArcResult {
+    ArcResult {
+        status: ArcStatus::Fail,
+        reason: Some(reason),
+        sealers,
+    }
+}
+
+impl ArcVerifier {
+    pub fn new() -> ArcVerifier {
+        ArcVerifier {
+            headers: HeaderSection::new(),
+            chain: None,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let was_complete = self.headers.is_complete();
+        if let Some(body) = self.headers.update(data) {
+            if !was_complete {
+                self.parse_chain();
+            }
+            if let Some(Ok(p)) = &mut self.chain {
+                p.hasher.update(&body);
+            }
+        }
+    }
+
+    fn parse_chain(&mut self) {
+        let fields = self.headers.fields().collect::<Vec<_>>();
+        let chain = match parse_chain(&fields) {
+            Ok(chain) => chain,
+            Err(reason) => {
+                trace!(reason = %reason, "Malformed ARC chain");
+                self.chain = Some(Err(fail(reason, Vec::new())));
+                return;
+            }
+        };
+        let last = match chain.sets.last() {
+            Some(set) => set,
+            None => {
+                self.chain = Some(Err(ArcResult {
+                    status: ArcStatus::None,
+                    reason: None,
+                    sealers: Vec::new(),
+                }));
+                return;
+            }
+        };
+        if let Err(reason) = chain.check_cv() {
+            self.chain = Some(Err(fail(reason, chain.sealers())));
+            return;
+        }
+        let ams = field_value(fields[last.ams])
+            .map(Signature::parse_arc)
+            .unwrap_or(Err(SignatureError::InvalidValue("b")));
+        let ams = match ams {
+            Ok((_, ams)) => ams,
+            Err(e) => {
+                let reason = format!(
+                    "ARC-Message-Signature i={} is invalid: {}",
+                    chain.sets.len(),
+                    e
+                );
+                self.chain = Some(Err(fail(reason, chain.sealers())));
+                return;
+            }
+        };
+        if ams
+            .signed_headers
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case("arc-seal"))
+        {
+            let reason = String::from("ARC-Message-Signature covers ARC-Seal");
+            self.chain = Some(Err(fail(reason, chain.sealers())));
+            return;
+        }
+        self.chain = Some(Ok(PendingChain {
+            hasher: BodyHasher::new(ams.body_canonicalization, ams.body_length),
+            chain,
+            ams,
+        }));
+    }
+
+    /// Returns the validation status of the ARC chain of the message
+    pub async fn finish<L: Lookup>(mut self, lookup: &L) -> ArcResult {
+        if self.headers.is_too_big() {
+            trace!("Header section is too big for ARC validation");
+            return fail(String::from("header section is too big"), Vec::new());
+        }
+        if !self.headers.is_complete() {
+            // The message has no body
+            self.headers.finish();
+            self.parse_chain();
+        }
+        let res = match self.chain.take() {
+            Some(Ok(p)) => self.verify(lookup, p).await,
+            Some(Err(res)) => res,
+            None => fail(String::from("header section is incomplete"), Vec::new()),
+        };
+        trace!(result = ?res, "Validated ARC chain");
+        res
+    }
+
+    async fn verify<L: Lookup>(&self, lookup: &L, p: PendingChain) -> ArcResult {
+        let fields = self.headers.fields().collect::<Vec<_>>();
+        let sets = &p.chain.sets;
+        let sealers = p.chain.sealers();
+        let instance = sets.len();
+
+        let ams = &p.ams;
+        let (body_hash, body_length) = p.hasher.finish();
+        if matches!(ams.body_length, Some(l) if l > body_length) {
+            let reason = format!("ARC-Message-Signature i={}: body is too short", instance);
+            return fail(reason, sealers);
+        }
+        if body_hash != ams.body_hash {
+            let reason = format!(
+                "ARC-Message-Signature i={}: body hash did not verify",
+                instance
+            );
+            return fail(reason, sealers);
+        }
+        let canon = ams.header_canonicalization;
+        let mut data = Vec::new();
+        self.headers
+            .signed_data(&ams.signed_headers, canon, &mut data);
+        let raw = String::from_utf8_lossy(fields[sets[instance - 1].ams]);
+        signature_field_data(&raw, canon, &mut data);
+        let verified = verify_with_key(
+            lookup,
+            &ams.selector,
+            &ams.domain,
+            ams.algorithm,
+            &data,
+            &ams.signature,
+        )
+        .await;
+        if let Err(reason) = verified {
+            let reason = format!("ARC-Message-Signature i={}: {}", instance, reason);
+            return fail(reason, sealers);
+        }
+
+        for (i, seal) in p.chain.seals.iter().enumerate().rev() {
+            let set = &sets[i];
+            let mut data = seal_data(&fields, &sets[..i], fields[set.aar], fields[set.ams]);
+            let raw = String::from_utf8_lossy(fields[set.seal]);
+            signature_field_data(&raw, Canonicalization::Relaxed, &mut data);
+            let verified = verify_with_key(
+                lookup,
+                &seal.selector,
+                &seal.domain,
+                seal.algorithm,
+                &data,
+                &seal.signature,
+            )
+            .await;
+            if let Err(reason) = verified {
+                return fail(format!("ARC-Seal i={}: {}", i + 1, reason), sealers);
+            }
+        }
+
+        ArcResult {
+            status: ArcStatus::Pass,
+            reason: None,
+            sealers,
+        }
+    }
+}
+
+/// Streaming ARC sealer, see RFC 8617 §5.1
+///
+/// The whole message, headers included, is to be passed through `update`,
+/// after which `finish` returns the ARC set to prepend to the message.
+pub struct Sealer {
+    config: Arc<SigningConfig>,
+    headers: HeaderSection,
+    hasher: BodyHasher,
+}
+
+impl Sealer {
+    pub fn new(config: Arc<SigningConfig>) -> Sealer {
+        let hasher = BodyHasher::new(config.body_canonicalization, None);
+        Sealer {
+            config,
+            headers: HeaderSection::new(),
+            hasher,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        if let Some(body) = self.headers.update(data) {
+            self.hasher.update(&body);
+        }
+    }
+
+    /// Returns the `ARC-Seal`, `ARC-Message-Signature` and
+    /// `ARC-Authentication-Results` header fields, in this order and
+    /// including their final CRLF
+    ///
+    /// `chain` is the status of the validation of the existing ARC chain, and
+    /// `authres` the authentication results to record, formatted like the
+    /// part of an `Authentication-Results` header field value that follows
+    /// the `:`.
+    pub fn finish(self, chain: ArcStatus, authres: &str) -> Result<Vec<u8>, SignError> {
+        self.finish_at(chain, authres, unix_now())
+    }
+
+    fn finish_at(
+        mut self,
+        chain: ArcStatus,
+        authres: &str,
+        now: u64,
+    ) -> Result<Vec<u8>, SignError> {
+        self.headers.finish();
+        if self.headers.is_too_big() {
+            return Err(SignError::HeadersTooBig);
+        }
+        let cfg = &*self.config;
+        let fields = self.headers.fields().collect::<Vec<_>>();
+        let existing = parse_chain(&fields).map_err(|_| SignError::InvalidArcChain)?;
+        // RFC 8617 §5.1.1: chains that have failed must not be sealed further
+        if matches!(existing.seals.last(), Some(s) if s.chain == ArcStatus::Fail) {
+            return Err(SignError::FailedArcChain);
+        }
+        let instance = existing.sets.len() as u32 + 1;
+        if instance > MAX_ARC_INSTANCE {
+            return Err(SignError::TooManyArcSets);
+        }
+        let cv = match (instance, chain) {
+            (1, _) => ArcStatus::None,
+            (_, ArcStatus::Pass) => ArcStatus::Pass,
+            _ => ArcStatus::Fail,
+        };
+        let (body_hash, _) = self.hasher.finish();
+        let algorithm = cfg.key.algorithm().name();
+
+        let aar = format!(
+            "ARC-Authentication-Results: i={}; {}\r\n",
+            instance,
+            authres.trim()
+        );
+
+        let names = signed_names(&self.headers, &cfg.signed_headers)
+            .into_iter()
+            .filter(|n| !n.eq_ignore_ascii_case("arc-seal"))
+            .collect::<Vec<_>>();
+        let tags = [
+            format!("i={};", instance),
+            format!("a={};", algorithm),
+            format!(
+                "c={}/{};",
+                cfg.header_canonicalization.name(),
+                cfg.body_canonicalization.name()
+            ),
+            format!("d={};", cfg.domain),
+            format!("s={};", cfg.selector),
+            format!("t={};", now),
+            format!("h={};", names.join(":")),
+            format!("bh={};", base64::encode(&body_hash)),
+            String::from("b="),
+        ];
+        let mut data = Vec::new();
+        self.headers
+            .signed_data(&names, cfg.header_canonicalization, &mut data);
+        let ams = sign_field(
+            "ARC-Message-Signature",
+            &tags,
+            &cfg.key,
+            cfg.header_canonicalization,
+            data,
+        )?;
+
+        let tags = [
+            format!("i={};", instance),
+            format!("a={};", algorithm),
+            format!("t={};", now),
+            format!("cv={};", cv.name()),
+            format!("d={};", cfg.domain),
+            format!("s={};", cfg.selector),
+            String::from("b="),
+        ];
+        let data = seal_data(&fields, &existing.sets, aar.as_bytes(), ams.as_bytes());
+        let seal = sign_field("ARC-Seal", &tags, &cfg.key, Canonicalization::Relaxed, data)?;
+
+        Ok([seal, ams, aar].concat().into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use ring::signature::KeyPair;
+
+    use super::*;
+    use crate::{LookupError, SigningKey};
+
+    const MESSAGE: &str = concat!(
+        "From: Joe SixPack <joe@football.example.com>\r\n",
+        "To: list@lists.example.org\r\n",
+        "Subject: Is dinner ready?\r\n",
+        "\r\n",
+        "Hi.\r\n",
+        "\r\n",
+        "We lost the game.  Are you hungry yet?\r\n",
+    );
+
+    const NOW: u64 = 1_600_000_000;
+
+    struct StubLookup(HashMap<String, String>);
+
+    #[async_trait::async_trait]
+    impl Lookup for StubLookup {
+        async fn lookup_txt(&self, name: &str) -> Result<Vec<String>, LookupError> {
+            Ok(self.0.get(name).into_iter().cloned().collect())
+        }
+    }
+
+    fn config(key: &str, domain: &str) -> SigningConfig {
+        SigningConfig {
+            domain: String::from(domain),
+            selector: String::from("arc"),
+            key: SigningKey::from_pem(key).unwrap(),
+            signed_headers: vec![String::from("Subject"), String::from("To")],
+            header_canonicalization: Canonicalization::Relaxed,
+            body_canonicalization: Canonicalization::Relaxed,
+        }
+    }
+
+    fn key_record(key: &SigningKey) -> String {
+        let (k, p) = match key {
+            SigningKey::Rsa(k) => ("rsa", k.public_key().as_ref()),
+            SigningKey::Ed25519(k) => ("ed25519", k.public_key().as_ref()),
+        };
+        format!("v=DKIM1; k={}; p={}", k, base64::encode(p))
+    }
+
+    fn seal(
+        msg: &[u8],
+        config: &Arc<SigningConfig>,
+        chain: ArcStatus,
+    ) -> Result<Vec<u8>, SignError> {
+        let mut sealer = Sealer::new(config.clone());
+        for chunk in msg.chunks(7) {
+            sealer.update(chunk);
+        }
+        let set = sealer.finish_at(chain, "mx.example.org; spf=pass", NOW)?;
+        Ok([set, msg.to_vec()].concat())
+    }
+
+    fn validate(msg: &[u8], lookup: &StubLookup) -> ArcResult {
+        let mut verifier = ArcVerifier::new();
+        for chunk in msg.chunks(5) {
+            verifier.update(chunk);
+        }
+        futures::executor::block_on(verifier.finish(lookup))
+    }
+
+    #[test]
+    fn sealed_chains_validate() {
+        let configs = [
+            Arc::new(config(
+                include_str!("../res/rsa-pkcs1.pem"),
+                "lists.example.org",
+            )),
+            Arc::new(config(include_str!("../res/ed25519.pem"), "example.net")),
+        ];
+        let lookup = StubLookup(
+            configs
+                .iter()
+                .map(|c| (format!("arc._domainkey.{}", c.domain), key_record(&c.key)))
+                .collect(),
+        );
+
+        assert_eq!(
+            validate(MESSAGE.as_bytes(), &lookup).status,
+            ArcStatus::None
+        );
+
+        let once = seal(MESSAGE.as_bytes(), &configs[0], ArcStatus::None).unwrap();
+        let text = String::from_utf8(once.clone()).unwrap();
+        println!("Sealed once:\n{}", text);
+        assert!(text.starts_with("ARC-Seal: i=1; a=rsa-sha256;"));
+        assert!(text.contains("cv=none;"));
+        assert!(text.contains("h=Subject:To:From;"));
+        assert!(text.contains("ARC-Authentication-Results: i=1; mx.example.org; spf=pass\r\n"));
+        assert_eq!(validate(&once, &lookup), ArcResult {
+            status: ArcStatus::Pass,
+            reason: None,
+            sealers: vec![String::from("lists.example.org")],
+        });
+
+        // Modifications made after the first hop are covered by the second
+        // set only
+        let modified = String::from_utf8(once)
+            .unwrap()
+            .replace("Subject: Is", "Subject: [list] Is");
+        let twice = seal(modified.as_bytes(), &configs[1], ArcStatus::Pass).unwrap();
+        assert!(String::from_utf8_lossy(&twice).contains("cv=pass;"));
+        let res = validate(&twice, &lookup);
+        assert_eq!(res.status, ArcStatus::Pass, "{:?}", res);
+        assert_eq!(res.sealers, vec!["lists.example.org", "example.net"]);
+
+        let tests: &[(&str, &str, &str)] = &[
+            (
+                "hungry",
+                "thirsty",
+                "ARC-Message-Signature i=2: body hash did not verify",
+            ),
+            (
+                "dinner",
+                "lunch",
+                "ARC-Message-Signature i=2: signature did not verify",
+            ),
+            // Seals are verified from the most recent one, which covers all
+            // the previous sets
+            (
+                "i=1; mx.example.org; spf=pass",
+                "i=1; mx.example.org; spf=fail",
+                "ARC-Seal i=2: signature did not verify",
+            ),
+            ("cv=pass", "cv=none", "ARC-Seal i=2 has an invalid cv= tag"),
+            ("ARC-Seal: i=1", "X-Seal: i=1", "ARC set 1 is incomplete"),
+            (
+                "ARC-Seal: i=1",
+                "ARC-Seal: i=2",
+                "duplicate ARC-Seal for instance 2",
+            ),
+        ];
+        for (from, to, reason) in tests {
+            println!("Testing replacing {:?} with {:?}", from, to);
+            let msg = String::from_utf8_lossy(&twice).replacen(from, to, 1);
+            let res = validate(msg.as_bytes(), &lookup);
+            assert_eq!(res.status, ArcStatus::Fail);
+            assert_eq!(res.reason.as_deref(), Some(*reason));
+        }
+    }
+
+    #[test]
+    fn sealing_refusals() {
+        let config = Arc::new(config(include_str!("../res/ed25519.pem"), "example.net"));
+        let once = seal(MESSAGE.as_bytes(), &config, ArcStatus::None).unwrap();
+
+        // A failed validation is recorded, after which the chain is closed
+        let failed = seal(&once, &config, ArcStatus::Fail).unwrap();
+        assert!(String::from_utf8_lossy(&failed).contains("cv=fail;"));
+        assert!(matches!(
+            seal(&failed, &config, ArcStatus::Pass),
+            Err(SignError::FailedArcChain)
+        ));
+
+        let broken = String::from_utf8_lossy(&once).replacen("ARC-Seal: i=1", "ARC-Seal: i=3", 1);
+        assert!(matches!(
+            seal(broken.as_bytes(), &config, ArcStatus::Pass),
+            Err(SignError::InvalidArcChain)
+        ));
+
+        let mut msg = MESSAGE.as_bytes().to_vec();
+        for _ in 0..MAX_ARC_INSTANCE {
+            msg = seal(&msg, &config, ArcStatus::Pass).unwrap();
+        }
+        assert!(matches!(
+            seal(&msg, &config, ArcStatus::Pass),
+            Err(SignError::TooManyArcSets)
+        ));
+    }
+}
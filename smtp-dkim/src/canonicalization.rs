@@ -0,0 +1,211 @@
+use std::str::FromStr;
+
+/// Canonicalization algorithm, see RFC 6376 §3.4
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Canonicalization {
+    Simple,
+    Relaxed,
+}
+
+impl Canonicalization {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Canonicalization::Simple => "simple",
+            Canonicalization::Relaxed => "relaxed",
+        }
+    }
+}
+
+impl FromStr for Canonicalization {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Canonicalization, ()> {
+        match s {
+            "simple" => Ok(Canonicalization::Simple),
+            "relaxed" => Ok(Canonicalization::Relaxed),
+            _ => Err(()),
+        }
+    }
+}
+
+fn is_wsp(b: u8) -> bool {
+    b == b' ' || b == b'\t'
+}
+
+/// Canonicalizes a header field, `raw` being the whole field including its
+/// name and its final CRLF
+pub fn canonicalize_header(c: Canonicalization, raw: &[u8], out: &mut Vec<u8>) {
+    match c {
+        Canonicalization::Simple => out.extend_from_slice(raw),
+        Canonicalization::Relaxed => {
+            let colon = raw.iter().position(|&b| b == b':').unwrap_or(raw.len());
+            let name = &raw[..colon];
+            let name_end = name.iter().rposition(|&b| !is_wsp(b)).map_or(0, |i| i + 1);
+            out.extend(name[..name_end].iter().map(|b| b.to_ascii_lowercase()));
+            out.push(b':');
+            let mut pending_wsp = false;
+            let mut seen_content = false;
+            for &b in raw.get(colon + 1..).unwrap_or(&[]) {
+                match b {
+                    // Unfold
+                    b'\r' | b'\n' => (),
+                    b if is_wsp(b) => pending_wsp = true,
+                    b => {
+                        if pending_wsp && seen_content {
+                            out.push(b' ');
+                        }
+                        pending_wsp = false;
+                        seen_content = true;
+                        out.push(b);
+                    }
+                }
+            }
+            out.extend_from_slice(b"\r\n");
+        }
+    }
+}
+
+/// Streaming body canonicalizer, see RFC 6376 §3.4.3 and §3.4.4
+pub struct BodyCanonicalizer {
+    canon: Canonicalization,
+    /// Number of empty lines not output yet, as they are dropped if they
+    /// end the body
+    pending_crlfs: usize,
+    pending_wsp: bool,
+    pending_cr: bool,
+    line_has_content: bool,
+    wrote_anything: bool,
+}
+
+impl BodyCanonicalizer {
+    pub fn new(canon: Canonicalization) -> BodyCanonicalizer {
+        BodyCanonicalizer {
+            canon,
+            pending_crlfs: 0,
+            pending_wsp: false,
+            pending_cr: false,
+            line_has_content: false,
+            wrote_anything: false,
+        }
+    }
+
+    /// Canonicalizes `input`, appending the result to `out`
+    pub fn update(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        for &b in input {
+            if self.pending_cr {
+                self.pending_cr = false;
+                if b == b'\n' {
+                    self.end_line(out);
+                    continue;
+                }
+                self.content(b'\r', out);
+            }
+            match b {
+                b'\r' => self.pending_cr = true,
+                b if is_wsp(b) && self.canon == Canonicalization::Relaxed => {
+                    self.pending_wsp = true
+                }
+                b => self.content(b, out),
+            }
+        }
+    }
+
+    /// Canonicalizes the end of the body, appending the result to `out`
+    pub fn finish(mut self, out: &mut Vec<u8>) {
+        if self.pending_cr {
+            self.pending_cr = false;
+            self.content(b'\r', out);
+        }
+        if self.line_has_content {
+            self.end_line(out);
+        }
+        if !self.wrote_anything && self.canon == Canonicalization::Simple {
+            out.extend_from_slice(b"\r\n");
+        }
+    }
+
+    fn content(&mut self, b: u8, out: &mut Vec<u8>) {
+        for _ in 0..self.pending_crlfs {
+            out.extend_from_slice(b"\r\n");
+        }
+        self.pending_crlfs = 0;
+        if self.pending_wsp {
+            out.push(b' ');
+            self.pending_wsp = false;
+        }
+        out.push(b);
+        self.line_has_content = true;
+        self.wrote_anything = true;
+    }
+
+    fn end_line(&mut self, out: &mut Vec<u8>) {
+        // Relaxed canonicalization ignores whitespace at the end of lines
+        self.pending_wsp = false;
+        if self.line_has_content {
+            out.extend_from_slice(b"\r\n");
+            self.line_has_content = false;
+        } else {
+            self.pending_crlfs += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(c: Canonicalization, chunks: &[&str]) -> String {
+        let mut canon = BodyCanonicalizer::new(c);
+        let mut out = Vec::new();
+        for chunk in chunks {
+            canon.update(chunk.as_bytes(), &mut out);
+        }
+        canon.finish(&mut out);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn rfc6376_example() {
+        // See RFC 6376 §3.4.6
+        let headers = b"A: X\r\nB : Y\t\r\n\tZ  \r\n";
+        let mut out = Vec::new();
+        canonicalize_header(Canonicalization::Relaxed, &headers[..6], &mut out);
+        canonicalize_header(Canonicalization::Relaxed, &headers[6..], &mut out);
+        assert_eq!(out, b"a:X\r\nb:Y Z\r\n");
+        let mut out = Vec::new();
+        canonicalize_header(Canonicalization::Simple, &headers[6..], &mut out);
+        assert_eq!(out, &headers[6..]);
+
+        let b = " C \r\nD \t E\r\n\r\n\r\n";
+        assert_eq!(body(Canonicalization::Relaxed, &[b]), " C\r\nD E\r\n");
+        assert_eq!(body(Canonicalization::Simple, &[b]), " C \r\nD \t E\r\n");
+    }
+
+    #[test]
+    fn bodies() {
+        use Canonicalization::*;
+        let tests: &[(Canonicalization, &[&str], &str)] = &[
+            (Simple, &[], "\r\n"),
+            (Relaxed, &[], ""),
+            (Simple, &["\r\n\r\n"], "\r\n"),
+            (Relaxed, &["\r\n \r\n"], ""),
+            (Simple, &["a"], "a\r\n"),
+            (Relaxed, &["a  \t"], "a\r\n"),
+            (
+                Simple,
+                &["a\r", "\n\r", "\n", "b\r\n\r\n"],
+                "a\r\n\r\nb\r\n",
+            ),
+            (
+                Relaxed,
+                &["a \r", "\n  \r\nb\r", "\r\n"],
+                "a\r\n\r\nb\r\r\n",
+            ),
+            (Relaxed, &["\t x  y\r\n"], " x y\r\n"),
+        ];
+        for (c, chunks, out) in tests {
+            println!("Testing {:?} {:?}", c, chunks);
+            assert_eq!(body(*c, chunks), *out);
+        }
+    }
+}
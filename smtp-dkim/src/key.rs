@@ -0,0 +1,173 @@
+use crate::tag::{self, TagListError};
+
+#[derive(Debug, thiserror::Error)]
+pub enum KeyError {
+    #[error("Key record is not a valid tag list")]
+    TagList(#[from] TagListError),
+
+    #[error("Unsupported key record version ‘{0}’")]
+    UnsupportedVersion(String),
+
+    #[error("Key record is missing the public key")]
+    MissingKey,
+
+    #[error("Key has been revoked")]
+    Revoked,
+
+    #[error("Invalid public key data")]
+    InvalidKey,
+}
+
+/// A parsed DKIM key record, see RFC 6376 §3.6.1
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KeyRecord {
+    /// Acceptable hash algorithms, `None` meaning all of them
+    pub hash_algorithms: Option<Vec<String>>,
+    pub key_type: String,
+    /// Public key data, decoded from base64
+    pub public_key: Vec<u8>,
+    /// Service types, `None` meaning all of them
+    pub service_types: Option<Vec<String>>,
+    pub flags: Vec<String>,
+}
+
+fn colon_list(v: &str) -> Vec<String> {
+    v.split(':').map(|s| tag::trim_fws(s).to_owned()).collect()
+}
+
+impl KeyRecord {
+    pub fn parse(record: &str) -> Result<KeyRecord, KeyError> {
+        let tags = tag::parse_tag_list(record)?;
+        if let Some(v) = tag::get(&tags, "v") {
+            // The version tag, if present, must be first
+            if v != "DKIM1" || tags[0].0 != "v" {
+                return Err(KeyError::UnsupportedVersion(v.to_owned()));
+            }
+        }
+        let public_key = tag::get(&tags, "p").ok_or(KeyError::MissingKey)?;
+        let public_key = tag::strip_fws(public_key);
+        if public_key.is_empty() {
+            return Err(KeyError::Revoked);
+        }
+        let public_key = base64::decode(public_key).map_err(|_| KeyError::InvalidKey)?;
+        Ok(KeyRecord {
+            hash_algorithms: tag::get(&tags, "h").map(colon_list),
+            key_type: tag::get(&tags, "k").unwrap_or("rsa").to_owned(),
+            public_key,
+            service_types: tag::get(&tags, "s").map(colon_list),
+            flags: tag::get(&tags, "t").map(colon_list).unwrap_or_default(),
+        })
+    }
+
+    pub fn allows_hash(&self, hash: &str) -> bool {
+        match &self.hash_algorithms {
+            None => true,
+            Some(h) => h.iter().any(|h| h == hash),
+        }
+    }
+
+    pub fn allows_email(&self) -> bool {
+        match &self.service_types {
+            None => true,
+            Some(s) => s.iter().any(|s| s == "*" || s == "email"),
+        }
+    }
+
+    /// Whether the `s` flag is set, forbidding the identity to be a subdomain
+    /// of the signing domain
+    pub fn is_strict(&self) -> bool {
+        self.flags.iter().any(|f| f == "s")
+    }
+
+    /// Returns the public key in the format expected by `ring`
+    ///
+    /// RSA keys are stored as a `SubjectPublicKeyInfo`, from which the
+    /// `RSAPublicKey` is extracted, though some records directly contain the
+    /// `RSAPublicKey`
+    pub fn ring_public_key(&self) -> Result<&[u8], KeyError> {
+        match self.key_type.as_str() {
+            "rsa" => Ok(rsa_public_key_from_spki(&self.public_key).unwrap_or(&self.public_key)),
+            "ed25519" if self.public_key.len() == 32 => Ok(&self.public_key),
+            _ => Err(KeyError::InvalidKey),
+        }
+    }
+}
+
+/// Splits a DER value with tag `tag` out of `data`, returning its contents
+/// and what follows it
+fn der_read(data: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    if *data.first()? != tag {
+        return None;
+    }
+    let (len, rest) = match *data.get(1)? {
+        l if l < 0x80 => (l as usize, &data[2..]),
+        l => {
+            let n = (l & 0x7F) as usize;
+            if n == 0 || n > 4 || data.len() < 2 + n {
+                return None;
+            }
+            let len = data[2..2 + n]
+                .iter()
+                .fold(0usize, |acc, &b| (acc << 8) | b as usize);
+            (len, &data[2 + n..])
+        }
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some(rest.split_at(len))
+}
+
+fn rsa_public_key_from_spki(spki: &[u8]) -> Option<&[u8]> {
+    const SEQUENCE: u8 = 0x30;
+    const BIT_STRING: u8 = 0x03;
+    let (spki, _) = der_read(spki, SEQUENCE)?;
+    let (_algorithm, rest) = der_read(spki, SEQUENCE)?;
+    let (key, _) = der_read(rest, BIT_STRING)?;
+    // Skip the number of unused bits, that must be 0
+    match key.split_first()? {
+        (0, key) => Some(key),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_records() {
+        let key = KeyRecord::parse("v=DKIM1; k=ed25519; h=sha256; t=y:s; p=AAAA").unwrap();
+        assert_eq!(key.key_type, "ed25519");
+        assert!(key.allows_hash("sha256"));
+        assert!(!key.allows_hash("sha1"));
+        assert!(key.allows_email());
+        assert!(key.is_strict());
+        assert_eq!(key.public_key, vec![0, 0, 0]);
+
+        let key = KeyRecord::parse("p=AAAA; s=other").unwrap();
+        assert_eq!(key.key_type, "rsa");
+        assert!(!key.allows_email());
+        assert!(!key.is_strict());
+
+        assert!(matches!(
+            KeyRecord::parse("v=DKIM1; p="),
+            Err(KeyError::Revoked)
+        ));
+        assert!(KeyRecord::parse("v=DKIM2; p=AAAA").is_err());
+        assert!(KeyRecord::parse("p=AAAA; v=DKIM1").is_err());
+        assert!(KeyRecord::parse("k=rsa").is_err());
+    }
+
+    #[test]
+    fn spki_extraction() {
+        // SEQUENCE { SEQUENCE { OID rsaEncryption, NULL }, BIT STRING { 0, SEQUENCE {}
+        // } }
+        let spki = [
+            0x30, 0x14, 0x30, 0x0D, 0x06, 0x09, 0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x01,
+            0x01, 0x05, 0x00, 0x03, 0x03, 0x00, 0x30, 0x00,
+        ];
+        assert_eq!(rsa_public_key_from_spki(&spki), Some(&[0x30, 0x00][..]));
+        assert_eq!(rsa_public_key_from_spki(&spki[..10]), None);
+    }
+}
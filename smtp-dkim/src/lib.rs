@@ -0,0 +1,169 @@
+use async_trait::async_trait;
+#[cfg(feature = "trust-dns")]
+use trust_dns_resolver::{
+    error::{ResolveError, ResolveErrorKind},
+    proto::DnsHandle,
+    AsyncResolver, ConnectionProvider, Name,
+};
+
+#[cfg(feature = "crypto")]
+mod arc;
+#[cfg(feature = "crypto")]
+mod canonicalization;
+#[cfg(feature = "crypto")]
+mod key;
+#[cfg(feature = "crypto")]
+mod message;
+#[cfg(feature = "crypto")]
+mod signature;
+#[cfg(feature = "crypto")]
+mod signer;
+#[cfg(feature = "crypto")]
+mod tag;
+#[cfg(feature = "crypto")]
+mod verifier;
+
+#[cfg(feature = "crypto")]
+pub use arc::{ArcVerifier, Sealer};
+#[cfg(feature = "crypto")]
+pub use canonicalization::{canonicalize_header, BodyCanonicalizer, Canonicalization};
+#[cfg(feature = "crypto")]
+pub use key::{KeyError, KeyRecord};
+#[cfg(feature = "crypto")]
+pub use message::HeaderSection;
+#[cfg(feature = "crypto")]
+pub use signature::{strip_b_value, Algorithm, Signature, SignatureError};
+#[cfg(feature = "crypto")]
+pub use signer::{SignError, Signer, SigningConfig, SigningKey, SigningKeyError};
+#[cfg(feature = "crypto")]
+pub use tag::{parse_tag_list, TagListError};
+#[cfg(feature = "crypto")]
+pub use verifier::{BodyHasher, Verifier};
+
+/// Status of the verification of a DKIM signature, see RFC 8601 §2.7.1
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum DkimStatus {
+    Pass,
+    Fail,
+    TempError,
+    PermError,
+}
+
+impl DkimStatus {
+    /// Name of the status, as used in `Authentication-Results` headers
+    pub fn name(&self) -> &'static str {
+        match self {
+            DkimStatus::Pass => "pass",
+            DkimStatus::Fail => "fail",
+            DkimStatus::TempError => "temperror",
+            DkimStatus::PermError => "permerror",
+        }
+    }
+}
+
+/// Result of the verification of one DKIM signature
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct DkimResult {
+    pub status: DkimStatus,
+    /// Human-readable reason for a non-passing status
+    pub reason: Option<String>,
+    /// Signing domain (`d=` tag), unless the signature could not be parsed
+    pub domain: Option<String>,
+    /// Selector (`s=` tag), unless the signature could not be parsed
+    pub selector: Option<String>,
+    /// Agent or user identifier (`i=` tag, or its default value), unless the
+    /// signature could not be parsed
+    pub identity: Option<String>,
+    /// Signature data (`b=` tag), unless the signature could not be parsed
+    pub b: Option<String>,
+}
+
+/// Status of the validation of an ARC chain, see RFC 8617 §4.4
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum ArcStatus {
+    None,
+    Pass,
+    Fail,
+}
+
+impl ArcStatus {
+    /// Name of the status, as used in `cv=` tags and `Authentication-Results`
+    /// headers
+    pub fn name(&self) -> &'static str {
+        match self {
+            ArcStatus::None => "none",
+            ArcStatus::Pass => "pass",
+            ArcStatus::Fail => "fail",
+        }
+    }
+}
+
+/// Result of the validation of the ARC chain of a message
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct ArcResult {
+    pub status: ArcStatus,
+    /// Human-readable reason for a failing status
+    pub reason: Option<String>,
+    /// Signing domain of each `ARC-Seal`, from the oldest to the most recent,
+    /// unless the chain could not be parsed
+    pub sealers: Vec<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Temporary failure while looking up ‘{name}’: {reason}")]
+pub struct LookupError {
+    pub name: String,
+    pub reason: String,
+}
+
+/// DNS operations needed for retrieving DKIM keys
+///
+/// Names that do not exist or have no records of the requested type must
+/// return an empty list, errors are reserved for temporary failures.
+#[async_trait]
+pub trait Lookup: Send + Sync {
+    /// Returns the TXT records of `name`, the strings of each record being
+    /// concatenated
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<String>, LookupError>;
+}
+
+#[cfg(feature = "trust-dns")]
+#[async_trait]
+impl<C, P> Lookup for AsyncResolver<C, P>
+where
+    C: DnsHandle<Error = ResolveError>,
+    P: ConnectionProvider<Conn = C>,
+{
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<String>, LookupError> {
+        // Parse as a fully-qualified name, so that the resolver does not try
+        // search domains
+        let mut fqdn = match Name::from_utf8(name) {
+            Ok(n) => n,
+            // Invalid names cannot have any records
+            Err(_) => return Ok(Vec::new()),
+        };
+        fqdn.set_fqdn(true);
+        match self.txt_lookup(fqdn).await {
+            Ok(r) => Ok(r
+                .iter()
+                .map(|txt| {
+                    txt.txt_data()
+                        .iter()
+                        .map(|s| String::from_utf8_lossy(s))
+                        .collect::<String>()
+                })
+                .collect()),
+            Err(e) => match e.kind() {
+                ResolveErrorKind::NoRecordsFound { .. } => Ok(Vec::new()),
+                _ => Err(LookupError {
+                    name: name.to_owned(),
+                    reason: e.to_string(),
+                }),
+            },
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::{borrow::Cow, ops::Range};
+
+use crate::canonicalization::{canonicalize_header, Canonicalization};
+
+/// Maximum size of the header section that will be buffered
+const MAX_HEADER_SIZE: usize = 256 * 1024;
+
+/// Buffers the header section of a message that is being streamed, until its
+/// end is reached
+pub struct HeaderSection {
+    raw: Vec<u8>,
+    /// Ranges of the header fields in `raw`, set once the end of the header
+    /// section has been reached
+    fields: Option<Vec<Range<usize>>>,
+    too_big: bool,
+}
+
+impl Default for HeaderSection {
+    fn default() -> HeaderSection {
+        HeaderSection::new()
+    }
+}
+
+pub fn field_name(field: &[u8]) -> &[u8] {
+    let colon = field.iter().position(|&b| b == b':').unwrap_or(field.len());
+    let name = &field[..colon];
+    let end = name
+        .iter()
+        .rposition(|&b| b != b' ' && b != b'\t')
+        .map_or(0, |i| i + 1);
+    &name[..end]
+}
+
+fn split_fields(headers: &[u8]) -> Vec<Range<usize>> {
+    let mut res: Vec<Range<usize>> = Vec::new();
+    let mut start = 0;
+    while start < headers.len() {
+        let end = headers[start..]
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .map_or(headers.len(), |i| start + i + 2);
+        match (headers[start], res.last_mut()) {
+            (b' ', Some(last)) | (b'\t', Some(last)) => last.end = end,
+            _ => res.push(start..end),
+        }
+        start = end;
+    }
+    res
+}
+
+impl HeaderSection {
+    pub fn new() -> HeaderSection {
+        HeaderSection {
+            raw: Vec::new(),
+            fields: None,
+            too_big: false,
+        }
+    }
+
+    /// Whether the end of the header section has been reached
+    pub fn is_complete(&self) -> bool {
+        self.fields.is_some()
+    }
+
+    /// Whether the header section was too big to be buffered, in which case
+    /// it is dropped
+    pub fn is_too_big(&self) -> bool {
+        self.too_big
+    }
+
+    /// Feeds in the next bytes of the message, returning the part of them
+    /// that is in the body, if any
+    pub fn update<'a>(&mut self, data: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        if self.is_complete() {
+            return Some(Cow::Borrowed(data));
+        }
+        if self.too_big {
+            return None;
+        }
+        let search_from = self.raw.len().saturating_sub(3);
+        self.raw.extend_from_slice(data);
+        let end = if self.raw.starts_with(b"\r\n") {
+            Some(0)
+        } else {
+            self.raw[search_from..]
+                .windows(4)
+                .position(|w| w == b"\r\n\r\n")
+                .map(|i| search_from + i + 2)
+        };
+        match end {
+            Some(end) => {
+                let body = self.raw.split_off(end + 2);
+                self.raw.truncate(end);
+                self.fields = Some(split_fields(&self.raw));
+                Some(Cow::Owned(body))
+            }
+            None => {
+                if self.raw.len() > MAX_HEADER_SIZE {
+                    self.too_big = true;
+                    self.raw = Vec::new();
+                }
+                None
+            }
+        }
+    }
+
+    /// Marks the end of the message, for messages that have no body
+    pub fn finish(&mut self) {
+        if self.is_complete() || self.too_big {
+            return;
+        }
+        if !self.raw.is_empty() && !self.raw.ends_with(b"\r\n") {
+            self.raw.extend_from_slice(b"\r\n");
+        }
+        self.fields = Some(split_fields(&self.raw));
+    }
+
+    /// Returns the raw header fields, including their final CRLF
+    pub fn fields(&self) -> impl Iterator<Item = &[u8]> {
+        self.fields
+            .iter()
+            .flatten()
+            .map(move |f| &self.raw[f.clone()])
+    }
+
+    /// Appends to `out` the canonicalized header fields named in `names`, as
+    /// selected by RFC 6376 §5.4.2: fields are taken from the bottom up, and
+    /// names that do not match a (remaining) field are skipped
+    pub fn signed_data(&self, names: &[String], canon: Canonicalization, out: &mut Vec<u8>) {
+        let fields = self.fields().collect::<Vec<_>>();
+        let mut used = vec![false; fields.len()];
+        for name in names {
+            let found =
+                fields.iter().enumerate().rev().find(|(i, f)| {
+                    !used[*i] && field_name(f).eq_ignore_ascii_case(name.as_bytes())
+                });
+            if let Some((i, f)) = found {
+                used[i] = true;
+                canonicalize_header(canon, f, out);
+            }
+        }
+    }
+}
+
+/// Appends to `out` the canonicalized form of the signature header field
+/// `raw`, with the value of its `b=` tag removed and without its final CRLF
+pub fn signature_field_data(raw: &str, canon: Canonicalization, out: &mut Vec<u8>) {
+    let stripped = match raw.find(':') {
+        Some(colon) => format!(
+            "{}{}",
+            &raw[..=colon],
+            crate::signature::strip_b_value(&raw[colon + 1..])
+        ),
+        None => raw.to_owned(),
+    };
+    let start = out.len();
+    canonicalize_header(canon, stripped.as_bytes(), out);
+    if out[start..].ends_with(b"\r\n") {
+        out.truncate(out.len() - 2);
+    }
+}
@@ -0,0 +1,314 @@
+use crate::{
+    canonicalization::Canonicalization,
+    tag::{self, TagListError},
+};
+
+/// Signing algorithm, see RFC 6376 §3.3 and RFC 8463
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Algorithm {
+    RsaSha256,
+    Ed25519Sha256,
+}
+
+impl Algorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::RsaSha256 => "rsa-sha256",
+            Algorithm::Ed25519Sha256 => "ed25519-sha256",
+        }
+    }
+
+    /// Key type (`k=` tag of the key record) this algorithm uses
+    pub fn key_type(&self) -> &'static str {
+        match self {
+            Algorithm::RsaSha256 => "rsa",
+            Algorithm::Ed25519Sha256 => "ed25519",
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SignatureError {
+    #[error("Signature is not a valid tag list")]
+    TagList(#[from] TagListError),
+
+    #[error("Signature is missing required tag ‘{0}’")]
+    MissingTag(&'static str),
+
+    #[error("Unsupported signature version ‘{0}’")]
+    UnsupportedVersion(String),
+
+    #[error("Unsupported signature algorithm ‘{0}’")]
+    UnsupportedAlgorithm(String),
+
+    #[error("Unsupported canonicalization ‘{0}’")]
+    UnsupportedCanonicalization(String),
+
+    #[error("Unsupported query method ‘{0}’")]
+    UnsupportedQueryMethod(String),
+
+    #[error("Invalid value for tag ‘{0}’")]
+    InvalidValue(&'static str),
+
+    #[error("Signed headers do not include ‘From’")]
+    FromNotSigned,
+
+    #[error("Identity ‘{identity}’ is not in signing domain ‘{domain}’")]
+    IdentityNotInDomain { identity: String, domain: String },
+
+    #[error("Signature expires before it was made")]
+    ExpiresBeforeTimestamp,
+
+    #[error("Invalid ARC instance ‘{0}’")]
+    InvalidInstance(String),
+}
+
+/// Maximum number of ARC sets on a message, see RFC 8617 §4.2.1
+pub const MAX_ARC_INSTANCE: u32 = 50;
+
+/// A parsed `DKIM-Signature` header field, see RFC 6376 §3.5
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Signature {
+    pub algorithm: Algorithm,
+    /// Raw `b=` value, with whitespace removed
+    pub b: String,
+    pub signature: Vec<u8>,
+    pub body_hash: Vec<u8>,
+    pub header_canonicalization: Canonicalization,
+    pub body_canonicalization: Canonicalization,
+    pub domain: String,
+    pub signed_headers: Vec<String>,
+    /// Agent or user identifier, defaults to `@` followed by the domain
+    pub identity: String,
+    pub body_length: Option<u64>,
+    pub selector: String,
+    pub timestamp: Option<u64>,
+    pub expiration: Option<u64>,
+}
+
+fn parse_u64(tag: &'static str, v: &str) -> Result<u64, SignatureError> {
+    if v.is_empty() || v.len() > 76 || !v.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(SignatureError::InvalidValue(tag));
+    }
+    // Values that overflow are treated as infinite
+    Ok(v.parse().unwrap_or(u64::MAX))
+}
+
+pub(crate) fn parse_base64(tag: &'static str, v: &str) -> Result<Vec<u8>, SignatureError> {
+    base64::decode(tag::strip_fws(v)).map_err(|_| SignatureError::InvalidValue(tag))
+}
+
+/// Parses the value of the `i=` tag of an ARC header field
+pub(crate) fn parse_instance(v: &str) -> Result<u32, SignatureError> {
+    match v.parse::<u32>() {
+        Ok(i) if (1..=MAX_ARC_INSTANCE).contains(&i) && v.bytes().all(|b| b.is_ascii_digit()) => {
+            Ok(i)
+        }
+        _ => Err(SignatureError::InvalidInstance(v.to_owned())),
+    }
+}
+
+pub(crate) fn parse_algorithm(a: &str) -> Result<Algorithm, SignatureError> {
+    match a {
+        "rsa-sha256" => Ok(Algorithm::RsaSha256),
+        "ed25519-sha256" => Ok(Algorithm::Ed25519Sha256),
+        a => Err(SignatureError::UnsupportedAlgorithm(a.to_owned())),
+    }
+}
+
+fn is_subdomain(sub: &str, domain: &str) -> bool {
+    let (sub, domain) = (sub.to_ascii_lowercase(), domain.to_ascii_lowercase());
+    sub == domain || sub.ends_with(&format!(".{}", domain))
+}
+
+impl Signature {
+    /// Parses the value of a `DKIM-Signature` header field
+    pub fn parse(value: &str) -> Result<Signature, SignatureError> {
+        let tags = tag::parse_tag_list(value)?;
+        match tag::get(&tags, "v").ok_or(SignatureError::MissingTag("v"))? {
+            "1" => (),
+            v => return Err(SignatureError::UnsupportedVersion(v.to_owned())),
+        }
+        let mut sig = Signature::from_tags(&tags)?;
+        if !sig
+            .signed_headers
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case("from"))
+        {
+            return Err(SignatureError::FromNotSigned);
+        }
+        if let Some(i) = tag::get(&tags, "i") {
+            let at = i.rfind('@').ok_or(SignatureError::InvalidValue("i"))?;
+            if !is_subdomain(&i[at + 1..], &sig.domain) {
+                return Err(SignatureError::IdentityNotInDomain {
+                    identity: i.to_owned(),
+                    domain: sig.domain,
+                });
+            }
+            sig.identity = i.to_owned();
+        }
+        Ok(sig)
+    }
+
+    /// Parses the value of an `ARC-Message-Signature` header field, returning
+    /// its instance along with the signature, see RFC 8617 §4.1.2
+    pub fn parse_arc(value: &str) -> Result<(u32, Signature), SignatureError> {
+        let tags = tag::parse_tag_list(value)?;
+        let instance =
+            parse_instance(tag::get(&tags, "i").ok_or(SignatureError::MissingTag("i"))?)?;
+        Ok((instance, Signature::from_tags(&tags)?))
+    }
+
+    /// Parses the tags common to `DKIM-Signature` and `ARC-Message-Signature`
+    /// header fields, the identity being set to its default value
+    fn from_tags(tags: &[(&str, &str)]) -> Result<Signature, SignatureError> {
+        let required = |name: &'static str| -> Result<&str, SignatureError> {
+            tag::get(tags, name).ok_or(SignatureError::MissingTag(name))
+        };
+
+        let algorithm = parse_algorithm(required("a")?)?;
+        let b = tag::strip_fws(required("b")?);
+        let signature = parse_base64("b", &b)?;
+        let body_hash = parse_base64("bh", required("bh")?)?;
+
+        let (header_canonicalization, body_canonicalization) = match tag::get(tags, "c") {
+            None => (Canonicalization::Simple, Canonicalization::Simple),
+            Some(c) => {
+                let unsupported = || SignatureError::UnsupportedCanonicalization(c.to_owned());
+                let mut parts = c.splitn(2, '/');
+                let h = parts.next().unwrap_or("");
+                let b = parts.next().unwrap_or("simple");
+                (
+                    h.parse().map_err(|()| unsupported())?,
+                    b.parse().map_err(|()| unsupported())?,
+                )
+            }
+        };
+
+        let domain = required("d")?.to_owned();
+        if domain.is_empty() {
+            return Err(SignatureError::InvalidValue("d"));
+        }
+        let signed_headers = required("h")?
+            .split(':')
+            .map(|h| tag::trim_fws(h).to_owned())
+            .collect::<Vec<_>>();
+        if signed_headers.iter().any(|h| h.is_empty()) {
+            return Err(SignatureError::InvalidValue("h"));
+        }
+        let identity = format!("@{}", domain);
+
+        let body_length = tag::get(tags, "l").map(|l| parse_u64("l", l)).transpose()?;
+
+        if let Some(q) = tag::get(tags, "q") {
+            if !q.split(':').any(|m| tag::trim_fws(m) == "dns/txt") {
+                return Err(SignatureError::UnsupportedQueryMethod(q.to_owned()));
+            }
+        }
+
+        let selector = required("s")?.to_owned();
+        if selector.is_empty() {
+            return Err(SignatureError::InvalidValue("s"));
+        }
+
+        let timestamp = tag::get(tags, "t").map(|t| parse_u64("t", t)).transpose()?;
+        let expiration = tag::get(tags, "x").map(|x| parse_u64("x", x)).transpose()?;
+        if let (Some(t), Some(x)) = (timestamp, expiration) {
+            if x < t {
+                return Err(SignatureError::ExpiresBeforeTimestamp);
+            }
+        }
+
+        Ok(Signature {
+            algorithm,
+            b,
+            signature,
+            body_hash,
+            header_canonicalization,
+            body_canonicalization,
+            domain,
+            signed_headers,
+            identity,
+            body_length,
+            selector,
+            timestamp,
+            expiration,
+        })
+    }
+
+    /// Domain of the identity, ie. what follows the `@` of the `i=` tag
+    pub fn identity_domain(&self) -> &str {
+        match self.identity.rfind('@') {
+            Some(i) => &self.identity[i + 1..],
+            None => &self.identity,
+        }
+    }
+}
+
+/// Returns `value` (the value of a `DKIM-Signature` header field) with the
+/// value of its `b=` tag removed, as needed for computing the header hash
+pub fn strip_b_value(value: &str) -> String {
+    value
+        .split(';')
+        .map(|spec| match spec.find('=') {
+            Some(i) if tag::trim_fws(&spec[..i]) == "b" => &spec[..=i],
+            _ => spec,
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALID: &str = "v=1; a=rsa-sha256; c=relaxed; d=example.org;\r\n s=sel; \
+                         i=user@mail.example.org; h=From:To : Subject; l=42; t=10; x=20;\r\n \
+                         bh=AAAA; b=AA\r\n\tAA";
+
+    #[test]
+    fn valid_signature() {
+        let sig = Signature::parse(VALID).unwrap();
+        assert_eq!(sig.algorithm, Algorithm::RsaSha256);
+        assert_eq!(sig.header_canonicalization, Canonicalization::Relaxed);
+        assert_eq!(sig.body_canonicalization, Canonicalization::Simple);
+        assert_eq!(sig.domain, "example.org");
+        assert_eq!(sig.selector, "sel");
+        assert_eq!(sig.signed_headers, vec!["From", "To", "Subject"]);
+        assert_eq!(sig.identity_domain(), "mail.example.org");
+        assert_eq!(sig.body_length, Some(42));
+        assert_eq!((sig.timestamp, sig.expiration), (Some(10), Some(20)));
+        assert_eq!(sig.b, "AAAA");
+        assert_eq!(sig.signature, vec![0, 0, 0]);
+    }
+
+    #[test]
+    fn invalid_signatures() {
+        let tests: &[(&str, &str)] = &[
+            ("v=1", "v=2"),
+            ("a=rsa-sha256", "a=rsa-sha1"),
+            ("c=relaxed", "c=relaxed/strange"),
+            (" d=example.org;", ""),
+            ("h=From:To", "h=To:Subject"),
+            ("h=From:To", "h=From::To"),
+            ("i=user@mail.example.org", "i=user@example.com"),
+            ("l=42", "l=-1"),
+            ("x=20", "x=5"),
+            ("bh=AAAA", "bh=!"),
+        ];
+        for (from, to) in tests {
+            let s = VALID.replace(from, to);
+            println!("Testing {:?}", s);
+            assert!(Signature::parse(&s).is_err());
+        }
+    }
+
+    #[test]
+    fn b_value_stripping() {
+        assert_eq!(
+            strip_b_value(" v=1; bh=abc; b=def\r\n ghi; d=x"),
+            " v=1; bh=abc; b=; d=x"
+        );
+        assert_eq!(strip_b_value("v=1; b = abc"), "v=1; b =");
+    }
+}
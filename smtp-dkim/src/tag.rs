@@ -0,0 +1,90 @@
+#[derive(Debug, thiserror::Error)]
+pub enum TagListError {
+    #[error("Invalid tag name ‘{0}’")]
+    InvalidName(String),
+
+    #[error("Tag ‘{0}’ has no value")]
+    MissingValue(String),
+
+    #[error("Tag ‘{0}’ appears more than once")]
+    Duplicate(String),
+}
+
+/// Parses a tag-value list (RFC 6376 §3.2), returning the (name, value) pairs
+/// in order, with the whitespace around values stripped
+pub fn parse_tag_list(s: &str) -> Result<Vec<(&str, &str)>, TagListError> {
+    let mut res: Vec<(&str, &str)> = Vec::new();
+    let mut specs = s.split(';').collect::<Vec<_>>();
+    // A trailing ‘;’ is allowed
+    if specs.len() > 1 && specs.last().map(|l| trim_fws(l).is_empty()) == Some(true) {
+        specs.pop();
+    }
+    for spec in specs {
+        let (name, value) = match spec.find('=') {
+            Some(i) => (trim_fws(&spec[..i]), trim_fws(&spec[i + 1..])),
+            None => return Err(TagListError::MissingValue(trim_fws(spec).to_owned())),
+        };
+        if !is_tag_name(name) {
+            return Err(TagListError::InvalidName(name.to_owned()));
+        }
+        if res.iter().any(|(n, _)| *n == name) {
+            return Err(TagListError::Duplicate(name.to_owned()));
+        }
+        res.push((name, value));
+    }
+    Ok(res)
+}
+
+/// Returns the value of tag `name`, if it is in `tags`
+pub fn get<'a>(tags: &[(&str, &'a str)], name: &str) -> Option<&'a str> {
+    tags.iter().find(|(n, _)| *n == name).map(|(_, v)| *v)
+}
+
+pub fn trim_fws(s: &str) -> &str {
+    s.trim_matches(|c| matches!(c, ' ' | '\t' | '\r' | '\n'))
+}
+
+/// Removes all folding whitespace from `s`, as is allowed in eg. base64 values
+pub fn strip_fws(s: &str) -> String {
+    s.chars()
+        .filter(|c| !matches!(c, ' ' | '\t' | '\r' | '\n'))
+        .collect()
+}
+
+fn is_tag_name(n: &str) -> bool {
+    n.starts_with(|c: char| c.is_ascii_alphabetic())
+        && n.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tag_lists() {
+        type Tags<'a> = &'a [(&'a str, &'a str)];
+        let tests: &[(&str, Option<Tags>)] = &[
+            (
+                "v=1; a=rsa-sha256",
+                Some(&[("v", "1"), ("a", "rsa-sha256")]),
+            ),
+            ("v=1;", Some(&[("v", "1")])),
+            (
+                " k = rsa ;\r\n\tp=MIGf\r\n MA0 ",
+                Some(&[("k", "rsa"), ("p", "MIGf\r\n MA0")]),
+            ),
+            ("p=", Some(&[("p", "")])),
+            ("v=1; v=1", None),
+            ("v=1; 1a=2", None),
+            ("v=1; a", None),
+            ("v=1;;", None),
+        ];
+        for (inp, out) in tests {
+            println!("Testing {:?}", inp);
+            match out {
+                Some(out) => assert_eq!(parse_tag_list(inp).unwrap(), *out),
+                None => assert!(parse_tag_list(inp).is_err()),
+            }
+        }
+    }
+}
@@ -0,0 +1,540 @@
+use std::time::SystemTime;
+
+use ring::{digest, signature};
+use tracing::trace;
+
+use crate::{
+    canonicalization::{BodyCanonicalizer, Canonicalization},
+    key::KeyRecord,
+    message::{field_name, signature_field_data, HeaderSection},
+    signature::{Algorithm, Signature},
+    DkimResult, DkimStatus, Lookup,
+};
+
+/// Maximum number of signatures that will be verified on a single message
+const MAX_SIGNATURES: usize = 8;
+
+/// SHA-256 context that only hashes the first `remaining` bytes, if set
+struct LimitedHash {
+    ctx: digest::Context,
+    remaining: Option<u64>,
+    /// Number of bytes that were given, including the ones not hashed
+    length: u64,
+}
+
+impl LimitedHash {
+    fn update(&mut self, data: &[u8]) {
+        let len = data.len() as u64;
+        self.length += len;
+        let n = match &mut self.remaining {
+            None => len,
+            Some(r) => {
+                let n = std::cmp::min(*r, len);
+                *r -= n;
+                n
+            }
+        };
+        self.ctx.update(&data[..n as usize]);
+    }
+}
+
+/// Hashes a body as per a body canonicalization and length limit
+pub struct BodyHasher {
+    canon: BodyCanonicalizer,
+    hash: LimitedHash,
+    buf: Vec<u8>,
+}
+
+impl BodyHasher {
+    pub fn new(canon: Canonicalization, length: Option<u64>) -> BodyHasher {
+        BodyHasher {
+            canon: BodyCanonicalizer::new(canon),
+            hash: LimitedHash {
+                ctx: digest::Context::new(&digest::SHA256),
+                remaining: length,
+                length: 0,
+            },
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.buf.clear();
+        self.canon.update(data, &mut self.buf);
+        self.hash.update(&self.buf);
+    }
+
+    /// Returns the body hash and the length of the canonicalized body
+    pub fn finish(mut self) -> (Vec<u8>, u64) {
+        self.buf.clear();
+        self.canon.finish(&mut self.buf);
+        self.hash.update(&self.buf);
+        (self.hash.ctx.finish().as_ref().to_vec(), self.hash.length)
+    }
+}
+
+struct PendingSignature {
+    /// Index of the signature's header field in the header section
+    field: usize,
+    sig: Signature,
+    hasher: BodyHasher,
+}
+
+/// Streaming DKIM verifier
+///
+/// The whole message, headers included, is to be passed through `update`,
+/// after which `finish` returns the result of each signature found in the
+/// message.
+#[derive(Default)]
+pub struct Verifier {
+    headers: HeaderSection,
+    /// Signatures found in the headers, in order, those that could not be
+    /// parsed having their result already computed
+    signatures: Vec<Result<PendingSignature, DkimResult>>,
+}
+
+fn invalid_signature(reason: String) -> DkimResult {
+    DkimResult {
+        status: DkimStatus::PermError,
+        reason: Some(reason),
+        domain: None,
+        selector: None,
+        identity: None,
+        b: None,
+    }
+}
+
+fn result(status: DkimStatus, reason: Option<&str>, sig: &Signature) -> DkimResult {
+    DkimResult {
+        status,
+        reason: reason.map(String::from),
+        domain: Some(sig.domain.clone()),
+        selector: Some(sig.selector.clone()),
+        identity: Some(sig.identity.clone()),
+        b: Some(sig.b.clone()),
+    }
+}
+
+impl Verifier {
+    pub fn new() -> Verifier {
+        Verifier {
+            headers: HeaderSection::new(),
+            signatures: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let was_complete = self.headers.is_complete();
+        if let Some(body) = self.headers.update(data) {
+            if !was_complete {
+                self.parse_signatures();
+            }
+            for p in self.signatures.iter_mut().flatten() {
+                p.hasher.update(&body);
+            }
+        }
+    }
+
+    fn parse_signatures(&mut self) {
+        for (i, raw) in self.headers.fields().enumerate() {
+            if !field_name(raw).eq_ignore_ascii_case(b"dkim-signature") {
+                continue;
+            }
+            if self.signatures.len() >= MAX_SIGNATURES {
+                trace!("Ignoring DKIM signatures past the maximum number of signatures");
+                break;
+            }
+            let value = std::str::from_utf8(raw)
+                .ok()
+                .and_then(|r| r.find(':').map(|i| &r[i + 1..]));
+            self.signatures.push(match value.map(Signature::parse) {
+                Some(Ok(sig)) => Ok(PendingSignature {
+                    field: i,
+                    hasher: BodyHasher::new(sig.body_canonicalization, sig.body_length),
+                    sig,
+                }),
+                Some(Err(e)) => {
+                    trace!(error = ?e, "Invalid DKIM signature");
+                    Err(invalid_signature(e.to_string()))
+                }
+                None => Err(invalid_signature(String::from(
+                    "signature is not valid UTF-8",
+                ))),
+            });
+        }
+    }
+
+    /// Returns the results for all the signatures of the message, in the
+    /// order in which they appear in the message
+    pub async fn finish<L: Lookup>(self, lookup: &L) -> Vec<DkimResult> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.finish_at(lookup, now).await
+    }
+
+    async fn finish_at<L: Lookup>(mut self, lookup: &L, now: u64) -> Vec<DkimResult> {
+        if self.headers.is_too_big() {
+            trace!("Header section is too big for DKIM verification");
+            return Vec::new();
+        }
+        if !self.headers.is_complete() {
+            // The message has no body
+            self.headers.finish();
+            self.parse_signatures();
+        }
+        let mut results = Vec::with_capacity(self.signatures.len());
+        for s in std::mem::take(&mut self.signatures) {
+            let res = match s {
+                Ok(p) => self.verify(lookup, p, now).await,
+                Err(res) => res,
+            };
+            trace!(result = ?res, "Verified DKIM signature");
+            results.push(res);
+        }
+        results
+    }
+
+    async fn verify<L: Lookup>(&self, lookup: &L, p: PendingSignature, now: u64) -> DkimResult {
+        let sig = &p.sig;
+        if matches!(sig.expiration, Some(x) if x < now) {
+            return result(DkimStatus::PermError, Some("signature expired"), sig);
+        }
+
+        let key = match fetch_key(lookup, &sig.selector, &sig.domain).await {
+            Ok(key) => key,
+            Err((status, reason)) => return result(status, Some(&reason), sig),
+        };
+        if let Err(reason) = check_key(&key, sig.algorithm) {
+            return result(DkimStatus::PermError, Some(reason), sig);
+        }
+        if key.is_strict() && !sig.identity_domain().eq_ignore_ascii_case(&sig.domain) {
+            return result(
+                DkimStatus::PermError,
+                Some("key does not allow identity subdomains"),
+                sig,
+            );
+        }
+        let public_key = match key.ring_public_key() {
+            Ok(k) => k,
+            Err(_) => return result(DkimStatus::PermError, Some("invalid public key"), sig),
+        };
+
+        let (body_hash, body_length) = p.hasher.finish();
+        if matches!(sig.body_length, Some(l) if l > body_length) {
+            return result(DkimStatus::Fail, Some("body is shorter than l="), sig);
+        }
+        if body_hash != sig.body_hash {
+            return result(DkimStatus::Fail, Some("body hash did not verify"), sig);
+        }
+
+        let data = match self.signed_data(p.field, sig) {
+            Some(data) => data,
+            None => return result(DkimStatus::PermError, Some("invalid signature header"), sig),
+        };
+        if verify_signature(sig.algorithm, public_key, &data, &sig.signature) {
+            result(DkimStatus::Pass, None, sig)
+        } else {
+            result(DkimStatus::Fail, Some("signature did not verify"), sig)
+        }
+    }
+
+    /// Computes the data covered by the header signature, see RFC 6376 §3.7
+    fn signed_data(&self, field: usize, sig: &Signature) -> Option<Vec<u8>> {
+        let canon = sig.header_canonicalization;
+        let mut data = Vec::new();
+        self.headers
+            .signed_data(&sig.signed_headers, canon, &mut data);
+        let raw = std::str::from_utf8(self.headers.fields().nth(field)?).ok()?;
+        signature_field_data(raw, canon, &mut data);
+        Some(data)
+    }
+}
+
+/// Retrieves the key record published by `domain` under `selector`, or the
+/// status and reason to return if there is none
+pub(crate) async fn fetch_key<L: Lookup>(
+    lookup: &L,
+    selector: &str,
+    domain: &str,
+) -> Result<KeyRecord, (DkimStatus, String)> {
+    let name = format!("{}._domainkey.{}", selector, domain);
+    let records = match lookup.lookup_txt(&name).await {
+        Ok(r) => r,
+        Err(e) => {
+            trace!(error = ?e, "Temporary error while looking up DKIM key");
+            return Err((DkimStatus::TempError, String::from("key lookup failed")));
+        }
+    };
+    let mut error = None;
+    for r in records {
+        match KeyRecord::parse(&r) {
+            Ok(key) => return Ok(key),
+            Err(e) => error = Some(e),
+        }
+    }
+    Err(match error {
+        None => (DkimStatus::PermError, String::from("no key for signature")),
+        Some(e) => (DkimStatus::PermError, e.to_string()),
+    })
+}
+
+/// Checks that `key` can be used for verifying a signature made with
+/// `algorithm`, returning the reason why not otherwise
+pub(crate) fn check_key(key: &KeyRecord, algorithm: Algorithm) -> Result<(), &'static str> {
+    if !key.allows_hash("sha256") {
+        return Err("key does not allow sha256");
+    }
+    if !key.allows_email() {
+        return Err("key is not for email");
+    }
+    if key.key_type != algorithm.key_type() {
+        return Err("key type does not match");
+    }
+    Ok(())
+}
+
+/// Returns whether `sig` is a valid signature of `data` by `public_key`
+pub(crate) fn verify_signature(
+    algorithm: Algorithm,
+    public_key: &[u8],
+    data: &[u8],
+    sig: &[u8],
+) -> bool {
+    let verified = match algorithm {
+        Algorithm::RsaSha256 => signature::UnparsedPublicKey::new(
+            &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+            public_key,
+        )
+        .verify(data, sig),
+        Algorithm::Ed25519Sha256 => {
+            signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+                .verify(digest::digest(&digest::SHA256, data).as_ref(), sig)
+        }
+    };
+    verified.is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::LookupError;
+
+    // Generated with an independent implementation, the RSA signature only
+    // covers the first 30 bytes of the body
+    const MESSAGE: &str = concat!(
+        "DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed; d=football.example.com; \
+         s=brisbane; t=1528637909;\r\n",
+        " h=From:To:Subject:Date; bh=2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=;\r\n",
+        " b=dpiZY5tgwLEeIRtTbv3HYcQhyJkSvljINMlhJ86QAadjIyQiVY+3v8/sYvHwNOsd\r\n",
+        "  6SR5ql7y7kfDQEfuQjGxCA==\r\n",
+        "DKIM-Signature: v=1; a=rsa-sha256; c=simple/simple; d=football.example.com; s=test; \
+         x=2000000000; l=30;\r\n",
+        " h=From:To:Subject:Date; bh=BplWJtv82EwqhTlYkxQldHQpaiMafUCfHaP+7MsVWSI=;\r\n",
+        " b=gtxyKF6P9+xPjRzMvxE4PHOuhwN3ktjo5noKDygbpGfZIxsAMz7UmUtZ7NcYnnL9\r\n",
+        "  W9eM+/hEScst8jh89nW1csSXo5BnNMaL3bOGobAZeqp9II9VBLOcKYnNcKpLhzWi\r\n",
+        "  ugIED3+UBFfExToi/O5UddDCYKdfM0/c5HPE9CiroJg=\r\n",
+        "From: Joe SixPack <joe@football.example.com>\r\n",
+        "To: Suzie Q <suzie@shopping.example.net>\r\n",
+        "Subject: Is dinner ready?\r\n",
+        "Date: Fri, 11 Jul 2003 21:00:37 -0700 (PDT)\r\n",
+        "\r\n",
+        "Hi.\r\n",
+        "\r\n",
+        "We lost the game.  Are you hungry yet?\r\n",
+        "\r\n",
+        "Joe.\r\n",
+        "\r\n",
+    );
+
+    const ED25519_KEY: &str = "v=DKIM1; k=ed25519; p=KXDo0F9di71rrKe8hSTEvtVnCsBZAlZgzXXTrtOytg8=";
+    const RSA_KEY: &str = "v=DKIM1; k=rsa; \
+                           p=MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQD4lvzbCY/\
+                           eXMKZCCUX68MiEksEXg8YqTjicFh2L8dvmatqIhP1/mIM0Whe6x//\
+                           uiStTz+rM+6dupt88L7+Q0x4mDcCeYg9WGao1P6A9+O8V2o18Q96U9yOqfefx/\
+                           Q1qsLiQ6SbSnT76sObD5n7fbpHxxK0PAYw2elOJN2o65ducQIDAQAB";
+
+    const NOW: u64 = 1_600_000_000;
+
+    /// `None` values make the lookup fail
+    struct StubLookup(HashMap<&'static str, Option<&'static str>>);
+
+    #[async_trait::async_trait]
+    impl Lookup for StubLookup {
+        async fn lookup_txt(&self, name: &str) -> Result<Vec<String>, LookupError> {
+            match self.0.get(name) {
+                None => Ok(Vec::new()),
+                Some(Some(r)) => Ok(vec![r.to_string()]),
+                Some(None) => Err(LookupError {
+                    name: name.to_owned(),
+                    reason: String::from("stub failure"),
+                }),
+            }
+        }
+    }
+
+    fn keys() -> StubLookup {
+        let mut keys = HashMap::new();
+        keys.insert(
+            "brisbane._domainkey.football.example.com",
+            Some(ED25519_KEY),
+        );
+        keys.insert("test._domainkey.football.example.com", Some(RSA_KEY));
+        StubLookup(keys)
+    }
+
+    fn verify_chunked(
+        msg: &str,
+        chunk_size: usize,
+        lookup: &StubLookup,
+        now: u64,
+    ) -> Vec<DkimResult> {
+        let mut v = Verifier::new();
+        for chunk in msg.as_bytes().chunks(chunk_size) {
+            v.update(chunk);
+        }
+        futures::executor::block_on(v.finish_at(lookup, now))
+    }
+
+    fn statuses(msg: &str, lookup: &StubLookup, now: u64) -> Vec<Status> {
+        verify_chunked(msg, 4096, lookup, now)
+            .into_iter()
+            .map(|r| (r.status, r.reason))
+            .collect()
+    }
+
+    type Status = (DkimStatus, Option<String>);
+
+    fn fail(reason: &str) -> Status {
+        (DkimStatus::Fail, Some(reason.to_owned()))
+    }
+
+    fn perm(reason: &str) -> Status {
+        (DkimStatus::PermError, Some(reason.to_owned()))
+    }
+
+    const PASS: Status = (DkimStatus::Pass, None);
+
+    #[test]
+    fn valid_signatures() {
+        for chunk_size in &[1, 3, 64, 4096] {
+            println!("Testing with chunks of {} bytes", chunk_size);
+            let res = verify_chunked(MESSAGE, *chunk_size, &keys(), NOW);
+            assert_eq!(res.len(), 2);
+            assert_eq!(res[0].status, DkimStatus::Pass);
+            assert_eq!(res[0].domain.as_deref(), Some("football.example.com"));
+            assert_eq!(res[0].selector.as_deref(), Some("brisbane"));
+            assert_eq!(res[0].identity.as_deref(), Some("@football.example.com"));
+            assert!(res[0].b.as_deref().unwrap().starts_with("dpiZY5tg"));
+            assert_eq!(res[1].status, DkimStatus::Pass);
+            assert_eq!(res[1].selector.as_deref(), Some("test"));
+        }
+    }
+
+    #[test]
+    fn modified_messages() {
+        let tests: &[(&str, &str, &[Status])] = &[
+            // Only relaxed canonicalization allows whitespace changes
+            ("We lost the game.", "We  lost the game. ", &[
+                PASS,
+                fail("body hash did not verify"),
+            ]),
+            ("Subject: Is", "Subject:  Is", &[
+                PASS,
+                fail("signature did not verify"),
+            ]),
+            // The RSA signature only covers the beginning of the body
+            ("hungry", "thirsty", &[
+                fail("body hash did not verify"),
+                PASS,
+            ]),
+            ("Joe.\r\n", "Joe.\r\nP.S.: Added content\r\n", &[
+                fail("body hash did not verify"),
+                PASS,
+            ]),
+            (
+                "Hi.\r\n\r\nWe lost the game.  Are you hungry yet?\r\n\r\nJoe.\r\n\r\n",
+                "Hi.\r\n",
+                &[
+                    fail("body hash did not verify"),
+                    fail("body is shorter than l="),
+                ],
+            ),
+            ("dinner", "lunch", &[
+                fail("signature did not verify"),
+                fail("signature did not verify"),
+            ]),
+            ("s=brisbane", "s=unknown", &[
+                perm("no key for signature"),
+                PASS,
+            ]),
+            (
+                "h=From:To:Subject:Date; bh=2j",
+                "h=To:Subject:Date; bh=2j",
+                &[perm("Signed headers do not include ‘From’"), PASS],
+            ),
+        ];
+        for (from, to, expected) in tests {
+            println!("Testing replacing {:?} with {:?}", from, to);
+            let msg = MESSAGE.replacen(from, to, 1);
+            assert_eq!(statuses(&msg, &keys(), NOW), *expected);
+        }
+    }
+
+    #[test]
+    fn key_problems() {
+        let mut lookup = keys();
+        lookup
+            .0
+            .insert("brisbane._domainkey.football.example.com", None);
+        lookup
+            .0
+            .insert("test._domainkey.football.example.com", Some("v=DKIM1; p="));
+        assert_eq!(statuses(MESSAGE, &lookup, NOW), vec![
+            (
+                DkimStatus::TempError,
+                Some(String::from("key lookup failed"))
+            ),
+            perm("Key has been revoked"),
+        ]);
+
+        let mut lookup = keys();
+        lookup
+            .0
+            .insert("brisbane._domainkey.football.example.com", Some(RSA_KEY));
+        lookup.0.insert(
+            "test._domainkey.football.example.com",
+            Some("v=DKIM1; t=s; s=other; p=AAAA"),
+        );
+        assert_eq!(statuses(MESSAGE, &lookup, NOW), vec![
+            perm("key type does not match"),
+            perm("key is not for email"),
+        ]);
+    }
+
+    #[test]
+    fn expired_signature() {
+        assert_eq!(statuses(MESSAGE, &keys(), 2_000_000_001), vec![
+            PASS,
+            perm("signature expired")
+        ]);
+    }
+
+    #[test]
+    fn unsigned_messages() {
+        let tests: &[&str] = &[
+            "",
+            "\r\nBody only\r\n",
+            "From: a@example.org\r\n",
+            "From: a@example.org\r\n\r\n",
+            "From: a@example.org\r\nSubject: test\r\n\r\nBody\r\n",
+        ];
+        for msg in tests {
+            println!("Testing {:?}", msg);
+            assert_eq!(statuses(msg, &keys(), NOW), vec![]);
+        }
+    }
+}
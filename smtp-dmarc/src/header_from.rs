@@ -0,0 +1,155 @@
+#[derive(Debug, thiserror::Error)]
+pub enum HeaderFromError {
+    #[error("Message has no From header field")]
+    NoFrom,
+
+    #[error("Message has multiple From header fields")]
+    MultipleFrom,
+
+    #[error("From header field has multiple addresses")]
+    MultipleAddresses,
+
+    #[error("Could not find a domain in From header field ‘{0}’")]
+    NoDomain(String),
+}
+
+fn field_name(field: &[u8]) -> &[u8] {
+    let colon = field.iter().position(|&b| b == b':').unwrap_or(field.len());
+    let name = &field[..colon];
+    let end = name
+        .iter()
+        .rposition(|&b| b != b' ' && b != b'\t')
+        .map_or(0, |i| i + 1);
+    &name[..end]
+}
+
+/// Removes comments from `value`, and splits it at the top-level commas
+fn split_addresses(value: &str) -> Vec<String> {
+    let mut res = vec![String::new()];
+    let mut comment_depth = 0usize;
+    let mut in_quotes = false;
+    let mut in_angle = false;
+    let mut escaped = false;
+    for c in value.chars() {
+        let cur = res.last_mut().unwrap();
+        if escaped {
+            escaped = false;
+            if comment_depth == 0 {
+                cur.push(c);
+            }
+            continue;
+        }
+        match c {
+            '\\' if in_quotes || comment_depth > 0 => escaped = true,
+            '(' if !in_quotes => comment_depth += 1,
+            ')' if !in_quotes && comment_depth > 0 => comment_depth -= 1,
+            _ if comment_depth > 0 => (),
+            '"' => {
+                in_quotes = !in_quotes;
+                cur.push(c);
+            }
+            '<' if !in_quotes => {
+                in_angle = true;
+                cur.push(c);
+            }
+            '>' if !in_quotes => {
+                in_angle = false;
+                cur.push(c);
+            }
+            ',' if !in_quotes && !in_angle => res.push(String::new()),
+            c => cur.push(c),
+        }
+    }
+    res.into_iter()
+        .map(|a| a.trim().to_owned())
+        .filter(|a| !a.is_empty())
+        .collect()
+}
+
+/// Returns the domain of a single address, either of the `name <addr>` or of
+/// the `addr` form
+fn address_domain(addr: &str) -> Option<String> {
+    let addr = match (addr.rfind('<'), addr.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &addr[start + 1..end],
+        _ => addr,
+    };
+    let domain = addr[addr.rfind('@')? + 1..].trim();
+    let domain = domain.trim_end_matches('.');
+    if domain.is_empty()
+        || !domain
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '.')
+    {
+        return None;
+    }
+    Some(domain.to_lowercase())
+}
+
+/// Returns the lowercase domain of the RFC5322.From header, given the header
+/// fields of the message (each including its final CRLF)
+///
+/// Messages without exactly one From address cannot be evaluated, see RFC 7489
+/// §6.6.1.
+pub fn header_from_domain<'a>(
+    fields: impl Iterator<Item = &'a [u8]>,
+) -> Result<String, HeaderFromError> {
+    let mut from = fields.filter(|f| field_name(f).eq_ignore_ascii_case(b"from"));
+    let field = from.next().ok_or(HeaderFromError::NoFrom)?;
+    if from.next().is_some() {
+        return Err(HeaderFromError::MultipleFrom);
+    }
+    let field = String::from_utf8_lossy(field);
+    let value = field.split_once(':').map_or("", |(_, v)| v);
+    let value = value.replace("\r\n", "");
+    let addresses = split_addresses(&value);
+    match addresses.len() {
+        0 => Err(HeaderFromError::NoDomain(value.trim().to_owned())),
+        1 => address_domain(&addresses[0])
+            .ok_or_else(|| HeaderFromError::NoDomain(value.trim().to_owned())),
+        _ => Err(HeaderFromError::MultipleAddresses),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn domain(headers: &str) -> Option<String> {
+        let mut fields: Vec<String> = Vec::new();
+        for line in headers.split_inclusive("\r\n") {
+            match fields.last_mut() {
+                Some(f) if line.starts_with(' ') => f.push_str(line),
+                _ => fields.push(line.to_owned()),
+            }
+        }
+        header_from_domain(fields.iter().map(|f| f.as_bytes())).ok()
+    }
+
+    #[test]
+    fn header_from_domains() {
+        let tests: &[(&str, Option<&str>)] = &[
+            ("From: joe@Example.COM\r\n", Some("example.com")),
+            (
+                "To: a@b.c\r\nfrom : \"Joe, <Q>\" <joe@example.com> (at @ home)\r\n",
+                Some("example.com"),
+            ),
+            (
+                "From: Joe\r\n <joe@mail.example.org.>\r\n",
+                Some("mail.example.org"),
+            ),
+            (
+                "From: joe@example.com (comment, with comma)\r\n",
+                Some("example.com"),
+            ),
+            ("To: joe@example.com\r\n", None),
+            ("From: joe@example.com\r\nFrom: joe@example.org\r\n", None),
+            ("From: joe@example.com, jane@example.com\r\n", None),
+            ("From: undisclosed\r\n", None),
+            ("From: joe@[127.0.0.1]\r\n", None),
+        ];
+        for (headers, res) in tests {
+            println!("Testing {:?}", headers);
+            assert_eq!(domain(headers).as_deref(), *res);
+        }
+    }
+}
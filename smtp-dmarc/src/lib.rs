@@ -0,0 +1,421 @@
+use smtp_dkim::DkimResult;
+#[cfg(feature = "evaluate")]
+use smtp_dkim::DkimStatus;
+use smtp_spf::SpfResult;
+#[cfg(feature = "evaluate")]
+use tracing::trace;
+
+mod header_from;
+#[cfg(feature = "evaluate")]
+mod psl;
+mod record;
+#[cfg(feature = "evaluate")]
+mod report;
+
+pub use header_from::{header_from_domain, HeaderFromError};
+#[cfg(feature = "evaluate")]
+pub use psl::organizational_domain;
+pub use record::{is_dmarc, Alignment, ParseError, Policy, Record};
+#[cfg(feature = "evaluate")]
+pub use report::{
+    aggregate, mailto_address, verify_destination, AggregateReport, DkimAuth, Observation,
+    Reporter, SpfAuth, SpfScope,
+};
+pub use smtp_dkim::{Lookup, LookupError};
+
+/// Status of a DMARC evaluation, see RFC 8601 §2.7.1
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum DmarcStatus {
+    /// The domain publishes no DMARC policy
+    None,
+    Pass,
+    Fail,
+    TempError,
+    PermError,
+}
+
+impl DmarcStatus {
+    /// Name of the status, as used in `Authentication-Results` headers
+    pub fn name(&self) -> &'static str {
+        match self {
+            DmarcStatus::None => "none",
+            DmarcStatus::Pass => "pass",
+            DmarcStatus::Fail => "fail",
+            DmarcStatus::TempError => "temperror",
+            DmarcStatus::PermError => "permerror",
+        }
+    }
+}
+
+/// Result of the DMARC evaluation of a message
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct DmarcResult {
+    pub status: DmarcStatus,
+    /// Human-readable reason for a non-passing status
+    pub reason: Option<String>,
+    /// Domain of the RFC5322.From header, if one could be found
+    pub header_from: Option<String>,
+    /// Domain at which the policy record was found
+    pub policy_domain: Option<String>,
+    /// Policy record that was applied
+    pub record: Option<Record>,
+    /// Policy requested by the domain owner for this message, were it to
+    /// fail
+    pub policy: Option<Policy>,
+    /// Policy that should actually be applied to the message, after `pct`
+    /// sampling
+    pub disposition: Policy,
+    /// Whether a passing DKIM signature is aligned with the From domain
+    pub dkim_aligned: bool,
+    /// Whether a passing SPF result is aligned with the From domain
+    pub spf_aligned: bool,
+}
+
+impl DmarcResult {
+    pub fn new(status: DmarcStatus, reason: Option<String>) -> DmarcResult {
+        DmarcResult {
+            status,
+            reason,
+            header_from: None,
+            policy_domain: None,
+            record: None,
+            policy: None,
+            disposition: Policy::None,
+            dkim_aligned: false,
+            spf_aligned: false,
+        }
+    }
+}
+
+/// Authentication results a DMARC evaluation is based on
+pub struct Query<'a> {
+    /// Domain of the RFC5322.From header, see `header_from_domain`
+    pub header_from: &'a str,
+    /// Domain authenticated by SPF along with the SPF result, that is the
+    /// MAIL FROM domain, or the HELO domain for the null reverse-path
+    pub spf: Option<(&'a str, &'a SpfResult)>,
+    pub dkim: &'a [DkimResult],
+}
+
+/// Looks up the DMARC record of `domain`, returning `None` if there is not
+/// exactly one
+#[cfg(feature = "evaluate")]
+async fn discover_policy(
+    lookup: &dyn Lookup,
+    domain: &str,
+) -> Result<Option<Result<Record, ParseError>>, LookupError> {
+    let name = format!("_dmarc.{}", domain);
+    let records = lookup
+        .lookup_txt(&name)
+        .await?
+        .into_iter()
+        .filter(|r| is_dmarc(r))
+        .collect::<Vec<_>>();
+    trace!(name = %name, records = ?records, "Retrieved DMARC records");
+    match records.as_slice() {
+        [r] => Ok(Some(Record::parse(r))),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(feature = "evaluate")]
+fn is_aligned(domain: &str, header_from: &str, mode: Alignment) -> bool {
+    let domain = domain.trim_end_matches('.');
+    match mode {
+        Alignment::Strict => domain.eq_ignore_ascii_case(header_from),
+        Alignment::Relaxed => organizational_domain(domain) == organizational_domain(header_from),
+    }
+}
+
+/// Evaluates the DMARC policy of the domain of the RFC5322.From header
+/// against the SPF and DKIM results of the message, see RFC 7489 §6.6
+#[cfg(feature = "evaluate")]
+pub async fn evaluate(lookup: &dyn Lookup, query: &Query<'_>) -> DmarcResult {
+    evaluate_with_sample(lookup, query, rand::random::<u8>() % 100).await
+}
+
+/// Same as `evaluate`, `sample` being the number between 0 and 99 used for
+/// `pct` sampling
+#[cfg(feature = "evaluate")]
+async fn evaluate_with_sample(lookup: &dyn Lookup, query: &Query<'_>, sample: u8) -> DmarcResult {
+    let header_from = query.header_from.trim_end_matches('.').to_ascii_lowercase();
+    let with_status = |status, reason: String| DmarcResult {
+        header_from: Some(header_from.clone()),
+        ..DmarcResult::new(status, Some(reason))
+    };
+
+    // Policy discovery, falling back to the organizational domain
+    let org_domain = organizational_domain(&header_from);
+    let mut policy_domain = header_from.clone();
+    let mut record = discover_policy(lookup, &policy_domain).await;
+    if matches!(record, Ok(None)) && org_domain != header_from {
+        policy_domain = org_domain;
+        record = discover_policy(lookup, &policy_domain).await;
+    }
+    let record = match record {
+        Ok(Some(Ok(r))) => r,
+        Ok(Some(Err(e))) => {
+            return DmarcResult {
+                policy_domain: Some(policy_domain),
+                ..with_status(
+                    DmarcStatus::PermError,
+                    format!("Invalid DMARC record: {}", e),
+                )
+            }
+        }
+        Ok(None) => {
+            return with_status(
+                DmarcStatus::None,
+                format!("No DMARC record for ‘{}’", header_from),
+            )
+        }
+        Err(e) => return with_status(DmarcStatus::TempError, e.to_string()),
+    };
+
+    // Identifier alignment
+    let dkim_aligned = query.dkim.iter().any(|r| {
+        r.status == DkimStatus::Pass
+            && matches!(&r.domain, Some(d) if is_aligned(d, &header_from, record.dkim_alignment))
+    });
+    let spf_aligned = matches!(
+        query.spf,
+        Some((d, SpfResult::Pass)) if is_aligned(d, &header_from, record.spf_alignment)
+    );
+
+    let policy = match record.subdomain_policy {
+        Some(sp) if policy_domain != header_from => sp,
+        _ => record.policy,
+    };
+    let (status, reason, disposition) = if dkim_aligned || spf_aligned {
+        (DmarcStatus::Pass, None, Policy::None)
+    } else {
+        let reason = String::from("No aligned passing DKIM signature nor SPF result");
+        let disposition = if sample < record.percent {
+            policy
+        } else {
+            policy.less_restrictive()
+        };
+        (DmarcStatus::Fail, Some(reason), disposition)
+    };
+    DmarcResult {
+        status,
+        reason,
+        header_from: Some(header_from),
+        policy_domain: Some(policy_domain),
+        record: Some(record),
+        policy: Some(policy),
+        disposition,
+        dkim_aligned,
+        spf_aligned,
+    }
+}
+
+#[cfg(all(test, feature = "evaluate"))]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use futures::executor;
+
+    #[derive(Default)]
+    struct StubLookup {
+        txt: HashMap<&'static str, Vec<&'static str>>,
+        failing: Vec<&'static str>,
+    }
+
+    #[async_trait::async_trait]
+    impl Lookup for StubLookup {
+        async fn lookup_txt(&self, name: &str) -> Result<Vec<String>, LookupError> {
+            if self.failing.contains(&name) {
+                return Err(LookupError {
+                    name: name.to_owned(),
+                    reason: "stub failure".to_owned(),
+                });
+            }
+            Ok(self
+                .txt
+                .get(name)
+                .into_iter()
+                .flatten()
+                .map(|s| String::from(*s))
+                .collect())
+        }
+    }
+
+    fn dkim(status: DkimStatus, domain: &str) -> DkimResult {
+        DkimResult {
+            status,
+            reason: None,
+            domain: Some(domain.to_owned()),
+            selector: Some(String::from("sel")),
+            identity: Some(format!("@{}", domain)),
+            b: None,
+        }
+    }
+
+    #[test]
+    fn evaluations() {
+        let mut lookup = StubLookup::default();
+        lookup.txt.insert("_dmarc.example.com", vec![
+            "v=DMARC1; p=reject; sp=quarantine; pct=50",
+        ]);
+        lookup.txt.insert("_dmarc.strict.example.org", vec![
+            "v=DMARC1; p=reject; adkim=s; aspf=s",
+        ]);
+        lookup.txt.insert("_dmarc.multi.example.net", vec![
+            "v=DMARC1; p=none",
+            "v=DMARC1; p=reject",
+        ]);
+        lookup
+            .txt
+            .insert("_dmarc.broken.example.net", vec!["v=DMARC1; p=bogus"]);
+        lookup.failing.push("_dmarc.failing.example.net");
+
+        let pass = SpfResult::Pass;
+        let fail = SpfResult::Fail { explanation: None };
+        let dkim_pass_sub = [dkim(DkimStatus::Pass, "mail.example.com")];
+        let dkim_fail = [dkim(DkimStatus::Fail, "example.com")];
+        let dkim_pass_strict = [dkim(DkimStatus::Pass, "strict.example.org.")];
+        let dkim_pass_org = [dkim(DkimStatus::Pass, "example.org")];
+
+        type Test<'a> = (Query<'a>, u8, DmarcStatus, Option<&'a str>, Policy);
+        let tests: Vec<Test> = vec![
+            // Relaxed alignment of DKIM
+            (
+                Query {
+                    header_from: "example.com",
+                    spf: Some(("other.example", &pass)),
+                    dkim: &dkim_pass_sub,
+                },
+                0,
+                DmarcStatus::Pass,
+                Some("example.com"),
+                Policy::None,
+            ),
+            // Relaxed alignment of SPF, subdomain policy and pct sampling
+            (
+                Query {
+                    header_from: "Sub.Example.com",
+                    spf: Some(("bounces.example.com", &pass)),
+                    dkim: &[],
+                },
+                0,
+                DmarcStatus::Pass,
+                Some("example.com"),
+                Policy::None,
+            ),
+            (
+                Query {
+                    header_from: "sub.example.com",
+                    spf: Some(("sub.example.com", &fail)),
+                    dkim: &dkim_fail,
+                },
+                10,
+                DmarcStatus::Fail,
+                Some("example.com"),
+                Policy::Quarantine,
+            ),
+            (
+                Query {
+                    header_from: "example.com",
+                    spf: None,
+                    dkim: &dkim_fail,
+                },
+                10,
+                DmarcStatus::Fail,
+                Some("example.com"),
+                Policy::Reject,
+            ),
+            (
+                Query {
+                    header_from: "example.com",
+                    spf: None,
+                    dkim: &dkim_fail,
+                },
+                60,
+                DmarcStatus::Fail,
+                Some("example.com"),
+                Policy::Quarantine,
+            ),
+            // Strict alignment
+            (
+                Query {
+                    header_from: "strict.example.org",
+                    spf: Some(("bounces.strict.example.org", &pass)),
+                    dkim: &dkim_pass_strict,
+                },
+                0,
+                DmarcStatus::Pass,
+                Some("strict.example.org"),
+                Policy::None,
+            ),
+            (
+                Query {
+                    header_from: "strict.example.org",
+                    spf: Some(("bounces.strict.example.org", &pass)),
+                    dkim: &dkim_pass_org,
+                },
+                0,
+                DmarcStatus::Fail,
+                Some("strict.example.org"),
+                Policy::Reject,
+            ),
+            // Errors
+            (
+                Query {
+                    header_from: "nodmarc.example.net",
+                    spf: None,
+                    dkim: &[],
+                },
+                0,
+                DmarcStatus::None,
+                None,
+                Policy::None,
+            ),
+            (
+                Query {
+                    header_from: "multi.example.net",
+                    spf: None,
+                    dkim: &[],
+                },
+                0,
+                DmarcStatus::None,
+                None,
+                Policy::None,
+            ),
+            (
+                Query {
+                    header_from: "broken.example.net",
+                    spf: None,
+                    dkim: &[],
+                },
+                0,
+                DmarcStatus::PermError,
+                Some("broken.example.net"),
+                Policy::None,
+            ),
+            (
+                Query {
+                    header_from: "failing.example.net",
+                    spf: None,
+                    dkim: &[],
+                },
+                0,
+                DmarcStatus::TempError,
+                None,
+                Policy::None,
+            ),
+        ];
+        for (query, sample, status, policy_domain, disposition) in tests {
+            println!("Testing {:?} with sample {}", query.header_from, sample);
+            let res = executor::block_on(evaluate_with_sample(&lookup, &query, sample));
+            println!("Result: {:?}", res);
+            assert_eq!(res.status, status);
+            assert_eq!(res.policy_domain.as_deref(), policy_domain);
+            assert_eq!(res.disposition, disposition);
+        }
+    }
+}
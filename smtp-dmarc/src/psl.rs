@@ -0,0 +1,99 @@
+use std::collections::HashSet;
+
+/// Public Suffix List, as retrieved from https://publicsuffix.org/list/
+const PUBLIC_SUFFIX_LIST: &str = include_str!("../res/public_suffix_list.dat");
+
+struct Rules {
+    normal: HashSet<&'static str>,
+    /// Rules of the form `*.suffix`, stored as `suffix`
+    wildcard: HashSet<&'static str>,
+    /// Rules of the form `!name`, stored as `name`
+    exception: HashSet<&'static str>,
+}
+
+lazy_static::lazy_static! {
+    static ref RULES: Rules = {
+        let mut rules = Rules {
+            normal: HashSet::new(),
+            wildcard: HashSet::new(),
+            exception: HashSet::new(),
+        };
+        for line in PUBLIC_SUFFIX_LIST.lines() {
+            // Rules end at the first whitespace
+            let rule = match line.split_whitespace().next() {
+                Some(r) if !r.starts_with("//") => r,
+                _ => continue,
+            };
+            if let Some(r) = rule.strip_prefix("*.") {
+                rules.wildcard.insert(r);
+            } else if let Some(r) = rule.strip_prefix('!') {
+                rules.exception.insert(r);
+            } else {
+                rules.normal.insert(rule);
+            }
+        }
+        rules
+    };
+}
+
+/// Returns the number of labels of the public suffix of `domain`, which must
+/// be lowercase and without a trailing dot
+fn public_suffix_labels(domain: &str) -> usize {
+    let labels = domain.split('.').collect::<Vec<_>>();
+    // Unlisted TLDs are public suffixes, following the implicit `*` rule
+    let mut res = 1;
+    for n in 1..=labels.len() {
+        let suffix = labels[labels.len() - n..].join(".");
+        if RULES.exception.contains(suffix.as_str()) {
+            // Exception rules take priority, and remove the leftmost label
+            return n - 1;
+        }
+        if RULES.normal.contains(suffix.as_str()) {
+            res = n;
+        }
+        if n < labels.len() && RULES.wildcard.contains(suffix.as_str()) {
+            res = n + 1;
+        }
+    }
+    res
+}
+
+/// Returns the organizational domain of `domain`, in lowercase, see RFC 7489
+/// §3.2
+///
+/// Public suffixes are their own organizational domain.
+pub fn organizational_domain(domain: &str) -> String {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    let suffix_labels = public_suffix_labels(&domain);
+    let labels = domain.split('.').collect::<Vec<_>>();
+    if labels.len() <= suffix_labels {
+        return domain;
+    }
+    labels[labels.len() - suffix_labels - 1..].join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn organizational_domains() {
+        let tests: &[(&str, &str)] = &[
+            ("example.com", "example.com"),
+            ("mail.EXAMPLE.com.", "example.com"),
+            ("a.b.c.example.co.uk", "example.co.uk"),
+            ("co.uk", "co.uk"),
+            ("com", "com"),
+            ("foo.bar.unlistedtld", "bar.unlistedtld"),
+            // Wildcard and exception rules: `*.ck` and `!www.ck`
+            ("a.b.ck", "a.b.ck"),
+            ("x.a.b.ck", "a.b.ck"),
+            ("www.ck", "www.ck"),
+            ("mail.www.ck", "www.ck"),
+        ];
+        for (domain, org) in tests {
+            println!("Testing {:?}", domain);
+            assert_eq!(organizational_domain(domain), *org);
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use std::str::FromStr;
+
+/// Policy requested by a domain owner, see RFC 7489 §6.3
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum Policy {
+    None,
+    Quarantine,
+    Reject,
+}
+
+impl Policy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Policy::None => "none",
+            Policy::Quarantine => "quarantine",
+            Policy::Reject => "reject",
+        }
+    }
+
+    /// Policy to apply to messages that are not selected by `pct`, see RFC
+    /// 7489 §6.6.4
+    pub fn less_restrictive(&self) -> Policy {
+        match self {
+            Policy::None | Policy::Quarantine => Policy::None,
+            Policy::Reject => Policy::Quarantine,
+        }
+    }
+}
+
+impl FromStr for Policy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Policy, ()> {
+        match s {
+            "none" => Ok(Policy::None),
+            "quarantine" => Ok(Policy::Quarantine),
+            "reject" => Ok(Policy::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Identifier alignment mode, see RFC 7489 §3.1
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum Alignment {
+    Relaxed,
+    Strict,
+}
+
+impl Alignment {
+    /// Short name of the mode, as used in the `adkim` and `aspf` tags
+    pub fn name(&self) -> &'static str {
+        match self {
+            Alignment::Relaxed => "r",
+            Alignment::Strict => "s",
+        }
+    }
+}
+
+impl FromStr for Alignment {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Alignment, ()> {
+        match s {
+            "r" => Ok(Alignment::Relaxed),
+            "s" => Ok(Alignment::Strict),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A DMARC policy record, see RFC 7489 §6.3
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct Record {
+    /// `p=` tag
+    pub policy: Policy,
+    /// `sp=` tag
+    pub subdomain_policy: Option<Policy>,
+    /// `adkim=` tag
+    pub dkim_alignment: Alignment,
+    /// `aspf=` tag
+    pub spf_alignment: Alignment,
+    /// `pct=` tag
+    pub percent: u8,
+    /// `rua=` tag
+    pub aggregate_uris: Vec<String>,
+    /// `ruf=` tag
+    pub failure_uris: Vec<String>,
+    /// `fo=` tag
+    pub failure_options: String,
+    /// `ri=` tag, in seconds
+    pub report_interval: u32,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseError {
+    #[error("Record does not start with ‘v=DMARC1’")]
+    NotDmarc,
+
+    #[error("Invalid tag ‘{0}’")]
+    InvalidTag(String),
+
+    #[error("Tag ‘{0}’ appears more than once")]
+    DuplicateTag(String),
+
+    #[error("Invalid value ‘{1}’ for tag ‘{0}’")]
+    InvalidValue(&'static str, String),
+}
+
+/// Returns whether the TXT record `txt` is meant to be a DMARC record
+pub fn is_dmarc(txt: &str) -> bool {
+    match txt.split(';').next() {
+        Some(v) => {
+            let v = v.split('=').map(str::trim).collect::<Vec<_>>();
+            v == ["v", "DMARC1"]
+        }
+        None => false,
+    }
+}
+
+fn parse_uris(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(str::trim)
+        // Only keep syntactically valid URIs, which all have a scheme
+        .filter(|u| matches!(u.find(':'), Some(i) if i > 0))
+        .map(str::to_owned)
+        .collect()
+}
+
+impl Record {
+    pub fn parse(s: &str) -> Result<Record, ParseError> {
+        if !is_dmarc(s) {
+            return Err(ParseError::NotDmarc);
+        }
+
+        let mut tags: Vec<(&str, &str)> = Vec::new();
+        for t in s.split(';').skip(1) {
+            let t = t.trim();
+            if t.is_empty() {
+                continue;
+            }
+            let (name, value) = match t.find('=') {
+                Some(i) => (t[..i].trim(), t[i + 1..].trim()),
+                None => return Err(ParseError::InvalidTag(t.to_owned())),
+            };
+            if tags.iter().any(|(n, _)| *n == name) {
+                return Err(ParseError::DuplicateTag(name.to_owned()));
+            }
+            tags.push((name, value));
+        }
+        let get = |name: &str| tags.iter().find(|(n, _)| *n == name).map(|(_, v)| *v);
+
+        let aggregate_uris = get("rua").map(parse_uris).unwrap_or_default();
+        let policy = get("p").and_then(|p| p.parse().ok());
+        let subdomain_policy = get("sp").map(|p| p.parse());
+        let (policy, subdomain_policy) = match (policy, subdomain_policy) {
+            (Some(p), None) => (p, None),
+            (Some(p), Some(Ok(sp))) => (p, Some(sp)),
+            // RFC 7489 §6.6.3: records with an invalid policy but a valid
+            // reporting URI are handled as though they requested no policy
+            _ if !aggregate_uris.is_empty() => (Policy::None, None),
+            _ => {
+                return Err(ParseError::InvalidValue(
+                    "p",
+                    get("p").unwrap_or("").to_owned(),
+                ))
+            }
+        };
+
+        let alignment = |name: &'static str| match get(name) {
+            None => Ok(Alignment::Relaxed),
+            Some(a) => a
+                .parse()
+                .map_err(|()| ParseError::InvalidValue(name, a.to_owned())),
+        };
+        let percent = match get("pct") {
+            None => 100,
+            Some(p) => match p.parse::<u8>() {
+                Ok(p) if p <= 100 => p,
+                _ => return Err(ParseError::InvalidValue("pct", p.to_owned())),
+            },
+        };
+        let report_interval = match get("ri") {
+            None => 86400,
+            Some(ri) => ri
+                .parse()
+                .map_err(|_| ParseError::InvalidValue("ri", ri.to_owned()))?,
+        };
+
+        Ok(Record {
+            policy,
+            subdomain_policy,
+            dkim_alignment: alignment("adkim")?,
+            spf_alignment: alignment("aspf")?,
+            percent,
+            aggregate_uris,
+            failure_uris: get("ruf").map(parse_uris).unwrap_or_default(),
+            failure_options: get("fo").unwrap_or("0").to_owned(),
+            report_interval,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records() {
+        let r = Record::parse("v=DMARC1; p=reject").unwrap();
+        assert_eq!(r, Record {
+            policy: Policy::Reject,
+            subdomain_policy: None,
+            dkim_alignment: Alignment::Relaxed,
+            spf_alignment: Alignment::Relaxed,
+            percent: 100,
+            aggregate_uris: Vec::new(),
+            failure_uris: Vec::new(),
+            failure_options: String::from("0"),
+            report_interval: 86400,
+        });
+
+        let r = Record::parse(
+            "v = DMARC1;p=quarantine; sp=none; adkim=s; aspf=r; pct=20; \
+             rua=mailto:dmarc@example.com!10m, mailto:other@example.org; ri=3600;",
+        )
+        .unwrap();
+        assert_eq!(r.policy, Policy::Quarantine);
+        assert_eq!(r.subdomain_policy, Some(Policy::None));
+        assert_eq!(r.dkim_alignment, Alignment::Strict);
+        assert_eq!(r.percent, 20);
+        assert_eq!(r.aggregate_uris, vec![
+            "mailto:dmarc@example.com!10m",
+            "mailto:other@example.org"
+        ]);
+        assert_eq!(r.report_interval, 3600);
+
+        // Invalid policies are ignored when there is a reporting URI
+        let r = Record::parse("v=DMARC1; p=bogus; rua=mailto:a@example.com").unwrap();
+        assert_eq!(r.policy, Policy::None);
+
+        for invalid in &[
+            "v=spf1 -all",
+            "p=reject; v=DMARC1",
+            "v=DMARC1",
+            "v=DMARC1; p=bogus",
+            "v=DMARC1; p=none; p=reject",
+            "v=DMARC1; p=none; pct=101",
+            "v=DMARC1; p=none; adkim=x",
+            "v=DMARC1; p=none; foo",
+        ] {
+            println!("Testing {:?}", invalid);
+            assert!(Record::parse(invalid).is_err());
+        }
+    }
+}
@@ -0,0 +1,511 @@
+use std::{collections::HashMap, net::IpAddr};
+
+use smtp_dkim::DkimResult;
+use smtp_spf::SpfResult;
+
+use crate::{is_dmarc, organizational_domain, DmarcResult, Lookup, LookupError, Policy, Record};
+
+/// Maximum length of the lines of the base64-encoded report
+const MAX_LINE_LEN: usize = 76;
+
+/// Identity checked by SPF
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum SpfScope {
+    Helo,
+    MailFrom,
+}
+
+impl SpfScope {
+    /// Name of the scope, as used in aggregate reports
+    pub fn name(&self) -> &'static str {
+        match self {
+            SpfScope::Helo => "helo",
+            SpfScope::MailFrom => "mfrom",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct DkimAuth {
+    pub domain: String,
+    pub selector: Option<String>,
+    pub result: String,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct SpfAuth {
+    pub domain: String,
+    pub scope: SpfScope,
+    pub result: String,
+}
+
+/// DMARC evaluation of one message, as recorded for aggregate reports
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct Observation {
+    /// UNIX timestamp of the reception of the message
+    pub time: u64,
+    pub source_ip: IpAddr,
+    pub header_from: String,
+    pub envelope_from: Option<String>,
+    pub policy_domain: String,
+    pub published: Record,
+    pub disposition: Policy,
+    pub dkim_aligned: bool,
+    pub spf_aligned: bool,
+    pub dkim: Vec<DkimAuth>,
+    pub spf: Option<SpfAuth>,
+}
+
+impl Observation {
+    /// Builds the observation for a message, returning `None` if no policy
+    /// record applied to it
+    pub fn new(
+        time: u64,
+        source_ip: IpAddr,
+        envelope_from: Option<String>,
+        dmarc: &DmarcResult,
+        dkim: &[DkimResult],
+        spf: Option<(&str, SpfScope, &SpfResult)>,
+    ) -> Option<Observation> {
+        Some(Observation {
+            time,
+            source_ip,
+            header_from: dmarc.header_from.clone()?,
+            envelope_from,
+            policy_domain: dmarc.policy_domain.clone()?,
+            published: dmarc.record.clone()?,
+            disposition: dmarc.disposition,
+            dkim_aligned: dmarc.dkim_aligned,
+            spf_aligned: dmarc.spf_aligned,
+            dkim: dkim
+                .iter()
+                .filter_map(|r| {
+                    Some(DkimAuth {
+                        domain: r.domain.clone()?,
+                        selector: r.selector.clone(),
+                        result: r.status.name().to_owned(),
+                    })
+                })
+                .collect(),
+            spf: spf.map(|(domain, scope, res)| SpfAuth {
+                domain: domain.to_owned(),
+                scope,
+                result: res.name().to_owned(),
+            }),
+        })
+    }
+}
+
+/// Information about the organization generating the reports
+pub struct Reporter {
+    pub org_name: String,
+    /// Address the reports are sent from
+    pub email: String,
+    pub extra_contact_info: Option<String>,
+}
+
+/// Observations that are reported together, as they only differ by their time
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct Row {
+    source_ip: IpAddr,
+    header_from: String,
+    envelope_from: Option<String>,
+    disposition: Policy,
+    dkim_aligned: bool,
+    spf_aligned: bool,
+    dkim: Vec<DkimAuth>,
+    spf: Option<SpfAuth>,
+}
+
+/// Aggregate report for one policy domain, see RFC 7489 §7.2
+pub struct AggregateReport {
+    pub policy_domain: String,
+    pub report_id: String,
+    /// UNIX timestamps of the beginning and end of the reported period
+    pub begin: u64,
+    pub end: u64,
+    /// Latest policy record seen for the domain
+    pub published: Record,
+    rows: Vec<(Row, u64)>,
+}
+
+/// Groups `observations` into one report per policy domain
+pub fn aggregate(observations: Vec<Observation>) -> Vec<AggregateReport> {
+    let mut reports: Vec<AggregateReport> = Vec::new();
+    let mut report_idx = HashMap::new();
+    let mut row_idx = HashMap::new();
+    for o in observations {
+        let r = *report_idx
+            .entry(o.policy_domain.clone())
+            .or_insert_with(|| {
+                reports.push(AggregateReport {
+                    policy_domain: o.policy_domain.clone(),
+                    report_id: String::new(),
+                    begin: o.time,
+                    end: o.time,
+                    published: o.published.clone(),
+                    rows: Vec::new(),
+                });
+                reports.len() - 1
+            });
+        let report = &mut reports[r];
+        report.begin = std::cmp::min(report.begin, o.time);
+        if o.time >= report.end {
+            report.end = o.time;
+            report.published = o.published;
+        }
+        let row = Row {
+            source_ip: o.source_ip,
+            header_from: o.header_from,
+            envelope_from: o.envelope_from,
+            disposition: o.disposition,
+            dkim_aligned: o.dkim_aligned,
+            spf_aligned: o.spf_aligned,
+            dkim: o.dkim,
+            spf: o.spf,
+        };
+        let rows = &mut report.rows;
+        let i = *row_idx.entry((r, row.clone())).or_insert_with(|| {
+            rows.push((row, 0));
+            rows.len() - 1
+        });
+        rows[i].1 += 1;
+    }
+    for r in &mut reports {
+        r.report_id = format!("{}.{}.{}", r.policy_domain, r.begin, r.end);
+    }
+    reports
+}
+
+fn escape(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            '"' => res.push_str("&quot;"),
+            '\'' => res.push_str("&apos;"),
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+fn pass_fail(pass: bool) -> &'static str {
+    if pass {
+        "pass"
+    } else {
+        "fail"
+    }
+}
+
+/// Returns the address of a `mailto:` reporting URI, dropping the size limit
+/// if any
+pub fn mailto_address(uri: &str) -> Option<String> {
+    if uri.len() < 7 || !uri[..7].eq_ignore_ascii_case("mailto:") {
+        return None;
+    }
+    let addr = &uri[7..];
+    let addr = addr.split(['!', '?']).next().unwrap_or("");
+    match addr.rfind('@') {
+        Some(i) if i > 0 && i + 1 < addr.len() => Some(addr.to_owned()),
+        _ => None,
+    }
+}
+
+/// Checks whether `address` accepts reports about `policy_domain`, see RFC
+/// 7489 §7.1
+pub async fn verify_destination(
+    lookup: &dyn Lookup,
+    policy_domain: &str,
+    address: &str,
+) -> Result<bool, LookupError> {
+    let dest_domain = match address.rfind('@') {
+        Some(i) => &address[i + 1..],
+        None => return Ok(false),
+    };
+    if organizational_domain(dest_domain) == organizational_domain(policy_domain) {
+        return Ok(true);
+    }
+    let name = format!("{}._report._dmarc.{}", policy_domain, dest_domain);
+    Ok(lookup.lookup_txt(&name).await?.iter().any(|r| is_dmarc(r)))
+}
+
+impl AggregateReport {
+    /// Addresses listed in the `rua=` tag of the published policy
+    pub fn recipients(&self) -> Vec<String> {
+        self.published
+            .aggregate_uris
+            .iter()
+            .filter_map(|u| mailto_address(u))
+            .collect()
+    }
+
+    /// Returns the XML report, see RFC 7489 Appendix C
+    pub fn to_xml(&self, reporter: &Reporter) -> String {
+        let mut res = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<feedback>\n");
+        res.push_str("  <report_metadata>\n");
+        res.push_str(&format!(
+            "    <org_name>{}</org_name>\n",
+            escape(&reporter.org_name)
+        ));
+        res.push_str(&format!("    <email>{}</email>\n", escape(&reporter.email)));
+        if let Some(info) = &reporter.extra_contact_info {
+            res.push_str(&format!(
+                "    <extra_contact_info>{}</extra_contact_info>\n",
+                escape(info)
+            ));
+        }
+        res.push_str(&format!(
+            "    <report_id>{}</report_id>\n",
+            escape(&self.report_id)
+        ));
+        res.push_str(&format!(
+            "    <date_range>\n      <begin>{}</begin>\n      <end>{}</end>\n    </date_range>\n",
+            self.begin, self.end
+        ));
+        res.push_str("  </report_metadata>\n");
+
+        let p = &self.published;
+        res.push_str("  <policy_published>\n");
+        res.push_str(&format!(
+            "    <domain>{}</domain>\n",
+            escape(&self.policy_domain)
+        ));
+        res.push_str(&format!("    <adkim>{}</adkim>\n", p.dkim_alignment.name()));
+        res.push_str(&format!("    <aspf>{}</aspf>\n", p.spf_alignment.name()));
+        res.push_str(&format!("    <p>{}</p>\n", p.policy.name()));
+        res.push_str(&format!(
+            "    <sp>{}</sp>\n",
+            p.subdomain_policy.unwrap_or(p.policy).name()
+        ));
+        res.push_str(&format!("    <pct>{}</pct>\n", p.percent));
+        res.push_str("  </policy_published>\n");
+
+        for (row, count) in &self.rows {
+            res.push_str("  <record>\n    <row>\n");
+            res.push_str(&format!("      <source_ip>{}</source_ip>\n", row.source_ip));
+            res.push_str(&format!("      <count>{}</count>\n", count));
+            res.push_str("      <policy_evaluated>\n");
+            res.push_str(&format!(
+                "        <disposition>{}</disposition>\n",
+                row.disposition.name()
+            ));
+            res.push_str(&format!(
+                "        <dkim>{}</dkim>\n",
+                pass_fail(row.dkim_aligned)
+            ));
+            res.push_str(&format!(
+                "        <spf>{}</spf>\n",
+                pass_fail(row.spf_aligned)
+            ));
+            res.push_str("      </policy_evaluated>\n    </row>\n");
+
+            res.push_str("    <identifiers>\n");
+            if let Some(from) = &row.envelope_from {
+                res.push_str(&format!(
+                    "      <envelope_from>{}</envelope_from>\n",
+                    escape(from)
+                ));
+            }
+            res.push_str(&format!(
+                "      <header_from>{}</header_from>\n",
+                escape(&row.header_from)
+            ));
+            res.push_str("    </identifiers>\n");
+
+            res.push_str("    <auth_results>\n");
+            for d in &row.dkim {
+                res.push_str("      <dkim>\n");
+                res.push_str(&format!("        <domain>{}</domain>\n", escape(&d.domain)));
+                if let Some(s) = &d.selector {
+                    res.push_str(&format!("        <selector>{}</selector>\n", escape(s)));
+                }
+                res.push_str(&format!("        <result>{}</result>\n", escape(&d.result)));
+                res.push_str("      </dkim>\n");
+            }
+            // The schema requires an SPF result
+            let (domain, scope, result) = match &row.spf {
+                Some(s) => (s.domain.as_str(), s.scope, s.result.as_str()),
+                None => (row.header_from.as_str(), SpfScope::MailFrom, "none"),
+            };
+            res.push_str("      <spf>\n");
+            res.push_str(&format!("        <domain>{}</domain>\n", escape(domain)));
+            res.push_str(&format!("        <scope>{}</scope>\n", scope.name()));
+            res.push_str(&format!("        <result>{}</result>\n", escape(result)));
+            res.push_str("      </spf>\n");
+            res.push_str("    </auth_results>\n  </record>\n");
+        }
+        res.push_str("</feedback>\n");
+        res
+    }
+
+    /// Returns the mail carrying the report, see RFC 7489 §7.2.1.1, `date`
+    /// being the value of the `Date` header
+    pub fn to_mail(&self, reporter: &Reporter, recipients: &[String], date: &str) -> Vec<u8> {
+        let receiver = match reporter.email.rfind('@') {
+            Some(i) => &reporter.email[i + 1..],
+            None => reporter.email.as_str(),
+        };
+        let boundary = format!("report-{}", self.report_id);
+        let filename = format!(
+            "{}!{}!{}!{}.xml",
+            receiver, self.policy_domain, self.begin, self.end
+        );
+
+        let mut res = String::new();
+        res.push_str(&format!("From: {}\r\n", reporter.email));
+        res.push_str(&format!("To: {}\r\n", recipients.join(",\r\n ")));
+        res.push_str(&format!("Date: {}\r\n", date));
+        res.push_str(&format!(
+            "Subject: Report Domain: {} Submitter: {} Report-ID: <{}>\r\n",
+            self.policy_domain, receiver, self.report_id
+        ));
+        res.push_str(&format!(
+            "Message-ID: <{}@{}>\r\n",
+            self.report_id, receiver
+        ));
+        res.push_str("MIME-Version: 1.0\r\n");
+        res.push_str(&format!(
+            "Content-Type: multipart/mixed; boundary=\"{}\"\r\n",
+            boundary
+        ));
+        res.push_str("\r\n");
+
+        res.push_str(&format!("--{}\r\n", boundary));
+        res.push_str("Content-Type: text/plain; charset=utf-8\r\n\r\n");
+        res.push_str(&format!(
+            "This is a DMARC aggregate report for {} from {}.\r\n",
+            self.policy_domain, reporter.org_name
+        ));
+
+        res.push_str(&format!("--{}\r\n", boundary));
+        res.push_str("Content-Type: application/xml\r\n");
+        res.push_str("Content-Transfer-Encoding: base64\r\n");
+        res.push_str(&format!(
+            "Content-Disposition: attachment; filename=\"{}\"\r\n\r\n",
+            filename
+        ));
+        let xml = base64::encode(self.to_xml(reporter));
+        for line in xml.as_bytes().chunks(MAX_LINE_LEN) {
+            // base64 is ASCII
+            res.push_str(std::str::from_utf8(line).unwrap());
+            res.push_str("\r\n");
+        }
+        res.push_str(&format!("--{}--\r\n", boundary));
+        res.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::Alignment;
+
+    fn observation(time: u64, ip: &str, disposition: Policy) -> Observation {
+        Observation {
+            time,
+            source_ip: ip.parse().unwrap(),
+            header_from: String::from("example.com"),
+            envelope_from: Some(String::from("bounces.example.com")),
+            policy_domain: String::from("example.com"),
+            published: Record {
+                policy: Policy::Reject,
+                subdomain_policy: None,
+                dkim_alignment: Alignment::Relaxed,
+                spf_alignment: Alignment::Strict,
+                percent: 100,
+                aggregate_uris: vec![
+                    String::from("mailto:dmarc@example.com!10m"),
+                    String::from("https://example.com/report"),
+                ],
+                failure_uris: Vec::new(),
+                failure_options: String::from("0"),
+                report_interval: 86400,
+            },
+            disposition,
+            dkim_aligned: disposition == Policy::None,
+            spf_aligned: false,
+            dkim: vec![DkimAuth {
+                domain: String::from("example.com"),
+                selector: Some(String::from("sel")),
+                result: String::from("pass"),
+            }],
+            spf: None,
+        }
+    }
+
+    #[test]
+    fn aggregate_reports() {
+        let mut other = observation(15, "192.0.2.1", Policy::None);
+        other.policy_domain = String::from("example.org");
+        let reports = aggregate(vec![
+            observation(10, "192.0.2.1", Policy::None),
+            other,
+            observation(20, "192.0.2.1", Policy::None),
+            observation(5, "2001:db8::1", Policy::Reject),
+        ]);
+        assert_eq!(reports.len(), 2);
+        let r = &reports[0];
+        assert_eq!((r.begin, r.end), (5, 20));
+        assert_eq!(r.report_id, "example.com.5.20");
+        assert_eq!(r.recipients(), vec!["dmarc@example.com"]);
+
+        let reporter = Reporter {
+            org_name: String::from("Kannader & co"),
+            email: String::from("noreply-dmarc@mx.example.net"),
+            extra_contact_info: None,
+        };
+        let xml = r.to_xml(&reporter);
+        println!("{}", xml);
+        assert!(xml.contains("<org_name>Kannader &amp; co</org_name>"));
+        assert!(xml.contains("<aspf>s</aspf>"));
+        assert_eq!(xml.matches("<record>").count(), 2);
+        assert!(xml.contains(concat!(
+            "      <source_ip>192.0.2.1</source_ip>\n",
+            "      <count>2</count>\n",
+            "      <policy_evaluated>\n",
+            "        <disposition>none</disposition>\n",
+            "        <dkim>pass</dkim>\n",
+            "        <spf>fail</spf>\n",
+        )));
+        assert!(xml.contains("<scope>mfrom</scope>\n        <result>none</result>"));
+
+        let mail = String::from_utf8(r.to_mail(
+            &reporter,
+            &r.recipients(),
+            "Thu, 1 Jan 1970 00:00:20 +0000",
+        ))
+        .unwrap();
+        println!("{}", mail);
+        assert!(mail.contains(
+            "Subject: Report Domain: example.com Submitter: mx.example.net Report-ID: \
+             <example.com.5.20>\r\n"
+        ));
+        assert!(mail.contains("filename=\"mx.example.net!example.com!5!20.xml\""));
+        assert!(mail.split("\r\n").all(|l| l.len() <= 998));
+    }
+
+    #[test]
+    fn mailto_addresses() {
+        let tests: &[(&str, Option<&str>)] = &[
+            ("mailto:dmarc@example.com", Some("dmarc@example.com")),
+            ("MAILTO:dmarc@example.com!50m", Some("dmarc@example.com")),
+            (
+                "mailto:dmarc@example.com?subject=x",
+                Some("dmarc@example.com"),
+            ),
+            ("https://example.com/", None),
+            ("mailto:example.com", None),
+        ];
+        for (uri, addr) in tests {
+            assert_eq!(mailto_address(uri).as_deref(), *addr);
+        }
+    }
+}
@@ -0,0 +1,353 @@
+use std::fmt;
+
+/// A property of an authentication result, eg. `header.d=example.org`
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct AuthProperty {
+    /// Property type, eg. `smtp` or `header`
+    pub ptype: String,
+    pub property: String,
+    pub value: String,
+}
+
+/// The result of one authentication method, see RFC 8601 §2.2
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct AuthResult {
+    /// Method name, eg. `spf` or `dkim`
+    pub method: String,
+    /// Result name, eg. `pass` or `fail`
+    pub result: String,
+    pub reason: Option<String>,
+    pub properties: Vec<AuthProperty>,
+}
+
+impl AuthResult {
+    pub fn new(method: &str, result: &str) -> AuthResult {
+        AuthResult {
+            method: method.to_owned(),
+            result: result.to_owned(),
+            reason: None,
+            properties: Vec::new(),
+        }
+    }
+
+    pub fn with_reason(mut self, reason: &str) -> AuthResult {
+        self.reason = Some(reason.to_owned());
+        self
+    }
+
+    pub fn with_property(mut self, ptype: &str, property: &str, value: &str) -> AuthResult {
+        self.properties.push(AuthProperty {
+            ptype: ptype.to_owned(),
+            property: property.to_owned(),
+            value: value.to_owned(),
+        });
+        self
+    }
+}
+
+/// The value of an `Authentication-Results` header field, see RFC 8601
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct AuthenticationResults {
+    /// Identifier of the host that performed the checks
+    pub authserv_id: String,
+    /// No results means that no authentication method was applied
+    pub results: Vec<AuthResult>,
+}
+
+/// Cursor over a header field value, that knows how to skip comments and
+/// whitespace (CFWS)
+struct Cursor<'a>(&'a str);
+
+impl<'a> Cursor<'a> {
+    fn skip_cfws(&mut self) {
+        let mut depth = 0usize;
+        let mut chars = self.0.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                ' ' | '\t' | '\r' | '\n' => (),
+                '(' => depth += 1,
+                ')' if depth > 0 => depth -= 1,
+                '\\' if depth > 0 => {
+                    chars.next();
+                }
+                _ if depth > 0 => (),
+                _ => {
+                    self.0 = &self.0[i..];
+                    return;
+                }
+            }
+        }
+        self.0 = "";
+    }
+
+    fn is_empty(&mut self) -> bool {
+        self.skip_cfws();
+        self.0.is_empty()
+    }
+
+    /// Consumes `c` if it is the next character after CFWS
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_cfws();
+        match self.0.strip_prefix(c) {
+            Some(rest) => {
+                self.0 = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Consumes a quoted string or an unquoted value, the latter ending at
+    /// CFWS, `;`, or any character for which `stop` returns true
+    fn value(&mut self, stop: impl Fn(char) -> bool) -> Option<String> {
+        self.skip_cfws();
+        if let Some(rest) = self.0.strip_prefix('"') {
+            let mut res = String::new();
+            let mut chars = rest.char_indices();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '"' => {
+                        self.0 = &rest[i + 1..];
+                        return Some(res);
+                    }
+                    '\\' => res.push(chars.next()?.1),
+                    '\r' | '\n' => (),
+                    c => res.push(c),
+                }
+            }
+            return None;
+        }
+        let end = self
+            .0
+            .find(|c: char| matches!(c, ' ' | '\t' | '\r' | '\n' | '(' | ';' | '"') || stop(c))
+            .unwrap_or(self.0.len());
+        if end == 0 {
+            return None;
+        }
+        let (res, rest) = self.0.split_at(end);
+        self.0 = rest;
+        Some(res.to_owned())
+    }
+}
+
+/// Returns whether `s` can be written as a value without quoting it
+fn is_plain_value(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars().all(|c| {
+            c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~.@".contains(c) || !c.is_ascii()
+        })
+}
+
+fn write_value(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    if is_plain_value(s) {
+        return write!(f, "{}", s);
+    }
+    write!(f, "\"")?;
+    for c in s.chars() {
+        if c == '"' || c == '\\' {
+            write!(f, "\\")?;
+        }
+        write!(f, "{}", c)?;
+    }
+    write!(f, "\"")
+}
+
+impl AuthenticationResults {
+    /// Parses the value of an `Authentication-Results` header field
+    pub fn parse(value: &str) -> Option<AuthenticationResults> {
+        let mut cur = Cursor(value);
+        let authserv_id = cur.value(|_| false)?;
+        // Skip the version, only version 1 exists
+        if !cur.is_empty() && !cur.0.starts_with(';') {
+            let version = cur.value(|_| false)?;
+            if !version.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+        }
+
+        let mut results = Vec::new();
+        while !cur.is_empty() {
+            if !cur.eat(';') {
+                return None;
+            }
+            if cur.is_empty() {
+                break;
+            }
+            let method = cur.value(|c| c == '=' || c == '/')?;
+            if cur.eat('/') {
+                cur.value(|c| c == '=')?;
+            }
+            if !cur.eat('=') {
+                // `none` stands for the absence of results
+                if method.eq_ignore_ascii_case("none") && results.is_empty() && cur.is_empty() {
+                    break;
+                }
+                return None;
+            }
+            let mut res = AuthResult::new(&method, &cur.value(|c| c == '=')?);
+            while !cur.is_empty() && !cur.0.starts_with(';') {
+                let name = cur.value(|c| c == '=')?;
+                if !cur.eat('=') {
+                    return None;
+                }
+                let value = cur.value(|_| false)?;
+                if name.eq_ignore_ascii_case("reason")
+                    && res.reason.is_none()
+                    && res.properties.is_empty()
+                {
+                    res.reason = Some(value);
+                    continue;
+                }
+                let (ptype, property) = name.split_once('.')?;
+                res = res.with_property(ptype, property, &value);
+            }
+            results.push(res);
+        }
+
+        Some(AuthenticationResults {
+            authserv_id,
+            results,
+        })
+    }
+
+    /// Returns the authserv-id of the value of an `Authentication-Results`
+    /// header field, even if the rest of the value is malformed
+    pub fn parse_authserv_id(value: &str) -> Option<String> {
+        Cursor(value).value(|_| false)
+    }
+
+    /// Returns the whole header field, including its final CRLF
+    pub fn to_header(&self) -> String {
+        format!("Authentication-Results: {}\r\n", self)
+    }
+}
+
+/// Formats the value of the header field, with one result per line
+impl fmt::Display for AuthenticationResults {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_value(f, &self.authserv_id)?;
+        if self.results.is_empty() {
+            return write!(f, "; none");
+        }
+        for r in &self.results {
+            write!(f, ";\r\n\t{}={}", r.method, r.result)?;
+            if let Some(reason) = &r.reason {
+                write!(f, " reason=")?;
+                write_value(f, reason)?;
+            }
+            for p in &r.properties {
+                write!(f, " {}.{}=", p.ptype, p.property)?;
+                write_value(f, &p.value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_valid() {
+        let tests: &[(&str, AuthenticationResults)] = &[
+            ("example.org 1; none", AuthenticationResults {
+                authserv_id: String::from("example.org"),
+                results: Vec::new(),
+            }),
+            (
+                " example.com;\r\n spf=pass smtp.mailfrom=example.net",
+                AuthenticationResults {
+                    authserv_id: String::from("example.com"),
+                    results: vec![AuthResult::new("spf", "pass").with_property(
+                        "smtp",
+                        "mailfrom",
+                        "example.net",
+                    )],
+                },
+            ),
+            (
+                "example.com (a comment);\r\n\tauth=pass (cram-md5) \
+                 smtp.auth=sender@example.net;\r\n spf = pass smtp.mailfrom=example.net;\r\n \
+                 dkim/1=fail reason=\"bad \\\"sig\\\"\" header.d=example.net header.b=ab+/c=;",
+                AuthenticationResults {
+                    authserv_id: String::from("example.com"),
+                    results: vec![
+                        AuthResult::new("auth", "pass").with_property(
+                            "smtp",
+                            "auth",
+                            "sender@example.net",
+                        ),
+                        AuthResult::new("spf", "pass").with_property(
+                            "smtp",
+                            "mailfrom",
+                            "example.net",
+                        ),
+                        AuthResult::new("dkim", "fail")
+                            .with_reason("bad \"sig\"")
+                            .with_property("header", "d", "example.net")
+                            .with_property("header", "b", "ab+/c="),
+                    ],
+                },
+            ),
+        ];
+        for (inp, out) in tests {
+            println!("Test: {:?}", inp);
+            assert_eq!(AuthenticationResults::parse(inp).as_ref(), Some(out));
+        }
+    }
+
+    #[test]
+    fn parse_invalid() {
+        let tests: &[&str] = &[
+            "",
+            "(only a comment)",
+            "example.org v1; none",
+            "example.org; spf",
+            "example.org; spf=pass smtp.mailfrom",
+            "example.org; spf=pass mailfrom=example.net",
+            "example.org; dkim=pass reason=\"unterminated",
+        ];
+        for inp in tests {
+            println!("Test: {:?}", inp);
+            assert_eq!(AuthenticationResults::parse(inp), None);
+        }
+        assert_eq!(
+            AuthenticationResults::parse_authserv_id(" (c) \"example.org\"; spf"),
+            Some(String::from("example.org"))
+        );
+    }
+
+    #[test]
+    fn build_then_parse() {
+        let res = AuthenticationResults {
+            authserv_id: String::from("mx.example.org"),
+            results: vec![
+                AuthResult::new("spf", "softfail").with_property("smtp", "helo", "example.net"),
+                AuthResult::new("dkim", "permerror")
+                    .with_reason("key is not for email")
+                    .with_property("header", "d", "example.net"),
+            ],
+        };
+        assert_eq!(
+            res.to_header(),
+            "Authentication-Results: mx.example.org;\r\n\tspf=softfail \
+             smtp.helo=example.net;\r\n\tdkim=permerror reason=\"key is not for email\" \
+             header.d=example.net\r\n"
+        );
+        assert_eq!(
+            AuthenticationResults::parse(&res.to_string()).as_ref(),
+            Some(&res)
+        );
+
+        let none = AuthenticationResults {
+            authserv_id: String::from("mx.example.org"),
+            results: Vec::new(),
+        };
+        assert_eq!(none.to_string(), "mx.example.org; none");
+        assert_eq!(AuthenticationResults::parse(&none.to_string()), Some(none));
+    }
+}
@@ -2,6 +2,7 @@
 
 pub use nom;
 
+mod authres;
 mod command;
 mod data;
 mod misc;
@@ -12,6 +13,7 @@ mod reply;
 use misc::*;
 // use reply::*;
 
+pub use authres::{AuthProperty, AuthResult, AuthenticationResults};
 pub use command::{Command, ParameterName, Parameters};
 pub use data::{DataUnescapeRes, DataUnescaper, EscapedDataReader, EscapingDataWriter};
 pub use misc::{next_crlf, Email, Hostname, Localpart, MaybeUtf8, NextCrLfState, Path};
@@ -114,11 +116,10 @@ pub mod fuzz {
                 .allow_invalid_utf8(true)
                 .build(r#"\r\n\.[^.]"#)
                 .unwrap();
-            assert!(
-                reg.find(&wire)
-                    .map(|(start, _)| start == wire.len() - 5)
-                    .unwrap_or(true)
-            );
+            assert!(reg
+                .find(&wire)
+                .map(|(start, _)| start == wire.len() - 5)
+                .unwrap_or(true));
         }
 
         // println!("Reading from the wire");
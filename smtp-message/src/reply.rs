@@ -74,6 +74,7 @@ impl ReplyCode {
     pub const BAD_SEQUENCE: ReplyCode = ReplyCode(*b"503");
     pub const PARAMETER_UNIMPLEMENTED: ReplyCode = ReplyCode(*b"504");
     pub const SERVER_DOES_NOT_ACCEPT_MAIL: ReplyCode = ReplyCode(*b"521");
+    pub const MUST_ISSUE_STARTTLS: ReplyCode = ReplyCode(*b"530");
     pub const MAILBOX_UNAVAILABLE: ReplyCode = ReplyCode(*b"550");
     pub const POLICY_REASON: ReplyCode = ReplyCode(*b"550");
     pub const USER_NOT_LOCAL: ReplyCode = ReplyCode(*b"551");
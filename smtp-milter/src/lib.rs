@@ -0,0 +1,715 @@
+use std::{future::Future, io, net::SocketAddr, time::Duration};
+
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use smol::future::FutureExt;
+use tracing::trace;
+
+mod modify;
+
+pub use modify::{apply_modifications, Modification};
+
+use modify::{split_field, split_message};
+
+/// Version of the milter protocol we speak
+const VERSION: u32 = 6;
+/// Maximum size of the body chunks sent to milters
+const MAX_BODY_CHUNK: usize = 65535;
+/// Maximum size of the packets milters can send us
+const MAX_PACKET: usize = 1024 * 1024;
+
+mod command {
+    pub const ABORT: u8 = b'A';
+    pub const BODY: u8 = b'B';
+    pub const CONNECT: u8 = b'C';
+    pub const BODY_EOB: u8 = b'E';
+    pub const HELO: u8 = b'H';
+    pub const HEADER: u8 = b'L';
+    pub const MAIL: u8 = b'M';
+    pub const EOH: u8 = b'N';
+    pub const OPTNEG: u8 = b'O';
+    pub const QUIT: u8 = b'Q';
+    pub const RCPT: u8 = b'R';
+    pub const DATA: u8 = b'T';
+}
+
+mod reply {
+    pub const ADD_RCPT: u8 = b'+';
+    pub const DEL_RCPT: u8 = b'-';
+    pub const ACCEPT: u8 = b'a';
+    pub const REPLACE_BODY: u8 = b'b';
+    pub const CONTINUE: u8 = b'c';
+    pub const DISCARD: u8 = b'd';
+    pub const ADD_HEADER: u8 = b'h';
+    pub const INSERT_HEADER: u8 = b'i';
+    pub const CHANGE_HEADER: u8 = b'm';
+    pub const OPTNEG: u8 = b'O';
+    pub const PROGRESS: u8 = b'p';
+    pub const QUARANTINE: u8 = b'q';
+    pub const REJECT: u8 = b'r';
+    pub const SKIP: u8 = b's';
+    pub const TEMPFAIL: u8 = b't';
+    pub const REPLY_CODE: u8 = b'y';
+}
+
+/// Actions we let milters take
+mod action {
+    pub const ADD_HEADERS: u32 = 0x01;
+    pub const CHANGE_BODY: u32 = 0x02;
+    pub const ADD_RCPT: u32 = 0x04;
+    pub const DEL_RCPT: u32 = 0x08;
+    pub const CHANGE_HEADERS: u32 = 0x10;
+    pub const QUARANTINE: u32 = 0x20;
+    pub const ALL: u32 =
+        ADD_HEADERS | CHANGE_BODY | ADD_RCPT | DEL_RCPT | CHANGE_HEADERS | QUARANTINE;
+}
+
+/// Protocol steps milters can ask us to skip (`NO_*`) or not to wait for a
+/// reply to (`NR_*`)
+mod step {
+    pub const NO_CONNECT: u32 = 0x01;
+    pub const NO_HELO: u32 = 0x02;
+    pub const NO_MAIL: u32 = 0x04;
+    pub const NO_RCPT: u32 = 0x08;
+    pub const NO_BODY: u32 = 0x10;
+    pub const NO_HEADERS: u32 = 0x20;
+    pub const NO_EOH: u32 = 0x40;
+    pub const NR_HEADER: u32 = 0x80;
+    pub const NO_UNKNOWN: u32 = 0x100;
+    pub const NO_DATA: u32 = 0x200;
+    pub const SKIP: u32 = 0x400;
+    pub const NR_CONNECT: u32 = 0x1000;
+    pub const NR_HELO: u32 = 0x2000;
+    pub const NR_MAIL: u32 = 0x4000;
+    pub const NR_RCPT: u32 = 0x8000;
+    pub const NR_DATA: u32 = 0x10000;
+    pub const NR_UNKNOWN: u32 = 0x20000;
+    pub const NR_EOH: u32 = 0x40000;
+    pub const NR_BODY: u32 = 0x80000;
+    pub const ALL: u32 = NO_CONNECT
+        | NO_HELO
+        | NO_MAIL
+        | NO_RCPT
+        | NO_BODY
+        | NO_HEADERS
+        | NO_EOH
+        | NR_HEADER
+        | NO_UNKNOWN
+        | NO_DATA
+        | SKIP
+        | NR_CONNECT
+        | NR_HELO
+        | NR_MAIL
+        | NR_RCPT
+        | NR_DATA
+        | NR_UNKNOWN
+        | NR_EOH
+        | NR_BODY;
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error talking to the milter")]
+    Io(#[source] io::Error),
+
+    #[error("Timed out talking to the milter")]
+    Timeout,
+
+    #[error("Milter sent a packet of {0} bytes, which is too big")]
+    PacketTooBig(usize),
+
+    #[error("Milter sent an unexpected ‘{}’ packet", char::from(*.0))]
+    UnexpectedPacket(u8),
+
+    #[error("Milter sent an invalid ‘{}’ packet", char::from(*.0))]
+    InvalidPacket(u8),
+
+    #[error("Milter speaks protocol version {0}, which is not supported")]
+    UnsupportedVersion(u32),
+
+    #[error("Milter asked for unsupported actions {actions:#x} or protocol steps {steps:#x}")]
+    UnsupportedOptions { actions: u32, steps: u32 },
+}
+
+/// Answer of a milter to a protocol step
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Response {
+    Continue,
+    /// Accept the message, or the connection if before `MAIL FROM`, without
+    /// calling the milter again for it
+    Accept,
+    /// Accept the message and silently drop it
+    Discard,
+    Reject,
+    TempFail,
+    /// Reject with a custom reply, eg. `550 5.7.1 Spam detected`
+    ReplyCode(String),
+}
+
+/// Connection to a milter, that expects the protocol steps to be called in
+/// the order of the SMTP session
+pub struct Client<S> {
+    stream: S,
+    timeout: Duration,
+    version: u32,
+    actions: u32,
+    steps: u32,
+}
+
+fn nul_separated(data: &[u8]) -> Vec<String> {
+    let data = data.strip_suffix(b"\0").unwrap_or(data);
+    data.split(|&b| b == 0)
+        .map(|s| String::from_utf8_lossy(s).into_owned())
+        .collect()
+}
+
+/// Parses the packets that end a protocol step
+fn parse_response(cmd: u8, data: &[u8]) -> Option<Response> {
+    Some(match cmd {
+        reply::CONTINUE => Response::Continue,
+        reply::ACCEPT => Response::Accept,
+        reply::DISCARD => Response::Discard,
+        reply::REJECT => Response::Reject,
+        reply::TEMPFAIL => Response::TempFail,
+        reply::REPLY_CODE => {
+            let data = data.strip_suffix(b"\0").unwrap_or(data);
+            Response::ReplyCode(String::from_utf8_lossy(data).into_owned())
+        }
+        _ => return None,
+    })
+}
+
+async fn with_timeout<T>(
+    timeout: Duration,
+    f: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    f.or(async {
+        smol::Timer::after(timeout).await;
+        Err(Error::Timeout)
+    })
+    .await
+}
+
+fn push_str(data: &mut Vec<u8>, s: &str) {
+    data.extend_from_slice(s.as_bytes());
+    data.push(0);
+}
+
+impl<S> Client<S>
+where
+    S: Unpin + Send + AsyncRead + AsyncWrite,
+{
+    /// Negotiates the protocol options with the milter at the other end of
+    /// `stream`, every subsequent read or write failing after `timeout`
+    pub async fn negotiate(stream: S, timeout: Duration) -> Result<Client<S>, Error> {
+        let mut client = Client {
+            stream,
+            timeout,
+            version: VERSION,
+            actions: action::ALL,
+            steps: step::ALL,
+        };
+        let mut data = Vec::with_capacity(12);
+        data.extend_from_slice(&VERSION.to_be_bytes());
+        data.extend_from_slice(&action::ALL.to_be_bytes());
+        data.extend_from_slice(&step::ALL.to_be_bytes());
+        client.send(command::OPTNEG, &data).await?;
+
+        let (cmd, data) = client.read_packet().await?;
+        if cmd != reply::OPTNEG {
+            return Err(Error::UnexpectedPacket(cmd));
+        }
+        // Version 6 milters may append the macros they want, ignore them
+        if data.len() < 12 {
+            return Err(Error::InvalidPacket(cmd));
+        }
+        let word = |i: usize| u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        let (version, actions, steps) = (word(0), word(4), word(8));
+        trace!(version, actions, steps, "Negotiated milter options");
+        if version < 2 {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        if actions & !action::ALL != 0 || steps & !step::ALL != 0 {
+            return Err(Error::UnsupportedOptions { actions, steps });
+        }
+        client.version = version.min(VERSION);
+        client.actions = actions;
+        client.steps = steps;
+        Ok(client)
+    }
+
+    async fn send(&mut self, cmd: u8, data: &[u8]) -> Result<(), Error> {
+        let mut packet = Vec::with_capacity(data.len() + 5);
+        packet.extend_from_slice(&(data.len() as u32 + 1).to_be_bytes());
+        packet.push(cmd);
+        packet.extend_from_slice(data);
+        let stream = &mut self.stream;
+        with_timeout(self.timeout, async {
+            stream.write_all(&packet).await.map_err(Error::Io)?;
+            stream.flush().await.map_err(Error::Io)
+        })
+        .await
+    }
+
+    async fn read_packet(&mut self) -> Result<(u8, Vec<u8>), Error> {
+        let stream = &mut self.stream;
+        with_timeout(self.timeout, async {
+            let mut len = [0; 4];
+            stream.read_exact(&mut len).await.map_err(Error::Io)?;
+            let len = u32::from_be_bytes(len) as usize;
+            if len == 0 {
+                return Err(Error::InvalidPacket(0));
+            }
+            if len > MAX_PACKET {
+                return Err(Error::PacketTooBig(len));
+            }
+            let mut data = vec![0; len];
+            stream.read_exact(&mut data).await.map_err(Error::Io)?;
+            let cmd = data.remove(0);
+            Ok((cmd, data))
+        })
+        .await
+    }
+
+    /// Reads the next packet that is not a progress notification
+    async fn read_response_packet(&mut self) -> Result<(u8, Vec<u8>), Error> {
+        loop {
+            let (cmd, data) = self.read_packet().await?;
+            if cmd != reply::PROGRESS {
+                return Ok((cmd, data));
+            }
+        }
+    }
+
+    async fn response(&mut self) -> Result<Response, Error> {
+        let (cmd, data) = self.read_response_packet().await?;
+        parse_response(cmd, &data).ok_or(Error::UnexpectedPacket(cmd))
+    }
+
+    /// Runs a protocol step, unless the milter asked to skip it
+    async fn step(&mut self, no: u32, nr: u32, cmd: u8, data: &[u8]) -> Result<Response, Error> {
+        if self.steps & no != 0 {
+            return Ok(Response::Continue);
+        }
+        self.send(cmd, data).await?;
+        if self.steps & nr != 0 {
+            return Ok(Response::Continue);
+        }
+        self.response().await
+    }
+
+    /// `hostname` is the name of the client, or its address in brackets if
+    /// it has none
+    pub async fn connect(
+        &mut self,
+        hostname: &str,
+        addr: Option<SocketAddr>,
+    ) -> Result<Response, Error> {
+        let mut data = Vec::new();
+        push_str(&mut data, hostname);
+        match addr {
+            None => data.push(b'U'),
+            Some(addr) => {
+                data.push(if addr.is_ipv4() { b'4' } else { b'6' });
+                data.extend_from_slice(&addr.port().to_be_bytes());
+                push_str(&mut data, &addr.ip().to_string());
+            }
+        }
+        let (no, nr) = (step::NO_CONNECT, step::NR_CONNECT);
+        self.step(no, nr, command::CONNECT, &data).await
+    }
+
+    pub async fn helo(&mut self, hostname: &str) -> Result<Response, Error> {
+        let mut data = Vec::new();
+        push_str(&mut data, hostname);
+        self.step(step::NO_HELO, step::NR_HELO, command::HELO, &data)
+            .await
+    }
+
+    /// `from` is the reverse-path, in angle brackets, and `args` the ESMTP
+    /// parameters
+    pub async fn mail(&mut self, from: &str, args: &[&str]) -> Result<Response, Error> {
+        let mut data = Vec::new();
+        push_str(&mut data, from);
+        for a in args {
+            push_str(&mut data, a);
+        }
+        self.step(step::NO_MAIL, step::NR_MAIL, command::MAIL, &data)
+            .await
+    }
+
+    /// `to` is the forward-path, in angle brackets, and `args` the ESMTP
+    /// parameters
+    pub async fn rcpt(&mut self, to: &str, args: &[&str]) -> Result<Response, Error> {
+        let mut data = Vec::new();
+        push_str(&mut data, to);
+        for a in args {
+            push_str(&mut data, a);
+        }
+        self.step(step::NO_RCPT, step::NR_RCPT, command::RCPT, &data)
+            .await
+    }
+
+    /// Sends `message`, with CRLF line endings, and returns the final
+    /// response of the milter along with the modifications it asks for
+    ///
+    /// The modifications are only returned if the message is not rejected.
+    pub async fn message(
+        &mut self,
+        message: &[u8],
+    ) -> Result<(Response, Vec<Modification>), Error> {
+        macro_rules! check {
+            ($step:expr) => {
+                match $step {
+                    Response::Continue => (),
+                    r => return Ok((r, Vec::new())),
+                }
+            };
+        }
+
+        if self.version >= 4 {
+            check!(
+                self.step(step::NO_DATA, step::NR_DATA, command::DATA, &[])
+                    .await?
+            );
+        }
+        let (fields, body) = split_message(message);
+        for field in fields {
+            let (name, value) = split_field(field);
+            let mut data = Vec::new();
+            push_str(&mut data, &name);
+            push_str(&mut data, &value);
+            let (no, nr) = (step::NO_HEADERS, step::NR_HEADER);
+            check!(self.step(no, nr, command::HEADER, &data).await?);
+        }
+        check!(
+            self.step(step::NO_EOH, step::NR_EOH, command::EOH, &[])
+                .await?
+        );
+        for chunk in body.chunks(MAX_BODY_CHUNK) {
+            if self.steps & step::NO_BODY != 0 {
+                break;
+            }
+            self.send(command::BODY, chunk).await?;
+            if self.steps & step::NR_BODY != 0 {
+                continue;
+            }
+            let (cmd, data) = self.read_response_packet().await?;
+            // The milter may not need the rest of the body
+            if cmd == reply::SKIP && self.steps & step::SKIP != 0 {
+                break;
+            }
+            check!(parse_response(cmd, &data).ok_or(Error::UnexpectedPacket(cmd))?);
+        }
+        self.send(command::BODY_EOB, &[]).await?;
+        self.end_of_message().await
+    }
+
+    /// Reads the modifications and the final response sent at the end of a
+    /// message
+    async fn end_of_message(&mut self) -> Result<(Response, Vec<Modification>), Error> {
+        let mut mods = Vec::new();
+        let mut body: Option<Vec<u8>> = None;
+        loop {
+            let (cmd, data) = self.read_packet().await?;
+            let allowed = match cmd {
+                reply::ADD_HEADER => action::ADD_HEADERS,
+                reply::INSERT_HEADER | reply::CHANGE_HEADER => action::CHANGE_HEADERS,
+                reply::REPLACE_BODY => action::CHANGE_BODY,
+                reply::ADD_RCPT => action::ADD_RCPT,
+                reply::DEL_RCPT => action::DEL_RCPT,
+                reply::QUARANTINE => action::QUARANTINE,
+                _ => 0,
+            };
+            if allowed != 0 && self.actions & allowed == 0 {
+                return Err(Error::UnexpectedPacket(cmd));
+            }
+            match cmd {
+                reply::ADD_HEADER => match &nul_separated(&data)[..] {
+                    [name, value] => mods.push(Modification::AddHeader {
+                        name: name.clone(),
+                        value: value.clone(),
+                    }),
+                    _ => return Err(Error::InvalidPacket(cmd)),
+                },
+                reply::INSERT_HEADER | reply::CHANGE_HEADER => {
+                    if data.len() < 4 {
+                        return Err(Error::InvalidPacket(cmd));
+                    }
+                    let index = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+                    let (name, value) = match &nul_separated(&data[4..])[..] {
+                        [name, value] => (name.clone(), value.clone()),
+                        _ => return Err(Error::InvalidPacket(cmd)),
+                    };
+                    mods.push(if cmd == reply::INSERT_HEADER {
+                        Modification::InsertHeader { index, name, value }
+                    } else {
+                        Modification::ChangeHeader { index, name, value }
+                    });
+                }
+                reply::REPLACE_BODY => body.get_or_insert_with(Vec::new).extend_from_slice(&data),
+                reply::ADD_RCPT | reply::DEL_RCPT | reply::QUARANTINE => {
+                    let arg = nul_separated(&data)
+                        .into_iter()
+                        .next()
+                        .ok_or(Error::InvalidPacket(cmd))?;
+                    mods.push(match cmd {
+                        reply::ADD_RCPT => Modification::AddRecipient(arg),
+                        reply::DEL_RCPT => Modification::DeleteRecipient(arg),
+                        _ => Modification::Quarantine(arg),
+                    });
+                }
+                reply::PROGRESS => (),
+                _ => {
+                    let res = match parse_response(cmd, &data) {
+                        // Continuing at the end of the message accepts it
+                        Some(Response::Continue) => Response::Accept,
+                        Some(res) => res,
+                        None => return Err(Error::UnexpectedPacket(cmd)),
+                    };
+                    if let Some(body) = body {
+                        mods.push(Modification::ReplaceBody(body));
+                    }
+                    return Ok((res, mods));
+                }
+            }
+        }
+    }
+
+    /// Aborts the current message, so that a new one can be started
+    pub async fn abort(&mut self) -> Result<(), Error> {
+        self.send(command::ABORT, &[]).await
+    }
+
+    /// Closes the connection to the milter
+    pub async fn quit(mut self) -> Result<(), Error> {
+        self.send(command::QUIT, &[]).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use smol::net::unix::UnixStream;
+
+    /// Packets the mock milter expects, each with the packets it answers
+    type Script = Vec<(u8, Vec<(u8, Vec<u8>)>)>;
+
+    async fn read_packet(stream: &mut UnixStream) -> (u8, Vec<u8>) {
+        let mut len = [0; 4];
+        stream.read_exact(&mut len).await.unwrap();
+        let mut data = vec![0; u32::from_be_bytes(len) as usize];
+        stream.read_exact(&mut data).await.unwrap();
+        let cmd = data.remove(0);
+        (cmd, data)
+    }
+
+    async fn write_packet(stream: &mut UnixStream, cmd: u8, data: &[u8]) {
+        stream
+            .write_all(&(data.len() as u32 + 1).to_be_bytes())
+            .await
+            .unwrap();
+        stream.write_all(&[cmd]).await.unwrap();
+        stream.write_all(data).await.unwrap();
+    }
+
+    /// Runs a milter that follows `script`, and returns the data of the
+    /// packets it received, along with the still open stream
+    async fn mock(
+        mut stream: UnixStream,
+        steps: u32,
+        script: Script,
+    ) -> (Vec<Vec<u8>>, UnixStream) {
+        let (cmd, _) = read_packet(&mut stream).await;
+        assert_eq!(cmd, b'O');
+        let mut optneg = Vec::new();
+        for word in &[6, action::ALL, steps] {
+            optneg.extend_from_slice(&word.to_be_bytes());
+        }
+        write_packet(&mut stream, b'O', &optneg).await;
+
+        let mut received = Vec::new();
+        for (expected, answers) in script {
+            let (cmd, data) = read_packet(&mut stream).await;
+            assert_eq!(char::from(cmd), char::from(expected));
+            received.push(data);
+            for (cmd, data) in answers {
+                write_packet(&mut stream, cmd, &data).await;
+            }
+        }
+        (received, stream)
+    }
+
+    fn reply(cmd: u8) -> Vec<(u8, Vec<u8>)> {
+        vec![(cmd, Vec::new())]
+    }
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn transaction() {
+        let steps = step::NO_HELO | step::NR_HEADER | step::SKIP;
+        let script: Script = vec![
+            (b'C', reply(b'c')),
+            (b'M', reply(b'c')),
+            (b'R', vec![(b'p', Vec::new()), (b'c', Vec::new())]),
+            (b'T', reply(b'c')),
+            (b'L', Vec::new()),
+            (b'L', Vec::new()),
+            (b'N', reply(b'c')),
+            (b'B', reply(b's')),
+            (b'E', vec![
+                (b'p', Vec::new()),
+                (b'h', b"X-Spam\0yes\0".to_vec()),
+                (b'm', b"\0\0\0\x01Subject\0\0".to_vec()),
+                (b'b', b"new ".to_vec()),
+                (b'b', b"body\r\n".to_vec()),
+                (b'+', b"<c@example.org>\0".to_vec()),
+                (b'q', b"virus\0".to_vec()),
+                (b'c', Vec::new()),
+            ]),
+        ];
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let addr = "192.0.2.1:25".parse().unwrap();
+        let (res, (received, _)) = smol::block_on(async {
+            futures::join!(
+                async {
+                    let mut c = Client::negotiate(ours, TIMEOUT).await.unwrap();
+                    assert_eq!(
+                        c.connect("client.example.net", Some(addr)).await.unwrap(),
+                        Response::Continue
+                    );
+                    assert_eq!(
+                        c.helo("client.example.net").await.unwrap(),
+                        Response::Continue
+                    );
+                    assert_eq!(
+                        c.mail("<a@example.net>", &["BODY=8BITMIME"]).await.unwrap(),
+                        Response::Continue
+                    );
+                    assert_eq!(
+                        c.rcpt("<b@example.org>", &[]).await.unwrap(),
+                        Response::Continue
+                    );
+                    c.message(b"From: a@example.net\r\nSubject: hi\r\n\r\nbody\r\n")
+                        .await
+                        .unwrap()
+                },
+                mock(theirs, steps, script)
+            )
+        });
+
+        assert_eq!(
+            res,
+            (Response::Accept, vec![
+                Modification::AddHeader {
+                    name: "X-Spam".to_owned(),
+                    value: "yes".to_owned(),
+                },
+                Modification::ChangeHeader {
+                    index: 1,
+                    name: "Subject".to_owned(),
+                    value: String::new(),
+                },
+                Modification::AddRecipient("<c@example.org>".to_owned()),
+                Modification::Quarantine("virus".to_owned()),
+                Modification::ReplaceBody(b"new body\r\n".to_vec()),
+            ])
+        );
+        assert_eq!(received, vec![
+            b"client.example.net\0\x34\0\x19192.0.2.1\0".to_vec(),
+            b"<a@example.net>\0BODY=8BITMIME\0".to_vec(),
+            b"<b@example.org>\0".to_vec(),
+            Vec::new(),
+            b"From\0a@example.net\0".to_vec(),
+            b"Subject\0hi\0".to_vec(),
+            Vec::new(),
+            b"body\r\n".to_vec(),
+            Vec::new(),
+        ]);
+    }
+
+    #[test]
+    fn rejections() {
+        let script: Script = vec![
+            (b'R', vec![(b'y', b"550 5.1.1 No such user\0".to_vec())]),
+            (b'R', reply(b'c')),
+            (b'T', reply(b'c')),
+            (b'L', reply(b'c')),
+            (b'N', reply(b'c')),
+            (b'B', reply(b't')),
+            (b'A', Vec::new()),
+        ];
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        smol::block_on(async {
+            futures::join!(
+                async {
+                    let mut c = Client::negotiate(ours, TIMEOUT).await.unwrap();
+                    assert_eq!(
+                        c.rcpt("<nobody@example.org>", &[]).await.unwrap(),
+                        Response::ReplyCode("550 5.1.1 No such user".to_owned())
+                    );
+                    assert_eq!(
+                        c.rcpt("<b@example.org>", &[]).await.unwrap(),
+                        Response::Continue
+                    );
+                    assert_eq!(
+                        c.message(b"Subject: hi\r\n\r\nbody\r\n").await.unwrap(),
+                        (Response::TempFail, Vec::new())
+                    );
+                    c.abort().await.unwrap();
+                },
+                mock(theirs, 0, script)
+            )
+        });
+    }
+
+    #[test]
+    fn failures() {
+        // Options we did not offer
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        smol::block_on(async {
+            futures::join!(
+                async {
+                    assert!(matches!(
+                        Client::negotiate(ours, TIMEOUT).await,
+                        Err(Error::UnsupportedOptions {
+                            steps: 0x100000,
+                            ..
+                        })
+                    ));
+                },
+                mock(theirs, 0x100000, Vec::new())
+            )
+        });
+
+        // Milter that stops answering
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        smol::block_on(async {
+            futures::join!(
+                async {
+                    let mut c = Client::negotiate(ours, Duration::from_millis(50))
+                        .await
+                        .unwrap();
+                    assert!(matches!(c.helo("example.net").await, Err(Error::Timeout)));
+                },
+                mock(theirs, 0, vec![(b'H', Vec::new())])
+            )
+        });
+
+        // Modification that was not negotiated, at the wrong time
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        smol::block_on(async {
+            futures::join!(
+                async {
+                    let mut c = Client::negotiate(ours, TIMEOUT).await.unwrap();
+                    assert!(matches!(
+                        c.helo("example.net").await,
+                        Err(Error::UnexpectedPacket(b'h'))
+                    ));
+                },
+                mock(theirs, 0, vec![(b'H', vec![(b'h', b"A\0b\0".to_vec())])])
+            )
+        });
+    }
+}
@@ -0,0 +1,199 @@
+/// Modification of a message requested by a milter at the end of the message
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Modification {
+    AddHeader {
+        name: String,
+        value: String,
+    },
+    /// Inserts a header field before the `index`-th one, counting from 0
+    InsertHeader {
+        index: u32,
+        name: String,
+        value: String,
+    },
+    /// Replaces the `index`-th header field named `name`, counting from 1, an
+    /// empty `value` deleting it
+    ChangeHeader {
+        index: u32,
+        name: String,
+        value: String,
+    },
+    ReplaceBody(Vec<u8>),
+    AddRecipient(String),
+    DeleteRecipient(String),
+    /// Puts the message in quarantine, for the given reason
+    Quarantine(String),
+}
+
+/// Splits `message` into its header fields, each with its final CRLF, and
+/// its body
+pub(crate) fn split_message(message: &[u8]) -> (Vec<&[u8]>, &[u8]) {
+    let mut fields = Vec::new();
+    let mut field_start = 0;
+    let mut pos = 0;
+    while pos < message.len() {
+        let line_end = message[pos..]
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .map_or(message.len(), |i| pos + i + 2);
+        if line_end == pos + 2 {
+            // Empty line, the body starts
+            if field_start < pos {
+                fields.push(&message[field_start..pos]);
+            }
+            return (fields, &message[line_end..]);
+        }
+        if pos > field_start && message[pos] != b' ' && message[pos] != b'\t' {
+            fields.push(&message[field_start..pos]);
+            field_start = pos;
+        }
+        pos = line_end;
+    }
+    if field_start < pos {
+        fields.push(&message[field_start..pos]);
+    }
+    (fields, &[])
+}
+
+/// Returns the name and value of a header field, in the form milters expect
+/// them: without the whitespace after the colon, and with LF line endings
+pub(crate) fn split_field(field: &[u8]) -> (String, String) {
+    let field = String::from_utf8_lossy(field);
+    let field = field.strip_suffix("\r\n").unwrap_or(&field);
+    match field.split_once(':') {
+        Some((name, value)) => (
+            name.trim_end().to_owned(),
+            value.trim_start_matches([' ', '\t']).replace("\r\n", "\n"),
+        ),
+        None => (field.to_owned(), String::new()),
+    }
+}
+
+fn build_field(name: &str, value: &str) -> Vec<u8> {
+    let mut res = format!("{}: ", name).into_bytes();
+    let mut last = 0;
+    for &b in value.as_bytes() {
+        if b == b'\n' && last != b'\r' {
+            res.push(b'\r');
+        }
+        res.push(b);
+        last = b;
+    }
+    res.extend_from_slice(b"\r\n");
+    res
+}
+
+fn field_name(field: &[u8]) -> &[u8] {
+    let colon = field.iter().position(|&b| b == b':').unwrap_or(field.len());
+    let mut name = &field[..colon];
+    while let [rest @ .., b' ' | b'\t'] = name {
+        name = rest;
+    }
+    name
+}
+
+/// Applies to `message` the header and body modifications of `mods`, in
+/// order
+///
+/// Recipient changes and quarantine requests are not about the message
+/// contents, and are thus ignored.
+pub fn apply_modifications(message: &[u8], mods: &[Modification]) -> Vec<u8> {
+    let (fields, body) = split_message(message);
+    let mut fields = fields.into_iter().map(|f| f.to_vec()).collect::<Vec<_>>();
+    let mut body = body.to_vec();
+    for m in mods {
+        match m {
+            Modification::AddHeader { name, value } => fields.push(build_field(name, value)),
+            Modification::InsertHeader { index, name, value } => {
+                let index = (*index as usize).min(fields.len());
+                fields.insert(index, build_field(name, value));
+            }
+            Modification::ChangeHeader { index, name, value } => {
+                let pos = fields
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, f)| field_name(f).eq_ignore_ascii_case(name.as_bytes()))
+                    .nth((*index as usize).saturating_sub(1))
+                    .map(|(i, _)| i);
+                match (pos, value.is_empty()) {
+                    (Some(pos), true) => {
+                        fields.remove(pos);
+                    }
+                    (Some(pos), false) => fields[pos] = build_field(name, value),
+                    (None, true) => (),
+                    (None, false) => fields.push(build_field(name, value)),
+                }
+            }
+            Modification::ReplaceBody(b) => body = b.clone(),
+            Modification::AddRecipient(_)
+            | Modification::DeleteRecipient(_)
+            | Modification::Quarantine(_) => (),
+        }
+    }
+    let mut res = fields.concat();
+    res.extend_from_slice(b"\r\n");
+    res.extend_from_slice(&body);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split() {
+        let msg = b"From: a@example.org\r\nSubject: hello\r\n  world\r\n\r\nbody\r\n";
+        let (fields, body) = split_message(msg);
+        assert_eq!(fields, vec![
+            &b"From: a@example.org\r\n"[..],
+            &b"Subject: hello\r\n  world\r\n"[..],
+        ]);
+        assert_eq!(body, b"body\r\n");
+        assert_eq!(
+            split_field(fields[1]),
+            ("Subject".to_owned(), "hello\n  world".to_owned())
+        );
+
+        let (fields, body) = split_message(b"From: a@example.org\r\n");
+        assert_eq!(fields, vec![&b"From: a@example.org\r\n"[..]]);
+        assert_eq!(body, b"");
+    }
+
+    #[test]
+    fn apply() {
+        let msg = b"Received: one\r\nX-Spam: yes\r\nReceived: two\r\nX-Spam: maybe\r\n\r\nbody\r\n";
+        let mods = [
+            Modification::ChangeHeader {
+                index: 2,
+                name: "X-Spam".to_owned(),
+                value: "no\n\treally".to_owned(),
+            },
+            Modification::ChangeHeader {
+                index: 1,
+                name: "x-spam".to_owned(),
+                value: String::new(),
+            },
+            Modification::ChangeHeader {
+                index: 1,
+                name: "X-Missing".to_owned(),
+                value: String::new(),
+            },
+            Modification::InsertHeader {
+                index: 0,
+                name: "X-First".to_owned(),
+                value: "1".to_owned(),
+            },
+            Modification::AddHeader {
+                name: "X-Last".to_owned(),
+                value: "2".to_owned(),
+            },
+            Modification::AddRecipient("<b@example.org>".to_owned()),
+            Modification::ReplaceBody(b"new body\r\n".to_vec()),
+        ];
+        assert_eq!(
+            String::from_utf8(apply_modifications(msg, &mods)).unwrap(),
+            "X-First: 1\r\nReceived: one\r\nReceived: two\r\nX-Spam: no\r\n\treally\r\n\
+             X-Last: 2\r\n\r\nnew body\r\n"
+        );
+    }
+}
@@ -27,6 +27,7 @@ pub const METADATA_FILE: &str = "metadata";
 pub const SCHEDULE_FILE: &str = "schedule";
 pub const TMP_METADATA_FILE_PREFIX: &str = "metadata.";
 pub const TMP_SCHEDULE_FILE_PREFIX: &str = "schedule.";
+pub const TMP_CONTENTS_FILE_PREFIX: &str = "contents.";
 
 const ONLY_USER_RW: u32 = 0o600;
 const ONLY_USER_RWX: u32 = 0o700;
@@ -137,6 +138,9 @@ pub enum Error {
     #[error("Flushing the changes to file ‘{0}’ of mail ‘{1}’ in {2:?} queue")]
     FlushingMailContents(&'static str, String, QueueType, #[source] io::Error),
 
+    #[error("Prepending data into file ‘{0}’ in mail ‘{1}’ of {2:?} queue")]
+    PrependingMailContents(String, String, QueueType, #[source] io::Error),
+
     #[error("Creating folder ‘{0}’ in mail ‘{1}’ of {2:?} queue")]
     CreatingFolderInMail(String, String, QueueType, #[source] io::Error),
 
@@ -317,7 +321,7 @@ where
 
             Ok(FsEnqueuer {
                 mail_uuid: mail_uuid.to_string(),
-                mail_dir,
+                mail_dir: Arc::new(mail_dir),
                 queue,
                 writer: Box::pin(smol::Unblock::new(contents_file)),
                 phantom: PhantomData,
@@ -863,7 +867,7 @@ impl smtp_queue::PendingCleanupMail for FsPendingCleanupMail {
 
 pub struct FsEnqueuer<U> {
     mail_uuid: String,
-    mail_dir: Dir,
+    mail_dir: Arc<Dir>,
     queue: Arc<Dir>,
     writer: Pin<Box<dyn 'static + Send + AsyncWrite>>,
     // FsEnqueuer needs the U type parameter just so as to be able to take it as a parameter later
@@ -968,6 +972,74 @@ impl<U> smtp_queue::StorageEnqueuer<U, FsStorage<U>, FsQueuedMail> for FsEnqueue
 where
     U: 'static + Send + Sync + for<'a> serde::Deserialize<'a> + serde::Serialize,
 {
+    async fn prepend(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        if let Err(e) = self.flush().await {
+            return Err(Error::FlushingMailContents(
+                CONTENTS_FILE,
+                self.mail_uuid.clone(),
+                QueueType::Data,
+                e,
+            ));
+        }
+        let mail_uuid = self.mail_uuid.clone();
+        let mail_dir = self.mail_dir.clone();
+        let contents_file = unblock(move || {
+            use std::io::Write;
+
+            // Write the data then the current contents into a new file, and
+            // atomically replace the contents with it
+            let mut tmp_contents_file = String::from(TMP_CONTENTS_FILE_PREFIX);
+            let mut uuid_buf: [u8; 45] = Uuid::encode_buffer();
+            let uuid = Uuid::new_v4().as_hyphenated().encode_lower(&mut uuid_buf);
+            tmp_contents_file.push_str(uuid);
+
+            let mut tmp_file = mail_dir
+                .new_file(&tmp_contents_file, ONLY_USER_RW)
+                .map_err(|e| {
+                    Error::CreatingFileInMail(
+                        tmp_contents_file.clone(),
+                        PathBuf::from(&mail_uuid),
+                        QueueType::Data,
+                        e,
+                    )
+                })?;
+            let copied = mail_dir.open_file(CONTENTS_FILE).and_then(|mut contents| {
+                tmp_file.write_all(&data)?;
+                io::copy(&mut contents, &mut tmp_file)?;
+                tmp_file.flush()
+            });
+            if let Err(e) = copied {
+                let _ = mail_dir.remove_file(&tmp_contents_file);
+                return Err(Error::PrependingMailContents(
+                    tmp_contents_file,
+                    mail_uuid,
+                    QueueType::Data,
+                    e,
+                ));
+            }
+            if let Err(e) = mail_dir.local_rename(&tmp_contents_file, CONTENTS_FILE) {
+                let _ = mail_dir.remove_file(&tmp_contents_file);
+                return Err(Error::RenamingFileInMail(
+                    tmp_contents_file,
+                    CONTENTS_FILE,
+                    Arc::new(mail_uuid),
+                    QueueType::Data,
+                    e,
+                ));
+            }
+
+            // Further writes go after what has been written so far
+            mail_dir
+                .append_file(CONTENTS_FILE, ONLY_USER_RW)
+                .map_err(|e| {
+                    Error::OpeningFileInMail(CONTENTS_FILE, Arc::new(mail_uuid), QueueType::Data, e)
+                })
+        })
+        .await?;
+        self.writer = Box::pin(smol::Unblock::new(contents_file));
+        Ok(())
+    }
+
     async fn commit(
         mut self,
         destinations: Vec<(MailMetadata<U>, ScheduleInfo)>,
@@ -1143,4 +1215,42 @@ mod tests {
         });
         confirm(path, "res/cleanup-broken-link/after");
     }
+
+    #[test]
+    fn prepend_to_contents() {
+        use smtp_queue::StorageEnqueuer;
+
+        let (_dir, path) = setup("res/create-queue-folders/after");
+        smol::block_on(async {
+            let stor = FsStorage::<()>::new(path.clone())
+                .await
+                .expect("creating storage");
+            let mut enqueuer = stor.enqueue().await.expect("opening enqueuer");
+            enqueuer
+                .write_all(b"Subject: test\r\n")
+                .await
+                .expect("writing");
+            enqueuer
+                .prepend(b"X-Prepended: yes\r\n".to_vec())
+                .await
+                .expect("prepending");
+            enqueuer.write_all(b"\r\nbody\r\n").await.expect("writing");
+            enqueuer.commit(Vec::new()).await.expect("committing");
+        });
+
+        let mails = std::fs::read_dir(path.join(DATA_DIR))
+            .expect("listing data dir")
+            .collect::<Vec<_>>();
+        assert_eq!(mails.len(), 1, "enqueuing did not create exactly one mail");
+        let mail_dir = mails.into_iter().next().unwrap().expect("reading data dir");
+        let files = std::fs::read_dir(mail_dir.path())
+            .expect("listing mail dir")
+            .map(|f| f.expect("reading mail dir").file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(files, vec![CONTENTS_FILE], "temporary files were left over");
+        assert_eq!(
+            std::fs::read(mail_dir.path().join(CONTENTS_FILE)).expect("reading contents"),
+            b"X-Prepended: yes\r\nSubject: test\r\n\r\nbody\r\n"
+        );
+    }
 }
@@ -50,6 +50,14 @@ use smtp_message::Email;
 pub struct MailMetadata<U> {
     pub from: Option<Email>,
     pub to: Email,
+    /// Whether the mail must only be relayed over verified TLS (RFC 8689
+    /// `REQUIRETLS`)
+    #[serde(default)]
+    pub require_tls: bool,
+    /// Whether the mail asked with a `TLS-Required: No` header field that the
+    /// usual TLS policies be ignored (RFC 8689)
+    #[serde(default)]
+    pub tls_optional: bool,
     pub metadata: U,
 }
 
@@ -167,6 +175,10 @@ pub trait StorageEnqueuer<U, S, QueuedMail>: Send + AsyncWrite
 where
     S: ?Sized + Storage<U>,
 {
+    /// Prepends `data` to what has already been written, for headers that
+    /// can only be computed once the whole message is known
+    async fn prepend(&mut self, data: Vec<u8>) -> Result<(), S::Error>;
+
     async fn commit(
         self,
         destinations: Vec<(MailMetadata<U>, ScheduleInfo)>,
@@ -541,6 +553,14 @@ where
     S: Storage<U>,
     T: Transport<U>,
 {
+    pub async fn prepend(&mut self, data: Vec<u8>) -> Result<(), S::Error> {
+        self.enqueuer
+            .as_mut()
+            .expect("Tried prepending to enqueuer after having committed it")
+            .prepend(data)
+            .await
+    }
+
     pub async fn commit(
         self,
         destinations: Vec<(MailMetadata<U>, ScheduleInfo)>,
@@ -0,0 +1,141 @@
+use std::time::Duration;
+
+use futures::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use crate::{read_response, with_timeout, Error};
+
+/// Size of the chunks in which the message is streamed to clamd
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Verdict of clamd on a message
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum VirusResult {
+    Clean,
+    /// The message contains the virus with this signature name
+    Infected(String),
+}
+
+fn parse_response(response: &[u8]) -> Result<VirusResult, Error> {
+    let response = std::str::from_utf8(response)
+        .map_err(|_| Error::InvalidResponse("response is not UTF-8"))?
+        .trim_end_matches(['\0', '\n']);
+    if response.ends_with("size limit exceeded. ERROR") {
+        return Err(Error::SizeLimitExceeded);
+    }
+    let result = response
+        .strip_prefix("stream: ")
+        .ok_or(Error::InvalidResponse("not an INSTREAM response"))?;
+    if result == "OK" {
+        Ok(VirusResult::Clean)
+    } else if let Some(virus) = result.strip_suffix(" FOUND") {
+        Ok(VirusResult::Infected(virus.to_owned()))
+    } else if let Some(error) = result.strip_suffix(" ERROR") {
+        Err(Error::ScannerFailed(error.to_owned()))
+    } else {
+        Err(Error::InvalidResponse("unknown INSTREAM result"))
+    }
+}
+
+/// Checks `message` with the clamd listening at the other end of `stream`,
+/// using its `INSTREAM` command
+///
+/// clamd refuses messages bigger than its `StreamMaxLength` setting, in
+/// which case this returns `Error::SizeLimitExceeded`.
+pub async fn check_clamd<S>(
+    mut stream: S,
+    message: &[u8],
+    timeout: Duration,
+) -> Result<VirusResult, Error>
+where
+    S: Unpin + AsyncRead + AsyncWrite,
+{
+    with_timeout(timeout, async {
+        stream.write_all(b"zINSTREAM\0").await.map_err(Error::Io)?;
+        for chunk in message.chunks(CHUNK_SIZE) {
+            stream
+                .write_all(&(chunk.len() as u32).to_be_bytes())
+                .await
+                .map_err(Error::Io)?;
+            match stream.write_all(chunk).await {
+                // clamd closes the connection once the size limit is exceeded,
+                // its response tells why
+                Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => break,
+                r => r.map_err(Error::Io)?,
+            }
+        }
+        // A zero-length chunk ends the stream, this fails like the above
+        // when clamd already stopped reading
+        let _ = stream.write_all(&0u32.to_be_bytes()).await;
+        let _ = stream.flush().await;
+        let response = read_response(&mut stream).await?;
+        parse_response(&response)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::AsyncReadExt;
+    use smol::net::unix::UnixStream;
+
+    use super::*;
+
+    /// Runs a stand-in clamd that answers `response` once the whole stream
+    /// was received, returning the decoded stream
+    fn scan(message: &[u8], response: &'static [u8]) -> (Vec<u8>, Result<VirusResult, Error>) {
+        smol::block_on(async {
+            let (client, mut server) = UnixStream::pair().unwrap();
+            let clamd = smol::spawn(async move {
+                let mut command = [0; 10];
+                server.read_exact(&mut command).await.unwrap();
+                assert_eq!(&command, b"zINSTREAM\0");
+                let mut data = Vec::new();
+                loop {
+                    let mut len = [0; 4];
+                    server.read_exact(&mut len).await.unwrap();
+                    let len = u32::from_be_bytes(len) as usize;
+                    if len == 0 {
+                        break;
+                    }
+                    assert!(len <= CHUNK_SIZE);
+                    let start = data.len();
+                    data.resize(start + len, 0);
+                    server.read_exact(&mut data[start..]).await.unwrap();
+                }
+                server.write_all(response).await.unwrap();
+                data
+            });
+            let res = check_clamd(client, message, Duration::from_secs(5)).await;
+            (clamd.await, res)
+        })
+    }
+
+    #[test]
+    fn clamd() {
+        let message = b"Subject: hi\r\n\r\nbody\r\n";
+        let (data, res) = scan(message, b"stream: OK\0");
+        assert_eq!(data, message);
+        assert_eq!(res.unwrap(), VirusResult::Clean);
+
+        let message = vec![b'a'; 3 * CHUNK_SIZE + 12];
+        let (data, res) = scan(&message, b"stream: Eicar-Test-Signature FOUND\0");
+        assert_eq!(data, message);
+        assert_eq!(
+            res.unwrap(),
+            VirusResult::Infected("Eicar-Test-Signature".to_owned())
+        );
+    }
+
+    #[test]
+    fn failures() {
+        let message = b"Subject: hi\r\n\r\nbody\r\n";
+        let (_, res) = scan(message, b"INSTREAM size limit exceeded. ERROR\0");
+        assert!(matches!(res, Err(Error::SizeLimitExceeded)));
+
+        let (_, res) = scan(message, b"stream: Can't allocate memory ERROR\0");
+        assert!(matches!(res, Err(Error::ScannerFailed(e)) if e == "Can't allocate memory"));
+
+        let (_, res) = scan(message, b"PONG\0");
+        assert!(matches!(res, Err(Error::InvalidResponse(_))));
+    }
+}
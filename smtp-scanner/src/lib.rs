@@ -0,0 +1,238 @@
+use std::{fmt::Write, io};
+
+#[cfg(feature = "client")]
+mod clamd;
+#[cfg(feature = "client")]
+mod rspamd;
+#[cfg(feature = "client")]
+mod spamd;
+
+#[cfg(feature = "client")]
+pub use clamd::{check_clamd, VirusResult};
+#[cfg(feature = "client")]
+pub use rspamd::{check_rspamd, Envelope};
+#[cfg(feature = "client")]
+pub use spamd::check_spamd;
+
+/// Maximum size of the responses scanners can send us
+#[cfg(feature = "client")]
+const MAX_RESPONSE: usize = 1024 * 1024;
+/// Lines of the `X-Spam-Status` header are folded after this many characters
+const FOLD_AT: usize = 78;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error talking to the scanner")]
+    Io(#[source] io::Error),
+
+    #[error("Timed out talking to the scanner")]
+    Timeout,
+
+    #[error("Scanner sent a response bigger than {0} bytes")]
+    ResponseTooBig(usize),
+
+    #[error("Scanner sent an invalid response: {0}")]
+    InvalidResponse(&'static str),
+
+    #[error("Scanner failed with ‘{0}’")]
+    ScannerFailed(String),
+
+    #[error("Message is bigger than what the scanner accepts")]
+    SizeLimitExceeded,
+}
+
+/// Action the scanner recommends taking on the message, following the rspamd
+/// naming
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum SpamAction {
+    NoAction,
+    Greylist,
+    AddHeader,
+    RewriteSubject,
+    SoftReject,
+    Reject,
+    /// Custom action configured in the scanner
+    Other(String),
+}
+
+impl SpamAction {
+    pub fn parse(action: &str) -> SpamAction {
+        match action {
+            "no action" => SpamAction::NoAction,
+            "greylist" => SpamAction::Greylist,
+            "add header" => SpamAction::AddHeader,
+            "rewrite subject" => SpamAction::RewriteSubject,
+            "soft reject" => SpamAction::SoftReject,
+            "reject" => SpamAction::Reject,
+            a => SpamAction::Other(a.to_owned()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            SpamAction::NoAction => "no action",
+            SpamAction::Greylist => "greylist",
+            SpamAction::AddHeader => "add header",
+            SpamAction::RewriteSubject => "rewrite subject",
+            SpamAction::SoftReject => "soft reject",
+            SpamAction::Reject => "reject",
+            SpamAction::Other(a) => a,
+        }
+    }
+}
+
+/// Verdict of a spam scanner on a message
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct SpamResult {
+    pub is_spam: bool,
+    pub score: f64,
+    /// Score from which the message is considered as spam
+    pub required_score: f64,
+    /// Names of the rules that matched
+    pub symbols: Vec<String>,
+    pub action: SpamAction,
+}
+
+impl SpamResult {
+    /// Returns the `X-Spam-*` header fields describing this result, each
+    /// ending with CRLF
+    pub fn headers(&self) -> String {
+        let mut res = String::new();
+        let flag = if self.is_spam { "YES" } else { "NO" };
+        let level = "*".repeat(self.score.clamp(0., 50.) as usize);
+        // Writing to a String cannot fail
+        let _ = write!(
+            res,
+            "X-Spam-Flag: {}\r\nX-Spam-Score: {:.2}\r\nX-Spam-Level: {}\r\nX-Spam-Action: {}\r\n",
+            flag,
+            self.score,
+            level,
+            self.action.name(),
+        );
+        let mut line = format!(
+            "X-Spam-Status: {}, score={:.2} required={:.2} tests=",
+            if self.is_spam { "Yes" } else { "No" },
+            self.score,
+            self.required_score,
+        );
+        for (i, s) in self.symbols.iter().enumerate() {
+            let sep = if i + 1 < self.symbols.len() { "," } else { "" };
+            if line.len() + s.len() + sep.len() > FOLD_AT && !line.ends_with('=') {
+                res.push_str(&line);
+                res.push_str("\r\n");
+                line = String::from("\t");
+            }
+            line.push_str(s);
+            line.push_str(sep);
+        }
+        res.push_str(&line);
+        res.push_str("\r\n");
+        res
+    }
+}
+
+/// Reads the whole response of the scanner, that closes the connection once
+/// done
+#[cfg(feature = "client")]
+async fn read_response<S>(stream: &mut S) -> Result<Vec<u8>, Error>
+where
+    S: Unpin + futures::AsyncRead,
+{
+    use futures::AsyncReadExt;
+
+    let mut res = Vec::new();
+    (&mut *stream)
+        .take(MAX_RESPONSE as u64 + 1)
+        .read_to_end(&mut res)
+        .await
+        .map_err(Error::Io)?;
+    if res.len() > MAX_RESPONSE {
+        return Err(Error::ResponseTooBig(MAX_RESPONSE));
+    }
+    Ok(res)
+}
+
+/// Response of a scanner, split into its status line, its header fields with
+/// lowercase names and its body
+#[cfg(feature = "client")]
+struct Response<'a> {
+    status: &'a str,
+    headers: Vec<(String, &'a str)>,
+    body: &'a [u8],
+}
+
+#[cfg(feature = "client")]
+fn split_response(response: &[u8]) -> Result<Response<'_>, Error> {
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or(Error::InvalidResponse("no end of headers"))?;
+    let head = std::str::from_utf8(&response[..end])
+        .map_err(|_| Error::InvalidResponse("headers are not UTF-8"))?;
+    let mut lines = head.split("\r\n");
+    let status = lines.next().unwrap_or("");
+    let mut headers = Vec::new();
+    for l in lines {
+        let (name, value) = l
+            .split_once(':')
+            .ok_or(Error::InvalidResponse("invalid header"))?;
+        headers.push((name.trim().to_ascii_lowercase(), value.trim()));
+    }
+    Ok(Response {
+        status,
+        headers,
+        body: &response[end + 4..],
+    })
+}
+
+#[cfg(feature = "client")]
+async fn with_timeout<T>(
+    timeout: std::time::Duration,
+    f: impl std::future::Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    use smol::future::FutureExt;
+
+    f.or(async {
+        smol::Timer::after(timeout).await;
+        Err(Error::Timeout)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers() {
+        let res = SpamResult {
+            is_spam: true,
+            score: 7.5,
+            required_score: 5.,
+            symbols: (0..8).map(|i| format!("SOME_LONG_RULE_{}", i)).collect(),
+            action: SpamAction::AddHeader,
+        };
+        assert_eq!(
+            res.headers(),
+            "X-Spam-Flag: YES\r\nX-Spam-Score: 7.50\r\nX-Spam-Level: *******\r\nX-Spam-Action: \
+             add header\r\nX-Spam-Status: Yes, score=7.50 required=5.00 \
+             tests=SOME_LONG_RULE_0,\r\n\tSOME_LONG_RULE_1,SOME_LONG_RULE_2,SOME_LONG_RULE_3,\
+             SOME_LONG_RULE_4,\r\n\tSOME_LONG_RULE_5,SOME_LONG_RULE_6,SOME_LONG_RULE_7\r\n"
+        );
+
+        let res = SpamResult {
+            is_spam: false,
+            score: -1.,
+            required_score: 5.,
+            symbols: Vec::new(),
+            action: SpamAction::NoAction,
+        };
+        assert_eq!(
+            res.headers(),
+            "X-Spam-Flag: NO\r\nX-Spam-Score: -1.00\r\nX-Spam-Level: \r\nX-Spam-Action: no \
+             action\r\nX-Spam-Status: No, score=-1.00 required=5.00 tests=\r\n"
+        );
+    }
+}
@@ -0,0 +1,246 @@
+use std::{collections::BTreeMap, net::IpAddr, time::Duration};
+
+use futures::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use crate::{read_response, split_response, with_timeout, Error, Response, SpamAction, SpamResult};
+
+/// What rspamd is told about the SMTP session the message comes from
+#[derive(Clone, Debug, Default)]
+pub struct Envelope {
+    pub ip: Option<IpAddr>,
+    pub helo: Option<String>,
+    /// Name of the client, as given by its forward-confirmed reverse DNS
+    pub hostname: Option<String>,
+    pub from: Option<String>,
+    pub rcpt: Vec<String>,
+    /// Authenticated user, if any
+    pub user: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct Reply {
+    score: f64,
+    required_score: f64,
+    action: String,
+    #[serde(default)]
+    symbols: BTreeMap<String, serde::de::IgnoredAny>,
+}
+
+/// Decodes a body sent with the chunked transfer encoding
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut res = Vec::new();
+    loop {
+        let line_end = body
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or(Error::InvalidResponse("truncated chunk"))?;
+        let size = std::str::from_utf8(&body[..line_end])
+            .ok()
+            .and_then(|l| usize::from_str_radix(l.split(';').next()?.trim(), 16).ok())
+            .ok_or(Error::InvalidResponse("invalid chunk size"))?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(res);
+        }
+        if body.len() < size + 2 {
+            return Err(Error::InvalidResponse("truncated chunk"));
+        }
+        res.extend_from_slice(&body[..size]);
+        body = &body[size + 2..];
+    }
+}
+
+fn parse_response(response: &[u8]) -> Result<SpamResult, Error> {
+    let Response {
+        status,
+        headers,
+        body,
+    } = split_response(response)?;
+    let mut status = status.splitn(3, ' ');
+    if !status.next().is_some_and(|p| p.starts_with("HTTP/")) {
+        return Err(Error::InvalidResponse("not an HTTP response"));
+    }
+    match (status.next(), status.next()) {
+        (Some("200"), _) => (),
+        (Some(code), reason) => {
+            return Err(Error::ScannerFailed(format!(
+                "{} {}",
+                code,
+                reason.unwrap_or("")
+            )))
+        }
+        (None, _) => return Err(Error::InvalidResponse("no status code")),
+    }
+    let header = |name: &str| headers.iter().find(|(n, _)| n == name).map(|(_, v)| *v);
+    let body = if header("transfer-encoding").is_some_and(|e| e.eq_ignore_ascii_case("chunked")) {
+        dechunk(body)?
+    } else {
+        match header("content-length").map(|l| l.parse::<usize>()) {
+            Some(Ok(len)) if len <= body.len() => body[..len].to_vec(),
+            Some(_) => return Err(Error::InvalidResponse("invalid content length")),
+            None => body.to_vec(),
+        }
+    };
+    let reply: Reply =
+        serde_json::from_slice(&body).map_err(|_| Error::InvalidResponse("invalid JSON"))?;
+    Ok(SpamResult {
+        is_spam: reply.score >= reply.required_score,
+        score: reply.score,
+        required_score: reply.required_score,
+        symbols: reply.symbols.into_keys().collect(),
+        action: SpamAction::parse(&reply.action),
+    })
+}
+
+/// Checks `message` with the rspamd listening at the other end of `stream`,
+/// using its `/checkv2` HTTP endpoint
+pub async fn check_rspamd<S>(
+    mut stream: S,
+    message: &[u8],
+    envelope: &Envelope,
+    timeout: Duration,
+) -> Result<SpamResult, Error>
+where
+    S: Unpin + AsyncRead + AsyncWrite,
+{
+    let mut request = format!(
+        "POST /checkv2 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n",
+        message.len()
+    );
+    let mut header = |name: &str, value: &str| {
+        // Values that would break the request are not worth sending
+        if !value.contains(['\r', '\n']) {
+            request.push_str(name);
+            request.push_str(": ");
+            request.push_str(value);
+            request.push_str("\r\n");
+        }
+    };
+    if let Some(ip) = envelope.ip {
+        header("IP", &ip.to_string());
+    }
+    if let Some(helo) = &envelope.helo {
+        header("Helo", helo);
+    }
+    if let Some(hostname) = &envelope.hostname {
+        header("Hostname", hostname);
+    }
+    if let Some(from) = &envelope.from {
+        header("From", from);
+    }
+    for rcpt in &envelope.rcpt {
+        header("Rcpt", rcpt);
+    }
+    if let Some(user) = &envelope.user {
+        header("User", user);
+    }
+    request.push_str("\r\n");
+    with_timeout(timeout, async {
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .map_err(Error::Io)?;
+        stream.write_all(message).await.map_err(Error::Io)?;
+        stream.flush().await.map_err(Error::Io)?;
+        let response = read_response(&mut stream).await?;
+        parse_response(&response)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::AsyncReadExt;
+    use smol::net::unix::UnixStream;
+
+    use super::*;
+
+    /// Runs a stand-in rspamd that answers `response` to a single request,
+    /// returning the request it received
+    fn scan(envelope: &Envelope, response: &'static [u8]) -> (Vec<u8>, Result<SpamResult, Error>) {
+        smol::block_on(async {
+            let (client, mut server) = UnixStream::pair().unwrap();
+            let rspamd = smol::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"body\r\n") {
+                    let n = server.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                server.write_all(response).await.unwrap();
+                request
+            });
+            let res = check_rspamd(
+                client,
+                b"Subject: hi\r\n\r\nbody\r\n",
+                envelope,
+                Duration::from_secs(5),
+            )
+            .await;
+            (rspamd.await, res)
+        })
+    }
+
+    #[test]
+    fn rspamd() {
+        let envelope = Envelope {
+            ip: Some("192.0.2.1".parse().unwrap()),
+            helo: Some("mail.example.org".to_owned()),
+            hostname: None,
+            from: Some("<a@example.org>".to_owned()),
+            rcpt: vec!["<b@example.com>".to_owned(), "<c@example.com>".to_owned()],
+            user: Some("evil\r\nX-Injected: yes".to_owned()),
+        };
+        let (request, res) = scan(
+            &envelope,
+            b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 151\r\n\r\n\
+              {\"is_skipped\":false,\"score\":6.2,\"required_score\":15.0,\"action\":\"add header\",\
+              \"symbols\":{\"R_SPF_FAIL\":{\"name\":\"R_SPF_FAIL\",\"score\":1.0},\"BAYES_SPAM\":{}}}",
+        );
+        assert_eq!(
+            String::from_utf8(request).unwrap(),
+            "POST /checkv2 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: \
+             21\r\nIP: 192.0.2.1\r\nHelo: mail.example.org\r\nFrom: <a@example.org>\r\nRcpt: \
+             <b@example.com>\r\nRcpt: <c@example.com>\r\n\r\nSubject: hi\r\n\r\nbody\r\n"
+        );
+        assert_eq!(res.unwrap(), SpamResult {
+            is_spam: false,
+            score: 6.2,
+            required_score: 15.,
+            symbols: vec!["BAYES_SPAM".to_owned(), "R_SPF_FAIL".to_owned()],
+            action: SpamAction::AddHeader,
+        });
+    }
+
+    #[test]
+    fn chunked() {
+        let (_, res) = scan(
+            &Envelope::default(),
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              20\r\n{\"score\":16,\"required_score\":15,\r\n\
+              12\r\n\"action\":\"reject\"}\r\n0\r\n\r\n",
+        );
+        assert_eq!(res.unwrap(), SpamResult {
+            is_spam: true,
+            score: 16.,
+            required_score: 15.,
+            symbols: Vec::new(),
+            action: SpamAction::Reject,
+        });
+    }
+
+    #[test]
+    fn failures() {
+        let (_, res) = scan(
+            &Envelope::default(),
+            b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n",
+        );
+        assert!(matches!(res, Err(Error::ScannerFailed(e)) if e == "500 Internal Server Error"));
+
+        let (_, res) = scan(
+            &Envelope::default(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\n{\"a\":1}",
+        );
+        assert!(matches!(res, Err(Error::InvalidResponse(_))));
+    }
+}
@@ -0,0 +1,194 @@
+use std::time::Duration;
+
+use futures::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use crate::{read_response, split_response, with_timeout, Error, Response, SpamAction, SpamResult};
+
+/// Parses the `Spam` header of spamd, eg. `True ; 15.0 / 5.0`
+fn parse_spam_header(value: &str) -> Option<(bool, f64, f64)> {
+    let (flag, scores) = value.split_once(';')?;
+    let (score, required) = scores.split_once('/')?;
+    let is_spam = match flag.trim() {
+        f if f.eq_ignore_ascii_case("true") || f.eq_ignore_ascii_case("yes") => true,
+        f if f.eq_ignore_ascii_case("false") || f.eq_ignore_ascii_case("no") => false,
+        _ => return None,
+    };
+    Some((
+        is_spam,
+        score.trim().parse().ok()?,
+        required.trim().parse().ok()?,
+    ))
+}
+
+fn parse_response(response: &[u8]) -> Result<SpamResult, Error> {
+    let Response {
+        status,
+        headers,
+        body,
+    } = split_response(response)?;
+    let mut status = status.splitn(3, ' ');
+    if !status.next().is_some_and(|p| p.starts_with("SPAMD/")) {
+        return Err(Error::InvalidResponse("not a spamd response"));
+    }
+    match (status.next(), status.next()) {
+        (Some("0"), _) => (),
+        (Some(code), message) => {
+            return Err(Error::ScannerFailed(format!(
+                "{} {}",
+                code,
+                message.unwrap_or("")
+            )))
+        }
+        (None, _) => return Err(Error::InvalidResponse("no status code")),
+    }
+    let (is_spam, score, required_score) = headers
+        .iter()
+        .find(|(name, _)| name == "spam")
+        .and_then(|(_, value)| parse_spam_header(value))
+        .ok_or(Error::InvalidResponse("no valid ‘Spam’ header"))?;
+    let symbols = String::from_utf8_lossy(body)
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_owned)
+        .collect();
+    Ok(SpamResult {
+        is_spam,
+        score,
+        required_score,
+        symbols,
+        // spamd has no notion of actions
+        action: if is_spam {
+            SpamAction::AddHeader
+        } else {
+            SpamAction::NoAction
+        },
+    })
+}
+
+/// Checks `message` with the spamd listening at the other end of `stream`,
+/// using the `SYMBOLS` command of the SPAMC protocol
+///
+/// `user` is the user whose preferences spamd should use, if any.
+pub async fn check_spamd<S>(
+    mut stream: S,
+    message: &[u8],
+    user: Option<&str>,
+    timeout: Duration,
+) -> Result<SpamResult, Error>
+where
+    S: Unpin + AsyncRead + AsyncWrite,
+{
+    let mut request = format!("SYMBOLS SPAMC/1.5\r\nContent-length: {}\r\n", message.len());
+    if let Some(user) = user.filter(|u| !u.contains(['\r', '\n'])) {
+        request.push_str("User: ");
+        request.push_str(user);
+        request.push_str("\r\n");
+    }
+    request.push_str("\r\n");
+    with_timeout(timeout, async {
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .map_err(Error::Io)?;
+        stream.write_all(message).await.map_err(Error::Io)?;
+        stream.flush().await.map_err(Error::Io)?;
+        let response = read_response(&mut stream).await?;
+        parse_response(&response)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::AsyncReadExt;
+    use smol::net::unix::UnixStream;
+
+    use super::*;
+
+    /// Runs a stand-in spamd that answers `response` to a single request,
+    /// returning the request it received
+    fn scan(
+        message: &[u8],
+        response: &'static [u8],
+        timeout: Duration,
+    ) -> (Vec<u8>, Result<SpamResult, Error>) {
+        smol::block_on(async {
+            let (client, mut server) = UnixStream::pair().unwrap();
+            let spamd = smol::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"body\r\n") {
+                    let n = server.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                if !response.is_empty() {
+                    server.write_all(response).await.unwrap();
+                    return request;
+                }
+                // Never answer
+                let _ = server.read(&mut buf).await;
+                request
+            });
+            let res = check_spamd(client, message, Some("alice"), timeout).await;
+            (spamd.await, res)
+        })
+    }
+
+    #[test]
+    fn spamd() {
+        let message = b"Subject: hi\r\n\r\nbody\r\n";
+        let (request, res) = scan(
+            message,
+            b"SPAMD/1.1 0 EX_OK\r\nContent-length: 19\r\nSpam: True ; 15.5 / 5.0\r\n\r\n\
+              BAYES_99,URIBL_RED\r\n",
+            Duration::from_secs(5),
+        );
+        assert_eq!(
+            request,
+            &b"SYMBOLS SPAMC/1.5\r\nContent-length: 21\r\nUser: alice\r\n\r\n\
+               Subject: hi\r\n\r\nbody\r\n"[..]
+        );
+        assert_eq!(res.unwrap(), SpamResult {
+            is_spam: true,
+            score: 15.5,
+            required_score: 5.,
+            symbols: vec!["BAYES_99".to_owned(), "URIBL_RED".to_owned()],
+            action: SpamAction::AddHeader,
+        });
+
+        let (_, res) = scan(
+            message,
+            b"SPAMD/1.1 0 EX_OK\r\nSpam: False ; -0.1 / 5.0\r\n\r\n",
+            Duration::from_secs(5),
+        );
+        assert_eq!(res.unwrap(), SpamResult {
+            is_spam: false,
+            score: -0.1,
+            required_score: 5.,
+            symbols: Vec::new(),
+            action: SpamAction::NoAction,
+        });
+    }
+
+    #[test]
+    fn failures() {
+        let message = b"Subject: hi\r\n\r\nbody\r\n";
+        let (_, res) = scan(
+            message,
+            b"SPAMD/1.0 76 Bad header line: (Content-length mismatch)\r\n\r\n",
+            Duration::from_secs(5),
+        );
+        assert!(matches!(res, Err(Error::ScannerFailed(e)) if e.starts_with("76 Bad header")));
+
+        let (_, res) = scan(
+            message,
+            b"SPAMD/1.1 0 EX_OK\r\n\r\n",
+            Duration::from_secs(5),
+        );
+        assert!(matches!(res, Err(Error::InvalidResponse(_))));
+
+        let (_, res) = scan(message, b"", Duration::from_millis(100));
+        assert!(matches!(res, Err(Error::Timeout)));
+    }
+}
@@ -1,4 +1,4 @@
-use std::io;
+use std::{io, net::SocketAddr};
 
 use smtp_message::{Email, Hostname, Reply};
 
@@ -57,6 +57,9 @@ pub struct MailMetadata<U> {
     pub user: U,
     pub from: Option<Email>,
     pub to: Vec<Email>,
+    /// Whether the `MAIL FROM` had the `REQUIRETLS` parameter (RFC 8689),
+    /// meaning the mail must only be relayed over verified TLS
+    pub require_tls: bool,
 }
 
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
@@ -67,9 +70,25 @@ pub struct HelloInfo {
     pub hostname: Hostname,
 }
 
+/// Certificate the client authenticated with, verified against the
+/// configured certificate authorities
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct ClientCertificate {
+    /// Subject name, eg. `CN=mx.example.org,O=Example`
+    pub subject: String,
+    /// Subject alternative names, eg. `DNS:mx.example.org`
+    pub alt_names: Vec<String>,
+    /// Hex-encoded SHA-256 hash of the certificate
+    pub sha256_fingerprint: String,
+}
+
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct ConnectionMetadata<U> {
     pub user: U,
+    pub peer_addr: Option<SocketAddr>,
     pub hello: Option<HelloInfo>,
     pub is_encrypted: bool,
+    /// Host name the client asked for through SNI when starting TLS
+    pub tls_server_name: Option<String>,
+    pub tls_client_cert: Option<ClientCertificate>,
 }
@@ -16,6 +16,7 @@ pub fn okay_hello(
     local_hostname: &str,
     banner: &str,
     can_do_tls: bool,
+    is_encrypted: bool,
 ) -> Reply {
     let mut built_banner = String::from(local_hostname);
     if !banner.is_empty() {
@@ -31,6 +32,10 @@ pub fn okay_hello(
         if can_do_tls {
             text.push(MaybeUtf8::Ascii("STARTTLS".into()));
         }
+        // RFC 8689 only allows REQUIRETLS on TLS sessions
+        if is_encrypted {
+            text.push(MaybeUtf8::Ascii("REQUIRETLS".into()));
+        }
     }
     Reply {
         code: ReplyCode::OKAY,
@@ -188,6 +193,80 @@ pub fn line_too_long() -> Reply<&'static str> {
     }
 }
 
+/// Usual value for rejecting a `MAIL FROM` with the `REQUIRETLS` parameter
+/// outside of a TLS session
+#[inline]
+pub fn require_tls_without_tls() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::MUST_ISSUE_STARTTLS,
+        ecode: Some(EnhancedReplyCode::PERMANENT_ENCRYPTION_NEEDED),
+        text: vec![MaybeUtf8::Ascii("REQUIRETLS needs a TLS session")],
+    }
+}
+
+/// Usual value for rejecting a recipient because of greylisting
+#[inline]
+pub fn greylisted() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::LOCAL_ERROR,
+        ecode: Some(EnhancedReplyCode::TRANSIENT_POLICY_OTHER),
+        text: vec![MaybeUtf8::Ascii("Greylisted, please try again later")],
+    }
+}
+
+/// Usual value for rejecting a command because a content filter refused it
+#[inline]
+pub fn rejected_by_filter() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::POLICY_REASON,
+        ecode: Some(EnhancedReplyCode::PERMANENT_DELIVERY_NOT_AUTHORIZED),
+        text: vec![MaybeUtf8::Ascii("Rejected by filter")],
+    }
+}
+
+/// Usual value for rejecting a message because the antivirus found a virus
+/// in it
+#[inline]
+pub fn virus_found() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::TRANSACTION_FAILED,
+        ecode: Some(EnhancedReplyCode::PERMANENT_DELIVERY_NOT_AUTHORIZED),
+        text: vec![MaybeUtf8::Ascii("Message contains a virus")],
+    }
+}
+
+/// Usual value for refusing a connection from a client that may not send
+/// mail here
+#[inline]
+pub fn connection_not_allowed() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::TRANSACTION_FAILED,
+        ecode: Some(EnhancedReplyCode::PERMANENT_DELIVERY_NOT_AUTHORIZED),
+        text: vec![MaybeUtf8::Ascii("Not allowed to send mail here")],
+    }
+}
+
+/// Usual value for rejecting a message bigger than the maximum message size
+#[inline]
+pub fn message_too_big() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::EXCEEDED_STORAGE,
+        ecode: Some(EnhancedReplyCode::PERMANENT_MESSAGE_TOO_BIG),
+        text: vec![MaybeUtf8::Ascii("Message too big")],
+    }
+}
+
+/// Usual value for rejecting a command because a content filter could not
+/// decide yet
+#[inline]
+pub fn filter_tempfail() -> Reply<&'static str> {
+    Reply {
+        code: ReplyCode::LOCAL_ERROR,
+        ecode: Some(EnhancedReplyCode::TRANSIENT_POLICY_OTHER),
+        text: vec![MaybeUtf8::Ascii("Temporarily rejected by filter, try again later")],
+    }
+}
+
 #[inline]
 pub fn internal_server_error() -> Reply<&'static str> {
     Reply {
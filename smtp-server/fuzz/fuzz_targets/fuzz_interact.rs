@@ -149,6 +149,11 @@ fuzz_target!(|data: &[u8]| {
     let reader = Cursor::new(data[2..].to_owned()).limited(chunk_size as usize);
     let writer = io::sink();
     let io = Duplex::new(reader, writer);
-    let _ignore_errors =
-        executor::block_on(interact(io, IsAlreadyTls::No, (), Arc::new(FuzzConfig)));
+    let _ignore_errors = executor::block_on(interact(
+        io,
+        IsAlreadyTls::No,
+        None,
+        (),
+        Arc::new(FuzzConfig),
+    ));
 });
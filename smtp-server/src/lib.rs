@@ -3,7 +3,7 @@
 
 pub mod protocol;
 
-use std::{cmp, io, ops::Range, pin::Pin, sync::Arc};
+use std::{cmp, io, net::SocketAddr, ops::Range, pin::Pin, sync::Arc};
 
 use async_trait::async_trait;
 use chrono::Utc;
@@ -13,10 +13,13 @@ use futures::{
 };
 use smol::future::FutureExt;
 use smtp_message::{
-    next_crlf, nom, Command, Email, EscapedDataReader, Hostname, MaybeUtf8, NextCrLfState, Reply,
+    next_crlf, nom, Command, Email, EscapedDataReader, Hostname, MaybeUtf8, NextCrLfState,
+    ParameterName, Reply,
 };
 
-pub use smtp_server_types::{reply, ConnectionMetadata, Decision, HelloInfo, MailMetadata};
+pub use smtp_server_types::{
+    reply, ClientCertificate, ConnectionMetadata, Decision, HelloInfo, MailMetadata,
+};
 
 pub use protocol::{Protocol, ProtocolName};
 
@@ -50,6 +53,20 @@ pub trait Config: Send + Sync {
         reply::welcome_banner(self.hostname(conn_meta), self.welcome_banner(conn_meta))
     }
 
+    /// Called once per connection to decide whether to talk with the client
+    ///
+    /// The reply is sent in place of the welcome banner, and the connection is
+    /// closed if it is rejected.
+    async fn filter_connection(
+        &self,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Decision<()> {
+        Decision::Accept {
+            reply: self.welcome_banner_reply(conn_meta),
+            res: (),
+        }
+    }
+
     /// Note: this function is only ever used for the default implementations of
     /// other functions in this trait. As such, it is OK to leave it
     /// `unimplemented!()` if other functions are implemented.
@@ -76,6 +93,7 @@ pub trait Config: Send + Sync {
                 self.hostname(conn_meta),
                 self.hello_banner(conn_meta),
                 self.can_do_tls(conn_meta),
+                conn_meta.is_encrypted,
             )
             .convert(),
             res: HelloInfo {
@@ -288,6 +306,14 @@ pub trait Config: Send + Sync {
         reply::bad_sequence().convert()
     }
 
+    #[allow(unused_variables)]
+    fn require_tls_without_tls(
+        &self,
+        conn_meta: &mut ConnectionMetadata<Self::ConnectionUserMeta>,
+    ) -> Reply {
+        reply::require_tls_without_tls().convert()
+    }
+
     #[allow(unused_variables)]
     fn rcpt_before_mail(
         &self,
@@ -393,6 +419,7 @@ pub enum IsAlreadyTls {
 pub async fn interact<IO, Cfg>(
     io: IO,
     is_already_tls: IsAlreadyTls,
+    peer_addr: Option<SocketAddr>,
     metadata: Cfg::ConnectionUserMeta,
     cfg: Arc<Cfg>,
 ) -> io::Result<()>
@@ -417,8 +444,11 @@ where
     // .collect() (present in `send_reply()`)
     let mut conn_meta = ConnectionMetadata {
         user: metadata,
+        peer_addr,
         hello: None,
         is_encrypted: is_already_tls == IsAlreadyTls::Yes,
+        tls_server_name: None,
+        tls_client_cert: None,
     };
     let mut mail_meta = None;
 
@@ -507,7 +537,16 @@ where
         };
     }
 
-    send_reply!(io, cfg.welcome_banner_reply(&mut conn_meta)).await?;
+    dispatch_decision! {
+        cfg.filter_connection(&mut conn_meta).await,
+        Reject(reply) => {
+            send_reply!(io, reply).await?;
+            return Ok(());
+        }
+        Accept(reply, ()) => {
+            send_reply!(io, reply).await?;
+        }
+    }
 
     loop {
         if unhandled.is_empty() {
@@ -599,10 +638,15 @@ where
             Some(Command::Mail {
                 path: _path,
                 email,
-                params: _params,
+                params,
             }) => {
+                let require_tls = params.0.iter().any(|(name, _)| match name {
+                    ParameterName::Other(n) => n.eq_ignore_ascii_case("REQUIRETLS"),
+                });
                 if conn_meta.hello.is_none() {
                     send_reply!(io, cfg.mail_before_hello(&mut conn_meta)).await?;
+                } else if require_tls && !conn_meta.is_encrypted {
+                    send_reply!(io, cfg.require_tls_without_tls(&mut conn_meta)).await?;
                 } else {
                     match mail_meta {
                         Some(_) => {
@@ -615,6 +659,7 @@ where
                                 user: cfg.new_mail(&mut conn_meta).await,
                                 from: None,
                                 to: Vec::with_capacity(4),
+                                require_tls,
                             };
                             dispatch_decision! {
                                 cfg.filter_from(
@@ -1105,7 +1150,8 @@ mod tests {
                     b"EHLO test\r\n\
                       STARTTLS\r\n",
                     b"<tls client>",
-                    b"EHLO test2\r\n",
+                    b"EHLO test2\r\n\
+                      MAIL FROM:<foo@test.example.com> REQUIRETLS\r\n",
                 ],
                 b"220 test.example.org Service ready\r\n\
                   250-test.example.org\r\n\
@@ -1120,7 +1166,24 @@ mod tests {
                   250-8BITMIME\r\n\
                   250-ENHANCEDSTATUSCODES\r\n\
                   250-PIPELINING\r\n\
-                  250 SMTPUTF8\r\n",
+                  250-SMTPUTF8\r\n\
+                  250 REQUIRETLS\r\n\
+                  250 2.0.0 Okay\r\n",
+                &[],
+            ),
+            (
+                &[b"EHLO test\r\n\
+                    MAIL FROM:<foo@test.example.com> REQUIRETLS\r\n\
+                    MAIL FROM:<foo@test.example.com>\r\n"],
+                b"220 test.example.org Service ready\r\n\
+                  250-test.example.org\r\n\
+                  250-8BITMIME\r\n\
+                  250-ENHANCEDSTATUSCODES\r\n\
+                  250-PIPELINING\r\n\
+                  250-SMTPUTF8\r\n\
+                  250 STARTTLS\r\n\
+                  530 5.7.10 REQUIRETLS needs a TLS session\r\n\
+                  250 2.0.0 Okay\r\n",
                 &[],
             ),
         ];
@@ -1151,7 +1214,7 @@ mod tests {
                     }
                 },
                 async move {
-                    interact(io, IsAlreadyTls::No, (), cfg)
+                    interact(io, IsAlreadyTls::No, None, (), cfg)
                         .await
                         .expect("calling interact");
                     let mut resp = Vec::new();
@@ -1209,7 +1272,7 @@ mod tests {
                 .await
                 .expect("writing to input pipe");
             std::mem::drop(inp_pipe_w);
-            interact(io, IsAlreadyTls::No, (), cfg)
+            interact(io, IsAlreadyTls::No, None, (), cfg)
                 .await
                 .expect_err("calling interact")
                 .kind()
@@ -1284,7 +1347,7 @@ mod tests {
                 .await
                 .expect("writing to input pipe");
             std::mem::drop(inp_pipe_w);
-            interact(io, IsAlreadyTls::No, (), cfg)
+            interact(io, IsAlreadyTls::No, None, (), cfg)
                 .await
                 .expect("calling interact");
         });
@@ -1332,6 +1395,6 @@ mod tests {
         let cfg = Arc::new(TestConfig {
             mails: Arc::new(Mutex::new(Vec::new())),
         });
-        assert_send(interact(MinBoundsIo, IsAlreadyTls::No, (), cfg));
+        assert_send(interact(MinBoundsIo, IsAlreadyTls::No, None, (), cfg));
     }
 }
@@ -0,0 +1,807 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use futures::future::{BoxFuture, FutureExt};
+use tracing::trace;
+#[cfg(feature = "trust-dns")]
+use trust_dns_resolver::{
+    error::{ResolveError, ResolveErrorKind},
+    proto::DnsHandle,
+    AsyncResolver, ConnectionProvider, Name,
+};
+
+mod macros;
+mod record;
+
+pub use macros::{MacroContext, MacroError, MacroString};
+pub use record::{is_spf, Directive, Mechanism, ParseError, Qualifier, Record};
+
+/// Maximum number of mechanisms and modifiers that do DNS lookups
+const MAX_LOOKUPS: usize = 10;
+/// Maximum number of lookups that return no answer
+const MAX_VOID_LOOKUPS: usize = 2;
+/// Maximum number of names a single `mx` or `ptr` will consider
+const MAX_NAMES_PER_MECHANISM: usize = 10;
+/// Maximum length of a domain name after macro expansion
+const MAX_DOMAIN_LEN: usize = 253;
+
+/// Result of an SPF check, see RFC 7208 §2.6
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum SpfResult {
+    None,
+    Neutral,
+    Pass,
+    Fail {
+        /// Expansion of the `exp=` modifier, if any
+        explanation: Option<String>,
+    },
+    SoftFail,
+    TempError,
+    PermError,
+}
+
+impl SpfResult {
+    /// Name of the result, as used in eg. `Received-SPF` or
+    /// `Authentication-Results` headers
+    pub fn name(&self) -> &'static str {
+        match self {
+            SpfResult::None => "none",
+            SpfResult::Neutral => "neutral",
+            SpfResult::Pass => "pass",
+            SpfResult::Fail { .. } => "fail",
+            SpfResult::SoftFail => "softfail",
+            SpfResult::TempError => "temperror",
+            SpfResult::PermError => "permerror",
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Temporary failure while looking up ‘{name}’: {reason}")]
+pub struct LookupError {
+    pub name: String,
+    pub reason: String,
+}
+
+/// DNS operations needed for evaluating SPF records
+///
+/// Names that do not exist or have no records of the requested type must
+/// return an empty list, errors are reserved for temporary failures.
+#[async_trait]
+pub trait Lookup: Send + Sync {
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<String>, LookupError>;
+    async fn lookup_a(&self, name: &str) -> Result<Vec<Ipv4Addr>, LookupError>;
+    async fn lookup_aaaa(&self, name: &str) -> Result<Vec<Ipv6Addr>, LookupError>;
+    async fn lookup_mx(&self, name: &str) -> Result<Vec<String>, LookupError>;
+    async fn lookup_ptr(&self, ip: IpAddr) -> Result<Vec<String>, LookupError>;
+}
+
+/// Parses `name` as a fully-qualified name, so that the resolver does not try
+/// search domains
+#[cfg(feature = "trust-dns")]
+fn fqdn(name: &str) -> Option<Name> {
+    let mut res = Name::from_utf8(name).ok()?;
+    res.set_fqdn(true);
+    Some(res)
+}
+
+#[cfg(feature = "trust-dns")]
+fn lookup_result<T, U>(
+    name: &str,
+    res: Result<T, ResolveError>,
+    f: impl FnOnce(T) -> Vec<U>,
+) -> Result<Vec<U>, LookupError> {
+    match res {
+        Ok(r) => Ok(f(r)),
+        Err(e) => match e.kind() {
+            ResolveErrorKind::NoRecordsFound { .. } => Ok(Vec::new()),
+            _ => Err(LookupError {
+                name: name.to_owned(),
+                reason: e.to_string(),
+            }),
+        },
+    }
+}
+
+#[cfg(feature = "trust-dns")]
+macro_rules! lookup_name {
+    ($resolver:expr, $fn:ident, $name:expr, $f:expr) => {
+        match fqdn($name) {
+            Some(n) => lookup_result($name, $resolver.$fn(n).await, $f),
+            // Invalid names cannot have any records
+            None => Ok(Vec::new()),
+        }
+    };
+}
+
+#[cfg(feature = "trust-dns")]
+#[async_trait]
+impl<C, P> Lookup for AsyncResolver<C, P>
+where
+    C: DnsHandle<Error = ResolveError>,
+    P: ConnectionProvider<Conn = C>,
+{
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<String>, LookupError> {
+        lookup_name!(self, txt_lookup, name, |r| {
+            r.iter()
+                .map(|txt| {
+                    txt.txt_data()
+                        .iter()
+                        .map(|s| String::from_utf8_lossy(s))
+                        .collect::<String>()
+                })
+                .collect()
+        })
+    }
+
+    async fn lookup_a(&self, name: &str) -> Result<Vec<Ipv4Addr>, LookupError> {
+        lookup_name!(self, ipv4_lookup, name, |r| r.iter().cloned().collect())
+    }
+
+    async fn lookup_aaaa(&self, name: &str) -> Result<Vec<Ipv6Addr>, LookupError> {
+        lookup_name!(self, ipv6_lookup, name, |r| r.iter().cloned().collect())
+    }
+
+    async fn lookup_mx(&self, name: &str) -> Result<Vec<String>, LookupError> {
+        lookup_name!(self, mx_lookup, name, |r| {
+            r.iter().map(|mx| mx.exchange().to_utf8()).collect()
+        })
+    }
+
+    async fn lookup_ptr(&self, ip: IpAddr) -> Result<Vec<String>, LookupError> {
+        lookup_result(&ip.to_string(), self.reverse_lookup(ip).await, |r| {
+            r.iter().map(|n| n.to_utf8()).collect()
+        })
+    }
+}
+
+/// Parameters of an SPF check
+#[derive(Clone, Copy, Debug)]
+pub struct Query<'a> {
+    /// IP address of the SMTP client
+    pub ip: IpAddr,
+    /// Domain the client gave in its HELO or EHLO command
+    pub helo: &'a str,
+    /// Local-part of the identity being checked
+    pub sender_localpart: &'a str,
+    /// Domain of the identity being checked
+    pub sender_domain: &'a str,
+    /// Domain name of the host running the check, used in explanations
+    pub receiver: &'a str,
+}
+
+impl<'a> Query<'a> {
+    /// Query for checking the `HELO` identity (RFC 7208 §2.3)
+    pub fn helo(ip: IpAddr, helo: &'a str, receiver: &'a str) -> Query<'a> {
+        Query {
+            ip,
+            helo,
+            sender_localpart: "postmaster",
+            sender_domain: helo,
+            receiver,
+        }
+    }
+
+    /// Query for checking the `MAIL FROM` identity (RFC 7208 §2.4)
+    ///
+    /// `sender` is the (local-part, domain) pair of the reverse-path, and
+    /// `None` for the null reverse-path, in which case the HELO identity is
+    /// checked instead
+    pub fn mail_from(
+        ip: IpAddr,
+        helo: &'a str,
+        sender: Option<(&'a str, &'a str)>,
+        receiver: &'a str,
+    ) -> Query<'a> {
+        match sender {
+            Some((localpart, domain)) => Query {
+                ip,
+                helo,
+                sender_localpart: if localpart.is_empty() {
+                    "postmaster"
+                } else {
+                    localpart
+                },
+                sender_domain: domain,
+                receiver,
+            },
+            None => Query::helo(ip, helo, receiver),
+        }
+    }
+}
+
+/// Evaluates the SPF policy of `query.sender_domain` for `query`
+pub async fn check_host<L: Lookup>(lookup: &L, query: &Query<'_>) -> SpfResult {
+    let mut eval = Evaluator {
+        lookup,
+        query: Query {
+            ip: canonical_ip(query.ip),
+            ..*query
+        },
+        lookups: 0,
+        void_lookups: 0,
+        now: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+    };
+    let domain = query.sender_domain.trim_end_matches('.').to_owned();
+    let res = eval.check_host(domain, true).await;
+    trace!(result = ?res, ip = %query.ip, domain = %query.sender_domain, "Evaluated SPF");
+    res
+}
+
+/// IPv4-mapped IPv6 addresses are to be treated as IPv4 addresses
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.octets() {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF, a, b, c, d] => {
+                IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+            }
+            _ => ip,
+        },
+        ip => ip,
+    }
+}
+
+/// See RFC 7208 §4.3
+fn is_valid_domain(d: &str) -> bool {
+    let labels = d.split('.').collect::<Vec<_>>();
+    labels.len() >= 2 && labels.iter().all(|l| !l.is_empty() && l.len() <= 63)
+}
+
+fn ip_in_network(ip: IpAddr, cidr4: u8, cidr6: u8, net: IpAddr) -> bool {
+    match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(cidr4)).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(cidr6)).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Why evaluation stopped before reaching a result
+enum Abort {
+    Temp,
+    Perm,
+}
+
+impl From<Abort> for SpfResult {
+    fn from(a: Abort) -> SpfResult {
+        match a {
+            Abort::Temp => SpfResult::TempError,
+            Abort::Perm => SpfResult::PermError,
+        }
+    }
+}
+
+struct Evaluator<'a, L> {
+    lookup: &'a L,
+    query: Query<'a>,
+    lookups: usize,
+    void_lookups: usize,
+    now: u64,
+}
+
+impl<'a, L: Lookup> Evaluator<'a, L> {
+    fn macro_context<'b>(
+        &'b self,
+        domain: &'b str,
+        validated_name: Option<&'b str>,
+    ) -> MacroContext<'b> {
+        MacroContext {
+            sender_localpart: self.query.sender_localpart,
+            sender_domain: self.query.sender_domain,
+            domain,
+            ip: self.query.ip,
+            helo: self.query.helo,
+            receiver: self.query.receiver,
+            validated_name,
+            now: self.now,
+        }
+    }
+
+    fn count_lookup(&mut self) -> Result<(), Abort> {
+        self.lookups += 1;
+        if self.lookups > MAX_LOOKUPS {
+            trace!("SPF evaluation exceeded the DNS lookup limit");
+            return Err(Abort::Perm);
+        }
+        Ok(())
+    }
+
+    fn count_void<T>(&mut self, res: &[T]) -> Result<(), Abort> {
+        if res.is_empty() {
+            self.void_lookups += 1;
+            if self.void_lookups > MAX_VOID_LOOKUPS {
+                trace!("SPF evaluation exceeded the void DNS lookup limit");
+                return Err(Abort::Perm);
+            }
+        }
+        Ok(())
+    }
+
+    async fn expand_domain(&self, spec: &MacroString, domain: &str) -> String {
+        let validated_name = if spec.needs_validated_name() {
+            self.validated_names(domain).await.into_iter().next()
+        } else {
+            None
+        };
+        let mut res = spec.expand(&self.macro_context(domain, validated_name.as_deref()));
+        while res.len() > MAX_DOMAIN_LEN {
+            match res.find('.') {
+                Some(i) => res.drain(..=i),
+                None => break,
+            };
+        }
+        res
+    }
+
+    fn check_host(&mut self, domain: String, is_top: bool) -> BoxFuture<'_, SpfResult> {
+        async move {
+            if !is_valid_domain(&domain) {
+                return SpfResult::None;
+            }
+            let record = match self.lookup.lookup_txt(&domain).await {
+                Ok(txts) => {
+                    let mut spf = txts.into_iter().filter(|t| is_spf(t));
+                    match (spf.next(), spf.next()) {
+                        (None, _) => return SpfResult::None,
+                        (Some(_), Some(_)) => return SpfResult::PermError,
+                        (Some(r), None) => r,
+                    }
+                }
+                Err(e) => {
+                    trace!(error = ?e, "Temporary error while looking up SPF record");
+                    return SpfResult::TempError;
+                }
+            };
+            let record = match Record::parse(&record) {
+                Ok(r) => r,
+                Err(e) => {
+                    trace!(error = ?e, domain = %domain, "Invalid SPF record");
+                    return SpfResult::PermError;
+                }
+            };
+            match self.eval_record(&domain, &record, is_top).await {
+                Ok(res) => res,
+                Err(a) => a.into(),
+            }
+        }
+        .boxed()
+    }
+
+    async fn eval_record(
+        &mut self,
+        domain: &str,
+        record: &Record,
+        is_top: bool,
+    ) -> Result<SpfResult, Abort> {
+        for d in &record.directives {
+            if d.mechanism.does_lookup() {
+                self.count_lookup()?;
+            }
+            if self.matches(domain, &d.mechanism).await? {
+                return Ok(match d.qualifier {
+                    Qualifier::Pass => SpfResult::Pass,
+                    Qualifier::Fail => SpfResult::Fail {
+                        explanation: match (&record.exp, is_top) {
+                            // Explanations are only computed for the record that is actually
+                            // used, so not for included records
+                            (Some(exp), true) => self.explanation(domain, exp).await,
+                            _ => None,
+                        },
+                    },
+                    Qualifier::SoftFail => SpfResult::SoftFail,
+                    Qualifier::Neutral => SpfResult::Neutral,
+                });
+            }
+        }
+        if let Some(redirect) = &record.redirect {
+            self.count_lookup()?;
+            let target = self.expand_domain(redirect, domain).await;
+            return Ok(match self.check_host(target, is_top).await {
+                SpfResult::None => SpfResult::PermError,
+                res => res,
+            });
+        }
+        Ok(SpfResult::Neutral)
+    }
+
+    async fn matches(&mut self, domain: &str, mechanism: &Mechanism) -> Result<bool, Abort> {
+        match mechanism {
+            Mechanism::All => Ok(true),
+            Mechanism::Include(spec) => {
+                let target = self.expand_domain(spec, domain).await;
+                match self.check_host(target, false).await {
+                    SpfResult::Pass => Ok(true),
+                    SpfResult::Fail { .. } | SpfResult::SoftFail | SpfResult::Neutral => Ok(false),
+                    SpfResult::TempError => Err(Abort::Temp),
+                    SpfResult::PermError | SpfResult::None => Err(Abort::Perm),
+                }
+            }
+            Mechanism::A {
+                domain: spec,
+                cidr4,
+                cidr6,
+            } => {
+                let target = match spec {
+                    Some(spec) => self.expand_domain(spec, domain).await,
+                    None => domain.to_owned(),
+                };
+                let ips = self.lookup_ips(&target).await?;
+                self.count_void(&ips)?;
+                Ok(ips
+                    .into_iter()
+                    .any(|ip| ip_in_network(self.query.ip, *cidr4, *cidr6, ip)))
+            }
+            Mechanism::Mx {
+                domain: spec,
+                cidr4,
+                cidr6,
+            } => {
+                let target = match spec {
+                    Some(spec) => self.expand_domain(spec, domain).await,
+                    None => domain.to_owned(),
+                };
+                let mxs = self
+                    .lookup
+                    .lookup_mx(&target)
+                    .await
+                    .map_err(|_| Abort::Temp)?;
+                self.count_void(&mxs)?;
+                if mxs.len() > MAX_NAMES_PER_MECHANISM {
+                    return Err(Abort::Perm);
+                }
+                for mx in mxs {
+                    let ips = self.lookup_ips(&mx).await?;
+                    self.count_void(&ips)?;
+                    if ips
+                        .into_iter()
+                        .any(|ip| ip_in_network(self.query.ip, *cidr4, *cidr6, ip))
+                    {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Mechanism::Ptr(spec) => {
+                let target = match spec {
+                    Some(spec) => self.expand_domain(spec, domain).await,
+                    None => domain.to_owned(),
+                };
+                let target = target.trim_end_matches('.').to_ascii_lowercase();
+                Ok(self
+                    .validated_names(&target)
+                    .await
+                    .into_iter()
+                    .any(|n| n == target || n.ends_with(&format!(".{}", target))))
+            }
+            Mechanism::Ip4 { addr, cidr } => {
+                Ok(ip_in_network(self.query.ip, *cidr, 0, IpAddr::V4(*addr)))
+            }
+            Mechanism::Ip6 { addr, cidr } => {
+                Ok(ip_in_network(self.query.ip, 0, *cidr, IpAddr::V6(*addr)))
+            }
+            Mechanism::Exists(spec) => {
+                let target = self.expand_domain(spec, domain).await;
+                // Always an A lookup, whatever the IP version of the client
+                let ips = self
+                    .lookup
+                    .lookup_a(&target)
+                    .await
+                    .map_err(|_| Abort::Temp)?;
+                self.count_void(&ips)?;
+                Ok(!ips.is_empty())
+            }
+        }
+    }
+
+    async fn lookup_ips(&self, name: &str) -> Result<Vec<IpAddr>, Abort> {
+        Ok(match self.query.ip {
+            IpAddr::V4(_) => self
+                .lookup
+                .lookup_a(name)
+                .await
+                .map_err(|_| Abort::Temp)?
+                .into_iter()
+                .map(IpAddr::V4)
+                .collect(),
+            IpAddr::V6(_) => self
+                .lookup
+                .lookup_aaaa(name)
+                .await
+                .map_err(|_| Abort::Temp)?
+                .into_iter()
+                .map(IpAddr::V6)
+                .collect(),
+        })
+    }
+
+    /// Validated domain names of the client (RFC 7208 §5.5), lowercased and
+    /// without trailing dot, with the names that are subdomains of
+    /// `domain` first
+    async fn validated_names(&self, domain: &str) -> Vec<String> {
+        // DNS errors here are to be ignored
+        let names = self
+            .lookup
+            .lookup_ptr(self.query.ip)
+            .await
+            .unwrap_or_default();
+        let mut res = Vec::new();
+        for name in names.into_iter().take(MAX_NAMES_PER_MECHANISM) {
+            let name = name.trim_end_matches('.').to_ascii_lowercase();
+            if let Ok(ips) = self.lookup_ips(&name).await {
+                if ips.contains(&self.query.ip) {
+                    res.push(name);
+                }
+            }
+        }
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        res.sort_by_key(|n| !(*n == domain || n.ends_with(&format!(".{}", domain))));
+        res
+    }
+
+    /// See RFC 7208 §6.2, any failure results in no explanation
+    async fn explanation(&self, domain: &str, exp: &MacroString) -> Option<String> {
+        let target = self.expand_domain(exp, domain).await;
+        let mut txts = self.lookup.lookup_txt(&target).await.ok()?;
+        if txts.len() != 1 {
+            return None;
+        }
+        let exp = MacroString::parse(&txts.pop().unwrap(), true).ok()?;
+        let validated_name = if exp.needs_validated_name() {
+            self.validated_names(domain).await.into_iter().next()
+        } else {
+            None
+        };
+        let res = exp.expand(&self.macro_context(domain, validated_name.as_deref()));
+        if res.is_ascii() {
+            Some(res)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use futures::executor;
+
+    #[derive(Default)]
+    struct StubLookup {
+        txt: HashMap<&'static str, Vec<&'static str>>,
+        a: HashMap<&'static str, Vec<Ipv4Addr>>,
+        aaaa: HashMap<&'static str, Vec<Ipv6Addr>>,
+        mx: HashMap<&'static str, Vec<&'static str>>,
+        ptr: HashMap<IpAddr, Vec<&'static str>>,
+        failing: Vec<&'static str>,
+    }
+
+    impl StubLookup {
+        fn get<T: Clone>(
+            &self,
+            map: &HashMap<&'static str, Vec<T>>,
+            name: &str,
+        ) -> Result<Vec<T>, LookupError> {
+            let name = name.trim_end_matches('.');
+            if self.failing.contains(&name) {
+                return Err(LookupError {
+                    name: name.to_owned(),
+                    reason: "stub failure".to_owned(),
+                });
+            }
+            Ok(map.get(name).cloned().unwrap_or_default())
+        }
+    }
+
+    #[async_trait]
+    impl Lookup for StubLookup {
+        async fn lookup_txt(&self, name: &str) -> Result<Vec<String>, LookupError> {
+            Ok(self
+                .get(&self.txt, name)?
+                .into_iter()
+                .map(String::from)
+                .collect())
+        }
+
+        async fn lookup_a(&self, name: &str) -> Result<Vec<Ipv4Addr>, LookupError> {
+            self.get(&self.a, name)
+        }
+
+        async fn lookup_aaaa(&self, name: &str) -> Result<Vec<Ipv6Addr>, LookupError> {
+            self.get(&self.aaaa, name)
+        }
+
+        async fn lookup_mx(&self, name: &str) -> Result<Vec<String>, LookupError> {
+            Ok(self
+                .get(&self.mx, name)?
+                .into_iter()
+                .map(String::from)
+                .collect())
+        }
+
+        async fn lookup_ptr(&self, ip: IpAddr) -> Result<Vec<String>, LookupError> {
+            Ok(self
+                .ptr
+                .get(&ip)
+                .cloned()
+                .unwrap_or_default()
+                .into_iter()
+                .map(String::from)
+                .collect())
+        }
+    }
+
+    fn stub() -> StubLookup {
+        let mut l = StubLookup::default();
+        l.txt.insert("example.com", vec![
+            "v=spf1 ip4:192.0.2.0/24 mx -include:bad.example.net ~all exp=exp.example.com",
+        ]);
+        l.txt.insert("exp.example.com", vec![
+            "%{i} is not allowed to send for %{d}",
+        ]);
+        l.txt
+            .insert("bad.example.net", vec!["v=spf1 ip4:198.51.100.66 -all"]);
+        l.mx.insert("example.com", vec!["mx.example.com"]);
+        l.a.insert("mx.example.com", vec![Ipv4Addr::new(203, 0, 113, 25)]);
+        l.aaaa
+            .insert("mx.example.com", vec!["2001:db8::25".parse().unwrap()]);
+        l.txt
+            .insert("redirect.example.org", vec!["v=spf1 redirect=example.com"]);
+        l.txt
+            .insert("twice.example.org", vec!["v=spf1 -all", "v=spf1 +all"]);
+        l.txt.insert("unrelated.example.org", vec![
+            "google-site-verification=foo",
+        ]);
+        l.txt
+            .insert("broken.example.org", vec!["v=spf1 ip4:192.0.2.300 -all"]);
+        l.txt.insert("ptr.example.org", vec!["v=spf1 ptr -all"]);
+        l.ptr
+            .insert("192.0.2.80".parse().unwrap(), vec!["host.ptr.example.org."]);
+        l.a.insert("host.ptr.example.org", vec![Ipv4Addr::new(192, 0, 2, 80)]);
+        l.ptr.insert("192.0.2.81".parse().unwrap(), vec![
+            "forged.ptr.example.org.",
+        ]);
+        l.txt.insert("exists.example.org", vec![
+            "v=spf1 exists:%{l}.users.%{d} -all",
+        ]);
+        l.a.insert("alice.users.exists.example.org", vec![Ipv4Addr::new(
+            127, 0, 0, 2,
+        )]);
+        l.txt
+            .insert("temp.example.org", vec!["v=spf1 a:down.example.org -all"]);
+        l.failing.push("down.example.org");
+        l.txt.insert("loop.example.org", vec![
+            "v=spf1 include:loop.example.org -all",
+        ]);
+        l.txt.insert("void.example.org", vec![
+            "v=spf1 a:n1.example.org a:n2.example.org a:n3.example.org +all",
+        ]);
+        l
+    }
+
+    fn check(domain: &str, localpart: &str, ip: &str) -> SpfResult {
+        let query = Query::mail_from(
+            ip.parse().unwrap(),
+            "client.example.net",
+            Some((localpart, domain)),
+            "mx.example.net",
+        );
+        executor::block_on(check_host(&stub(), &query))
+    }
+
+    #[test]
+    fn evaluation() {
+        let tests: &[(&str, &str, &str, SpfResult)] = &[
+            ("example.com", "user", "192.0.2.10", SpfResult::Pass),
+            ("example.com", "user", "::ffff:192.0.2.10", SpfResult::Pass),
+            ("example.com", "user", "203.0.113.25", SpfResult::Pass),
+            ("example.com", "user", "2001:db8::25", SpfResult::Pass),
+            ("example.com", "user", "2001:db8::26", SpfResult::SoftFail),
+            ("example.com", "user", "198.51.100.1", SpfResult::SoftFail),
+            (
+                "redirect.example.org",
+                "user",
+                "192.0.2.10",
+                SpfResult::Pass,
+            ),
+            (
+                "redirect.example.org",
+                "user",
+                "198.51.100.1",
+                SpfResult::SoftFail,
+            ),
+            (
+                "twice.example.org",
+                "user",
+                "192.0.2.10",
+                SpfResult::PermError,
+            ),
+            (
+                "unrelated.example.org",
+                "user",
+                "192.0.2.10",
+                SpfResult::None,
+            ),
+            ("nothing.example.org", "user", "192.0.2.10", SpfResult::None),
+            ("localhost", "user", "192.0.2.10", SpfResult::None),
+            (
+                "broken.example.org",
+                "user",
+                "192.0.2.10",
+                SpfResult::PermError,
+            ),
+            ("ptr.example.org", "user", "192.0.2.80", SpfResult::Pass),
+            ("ptr.example.org", "user", "192.0.2.81", SpfResult::Fail {
+                explanation: None,
+            }),
+            ("exists.example.org", "alice", "192.0.2.10", SpfResult::Pass),
+            ("exists.example.org", "bob", "192.0.2.10", SpfResult::Fail {
+                explanation: None,
+            }),
+            (
+                "temp.example.org",
+                "user",
+                "192.0.2.10",
+                SpfResult::TempError,
+            ),
+            (
+                "loop.example.org",
+                "user",
+                "192.0.2.10",
+                SpfResult::PermError,
+            ),
+            (
+                "void.example.org",
+                "user",
+                "192.0.2.10",
+                SpfResult::PermError,
+            ),
+        ];
+        for (domain, localpart, ip, res) in tests {
+            println!("Testing {}@{} from {}", localpart, domain, ip);
+            assert_eq!(check(domain, localpart, ip), *res);
+        }
+    }
+
+    #[test]
+    fn explanation() {
+        // An include that matches with a “-” qualifier fails with the explanation of
+        // the including record
+        assert_eq!(
+            check("example.com", "user", "198.51.100.66"),
+            SpfResult::Fail {
+                explanation: Some(
+                    "198.51.100.66 is not allowed to send for example.com".to_owned()
+                )
+            }
+        );
+    }
+
+    #[test]
+    fn helo_identity() {
+        let query = Query::helo(
+            "192.0.2.10".parse().unwrap(),
+            "example.com",
+            "mx.example.net",
+        );
+        assert_eq!(query.sender_localpart, "postmaster");
+        assert_eq!(
+            executor::block_on(check_host(&stub(), &query)),
+            SpfResult::Pass
+        );
+    }
+}
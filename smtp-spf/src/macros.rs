@@ -0,0 +1,353 @@
+use std::{fmt::Write, net::IpAddr};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Token {
+    Literal(String),
+    Macro {
+        letter: u8,
+        url_escape: bool,
+        keep: Option<usize>,
+        reverse: bool,
+        delimiters: String,
+    },
+}
+
+/// A string that can contain SPF macros (RFC 7208 §7)
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MacroString(Vec<Token>);
+
+#[derive(Debug, thiserror::Error)]
+pub enum MacroError {
+    #[error("Lone ‘%’ in macro string")]
+    LonePercent,
+
+    #[error("Unterminated macro expansion")]
+    Unterminated,
+
+    #[error("Invalid macro letter ‘{0}’")]
+    InvalidLetter(char),
+
+    #[error("Invalid macro transformers ‘{0}’")]
+    InvalidTransformers(String),
+
+    #[error("Invalid character ‘{0}’ in macro string")]
+    InvalidCharacter(char),
+
+    #[error("Domain spec does not end with a valid top label")]
+    InvalidDomainEnd,
+}
+
+/// All the information that macros can be expanded to
+pub struct MacroContext<'a> {
+    pub sender_localpart: &'a str,
+    pub sender_domain: &'a str,
+    pub domain: &'a str,
+    pub ip: IpAddr,
+    pub helo: &'a str,
+    pub receiver: &'a str,
+    /// Validated domain name of `ip`, only needed when the macro string
+    /// contains a `%{p}` macro
+    pub validated_name: Option<&'a str>,
+    /// Current time, in seconds since the epoch
+    pub now: u64,
+}
+
+impl MacroString {
+    /// `is_exp` must be set when parsing the explanation string, as it allows
+    /// more macro letters and spaces
+    pub fn parse(s: &str, is_exp: bool) -> Result<MacroString, MacroError> {
+        let mut res = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '%' => match chars.next() {
+                    Some('%') => literal.push('%'),
+                    Some('_') => literal.push(' '),
+                    Some('-') => literal.push_str("%20"),
+                    Some('{') => {
+                        let rest = chars.as_str();
+                        let end = rest.find('}').ok_or(MacroError::Unterminated)?;
+                        if !literal.is_empty() {
+                            res.push(Token::Literal(std::mem::take(&mut literal)));
+                        }
+                        res.push(parse_macro(&rest[..end], is_exp)?);
+                        chars = rest[end + 1..].chars();
+                    }
+                    _ => return Err(MacroError::LonePercent),
+                },
+                ' ' if is_exp => literal.push(' '),
+                '\x21'..='\x7E' => literal.push(c),
+                _ => return Err(MacroError::InvalidCharacter(c)),
+            }
+        }
+        if !literal.is_empty() {
+            res.push(Token::Literal(literal));
+        }
+        Ok(MacroString(res))
+    }
+
+    /// Parses a `domain-spec`, that must in addition end with either a macro or
+    /// a top label
+    pub fn parse_domain_spec(s: &str) -> Result<MacroString, MacroError> {
+        let res = MacroString::parse(s, false)?;
+        match res.0.last() {
+            Some(Token::Macro { .. }) => Ok(res),
+            Some(Token::Literal(l)) => {
+                let l = l.strip_suffix('.').unwrap_or(l);
+                match l.rfind('.') {
+                    Some(i) if is_toplabel(&l[i + 1..]) => Ok(res),
+                    _ => Err(MacroError::InvalidDomainEnd),
+                }
+            }
+            None => Err(MacroError::InvalidDomainEnd),
+        }
+    }
+
+    pub fn needs_validated_name(&self) -> bool {
+        self.0
+            .iter()
+            .any(|t| matches!(t, Token::Macro { letter: b'p', .. }))
+    }
+
+    pub fn expand(&self, ctx: &MacroContext) -> String {
+        let mut res = String::new();
+        for t in &self.0 {
+            match t {
+                Token::Literal(l) => res.push_str(l),
+                Token::Macro {
+                    letter,
+                    url_escape,
+                    keep,
+                    reverse,
+                    delimiters,
+                } => {
+                    let value = macro_value(*letter, ctx);
+                    let delimiters = if delimiters.is_empty() {
+                        "."
+                    } else {
+                        delimiters
+                    };
+                    let mut parts = value.split(|c| delimiters.contains(c)).collect::<Vec<_>>();
+                    if *reverse {
+                        parts.reverse();
+                    }
+                    if let Some(keep) = keep {
+                        let skip = parts.len().saturating_sub(*keep);
+                        parts.drain(..skip);
+                    }
+                    let value = parts.join(".");
+                    if *url_escape {
+                        push_url_escaped(&mut res, &value);
+                    } else {
+                        res.push_str(&value);
+                    }
+                }
+            }
+        }
+        res
+    }
+}
+
+fn parse_macro(m: &str, is_exp: bool) -> Result<Token, MacroError> {
+    let mut chars = m.chars();
+    let letter = chars.next().ok_or(MacroError::InvalidLetter('}'))?;
+    let allowed = match letter.to_ascii_lowercase() {
+        's' | 'l' | 'o' | 'd' | 'i' | 'p' | 'h' | 'v' => true,
+        'c' | 'r' | 't' => is_exp,
+        _ => false,
+    };
+    if !allowed {
+        return Err(MacroError::InvalidLetter(letter));
+    }
+    let rest = chars.as_str();
+    let digits_end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    let keep = match &rest[..digits_end] {
+        "" => None,
+        d => match d.parse::<usize>() {
+            Ok(n) if n > 0 => Some(n),
+            _ => return Err(MacroError::InvalidTransformers(rest.to_owned())),
+        },
+    };
+    let mut rest = &rest[digits_end..];
+    let reverse = match rest.strip_prefix(['r', 'R']) {
+        Some(r) => {
+            rest = r;
+            true
+        }
+        None => false,
+    };
+    if !rest.chars().all(|c| ".-+,/_=".contains(c)) {
+        return Err(MacroError::InvalidTransformers(m[1..].to_owned()));
+    }
+    Ok(Token::Macro {
+        letter: letter.to_ascii_lowercase() as u8,
+        url_escape: letter.is_ascii_uppercase(),
+        keep,
+        reverse,
+        delimiters: rest.to_owned(),
+    })
+}
+
+fn is_toplabel(l: &str) -> bool {
+    !l.is_empty()
+        && l.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        && !l.bytes().all(|b| b.is_ascii_digit())
+        && !l.starts_with('-')
+        && !l.ends_with('-')
+}
+
+fn macro_value(letter: u8, ctx: &MacroContext) -> String {
+    match letter {
+        b's' => format!("{}@{}", ctx.sender_localpart, ctx.sender_domain),
+        b'l' => ctx.sender_localpart.to_owned(),
+        b'o' => ctx.sender_domain.to_owned(),
+        b'd' => ctx.domain.to_owned(),
+        b'i' => match ctx.ip {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => {
+                let mut res = String::with_capacity(63);
+                for b in ip.octets().iter() {
+                    if !res.is_empty() {
+                        res.push('.');
+                    }
+                    write!(res, "{:x}.{:x}", b >> 4, b & 0xF).unwrap();
+                }
+                res
+            }
+        },
+        b'p' => ctx.validated_name.unwrap_or("unknown").to_owned(),
+        b'v' => match ctx.ip {
+            IpAddr::V4(_) => "in-addr".to_owned(),
+            IpAddr::V6(_) => "ip6".to_owned(),
+        },
+        b'h' => ctx.helo.to_owned(),
+        b'c' => ctx.ip.to_string(),
+        b'r' => ctx.receiver.to_owned(),
+        b't' => ctx.now.to_string(),
+        _ => unreachable!("macro letters are validated when parsing"),
+    }
+}
+
+fn push_url_escaped(res: &mut String, s: &str) {
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            res.push(b as char);
+        } else {
+            write!(res, "%{:02X}", b).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ctx(ip: IpAddr) -> MacroContext<'static> {
+        MacroContext {
+            sender_localpart: "strong-bad",
+            sender_domain: "email.example.com",
+            domain: "email.example.com",
+            ip,
+            helo: "mx.example.org",
+            receiver: "mx.example.net",
+            validated_name: None,
+            now: 1_000_000_000,
+        }
+    }
+
+    #[test]
+    fn rfc7208_examples() {
+        // See RFC 7208 §7.4
+        let tests: &[(&str, &str)] = &[
+            ("%{s}", "strong-bad@email.example.com"),
+            ("%{o}", "email.example.com"),
+            ("%{d}", "email.example.com"),
+            ("%{d4}", "email.example.com"),
+            ("%{d3}", "email.example.com"),
+            ("%{d2}", "example.com"),
+            ("%{d1}", "com"),
+            ("%{dr}", "com.example.email"),
+            ("%{d2r}", "example.email"),
+            ("%{l}", "strong-bad"),
+            ("%{l-}", "strong.bad"),
+            ("%{lr}", "strong-bad"),
+            ("%{lr-}", "bad.strong"),
+            ("%{l1r-}", "strong"),
+            (
+                "%{ir}.%{v}._spf.%{d2}",
+                "3.2.0.192.in-addr._spf.example.com",
+            ),
+            ("%{lr-}.lp._spf.%{d2}", "bad.strong.lp._spf.example.com"),
+            (
+                "%{lr-}.lp.%{ir}.%{v}._spf.%{d2}",
+                "bad.strong.lp.3.2.0.192.in-addr._spf.example.com",
+            ),
+            (
+                "%{ir}.%{v}.%{l1r-}.lp._spf.%{d2}",
+                "3.2.0.192.in-addr.strong.lp._spf.example.com",
+            ),
+            (
+                "%{d2}.trusted-domains.example.net",
+                "example.com.trusted-domains.example.net",
+            ),
+            ("%{p}.%%.%_.%-", "unknown.%. .%20"),
+            ("%{S}", "strong-bad%40email.example.com"),
+        ];
+        for (inp, out) in tests {
+            println!("Testing {:?}", inp);
+            let m = MacroString::parse(inp, false).unwrap();
+            assert_eq!(m.expand(&ctx("192.0.2.3".parse().unwrap())), *out);
+        }
+    }
+
+    #[test]
+    fn rfc7208_ipv6_example() {
+        let m = MacroString::parse("%{ir}.%{v}._spf.%{d2}", false).unwrap();
+        assert_eq!(
+            m.expand(&ctx("2001:db8::cb01".parse().unwrap())),
+            "1.0.b.c.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6._spf.example.com"
+        );
+    }
+
+    #[test]
+    fn explanation_macros() {
+        let m = MacroString::parse("%{i} is not one of %{d}'s servers (%{r}, %{t})", true).unwrap();
+        assert_eq!(
+            m.expand(&ctx("192.0.2.3".parse().unwrap())),
+            "192.0.2.3 is not one of email.example.com's servers (mx.example.net, 1000000000)"
+        );
+        assert!(MacroString::parse("%{r}", false).is_err());
+        assert!(MacroString::parse("a b", false).is_err());
+    }
+
+    #[test]
+    fn invalid_macro_strings() {
+        let tests: &[&str] = &["%", "%a", "%{d", "%{x}", "%{d0}", "%{d2q}", "%{}"];
+        for inp in tests {
+            println!("Testing {:?}", inp);
+            assert!(MacroString::parse(inp, false).is_err());
+        }
+    }
+
+    #[test]
+    fn domain_specs() {
+        let tests: &[(&str, bool)] = &[
+            ("example.com", true),
+            ("example.com.", true),
+            ("%{d}", true),
+            ("%{ir}.%{v}._spf.%{d2}", true),
+            ("_spf.%{d}.com", true),
+            ("example", false),
+            ("example.123", false),
+            ("example.-com", false),
+            ("", false),
+        ];
+        for (inp, out) in tests {
+            println!("Testing {:?}", inp);
+            assert_eq!(MacroString::parse_domain_spec(inp).is_ok(), *out);
+        }
+    }
+}
@@ -0,0 +1,405 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::macros::MacroString;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Qualifier {
+    Pass,
+    Fail,
+    SoftFail,
+    Neutral,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Mechanism {
+    All,
+    Include(MacroString),
+    A {
+        domain: Option<MacroString>,
+        cidr4: u8,
+        cidr6: u8,
+    },
+    Mx {
+        domain: Option<MacroString>,
+        cidr4: u8,
+        cidr6: u8,
+    },
+    Ptr(Option<MacroString>),
+    Ip4 {
+        addr: Ipv4Addr,
+        cidr: u8,
+    },
+    Ip6 {
+        addr: Ipv6Addr,
+        cidr: u8,
+    },
+    Exists(MacroString),
+}
+
+impl Mechanism {
+    /// Whether evaluating this mechanism counts towards the limit of 10 DNS
+    /// lookups (RFC 7208 §4.6.4)
+    pub fn does_lookup(&self) -> bool {
+        matches!(
+            self,
+            Mechanism::Include(_)
+                | Mechanism::A { .. }
+                | Mechanism::Mx { .. }
+                | Mechanism::Ptr(_)
+                | Mechanism::Exists(_)
+        )
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Directive {
+    pub qualifier: Qualifier,
+    pub mechanism: Mechanism,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Record {
+    pub directives: Vec<Directive>,
+    pub redirect: Option<MacroString>,
+    pub exp: Option<MacroString>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseError {
+    #[error("Record does not start with ‘v=spf1’")]
+    NotSpf,
+
+    #[error("Invalid term ‘{0}’")]
+    InvalidTerm(String),
+
+    #[error("Unknown mechanism ‘{0}’")]
+    UnknownMechanism(String),
+
+    #[error("Invalid CIDR length in ‘{0}’")]
+    InvalidCidr(String),
+
+    #[error("Invalid IP address in ‘{0}’")]
+    InvalidIp(String),
+
+    #[error("Invalid macro string ‘{0}’")]
+    InvalidMacroString(String),
+
+    #[error("Modifier ‘{0}’ appears more than once")]
+    DuplicateModifier(&'static str),
+}
+
+/// Returns whether `txt` looks like an SPF record, ie. starts with the version
+/// tag
+pub fn is_spf(txt: &str) -> bool {
+    let b = txt.as_bytes();
+    b.len() >= 6 && b[..6].eq_ignore_ascii_case(b"v=spf1") && (b.len() == 6 || b[6] == b' ')
+}
+
+impl Record {
+    pub fn parse(txt: &str) -> Result<Record, ParseError> {
+        if !is_spf(txt) {
+            return Err(ParseError::NotSpf);
+        }
+        let mut res = Record {
+            directives: Vec::new(),
+            redirect: None,
+            exp: None,
+        };
+        for term in txt[6..].split(' ').filter(|t| !t.is_empty()) {
+            if let Some(eq) = term.find('=') {
+                // Modifiers are “name=value”, and mechanisms can never have a ‘=’ in their
+                // name part
+                let (name, value) = (&term[..eq], &term[eq + 1..]);
+                if name.contains([':', '/']) {
+                    // eg. “exists:foo=bar.example.org”, ie. a mechanism with a ‘=’ in its
+                    // domain-spec
+                    res.directives.push(parse_directive(term)?);
+                    continue;
+                }
+                if !is_modifier_name(name) {
+                    return Err(ParseError::InvalidTerm(term.to_owned()));
+                }
+                let invalid = |_| ParseError::InvalidMacroString(value.to_owned());
+                if name.eq_ignore_ascii_case("redirect") {
+                    if res.redirect.is_some() {
+                        return Err(ParseError::DuplicateModifier("redirect"));
+                    }
+                    res.redirect = Some(MacroString::parse_domain_spec(value).map_err(invalid)?);
+                } else if name.eq_ignore_ascii_case("exp") {
+                    if res.exp.is_some() {
+                        return Err(ParseError::DuplicateModifier("exp"));
+                    }
+                    res.exp = Some(MacroString::parse_domain_spec(value).map_err(invalid)?);
+                } else {
+                    // Unknown modifiers MUST be ignored (RFC 7208 §6), but still have to be
+                    // syntactically valid
+                    MacroString::parse(value, false).map_err(invalid)?;
+                }
+            } else {
+                res.directives.push(parse_directive(term)?);
+            }
+        }
+        Ok(res)
+    }
+}
+
+fn is_modifier_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+fn parse_directive(term: &str) -> Result<Directive, ParseError> {
+    let (qualifier, rest) = match term.as_bytes().first() {
+        Some(b'+') => (Qualifier::Pass, &term[1..]),
+        Some(b'-') => (Qualifier::Fail, &term[1..]),
+        Some(b'~') => (Qualifier::SoftFail, &term[1..]),
+        Some(b'?') => (Qualifier::Neutral, &term[1..]),
+        _ => (Qualifier::Pass, term),
+    };
+    let name_end = rest.find([':', '/']).unwrap_or(rest.len());
+    let (name, args) = (&rest[..name_end], &rest[name_end..]);
+    let mechanism = if name.eq_ignore_ascii_case("all") {
+        if !args.is_empty() {
+            return Err(ParseError::InvalidTerm(term.to_owned()));
+        }
+        Mechanism::All
+    } else if name.eq_ignore_ascii_case("include") {
+        Mechanism::Include(required_domain(term, args)?)
+    } else if name.eq_ignore_ascii_case("a") || name.eq_ignore_ascii_case("mx") {
+        let (args, cidr4, cidr6) = parse_dual_cidr(term, args)?;
+        let domain = optional_domain(term, args)?;
+        if name.eq_ignore_ascii_case("a") {
+            Mechanism::A {
+                domain,
+                cidr4,
+                cidr6,
+            }
+        } else {
+            Mechanism::Mx {
+                domain,
+                cidr4,
+                cidr6,
+            }
+        }
+    } else if name.eq_ignore_ascii_case("ptr") {
+        Mechanism::Ptr(optional_domain(term, args)?)
+    } else if name.eq_ignore_ascii_case("ip4") {
+        let args = args
+            .strip_prefix(':')
+            .ok_or_else(|| ParseError::InvalidTerm(term.to_owned()))?;
+        let (addr, cidr) = split_cidr(term, args, 32)?;
+        Mechanism::Ip4 {
+            addr: addr
+                .parse()
+                .map_err(|_| ParseError::InvalidIp(term.to_owned()))?,
+            cidr,
+        }
+    } else if name.eq_ignore_ascii_case("ip6") {
+        let args = args
+            .strip_prefix(':')
+            .ok_or_else(|| ParseError::InvalidTerm(term.to_owned()))?;
+        let (addr, cidr) = split_cidr(term, args, 128)?;
+        Mechanism::Ip6 {
+            addr: addr
+                .parse()
+                .map_err(|_| ParseError::InvalidIp(term.to_owned()))?,
+            cidr,
+        }
+    } else if name.eq_ignore_ascii_case("exists") {
+        Mechanism::Exists(required_domain(term, args)?)
+    } else {
+        return Err(ParseError::UnknownMechanism(term.to_owned()));
+    };
+    Ok(Directive {
+        qualifier,
+        mechanism,
+    })
+}
+
+fn required_domain(term: &str, args: &str) -> Result<MacroString, ParseError> {
+    optional_domain(term, args)?.ok_or_else(|| ParseError::InvalidTerm(term.to_owned()))
+}
+
+fn optional_domain(term: &str, args: &str) -> Result<Option<MacroString>, ParseError> {
+    if args.is_empty() {
+        return Ok(None);
+    }
+    let domain = args
+        .strip_prefix(':')
+        .filter(|d| !d.is_empty())
+        .ok_or_else(|| ParseError::InvalidTerm(term.to_owned()))?;
+    MacroString::parse_domain_spec(domain)
+        .map(Some)
+        .map_err(|_| ParseError::InvalidMacroString(domain.to_owned()))
+}
+
+fn parse_cidr(term: &str, s: &str, max: u8) -> Result<u8, ParseError> {
+    // Leading zeroes are forbidden by the ABNF
+    if s.is_empty() || (s.len() > 1 && s.starts_with('0')) || !s.bytes().all(|b| b.is_ascii_digit())
+    {
+        return Err(ParseError::InvalidCidr(term.to_owned()));
+    }
+    match s.parse::<u8>() {
+        Ok(c) if c <= max => Ok(c),
+        _ => Err(ParseError::InvalidCidr(term.to_owned())),
+    }
+}
+
+fn split_cidr<'a>(term: &str, s: &'a str, max: u8) -> Result<(&'a str, u8), ParseError> {
+    match s.find('/') {
+        None => Ok((s, max)),
+        Some(i) => Ok((&s[..i], parse_cidr(term, &s[i + 1..], max)?)),
+    }
+}
+
+/// Splits the `[ ip4-cidr-length ] [ "/" ip6-cidr-length ]` suffix off `args`
+fn parse_dual_cidr<'a>(term: &str, args: &'a str) -> Result<(&'a str, u8, u8), ParseError> {
+    let mut args = args;
+    let mut cidr6 = 128;
+    if let Some(i) = args.rfind("//") {
+        cidr6 = parse_cidr(term, &args[i + 2..], 128)?;
+        args = &args[..i];
+    }
+    let mut cidr4 = 32;
+    if let Some(i) = args.rfind('/') {
+        let suffix = &args[i + 1..];
+        // A domain-spec could contain a ‘/’ in a macro's delimiters, only consider
+        // digits as a CIDR length
+        if !suffix.is_empty() && suffix.bytes().all(|b| b.is_ascii_digit()) {
+            cidr4 = parse_cidr(term, suffix, 32)?;
+            args = &args[..i];
+        }
+    }
+    Ok((args, cidr4, cidr6))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_detection() {
+        let tests: &[(&str, bool)] = &[
+            ("v=spf1", true),
+            ("v=spf1 -all", true),
+            ("V=SPF1 -all", true),
+            ("v=spf10 -all", false),
+            ("v=spf2.0/pra", false),
+            ("spf1", false),
+            ("", false),
+        ];
+        for (inp, out) in tests {
+            println!("Testing {:?}", inp);
+            assert_eq!(is_spf(inp), *out);
+        }
+    }
+
+    #[test]
+    fn valid_records() {
+        let tests: &[(&str, Record)] = &[
+            ("v=spf1 -all", Record {
+                directives: vec![Directive {
+                    qualifier: Qualifier::Fail,
+                    mechanism: Mechanism::All,
+                }],
+                redirect: None,
+                exp: None,
+            }),
+            (
+                "v=spf1  ip4:192.0.2.0/24 ~ip6:2001:db8::/32   ?a mx:example.org/28//64 ptr",
+                Record {
+                    directives: vec![
+                        Directive {
+                            qualifier: Qualifier::Pass,
+                            mechanism: Mechanism::Ip4 {
+                                addr: Ipv4Addr::new(192, 0, 2, 0),
+                                cidr: 24,
+                            },
+                        },
+                        Directive {
+                            qualifier: Qualifier::SoftFail,
+                            mechanism: Mechanism::Ip6 {
+                                addr: "2001:db8::".parse().unwrap(),
+                                cidr: 32,
+                            },
+                        },
+                        Directive {
+                            qualifier: Qualifier::Neutral,
+                            mechanism: Mechanism::A {
+                                domain: None,
+                                cidr4: 32,
+                                cidr6: 128,
+                            },
+                        },
+                        Directive {
+                            qualifier: Qualifier::Pass,
+                            mechanism: Mechanism::Mx {
+                                domain: Some(MacroString::parse("example.org", false).unwrap()),
+                                cidr4: 28,
+                                cidr6: 64,
+                            },
+                        },
+                        Directive {
+                            qualifier: Qualifier::Pass,
+                            mechanism: Mechanism::Ptr(None),
+                        },
+                    ],
+                    redirect: None,
+                    exp: None,
+                },
+            ),
+            (
+                "v=spf1 a//64 include:_spf.example.com exp=explain.%{d} redirect=_spf.%{d} foo=bar",
+                Record {
+                    directives: vec![
+                        Directive {
+                            qualifier: Qualifier::Pass,
+                            mechanism: Mechanism::A {
+                                domain: None,
+                                cidr4: 32,
+                                cidr6: 64,
+                            },
+                        },
+                        Directive {
+                            qualifier: Qualifier::Pass,
+                            mechanism: Mechanism::Include(
+                                MacroString::parse("_spf.example.com", false).unwrap(),
+                            ),
+                        },
+                    ],
+                    redirect: Some(MacroString::parse("_spf.%{d}", false).unwrap()),
+                    exp: Some(MacroString::parse("explain.%{d}", false).unwrap()),
+                },
+            ),
+        ];
+        for (inp, out) in tests {
+            println!("Testing {:?}", inp);
+            assert_eq!(Record::parse(inp).unwrap(), *out);
+        }
+    }
+
+    #[test]
+    fn invalid_records() {
+        let tests: &[&str] = &[
+            "v=spf2 -all",
+            "v=spf1 -all:foo",
+            "v=spf1 include",
+            "v=spf1 include:",
+            "v=spf1 ip4:192.0.2.0/33",
+            "v=spf1 ip4:192.0.2.0/024",
+            "v=spf1 ip6:192.0.2.0",
+            "v=spf1 ip4",
+            "v=spf1 a/",
+            "v=spf1 foo",
+            "v=spf1 redirect=a.example redirect=b.example",
+            "v=spf1 exp=a.example exp=b.example",
+            "v=spf1 1foo=bar",
+            "v=spf1 exists:%{z}.example.com",
+        ];
+        for inp in tests {
+            println!("Testing {:?}", inp);
+            assert!(Record::parse(inp).is_err());
+        }
+    }
+}